@@ -1,6 +1,15 @@
-use std::ops::Range;
+use std::{ops::Range, sync::Arc, time::Duration};
 
-use crate::target::Target;
+use crate::{
+    discovery::{HostDiscoveryMethod, HostDiscoveryPolicy},
+    err::PortscanErr,
+    service_detection::{
+        framework::{RuleLoudness, ServiceDetectionIntensity},
+        signatures::{parse_nmap_service_probes, parse_signatures, SignatureProbe},
+    },
+    target::Target,
+    traceroute::TracerouteMethod,
+};
 
 /// A [builder pattern](https://en.wikipedia.org/wiki/Builder_pattern) implementation to set all
 /// parameters for a scan.
@@ -9,10 +18,24 @@ pub struct ConfigBuilder {
     pub(crate) targets: Vec<Target>,
     pub(crate) ports: Vec<u16>,
     pub(crate) run_service_detection: bool,
-    pub(crate) ping: bool,
+    pub(crate) signatures: Vec<Arc<SignatureProbe>>,
+    pub(crate) service_detection_max_loudness: RuleLoudness,
+    pub(crate) service_detection_intensity: ServiceDetectionIntensity,
+    pub(crate) service_detection_rule_timeout: Duration,
+    pub(crate) service_detection_retries: u8,
+    pub(crate) record_service_detection_evidence: bool,
+    pub(crate) run_os_detection: bool,
+    pub(crate) host_discovery_methods: Vec<HostDiscoveryMethod>,
+    pub(crate) host_discovery_policy: HostDiscoveryPolicy,
+    pub(crate) ping_count: u16,
+    pub(crate) ping_interval: Duration,
+    pub(crate) ping_timeout: Duration,
     pub(crate) tracing: bool,
     pub(crate) throttle_range: Option<Range<u64>>,
     pub(crate) max_in_flight: u32,
+    pub(crate) traceroute: Option<TracerouteMethod>,
+    pub(crate) traceroute_max_hops: u8,
+    pub(crate) traceroute_timeout: Duration,
 }
 
 impl Default for ConfigBuilder {
@@ -23,10 +46,24 @@ impl Default for ConfigBuilder {
             targets: vec![],
             ports: vec![80],
             run_service_detection: false,
-            ping: false,
+            signatures: vec![],
+            service_detection_max_loudness: RuleLoudness::BangingTogetherPotsAndPans,
+            service_detection_intensity: ServiceDetectionIntensity::Exhaustive,
+            service_detection_rule_timeout: Duration::from_secs(30),
+            service_detection_retries: 1,
+            record_service_detection_evidence: false,
+            run_os_detection: false,
+            host_discovery_methods: vec![],
+            host_discovery_policy: HostDiscoveryPolicy::ScanAll,
+            ping_count: 1,
+            ping_interval: Duration::from_secs(1),
+            ping_timeout: Duration::from_millis(500),
             tracing: false,
             throttle_range: None,
             max_in_flight: 500_000,
+            traceroute: None,
+            traceroute_max_hops: 30,
+            traceroute_timeout: Duration::from_secs(2),
         }
     }
 }
@@ -48,9 +85,120 @@ impl ConfigBuilder {
         self.run_service_detection = run_service_detection;
     }
 
-    /// Set if we should ping each target before scanning or not.
+    /// Set the loudest service detection rules we are willing to run.  Rules
+    /// louder than this are skipped along with any rules depending on them.
+    /// By default every rule is allowed.
+    pub fn set_max_service_detection_loudness(&mut self, max_loudness: RuleLoudness) {
+        self.service_detection_max_loudness = max_loudness;
+    }
+
+    /// Set how hard service detection tries on each port.  Lower intensities
+    /// only run rules for services that are likely to be on the port.  By
+    /// default every rule with a hint for the port is run.
+    pub fn set_service_detection_intensity(&mut self, intensity: ServiceDetectionIntensity) {
+        self.service_detection_intensity = intensity;
+    }
+
+    /// Set how long a single service detection rule may run against a port
+    /// before we give up on it.  A rule that times out is recorded as failed
+    /// and rules depending on it are skipped.
+    pub fn set_service_detection_rule_timeout(&mut self, timeout: Duration) {
+        self.service_detection_rule_timeout = timeout;
+    }
+
+    /// Set how many times to retry a service detection rule that failed with
+    /// a transient IO error, like a reset connection.  Other failures and
+    /// timeouts are never retried.
+    pub fn set_service_detection_retries(&mut self, retries: u8) {
+        self.service_detection_retries = retries;
+    }
+
+    /// Set if each port should carry a record of every service detection rule
+    /// run against it, how long it took and what it saw or why it failed.
+    /// This is useful to see why a service was or wasn't identified.
+    pub fn set_record_service_detection_evidence(&mut self, record_evidence: bool) {
+        self.record_service_detection_evidence = record_evidence;
+    }
+
+    /// Load service detection signatures, in the TOML format described in
+    /// the `signatures` module, on top of the ones built in.  A probe with the
+    /// same name as one that is already loaded, built in or not, replaces it.
+    /// If anything in the signatures is invalid none of them are loaded.
+    pub fn add_service_detection_signatures(
+        &mut self,
+        signatures: &str,
+    ) -> Result<(), PortscanErr> {
+        let probes = parse_signatures(signatures)?;
+        self.load_signatures(probes);
+        Ok(())
+    }
+
+    /// Load probes from the contents of an nmap `nmap-service-probes` file.
+    /// They are named after the nmap probe with an `nmap-` prefix and replace
+    /// any loaded probe with the same name.  UDP probes and matches whose
+    /// regex the `regex` crate can't compile are skipped.  If any line is
+    /// malformed none of the probes are loaded.
+    pub fn add_nmap_service_probes(&mut self, probes: &str) -> Result<(), PortscanErr> {
+        let probes = parse_nmap_service_probes(probes)?;
+        self.load_signatures(probes);
+        Ok(())
+    }
+
+    /// Set if we should guess each host's OS from how it answered host
+    /// discovery.  This doesn't send anything extra but it needs an ICMP echo
+    /// or TCP SYN ping to have something to go on.  The TCP SYN ping gives the
+    /// best guesses.
+    pub fn set_run_os_detection(&mut self, run_os_detection: bool) {
+        self.run_os_detection = run_os_detection;
+    }
+
+    /// Set if we should ping each target before scanning or not.  This is a
+    /// shortcut for adding or removing [`HostDiscoveryMethod::IcmpEcho`].
     pub fn set_ping(&mut self, ping: bool) {
-        self.ping = ping;
+        if ping {
+            self.add_host_discovery_method(HostDiscoveryMethod::IcmpEcho);
+        } else {
+            self.host_discovery_methods
+                .retain(|method| *method != HostDiscoveryMethod::IcmpEcho);
+        }
+    }
+
+    /// Replace the list of methods used to check if each target is up before
+    /// scanning it.  Every method in the list is run against every target.
+    pub fn set_host_discovery_methods(&mut self, methods: Vec<HostDiscoveryMethod>) {
+        self.host_discovery_methods = methods;
+    }
+
+    /// Set which hosts get port scanned based on what host discovery found.
+    /// Hosts that are skipped still get a report with their discovery results.
+    pub fn set_host_discovery_policy(&mut self, policy: HostDiscoveryPolicy) {
+        self.host_discovery_policy = policy;
+    }
+
+    /// Add a method to check if each target is up before scanning it.  Adding
+    /// a method that is already in the list does nothing.
+    pub fn add_host_discovery_method(&mut self, method: HostDiscoveryMethod) {
+        if !self.host_discovery_methods.contains(&method) {
+            self.host_discovery_methods.push(method);
+        }
+    }
+
+    /// Set the number of echo requests sent to each target when pinging.  A
+    /// target is considered up if any of them get a reply.
+    pub fn set_ping_count(&mut self, ping_count: u16) {
+        self.ping_count = ping_count;
+    }
+
+    /// Set how long to wait between each round of echo requests.
+    pub fn set_ping_interval(&mut self, ping_interval: Duration) {
+        self.ping_interval = ping_interval;
+    }
+
+    /// Set how long to wait for replies after the last echo request is sent.
+    /// This is also how long the TCP based discovery methods wait for an
+    /// answer.
+    pub fn set_ping_timeout(&mut self, ping_timeout: Duration) {
+        self.ping_timeout = ping_timeout;
     }
 
     /// Enable or disable extremely detailed internal logging.  This is only
@@ -75,4 +223,30 @@ impl ConfigBuilder {
     pub fn set_max_in_flight(&mut self, max_in_flight: u32) {
         self.max_in_flight = max_in_flight;
     }
+
+    /// Trace the route to every host that gets a report using the given kind
+    /// of probe.  Pass `None` to turn traceroute off.  This needs privileged
+    /// access.
+    pub fn set_traceroute(&mut self, method: Option<TracerouteMethod>) {
+        self.traceroute = method;
+    }
+
+    /// Set the highest TTL, or hop limit for IPv6, to probe with when tracing
+    /// a route.
+    pub fn set_traceroute_max_hops(&mut self, max_hops: u8) {
+        self.traceroute_max_hops = max_hops;
+    }
+
+    /// Set how long to wait for routers to answer after the last traceroute
+    /// probe is sent.
+    pub fn set_traceroute_timeout(&mut self, timeout: Duration) {
+        self.traceroute_timeout = timeout;
+    }
+
+    fn load_signatures(&mut self, probes: Vec<SignatureProbe>) {
+        for probe in probes {
+            self.signatures.retain(|loaded| loaded.name != probe.name);
+            self.signatures.push(Arc::new(probe));
+        }
+    }
 }
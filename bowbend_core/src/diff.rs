@@ -0,0 +1,433 @@
+//! Tools for comparing the results of two scans.  This is useful when the same
+//! scan is run on a schedule and we want to know what changed between runs.
+//! Scans are first boiled down into a [`ScanSnapshot`], which can be saved to
+//! and loaded from disk, and then two snapshots can be compared to produce a
+//! list of [`ScanChange`]s.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io,
+    io::{BufReader, BufWriter},
+    net::IpAddr,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    icmp::PingResultType,
+    report::{PortStatus, Report},
+    target::Target,
+};
+
+/// A serializable summary of a full scan.  This only holds onto the details we
+/// need to diff two scans, not everything found in a [`Report`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanSnapshot {
+    /// One entry for each host we were able to scan.
+    pub hosts: Vec<HostSnapshot>,
+}
+
+/// A summary of everything we learned about one host.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HostSnapshot {
+    /// The original target as provided by the user
+    pub target: Target,
+    /// The IP that was actually scanned
+    pub ip: IpAddr,
+    /// If the host showed any sign of life.  Either it replied to a ping or
+    /// had at least one open port.
+    pub up: bool,
+    /// The state of every port scanned on the host
+    pub ports: BTreeMap<u16, PortSnapshot>,
+}
+
+/// A summary of what we learned about one port.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PortSnapshot {
+    /// If it is open, closed, filtered, etc
+    pub status: PortStatus,
+    /// If TLS or SSL was enabled on the port, if we checked.
+    pub tls_enabled: Option<bool>,
+    /// All services detected on the port.  This is `None` if service detection
+    /// wasn't run.
+    pub services: Option<Vec<ServiceSnapshot>>,
+}
+
+/// The name and version of a detected service.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct ServiceSnapshot {
+    /// A human readable name of the service
+    pub name: String,
+    /// The version of the service, if we were able to detect it.
+    pub version: Option<String>,
+}
+
+/// Hosts are matched up between scans by their IP and the original target used
+/// to find it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HostKey {
+    /// The original target as provided by the user
+    pub target: Target,
+    /// The IP that was actually scanned
+    pub ip: IpAddr,
+}
+
+/// One difference found between two scans.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScanChange {
+    /// The host was down or missing in the old scan, but is up in the new one.
+    HostAppeared(HostKey),
+    /// The host was up in the old scan, but is down or missing in the new one.
+    HostDisappeared(HostKey),
+    /// A port that wasn't open in the old scan is now open.
+    PortOpened {
+        /// The host the port belongs to
+        host: HostKey,
+        /// The port that changed
+        port: u16,
+    },
+    /// A port that was open in the old scan no longer is.
+    PortClosed {
+        /// The host the port belongs to
+        host: HostKey,
+        /// The port that changed
+        port: u16,
+    },
+    /// The services detected on an open port changed.  Either the name or the
+    /// version of a service is different.
+    ServiceChanged {
+        /// The host the port belongs to
+        host: HostKey,
+        /// The port that changed
+        port: u16,
+        /// The services detected in the old scan
+        before: Vec<ServiceSnapshot>,
+        /// The services detected in the new scan
+        after: Vec<ServiceSnapshot>,
+    },
+    /// TLS was enabled or disabled on an open port.
+    TlsChanged {
+        /// The host the port belongs to
+        host: HostKey,
+        /// The port that changed
+        port: u16,
+        /// If TLS was enabled in the old scan
+        before: bool,
+        /// If TLS is enabled in the new scan
+        after: bool,
+    },
+}
+
+impl ScanSnapshot {
+    /// Build a snapshot from the reports produced by a scan.  Reports for
+    /// targets we failed to scan, for example hostnames that failed to
+    /// resolve, are left out.
+    pub fn from_reports<'a>(reports: impl IntoIterator<Item = &'a Report>) -> Self {
+        let hosts = reports
+            .into_iter()
+            .filter_map(|report| {
+                let instance = report.instance.as_ref()?;
+                let contents = report.contents.as_ref().ok()?;
+                let ports: BTreeMap<u16, PortSnapshot> =
+                    contents
+                        .ports
+                        .iter()
+                        .flatten()
+                        .map(|(port, port_report)| {
+                            let services = port_report.service_detection_conclusions.as_ref().map(
+                                |conclusions| {
+                                    conclusions
+                                        .iter()
+                                        .map(|conclusion| ServiceSnapshot {
+                                            name: conclusion.service_name.clone(),
+                                            version: conclusion.service_version.clone(),
+                                        })
+                                        .collect()
+                                },
+                            );
+                            (
+                                *port,
+                                PortSnapshot {
+                                    status: port_report.status,
+                                    tls_enabled: port_report.tls_enabled,
+                                    services,
+                                },
+                            )
+                        })
+                        .collect();
+                let replied_to_ping = contents
+                    .icmp
+                    .as_ref()
+                    .map(|ping| matches!(ping.result_type, PingResultType::Reply(_)))
+                    .unwrap_or(false);
+                let answered_tcp_ping = [
+                    &contents.tcp_syn_ping,
+                    &contents.tcp_ack_ping,
+                    &contents.tcp_connect_ping,
+                ]
+                .into_iter()
+                .flatten()
+                .any(|ping| ping.host_up());
+                let answered_arp = contents
+                    .arp
+                    .as_ref()
+                    .map(|arp| arp.host_up())
+                    .unwrap_or(false);
+                let up = replied_to_ping
+                    || answered_tcp_ping
+                    || answered_arp
+                    || ports.values().any(|port| port.status == PortStatus::Open);
+                Some(HostSnapshot {
+                    target: report.target.clone(),
+                    ip: instance.get_ip(),
+                    up,
+                    ports,
+                })
+            })
+            .collect();
+        ScanSnapshot { hosts }
+    }
+
+    /// Load a snapshot previously written with [`ScanSnapshot::save`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(io::Error::from)
+    }
+
+    /// Write the snapshot to disk as JSON so it can be compared against later
+    /// scans.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self).map_err(io::Error::from)
+    }
+
+    /// Find everything that changed between this snapshot and a newer one.
+    /// Changes are returned in a stable order, sorted by host then port.
+    pub fn diff(&self, newer: &ScanSnapshot) -> Vec<ScanChange> {
+        let old_hosts = self.index_hosts();
+        let new_hosts = newer.index_hosts();
+        let keys: BTreeSet<_> = old_hosts.keys().chain(new_hosts.keys()).collect();
+
+        let mut changes = Vec::new();
+        for key in keys {
+            match (old_hosts.get(key), new_hosts.get(key)) {
+                (Some(old), Some(new)) if old.up && new.up => {
+                    diff_ports(old, new, &mut changes);
+                }
+                (old, new) => {
+                    let was_up = old.map(|host| host.up).unwrap_or(false);
+                    let is_up = new.map(|host| host.up).unwrap_or(false);
+                    // One of these two is always set since the key came from one of the maps
+                    let host = old.or(new).unwrap().key();
+                    if is_up && !was_up {
+                        changes.push(ScanChange::HostAppeared(host));
+                    } else if was_up && !is_up {
+                        changes.push(ScanChange::HostDisappeared(host));
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    fn index_hosts(&self) -> BTreeMap<(IpAddr, String), &HostSnapshot> {
+        self.hosts
+            .iter()
+            .map(|host| ((host.ip, host.target.to_string()), host))
+            .collect()
+    }
+}
+
+impl HostSnapshot {
+    /// The key used to match this host up with the same host in another scan.
+    pub fn key(&self) -> HostKey {
+        HostKey {
+            target: self.target.clone(),
+            ip: self.ip,
+        }
+    }
+}
+
+/// A convenience function to diff two sets of reports without building the
+/// snapshots by hand.
+pub fn diff_reports(old: &[Report], new: &[Report]) -> Vec<ScanChange> {
+    ScanSnapshot::from_reports(old).diff(&ScanSnapshot::from_reports(new))
+}
+
+fn diff_ports(old: &HostSnapshot, new: &HostSnapshot, changes: &mut Vec<ScanChange>) {
+    let ports: BTreeSet<u16> = old.ports.keys().chain(new.ports.keys()).cloned().collect();
+    for port in ports {
+        // A port that wasn't included in one of the scans is treated the same as a
+        // closed port.
+        let old_port = old
+            .ports
+            .get(&port)
+            .filter(|entry| entry.status == PortStatus::Open);
+        let new_port = new
+            .ports
+            .get(&port)
+            .filter(|entry| entry.status == PortStatus::Open);
+        match (old_port, new_port) {
+            (None, Some(_)) => changes.push(ScanChange::PortOpened {
+                host: new.key(),
+                port,
+            }),
+            (Some(_), None) => changes.push(ScanChange::PortClosed {
+                host: new.key(),
+                port,
+            }),
+            (Some(old_port), Some(new_port)) => {
+                if let (Some(before), Some(after)) = (old_port.tls_enabled, new_port.tls_enabled) {
+                    if before != after {
+                        changes.push(ScanChange::TlsChanged {
+                            host: new.key(),
+                            port,
+                            before,
+                            after,
+                        });
+                    }
+                }
+                // Rules don't produce their conclusions in any particular order, so compare
+                // them sorted
+                if let (Some(before), Some(after)) = (&old_port.services, &new_port.services) {
+                    let mut before = before.clone();
+                    before.sort();
+                    let mut after = after.clone();
+                    after.sort();
+                    if before != after {
+                        changes.push(ScanChange::ServiceChanged {
+                            host: new.key(),
+                            port,
+                            before,
+                            after,
+                        });
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::IpAddr};
+
+    use crate::{
+        diff::{HostSnapshot, PortSnapshot, ScanChange, ScanSnapshot, ServiceSnapshot},
+        report::PortStatus,
+        target::Target,
+    };
+
+    fn build_host(last_byte: u8, up: bool, ports: Vec<(u16, PortSnapshot)>) -> HostSnapshot {
+        let ip = IpAddr::from([10, 0, 0, last_byte]);
+        HostSnapshot {
+            target: Target::IP(ip),
+            ip,
+            up,
+            ports: ports.into_iter().collect::<BTreeMap<_, _>>(),
+        }
+    }
+
+    fn open_port(tls_enabled: Option<bool>, version: Option<&str>) -> PortSnapshot {
+        PortSnapshot {
+            status: PortStatus::Open,
+            tls_enabled,
+            services: Some(vec![ServiceSnapshot {
+                name: "nginx".to_string(),
+                version: version.map(str::to_string),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_hosts_appear_and_disappear() {
+        let old = ScanSnapshot {
+            hosts: vec![build_host(1, true, vec![]), build_host(2, false, vec![])],
+        };
+        let new = ScanSnapshot {
+            hosts: vec![build_host(2, true, vec![]), build_host(3, false, vec![])],
+        };
+        let changes = old.diff(&new);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], ScanChange::HostDisappeared(old.hosts[0].key()));
+        assert_eq!(changes[1], ScanChange::HostAppeared(new.hosts[0].key()));
+    }
+
+    #[test]
+    fn test_port_changes() {
+        let closed = PortSnapshot {
+            status: PortStatus::Closed,
+            tls_enabled: None,
+            services: None,
+        };
+        let old = ScanSnapshot {
+            hosts: vec![build_host(
+                1,
+                true,
+                vec![
+                    (22, closed.clone()),
+                    (80, open_port(None, None)),
+                    (443, open_port(Some(false), Some("1.18.0"))),
+                ],
+            )],
+        };
+        let new = ScanSnapshot {
+            hosts: vec![build_host(
+                1,
+                true,
+                vec![
+                    (22, open_port(None, None)),
+                    (80, closed),
+                    (443, open_port(Some(true), Some("1.25.3"))),
+                ],
+            )],
+        };
+        let key = old.hosts[0].key();
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                ScanChange::PortOpened {
+                    host: key.clone(),
+                    port: 22
+                },
+                ScanChange::PortClosed {
+                    host: key.clone(),
+                    port: 80
+                },
+                ScanChange::TlsChanged {
+                    host: key.clone(),
+                    port: 443,
+                    before: false,
+                    after: true
+                },
+                ScanChange::ServiceChanged {
+                    host: key,
+                    port: 443,
+                    before: open_port(None, Some("1.18.0")).services.unwrap(),
+                    after: open_port(None, Some("1.25.3")).services.unwrap(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_round_trip_through_disk() {
+        let snapshot = ScanSnapshot {
+            hosts: vec![build_host(
+                1,
+                true,
+                vec![(443, open_port(Some(true), None))],
+            )],
+        };
+        let path = std::env::temp_dir().join(format!("bowbend-diff-{}.json", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let loaded = ScanSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot, loaded);
+        assert!(snapshot.diff(&loaded).is_empty());
+    }
+}
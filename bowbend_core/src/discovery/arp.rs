@@ -0,0 +1,518 @@
+//! ARP based host discovery.  For targets on a directly attached subnet an ARP
+//! request is far more reliable than a ping since hosts can't really ignore it
+//! and still be on the network.  IPv6 targets get a Neighbor Solicitation,
+//! which is the IPv6 equivalent.  This works at the datalink layer so it needs
+//! privileged access.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{stream, stream::FuturesUnordered, Stream, StreamExt};
+use pnet::{
+    datalink,
+    datalink::{Channel, DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface},
+    ipnetwork::IpNetwork,
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket},
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        MutablePacket, Packet,
+    },
+};
+use tokio::task;
+use tracing::{debug, error, instrument};
+
+use crate::{
+    discovery::ndp::{build_solicitation, parse_advertisement},
+    target::TargetInstance,
+    PortscanErr,
+};
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ARP_PACKET_SIZE: usize = 28;
+
+/// The result of sending an ARP request, or a Neighbor Solicitation for IPv6,
+/// for a host.
+#[derive(Debug)]
+pub struct ArpResult {
+    /// The time the ARP request was sent or attempted to be sent
+    pub request_sent: SystemTime,
+    /// What we heard back, if anything
+    pub result_type: ArpResultType,
+}
+
+/// The possible outcomes of an ARP request.
+#[derive(Debug)]
+pub enum ArpResultType {
+    /// The host answered.
+    Reply {
+        /// The MAC address of the host that answered
+        mac: [u8; 6],
+        /// When the reply arrived
+        time_received: SystemTime,
+    },
+    /// Nobody answered before the timeout.
+    Timeout,
+    /// The target isn't on any of our directly attached subnets so ARP or
+    /// Neighbor Discovery can't reach it.  No request was sent.
+    NotOnLink,
+    /// We failed to send the request.
+    Error(io::Error),
+}
+
+impl ArpResult {
+    /// Whether the host answered the ARP request.
+    pub fn host_up(&self) -> bool {
+        matches!(self.result_type, ArpResultType::Reply { .. })
+    }
+
+    /// How long it took to get a reply.  This is `None` unless we got one.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        match self.result_type {
+            ArpResultType::Reply { time_received, .. } => {
+                time_received.duration_since(self.request_sent).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn without_reply(result_type: ArpResultType) -> Self {
+        ArpResult {
+            request_sent: SystemTime::now(),
+            result_type,
+        }
+    }
+}
+
+/// The targets that can be reached on one interface.
+struct OnLinkTargets {
+    interface: NetworkInterface,
+    source_ip: IpAddr,
+    targets: Vec<(TargetInstance, IpAddr)>,
+}
+
+#[instrument(level = "trace")]
+pub(crate) async fn arp_sweep(
+    targets: Vec<TargetInstance>,
+    wait: Duration,
+) -> Result<impl Stream<Item = (TargetInstance, ArpResult)>, PortscanErr> {
+    let interfaces = datalink::interfaces();
+    // IPv4 and IPv6 targets on the same interface need different source
+    // addresses
+    let mut on_link: HashMap<(u32, IpAddr), OnLinkTargets> = HashMap::new();
+    let mut results = Vec::new();
+    for target in targets {
+        let ip = target.get_ip();
+        match find_interface(&interfaces, ip) {
+            Some((interface, source_ip)) => on_link
+                .entry((interface.index, source_ip))
+                .or_insert_with(|| OnLinkTargets {
+                    interface: interface.clone(),
+                    source_ip,
+                    targets: vec![],
+                })
+                .targets
+                .push((target, ip)),
+            None => results.push((target, ArpResult::without_reply(ArpResultType::NotOnLink))),
+        }
+    }
+
+    let sweeps = FuturesUnordered::new();
+    for (_, group) in on_link {
+        let config = datalink::Config {
+            // The read loop needs to wake up regularly to check the deadline
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (mut tx, mut rx) = match datalink::channel(&group.interface, config) {
+            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+            Ok(_) => {
+                let error =
+                    || io::Error::new(io::ErrorKind::Unsupported, "Unsupported channel type");
+                results.extend(group.targets.into_iter().map(|(target, _)| {
+                    (
+                        target,
+                        ArpResult::without_reply(ArpResultType::Error(error())),
+                    )
+                }));
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                error!("Failed to open a datalink channel {:?}", e);
+                return Err(PortscanErr::InsufficientPermission);
+            }
+            Err(e) => {
+                let kind = e.kind();
+                let message = e.to_string();
+                results.extend(group.targets.into_iter().map(|(target, _)| {
+                    let error = io::Error::new(kind, message.clone());
+                    (
+                        target,
+                        ArpResult::without_reply(ArpResultType::Error(error)),
+                    )
+                }));
+                continue;
+            }
+        };
+        // The datalink channel is blocking so it gets its own thread
+        sweeps.push(task::spawn_blocking(move || {
+            sweep_interface(
+                &group.interface,
+                group.source_ip,
+                group.targets,
+                &mut *tx,
+                &mut *rx,
+                wait,
+            )
+        }));
+    }
+
+    // Same as the full open port scan, a failure here means the task was
+    // canceled or panicked.
+    let swept = sweeps.flat_map(|x| stream::iter(x.unwrap()));
+    Ok(stream::iter(results).chain(swept))
+}
+
+/// Find the interface a target is directly attached to along with the address
+/// we have on that subnet.
+fn find_interface(
+    interfaces: &[NetworkInterface],
+    target: IpAddr,
+) -> Option<(&NetworkInterface, IpAddr)> {
+    interfaces
+        .iter()
+        .filter(|interface| {
+            interface.is_up() && !interface.is_loopback() && interface.mac.is_some()
+        })
+        .find_map(|interface| {
+            interface
+                .ips
+                .iter()
+                .find(|network| match (network, target) {
+                    (IpNetwork::V4(network), IpAddr::V4(target)) => network.contains(target),
+                    (IpNetwork::V6(network), IpAddr::V6(target)) => network.contains(target),
+                    _ => false,
+                })
+                .map(|network| (interface, network.ip()))
+        })
+}
+
+fn sweep_interface(
+    interface: &NetworkInterface,
+    source_ip: IpAddr,
+    targets: Vec<(TargetInstance, IpAddr)>,
+    tx: &mut dyn DataLinkSender,
+    rx: &mut dyn DataLinkReceiver,
+    wait: Duration,
+) -> Vec<(TargetInstance, ArpResult)> {
+    // find_interface only hands back interfaces with a MAC
+    let source_mac = interface.mac.unwrap_or_else(MacAddr::zero);
+    let mut results = Vec::new();
+    let mut pending: HashMap<IpAddr, (TargetInstance, SystemTime)> = HashMap::new();
+    for (target, ip) in targets {
+        let request_sent = SystemTime::now();
+        let sent = match (source_ip, ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(ip)) => Ok(build_request(source_mac, source_ip, ip)),
+            (IpAddr::V6(source_ip), IpAddr::V6(ip)) => {
+                Ok(build_solicitation(source_mac, source_ip, ip))
+            }
+            // find_interface always matches the address family of the target
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Mismatched address families",
+            )),
+        }
+        .and_then(|frame| {
+            tx.send_to(&frame, None).unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "Failed to send ARP request",
+                ))
+            })
+        });
+        match sent {
+            Ok(()) => {
+                pending.insert(ip, (target, request_sent));
+            }
+            Err(e) => results.push((
+                target,
+                ArpResult {
+                    request_sent,
+                    result_type: ArpResultType::Error(e),
+                },
+            )),
+        }
+    }
+
+    let deadline = Instant::now() + wait;
+    while !pending.is_empty() && Instant::now() < deadline {
+        match rx.next() {
+            Ok(frame) => {
+                let answer = parse_reply(frame)
+                    .map(|(ip, mac)| (IpAddr::V4(ip), mac))
+                    .or_else(|| parse_advertisement(frame).map(|(ip, mac)| (IpAddr::V6(ip), mac)));
+                if let Some((ip, mac)) = answer {
+                    if let Some((target, request_sent)) = pending.remove(&ip) {
+                        results.push((
+                            target,
+                            ArpResult {
+                                request_sent,
+                                result_type: ArpResultType::Reply {
+                                    mac: mac.octets(),
+                                    time_received: SystemTime::now(),
+                                },
+                            },
+                        ));
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) => {}
+            Err(e) => {
+                error!("Failed to read from the datalink channel {:?}", e);
+                break;
+            }
+        }
+    }
+    results.extend(pending.into_values().map(|(target, request_sent)| {
+        (
+            target,
+            ArpResult {
+                request_sent,
+                result_type: ArpResultType::Timeout,
+            },
+        )
+    }));
+    results
+}
+
+fn build_request(source_mac: MacAddr, source_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Vec<u8> {
+    let mut buffer = vec![0u8; ETHERNET_HEADER_SIZE + ARP_PACKET_SIZE];
+    // Both unwraps are safe since the buffer is sized for the packets
+    let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+    ethernet.set_destination(MacAddr::broadcast());
+    ethernet.set_source(source_mac);
+    ethernet.set_ethertype(EtherTypes::Arp);
+    let mut arp = MutableArpPacket::new(ethernet.payload_mut()).unwrap();
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(source_mac);
+    arp.set_sender_proto_addr(source_ip);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target_ip);
+    buffer
+}
+
+/// Pull the sender out of an ARP reply.  Anything else is ignored.
+fn parse_reply(frame: &[u8]) -> Option<(Ipv4Addr, MacAddr)> {
+    let ethernet = EthernetPacket::new(frame)?;
+    if ethernet.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+    let arp = ArpPacket::new(ethernet.payload())?;
+    if arp.get_operation() != ArpOperations::Reply {
+        debug!("Ignoring ARP operation {:?}", arp.get_operation());
+        return None;
+    }
+    Some((arp.get_sender_proto_addr(), arp.get_sender_hw_addr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use pnet::{
+        datalink::{dummy, Channel, MacAddr},
+        ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network},
+        packet::{
+            arp::{ArpOperations, ArpPacket, MutableArpPacket},
+            ethernet::{EthernetPacket, MutableEthernetPacket},
+            ipv6::MutableIpv6Packet,
+            MutablePacket, Packet,
+        },
+    };
+
+    use crate::{
+        discovery::{
+            arp::{build_request, find_interface, parse_reply, sweep_interface, ArpResultType},
+            ndp::build_solicitation,
+        },
+        target::TargetInstance,
+    };
+
+    fn build_reply(sender_mac: MacAddr, sender_ip: Ipv4Addr) -> Box<[u8]> {
+        let mut buffer = build_request(sender_mac, sender_ip, Ipv4Addr::new(10, 0, 0, 1));
+        let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+        let mut arp = MutableArpPacket::new(ethernet.payload_mut()).unwrap();
+        arp.set_operation(ArpOperations::Reply);
+        buffer.into_boxed_slice()
+    }
+
+    fn on_link_interface() -> pnet::datalink::NetworkInterface {
+        let mut interface = dummy::dummy_interface(0);
+        interface.ips = vec![IpNetwork::V4(
+            Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap(),
+        )];
+        // IFF_UP
+        interface.flags = 0x1;
+        interface
+    }
+
+    #[test]
+    fn test_find_interface() {
+        let interfaces = vec![dummy::dummy_interface(1), on_link_interface()];
+        let (interface, source_ip) =
+            find_interface(&interfaces, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 20))).unwrap();
+        assert_eq!(interface.name, "eth0");
+        assert_eq!(source_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(find_interface(&interfaces, IpAddr::V4(Ipv4Addr::new(10, 0, 1, 20))).is_none());
+    }
+
+    #[test]
+    fn test_sweep_over_dummy_channel() {
+        let interface = on_link_interface();
+        let mut config = dummy::Config::default();
+        let inject = config.inject_handle().unwrap();
+        let sent = config.read_handle().unwrap();
+        let (mut tx, mut rx) = match dummy::channel(&interface, config).unwrap() {
+            Channel::Ethernet(tx, rx) => (tx, rx),
+            _ => panic!("The dummy channel is always ethernet"),
+        };
+
+        let first = Ipv4Addr::new(10, 0, 0, 20);
+        let second = Ipv4Addr::new(10, 0, 0, 30);
+        let first_mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0, 0, 1);
+        let second_mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0, 0, 2);
+        // Noise that should be skipped over
+        inject.send(Ok(vec![0u8; 10].into_boxed_slice())).unwrap();
+        inject
+            .send(Err(io::Error::from(io::ErrorKind::TimedOut)))
+            .unwrap();
+        inject
+            .send(Ok(build_reply(
+                MacAddr::new(1, 1, 1, 1, 1, 1),
+                Ipv4Addr::new(10, 0, 0, 99),
+            )))
+            .unwrap();
+        inject.send(Ok(build_reply(second_mac, second))).unwrap();
+        inject.send(Ok(build_reply(first_mac, first))).unwrap();
+
+        let targets = vec![
+            (TargetInstance::IP(IpAddr::V4(first)), IpAddr::V4(first)),
+            (TargetInstance::IP(IpAddr::V4(second)), IpAddr::V4(second)),
+        ];
+        let results = sweep_interface(
+            &interface,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            targets,
+            &mut *tx,
+            &mut *rx,
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(results.len(), 2);
+        for (target, result) in results {
+            let expected = if target.get_ip() == IpAddr::V4(first) {
+                first_mac
+            } else {
+                second_mac
+            };
+            assert!(matches!(
+                result.result_type,
+                ArpResultType::Reply { mac, .. } if mac == expected.octets()
+            ));
+        }
+
+        // Check the requests that went out on the wire
+        for target_ip in [first, second] {
+            let frame = sent.try_recv().unwrap();
+            let ethernet = EthernetPacket::new(&frame).unwrap();
+            assert_eq!(ethernet.get_destination(), MacAddr::broadcast());
+            let arp = ArpPacket::new(ethernet.payload()).unwrap();
+            assert_eq!(arp.get_operation(), ArpOperations::Request);
+            assert_eq!(arp.get_sender_hw_addr(), interface.mac.unwrap());
+            assert_eq!(arp.get_target_proto_addr(), target_ip);
+        }
+    }
+
+    #[test]
+    fn test_neighbor_solicitation_over_dummy_channel() {
+        let mut interface = on_link_interface();
+        let source_ip: Ipv6Addr = "fd00::1".parse().unwrap();
+        interface.ips = vec![IpNetwork::V6(Ipv6Network::new(source_ip, 64).unwrap())];
+        let mut config = dummy::Config::default();
+        let inject = config.inject_handle().unwrap();
+        let sent = config.read_handle().unwrap();
+        let (mut tx, mut rx) = match dummy::channel(&interface, config).unwrap() {
+            Channel::Ethernet(tx, rx) => (tx, rx),
+            _ => panic!("The dummy channel is always ethernet"),
+        };
+
+        let ip: Ipv6Addr = "fd00::20".parse().unwrap();
+        let mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0, 0, 1);
+        let interfaces = [interface.clone()];
+        let (_, found_source) = find_interface(&interfaces, IpAddr::V6(ip)).unwrap();
+        assert_eq!(found_source, IpAddr::V6(source_ip));
+        // Turn a solicitation from the target for itself into an advertisement.
+        // Without a target link-layer option the MAC comes from the ethernet header.
+        let mut advertisement = build_solicitation(mac, ip, ip);
+        let mut ethernet = MutableEthernetPacket::new(&mut advertisement).unwrap();
+        let mut ipv6 = MutableIpv6Packet::new(ethernet.payload_mut()).unwrap();
+        ipv6.payload_mut()[0] = 136;
+        inject.send(Ok(advertisement.into_boxed_slice())).unwrap();
+
+        let results = sweep_interface(
+            &interface,
+            IpAddr::V6(source_ip),
+            vec![(TargetInstance::IP(IpAddr::V6(ip)), IpAddr::V6(ip))],
+            &mut *tx,
+            &mut *rx,
+            Duration::from_secs(5),
+        );
+        assert!(matches!(
+            results[0].1.result_type,
+            ArpResultType::Reply { mac: answered, .. } if answered == mac.octets()
+        ));
+        let frame = sent.try_recv().unwrap();
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            ethernet.get_destination(),
+            MacAddr::new(0x33, 0x33, 0xff, 0, 0, 0x20)
+        );
+    }
+
+    #[test]
+    fn test_silent_host_times_out() {
+        let interface = on_link_interface();
+        let mut config = dummy::Config::default();
+        let _inject = config.inject_handle().unwrap();
+        let (mut tx, mut rx) = match dummy::channel(&interface, config).unwrap() {
+            Channel::Ethernet(tx, rx) => (tx, rx),
+            _ => panic!("The dummy channel is always ethernet"),
+        };
+        let ip = Ipv4Addr::new(10, 0, 0, 20);
+        let results = sweep_interface(
+            &interface,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![(TargetInstance::IP(IpAddr::V4(ip)), IpAddr::V4(ip))],
+            &mut *tx,
+            &mut *rx,
+            Duration::ZERO,
+        );
+        assert!(matches!(results[0].1.result_type, ArpResultType::Timeout));
+        assert!(parse_reply(&build_request(MacAddr::zero(), ip, ip)).is_none());
+    }
+}
@@ -0,0 +1,466 @@
+//! Host discovery runs before the port scan to find out which targets are
+//! actually up.  Many networks drop ICMP so we support several methods beside
+//! a plain ping, and the user can run as many of them as they want.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_stream::stream;
+use futures::{
+    stream::{self, select_all, BoxStream},
+    Stream, StreamExt,
+};
+use tokio::sync::Semaphore;
+
+use crate::{
+    discovery::{
+        arp::{arp_sweep, ArpResult, ArpResultType},
+        tcp_connect::tcp_connect_sweep,
+        tcp_raw::{tcp_raw_sweep, RawTcpProbe},
+    },
+    icmp::{icmp_sweep, PingResult, PingResultType, PingSettings},
+    os_detection::TcpFingerprint,
+    target::TargetInstance,
+    PortscanErr,
+};
+
+pub(crate) mod arp;
+pub(crate) mod ndp;
+mod tcp_connect;
+mod tcp_raw;
+
+/// The ways we can check if a host is up before scanning it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HostDiscoveryMethod {
+    /// Send ICMP echo requests.  This requires privileged access unless the
+    /// OS allows unprivileged ICMP sockets, like Linux does for groups in
+    /// `net.ipv4.ping_group_range`.  Unprivileged sockets never see ICMP
+    /// errors, so hosts that can't be reached just time out.
+    IcmpEcho,
+    /// Send a TCP SYN to each port.  Either a SYN/ACK or a RST means the host
+    /// is up.  This requires privileged access.
+    TcpSyn {
+        /// The ports to probe
+        ports: Vec<u16>,
+    },
+    /// Send a TCP ACK to each port.  Hosts answer an unexpected ACK with a RST,
+    /// which gets through firewalls that only filter new connections.  This
+    /// requires privileged access.
+    TcpAck {
+        /// The ports to probe
+        ports: Vec<u16>,
+    },
+    /// Attempt a full TCP connection to each port.  Either the connection
+    /// succeeding or being refused means the host is up.  This doesn't
+    /// require privileged access.
+    TcpConnect {
+        /// The ports to probe
+        ports: Vec<u16>,
+    },
+    /// Send an ARP request for each target on a directly attached IPv4
+    /// subnet, or a Neighbor Solicitation for each target on a directly
+    /// attached IPv6 subnet.  Other targets are skipped.  This requires
+    /// privileged access.
+    Arp,
+}
+
+/// What to do with each host once host discovery has run.  This has no effect
+/// unless at least one [`HostDiscoveryMethod`] is set.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HostDiscoveryPolicy {
+    /// Port scan every host unless every discovery method that ran failed to
+    /// even probe it.  Silence isn't enough to skip a host since plenty of
+    /// hosts ignore our probes.
+    #[default]
+    ScanAll,
+    /// Only port scan hosts that answered at least one discovery method.
+    ScanRespondersOnly,
+    /// Port scan hosts that answered along with hosts we couldn't check, for
+    /// example because every discovery method failed to send its probes.
+    /// Only hosts that stayed silent are skipped.
+    ScanRespondersAndUnknown,
+}
+
+/// What host discovery concluded about a host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum HostState {
+    /// The host answered at least one discovery method.
+    Up,
+    /// Nothing answered but at least one method got its probes out.
+    Silent,
+    /// None of the methods were able to probe the host.
+    Unknown,
+}
+
+/// The result of probing a host with one of the TCP based discovery methods.
+#[derive(Debug)]
+pub struct TcpPingResult {
+    /// The time we started sending probes to the host
+    pub probe_sent: SystemTime,
+    /// What we heard back, if anything
+    pub result_type: TcpPingResultType,
+    /// What the SYN/ACK looked like.  This is only set by the TCP SYN ping
+    /// when a port answered with one.
+    pub fingerprint: Option<TcpFingerprint>,
+}
+
+/// The possible outcomes of a TCP ping.  Only the first response from any of
+/// the probed ports is kept.
+#[derive(Debug)]
+pub enum TcpPingResultType {
+    /// A port accepted our connection or answered a SYN with a SYN/ACK.
+    Open {
+        /// The port that answered
+        port: u16,
+        /// When the answer arrived
+        time_received: SystemTime,
+    },
+    /// A port answered with a RST.  The port is closed but the host is up.
+    Reset {
+        /// The port that answered
+        port: u16,
+        /// When the answer arrived
+        time_received: SystemTime,
+    },
+    /// None of the ports answered before the timeout.
+    Timeout,
+    /// We failed to probe the host at all.
+    Error(io::Error),
+}
+
+impl TcpPingResult {
+    /// Whether the host answered on any port.
+    pub fn host_up(&self) -> bool {
+        matches!(
+            self.result_type,
+            TcpPingResultType::Open { .. } | TcpPingResultType::Reset { .. }
+        )
+    }
+
+    /// How long it took to hear back from the host.  This is `None` unless we
+    /// got an answer.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        match self.result_type {
+            TcpPingResultType::Open { time_received, .. }
+            | TcpPingResultType::Reset { time_received, .. } => {
+                time_received.duration_since(self.probe_sent).ok()
+            }
+            TcpPingResultType::Timeout | TcpPingResultType::Error(_) => None,
+        }
+    }
+}
+
+/// Everything the discovery methods learned about one host.  Each field is
+/// `None` if that method wasn't run.
+#[derive(Debug, Default)]
+pub(crate) struct HostDiscoveryResults {
+    pub icmp: Option<PingResult>,
+    pub tcp_syn: Option<TcpPingResult>,
+    pub tcp_ack: Option<TcpPingResult>,
+    pub tcp_connect: Option<TcpPingResult>,
+    pub arp: Option<ArpResult>,
+}
+
+impl HostDiscoveryResults {
+    /// Combine the results of every method that ran.  A single answer from any
+    /// of them means the host is up.
+    pub fn host_state(&self) -> HostState {
+        let icmp = self.icmp.as_ref().map(|ping| match ping.result_type {
+            PingResultType::Reply(_) => HostState::Up,
+            // Someone told us the host isn't reachable, which is as good as silence
+            PingResultType::Timeout
+            | PingResultType::Unreachable { .. }
+            | PingResultType::TtlExceeded { .. } => HostState::Silent,
+            PingResultType::Error(_) => HostState::Unknown,
+        });
+        let tcp = [&self.tcp_syn, &self.tcp_ack, &self.tcp_connect]
+            .into_iter()
+            .map(|result| {
+                result.as_ref().map(|ping| match ping.result_type {
+                    TcpPingResultType::Open { .. } | TcpPingResultType::Reset { .. } => {
+                        HostState::Up
+                    }
+                    TcpPingResultType::Timeout => HostState::Silent,
+                    TcpPingResultType::Error(_) => HostState::Unknown,
+                })
+            });
+        let arp = self.arp.as_ref().map(|arp| match arp.result_type {
+            ArpResultType::Reply { .. } => HostState::Up,
+            ArpResultType::Timeout => HostState::Silent,
+            ArpResultType::NotOnLink | ArpResultType::Error(_) => HostState::Unknown,
+        });
+        let states: Vec<HostState> = [icmp, arp].into_iter().chain(tcp).flatten().collect();
+        if states.contains(&HostState::Up) {
+            HostState::Up
+        } else if states.contains(&HostState::Silent) {
+            HostState::Silent
+        } else {
+            HostState::Unknown
+        }
+    }
+
+    /// Did every discovery method that ran fail to even probe the host?  ARP
+    /// not applying to a target doesn't count as it running.
+    fn every_probe_failed(&self) -> bool {
+        let icmp_failed = self
+            .icmp
+            .as_ref()
+            .map(|ping| matches!(ping.result_type, PingResultType::Error(_)));
+        let tcp_failed = [&self.tcp_syn, &self.tcp_ack, &self.tcp_connect]
+            .into_iter()
+            .map(|result| {
+                result
+                    .as_ref()
+                    .map(|ping| matches!(ping.result_type, TcpPingResultType::Error(_)))
+            });
+        let arp_failed = self.arp.as_ref().and_then(|arp| match arp.result_type {
+            ArpResultType::NotOnLink => None,
+            ArpResultType::Error(_) => Some(true),
+            _ => Some(false),
+        });
+        let failures: Vec<bool> = [icmp_failed, arp_failed]
+            .into_iter()
+            .chain(tcp_failed)
+            .flatten()
+            .collect();
+        !failures.is_empty() && failures.iter().all(|failed| *failed)
+    }
+
+    /// Whether the policy lets us port scan this host.  If no discovery
+    /// methods ran we know nothing, so the policy doesn't apply.
+    pub fn should_scan(&self, policy: HostDiscoveryPolicy) -> bool {
+        let ran_discovery = self.icmp.is_some()
+            || self.tcp_syn.is_some()
+            || self.tcp_ack.is_some()
+            || self.tcp_connect.is_some()
+            || self.arp.is_some();
+        if !ran_discovery {
+            return true;
+        }
+        match policy {
+            HostDiscoveryPolicy::ScanAll => !self.every_probe_failed(),
+            HostDiscoveryPolicy::ScanRespondersOnly => self.host_state() == HostState::Up,
+            HostDiscoveryPolicy::ScanRespondersAndUnknown => self.host_state() != HostState::Silent,
+        }
+    }
+
+    fn apply(&mut self, update: DiscoveryUpdate) {
+        match update {
+            DiscoveryUpdate::Icmp(result) => self.icmp = result,
+            DiscoveryUpdate::TcpSyn(result) => self.tcp_syn = Some(result),
+            DiscoveryUpdate::TcpAck(result) => self.tcp_ack = Some(result),
+            DiscoveryUpdate::TcpConnect(result) => self.tcp_connect = Some(result),
+            DiscoveryUpdate::Arp(result) => self.arp = Some(result),
+        }
+    }
+}
+
+/// A single discovery method reporting back on a single host.
+enum DiscoveryUpdate {
+    Icmp(Option<PingResult>),
+    TcpSyn(TcpPingResult),
+    TcpAck(TcpPingResult),
+    TcpConnect(TcpPingResult),
+    Arp(ArpResult),
+}
+
+/// Run every requested discovery method against every target.  A target is
+/// yielded once all of the methods have reported back on it.
+#[tracing::instrument(skip(target_stream))]
+pub(crate) async fn discover_hosts(
+    target_stream: impl Stream<Item = TargetInstance> + 'static + Send + Unpin,
+    methods: Vec<HostDiscoveryMethod>,
+    semaphore: Arc<Semaphore>,
+    ping_settings: PingSettings,
+) -> Result<BoxStream<'static, (TargetInstance, HostDiscoveryResults)>, PortscanErr> {
+    if methods.is_empty() {
+        return Ok(target_stream
+            .map(|target| (target, HostDiscoveryResults::default()))
+            .boxed());
+    }
+
+    // Each method needs to see every target so we can't stream them through.
+    let targets: Vec<TargetInstance> = target_stream.collect().await;
+    let mut updates = Vec::new();
+    for method in &methods {
+        let update_stream = match method {
+            HostDiscoveryMethod::IcmpEcho => icmp_sweep(
+                stream::iter(targets.clone()),
+                semaphore.clone(),
+                ping_settings.clone(),
+            )
+            .await?
+            .map(|(target, result)| (target, DiscoveryUpdate::Icmp(result)))
+            .boxed(),
+            HostDiscoveryMethod::TcpSyn { ports } => tcp_raw_sweep(
+                targets.clone(),
+                RawTcpProbe::Syn,
+                ports.clone(),
+                semaphore.clone(),
+                ping_settings.timeout,
+            )
+            .await?
+            .map(|(target, result)| (target, DiscoveryUpdate::TcpSyn(result)))
+            .boxed(),
+            HostDiscoveryMethod::TcpAck { ports } => tcp_raw_sweep(
+                targets.clone(),
+                RawTcpProbe::Ack,
+                ports.clone(),
+                semaphore.clone(),
+                ping_settings.timeout,
+            )
+            .await?
+            .map(|(target, result)| (target, DiscoveryUpdate::TcpAck(result)))
+            .boxed(),
+            HostDiscoveryMethod::TcpConnect { ports } => tcp_connect_sweep(
+                targets.clone(),
+                ports.clone(),
+                semaphore.clone(),
+                ping_settings.timeout,
+            )
+            .map(|(target, result)| (target, DiscoveryUpdate::TcpConnect(result)))
+            .boxed(),
+            HostDiscoveryMethod::Arp => arp_sweep(targets.clone(), ping_settings.timeout)
+                .await?
+                .map(|(target, result)| (target, DiscoveryUpdate::Arp(result)))
+                .boxed(),
+        };
+        updates.push(update_stream);
+    }
+    Ok(merge_updates(select_all(updates), methods.len()).boxed())
+}
+
+fn merge_updates(
+    mut updates: impl Stream<Item = (TargetInstance, DiscoveryUpdate)> + Unpin + Send + 'static,
+    method_count: usize,
+) -> impl Stream<Item = (TargetInstance, HostDiscoveryResults)> + Send + 'static {
+    stream! {
+        let mut pending: HashMap<TargetInstance, (HostDiscoveryResults, usize)> = HashMap::new();
+        while let Some((target, update)) = updates.next().await {
+            let entry = pending.entry(target.clone()).or_default();
+            entry.0.apply(update);
+            entry.1 += 1;
+            if entry.1 >= method_count {
+                // We just touched the entry above
+                let (results, _) = pending.remove(&target).unwrap();
+                yield (target, results);
+            }
+        }
+        // Every method is done.  Anything left over was missed by at least one
+        // of them, so hand back whatever we have.
+        for (target, (results, _)) in pending {
+            yield (target, results);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr},
+        time::SystemTime,
+    };
+
+    use futures::{stream, StreamExt};
+
+    use crate::{
+        discovery::{
+            merge_updates, DiscoveryUpdate, HostDiscoveryPolicy, HostDiscoveryResults, HostState,
+            TcpPingResult, TcpPingResultType,
+        },
+        target::TargetInstance,
+    };
+
+    fn build_result(result_type: TcpPingResultType) -> TcpPingResult {
+        TcpPingResult {
+            probe_sent: SystemTime::now(),
+            result_type,
+            fingerprint: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_waits_for_every_method() {
+        let first = TargetInstance::IP(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let second = TargetInstance::IP(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let updates = vec![
+            (
+                first.clone(),
+                DiscoveryUpdate::TcpConnect(build_result(TcpPingResultType::Timeout)),
+            ),
+            (
+                second.clone(),
+                DiscoveryUpdate::TcpConnect(build_result(TcpPingResultType::Timeout)),
+            ),
+            (
+                first.clone(),
+                DiscoveryUpdate::TcpSyn(build_result(TcpPingResultType::Reset {
+                    port: 443,
+                    time_received: SystemTime::now(),
+                })),
+            ),
+        ];
+        let merged: Vec<_> = merge_updates(stream::iter(updates), 2).collect().await;
+        assert_eq!(merged.len(), 2);
+        // The first target had every method report so it comes out first
+        assert_eq!(merged[0].0, first);
+        assert!(merged[0].1.tcp_syn.as_ref().unwrap().host_up());
+        assert!(!merged[0].1.tcp_connect.as_ref().unwrap().host_up());
+        assert_eq!(merged[1].0, second);
+        assert!(merged[1].1.tcp_syn.is_none());
+    }
+
+    #[test]
+    fn test_should_scan() {
+        let failed = || {
+            Some(build_result(TcpPingResultType::Error(io::Error::from(
+                io::ErrorKind::PermissionDenied,
+            ))))
+        };
+        let only_failures = HostDiscoveryResults {
+            tcp_syn: failed(),
+            tcp_connect: failed(),
+            ..Default::default()
+        };
+        assert_eq!(only_failures.host_state(), HostState::Unknown);
+        let silent = HostDiscoveryResults {
+            tcp_syn: failed(),
+            tcp_connect: Some(build_result(TcpPingResultType::Timeout)),
+            ..Default::default()
+        };
+        assert_eq!(silent.host_state(), HostState::Silent);
+        let up = HostDiscoveryResults {
+            tcp_syn: Some(build_result(TcpPingResultType::Timeout)),
+            tcp_connect: Some(build_result(TcpPingResultType::Reset {
+                port: 22,
+                time_received: SystemTime::now(),
+            })),
+            ..Default::default()
+        };
+        assert_eq!(up.host_state(), HostState::Up);
+
+        let expectations = [
+            // The default only skips hosts we couldn't probe at all
+            (HostDiscoveryPolicy::ScanAll, [true, true, false]),
+            (
+                HostDiscoveryPolicy::ScanRespondersOnly,
+                [true, false, false],
+            ),
+            (
+                HostDiscoveryPolicy::ScanRespondersAndUnknown,
+                [true, false, true],
+            ),
+        ];
+        for (policy, [scan_up, scan_silent, scan_unknown]) in expectations {
+            assert!(HostDiscoveryResults::default().should_scan(policy));
+            assert_eq!(up.should_scan(policy), scan_up);
+            assert_eq!(silent.should_scan(policy), scan_silent);
+            assert_eq!(only_failures.should_scan(policy), scan_unknown);
+        }
+    }
+}
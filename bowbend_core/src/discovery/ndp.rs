@@ -0,0 +1,559 @@
+//! IPv6 host discovery.  An IPv6 subnet is far too large to walk one address
+//! at a time, so for big networks we ask every host on the link to identify
+//! itself with an echo request to the all-nodes multicast address.  This module
+//! also holds the Neighbor Solicitation frames the ARP sweep uses for on-link
+//! IPv6 targets, since Neighbor Discovery is the IPv6 replacement for ARP.
+//! Everything here works at the datalink layer so it needs privileged access.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use ipnet::{IpNet, Ipv6Net};
+use pnet::{
+    datalink,
+    datalink::{Channel, DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface},
+    ipnetwork::IpNetwork,
+    packet::{
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        icmpv6::{checksum, Icmpv6Packet},
+        ip::IpNextHeaderProtocols,
+        ipv6::{Ipv6Packet, MutableIpv6Packet},
+        MutablePacket, Packet,
+    },
+};
+use rand::random;
+use tokio::task;
+use tracing::{debug, error, instrument};
+
+use crate::{
+    icmp::packet::{EchoReply, EchoRequest, IcmpV6},
+    report::Report,
+    target::{Target, TargetInstance},
+    PortscanErr,
+};
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const IPV6_HEADER_SIZE: usize = 40;
+/// The ICMPv6 header and target address of a solicitation or advertisement
+const NEIGHBOR_MESSAGE_SIZE: usize = 24;
+/// A link-layer address option carrying an ethernet address
+const LINK_LAYER_OPTION_SIZE: usize = 8;
+const ECHO_REQUEST_SIZE: usize = 8;
+const NEIGHBOR_SOLICITATION_TYPE: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT_TYPE: u8 = 136;
+const SOURCE_LINK_LAYER_OPTION: u8 = 1;
+const TARGET_LINK_LAYER_OPTION: u8 = 2;
+/// Neighbor Discovery messages must be sent with this hop limit so the receiver
+/// knows they didn't come from off the link.
+const NDP_HOP_LIMIT: u8 = 255;
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// Whether a network has too many hosts to enumerate and instead needs to be
+/// discovered with multicast.
+pub(crate) fn needs_multicast_discovery(network: &Ipv6Net) -> bool {
+    // Anything past a /112 is more than 65536 hosts
+    network.max_prefix_len() - network.prefix_len() > 16
+}
+
+/// Ask every host on the links attached to the given networks to answer an
+/// echo request.  The responders inside each network become targets for the
+/// rest of the scan.  Networks we can't reach at the link layer get a failed
+/// report since we have no way to find their hosts.
+#[instrument(level = "trace")]
+pub(crate) async fn multicast_discovery(
+    networks: Vec<Ipv6Net>,
+    wait: Duration,
+) -> (Vec<TargetInstance>, Vec<Report>) {
+    let mut instances = vec![];
+    let mut reports = vec![];
+    if networks.is_empty() {
+        return (instances, reports);
+    }
+    let failed = |network: Ipv6Net, e: PortscanErr| Report {
+        target: Target::Network(IpNet::V6(network)),
+        instance: None,
+        contents: Err(e),
+    };
+
+    let interfaces = datalink::interfaces();
+    let mut on_link: HashMap<u32, (NetworkInterface, Ipv6Addr, Vec<Ipv6Net>)> = HashMap::new();
+    for network in networks {
+        match find_interface(&interfaces, &network) {
+            Some((interface, source_ip)) => on_link
+                .entry(interface.index)
+                .or_insert_with(|| (interface.clone(), source_ip, vec![]))
+                .2
+                .push(network),
+            None => {
+                debug!(
+                    "{} isn't directly attached so we can't discover it",
+                    network
+                );
+                reports.push(failed(network, PortscanErr::NetworkTooLarge));
+            }
+        }
+    }
+
+    let sweeps = FuturesUnordered::new();
+    for (_, (interface, source_ip, networks)) in on_link {
+        let config = datalink::Config {
+            // The read loop needs to wake up regularly to check the deadline
+            read_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (mut tx, mut rx) = match datalink::channel(&interface, config) {
+            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+            Ok(_) => {
+                error!("Unsupported channel type on {}", interface.name);
+                reports.extend(
+                    networks
+                        .into_iter()
+                        .map(|network| failed(network, PortscanErr::NetworkTooLarge)),
+                );
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to open a datalink channel {:?}", e);
+                let permission_denied = e.kind() == io::ErrorKind::PermissionDenied;
+                reports.extend(networks.into_iter().map(|network| {
+                    if permission_denied {
+                        failed(network, PortscanErr::InsufficientPermission)
+                    } else {
+                        failed(network, PortscanErr::NetworkTooLarge)
+                    }
+                }));
+                continue;
+            }
+        };
+        // The datalink channel is blocking so it gets its own thread
+        sweeps.push(task::spawn_blocking(move || {
+            // find_interface only hands back interfaces with a MAC
+            let source_mac = interface.mac.unwrap_or_else(MacAddr::zero);
+            let responders =
+                collect_responders(source_mac, source_ip, random(), &mut *tx, &mut *rx, wait);
+            (networks, responders)
+        }));
+    }
+
+    let swept: Vec<_> = sweeps.collect().await;
+    for sweep in swept {
+        // Same as the full open port scan, a failure here means the task was
+        // canceled or panicked.
+        let (networks, responders) = sweep.unwrap();
+        for ip in responders {
+            // The same host could fall in more than one of the requested networks.  Scan
+            // it once, crediting the most specific one.
+            let network = networks
+                .iter()
+                .filter(|network| network.contains(&ip))
+                .max_by_key(|network| network.prefix_len());
+            if let Some(network) = network {
+                instances.push(TargetInstance::Network {
+                    network: IpNet::V6(*network),
+                    instance_ip: IpAddr::V6(ip),
+                });
+            }
+        }
+    }
+    (instances, reports)
+}
+
+/// Find an interface with an address inside the network, which tells us the
+/// network is on that link.
+fn find_interface<'a>(
+    interfaces: &'a [NetworkInterface],
+    network: &Ipv6Net,
+) -> Option<(&'a NetworkInterface, Ipv6Addr)> {
+    interfaces
+        .iter()
+        .filter(|interface| {
+            interface.is_up() && !interface.is_loopback() && interface.mac.is_some()
+        })
+        .find_map(|interface| {
+            interface.ips.iter().find_map(|ip| match ip {
+                IpNetwork::V6(ip) if network.contains(&ip.ip()) => Some((interface, ip.ip())),
+                _ => None,
+            })
+        })
+}
+
+/// Send one echo request to all nodes on the link and gather up everyone that
+/// answers before the deadline.
+fn collect_responders(
+    source_mac: MacAddr,
+    source_ip: Ipv6Addr,
+    identity: u16,
+    tx: &mut dyn DataLinkSender,
+    rx: &mut dyn DataLinkReceiver,
+    wait: Duration,
+) -> Vec<Ipv6Addr> {
+    let frame = match build_all_nodes_echo(source_mac, source_ip, identity) {
+        Ok(frame) => frame,
+        Err(e) => {
+            error!("Failed to build the multicast echo request {:?}", e);
+            return vec![];
+        }
+    };
+    match tx.send_to(&frame, None) {
+        Some(Ok(())) => {}
+        Some(Err(e)) => {
+            error!("Failed to send the multicast echo request {:?}", e);
+            return vec![];
+        }
+        None => {
+            error!("Failed to send the multicast echo request");
+            return vec![];
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut responders = vec![];
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline {
+        match rx.next() {
+            Ok(frame) => {
+                if let Some(ip) = parse_echo_reply(frame, identity) {
+                    if ip != source_ip && seen.insert(ip) {
+                        responders.push(ip);
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) => {}
+            Err(e) => {
+                error!("Failed to read from the datalink channel {:?}", e);
+                break;
+            }
+        }
+    }
+    responders
+}
+
+/// The multicast address a host listens on to hear solicitations for `ip`.
+fn solicited_node_address(ip: Ipv6Addr) -> Ipv6Addr {
+    let octets = ip.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(octets[13]),
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// IPv6 multicast addresses map onto ethernet multicast addresses by their
+/// last four bytes.
+fn multicast_mac(ip: Ipv6Addr) -> MacAddr {
+    let octets = ip.octets();
+    MacAddr::new(0x33, 0x33, octets[12], octets[13], octets[14], octets[15])
+}
+
+/// Wrap an ICMPv6 message in IPv6 and ethernet headers and fill in its
+/// checksum.
+fn build_frame(
+    source_mac: MacAddr,
+    source_ip: Ipv6Addr,
+    destination_ip: Ipv6Addr,
+    hop_limit: u8,
+    icmp: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + icmp.len()];
+    // Both unwraps are safe since the buffer is sized for the packets
+    let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+    ethernet.set_destination(multicast_mac(destination_ip));
+    ethernet.set_source(source_mac);
+    ethernet.set_ethertype(EtherTypes::Ipv6);
+    let mut ipv6 = MutableIpv6Packet::new(ethernet.payload_mut()).unwrap();
+    ipv6.set_version(6);
+    ipv6.set_payload_length(icmp.len() as u16);
+    ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6.set_hop_limit(hop_limit);
+    ipv6.set_source(source_ip);
+    ipv6.set_destination(destination_ip);
+    let payload = ipv6.payload_mut();
+    payload.copy_from_slice(icmp);
+    // The checksum covers a pseudo header built from the IPv6 addresses.  The
+    // packet is at least the size of the ICMPv6 header so this can't fail.
+    let sum = checksum(
+        &Icmpv6Packet::new(payload).unwrap(),
+        &source_ip,
+        &destination_ip,
+    );
+    payload[2..4].copy_from_slice(&sum.to_be_bytes());
+    buffer
+}
+
+/// Build a Neighbor Solicitation asking who has `target_ip`.  This is the IPv6
+/// version of an ARP request.
+pub(crate) fn build_solicitation(
+    source_mac: MacAddr,
+    source_ip: Ipv6Addr,
+    target_ip: Ipv6Addr,
+) -> Vec<u8> {
+    let mut icmp = [0u8; NEIGHBOR_MESSAGE_SIZE + LINK_LAYER_OPTION_SIZE];
+    icmp[0] = NEIGHBOR_SOLICITATION_TYPE;
+    icmp[8..24].copy_from_slice(&target_ip.octets());
+    // Tell the target where to send its answer.  The length is in units of 8
+    // bytes.
+    icmp[24] = SOURCE_LINK_LAYER_OPTION;
+    icmp[25] = 1;
+    icmp[26..32].copy_from_slice(&source_mac.octets());
+    build_frame(
+        source_mac,
+        source_ip,
+        solicited_node_address(target_ip),
+        NDP_HOP_LIMIT,
+        &icmp,
+    )
+}
+
+/// Pull the target address and MAC out of a Neighbor Advertisement.  Anything
+/// else is ignored.
+pub(crate) fn parse_advertisement(frame: &[u8]) -> Option<(Ipv6Addr, MacAddr)> {
+    let ethernet = EthernetPacket::new(frame)?;
+    let ipv6 = icmpv6_payload(&ethernet)?;
+    let icmp = ipv6.payload();
+    if icmp.len() < NEIGHBOR_MESSAGE_SIZE
+        || icmp[0] != NEIGHBOR_ADVERTISEMENT_TYPE
+        || ipv6.get_hop_limit() != NDP_HOP_LIMIT
+    {
+        return None;
+    }
+    let target: [u8; 16] = icmp[8..24].try_into().ok()?;
+    // Solicited advertisements should carry the target's link-layer address
+    // but the frame itself came from the target, so fall back on that.
+    let mut mac = ethernet.get_source();
+    let mut options = &icmp[NEIGHBOR_MESSAGE_SIZE..];
+    while options.len() >= 2 && options[1] != 0 {
+        let length = usize::from(options[1]) * 8;
+        if options.len() < length {
+            break;
+        }
+        if options[0] == TARGET_LINK_LAYER_OPTION && length >= LINK_LAYER_OPTION_SIZE {
+            mac = MacAddr::new(
+                options[2], options[3], options[4], options[5], options[6], options[7],
+            );
+        }
+        options = &options[length..];
+    }
+    Some((Ipv6Addr::from(target), mac))
+}
+
+/// Build an echo request to every node on the link.
+fn build_all_nodes_echo(
+    source_mac: MacAddr,
+    source_ip: Ipv6Addr,
+    identity: u16,
+) -> io::Result<Vec<u8>> {
+    let mut icmp = [0u8; ECHO_REQUEST_SIZE];
+    EchoRequest {
+        ident: identity,
+        seq_cnt: 0,
+        payload: &[],
+    }
+    .encode::<IcmpV6>(&mut icmp)?;
+    // A raw socket would fill in the ICMPv6 checksum for us but at the datalink
+    // layer we are on our own, so build_frame overwrites it.
+    Ok(build_frame(source_mac, source_ip, ALL_NODES, 1, &icmp))
+}
+
+/// Get the address of a host answering our multicast echo request.
+fn parse_echo_reply(frame: &[u8], identity: u16) -> Option<Ipv6Addr> {
+    let ethernet = EthernetPacket::new(frame)?;
+    let ipv6 = icmpv6_payload(&ethernet)?;
+    let reply = EchoReply::decode::<IcmpV6>(ipv6.payload()).ok()?;
+    if reply.ident != identity {
+        return None;
+    }
+    Some(ipv6.get_source())
+}
+
+fn icmpv6_payload<'a>(ethernet: &'a EthernetPacket<'a>) -> Option<Ipv6Packet<'a>> {
+    if ethernet.get_ethertype() != EtherTypes::Ipv6 {
+        return None;
+    }
+    let ipv6 = Ipv6Packet::new(ethernet.payload())?;
+    if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+        return None;
+    }
+    Some(ipv6)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, net::Ipv6Addr, thread, time::Duration};
+
+    use ipnet::Ipv6Net;
+    use pnet::{
+        datalink::{dummy, Channel, MacAddr},
+        ipnetwork::{IpNetwork, Ipv6Network},
+        packet::{
+            ethernet::{EthernetPacket, MutableEthernetPacket},
+            icmpv6::{checksum, Icmpv6Packet},
+            ipv6::{Ipv6Packet, MutableIpv6Packet},
+            MutablePacket, Packet,
+        },
+    };
+
+    use crate::discovery::ndp::{
+        build_all_nodes_echo, build_frame, build_solicitation, collect_responders, find_interface,
+        needs_multicast_discovery, parse_advertisement, parse_echo_reply, ALL_NODES, NDP_HOP_LIMIT,
+        NEIGHBOR_ADVERTISEMENT_TYPE, TARGET_LINK_LAYER_OPTION,
+    };
+
+    fn local_ip() -> Ipv6Addr {
+        "fd00::1".parse().unwrap()
+    }
+
+    fn build_advertisement(mac: MacAddr, ip: Ipv6Addr, hop_limit: u8) -> Vec<u8> {
+        let mut icmp = [0u8; 32];
+        icmp[0] = NEIGHBOR_ADVERTISEMENT_TYPE;
+        icmp[8..24].copy_from_slice(&ip.octets());
+        icmp[24] = TARGET_LINK_LAYER_OPTION;
+        icmp[25] = 1;
+        icmp[26..32].copy_from_slice(&mac.octets());
+        // Send it from a different MAC to make sure the option wins
+        build_frame(MacAddr::zero(), ip, local_ip(), hop_limit, &icmp)
+    }
+
+    fn build_echo_reply(source: Ipv6Addr, identity: u16) -> Box<[u8]> {
+        let mut frame = build_all_nodes_echo(MacAddr::zero(), source, identity).unwrap();
+        let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+        let mut ipv6 = MutableIpv6Packet::new(ethernet.payload_mut()).unwrap();
+        ipv6.payload_mut()[0] = 129;
+        frame.into_boxed_slice()
+    }
+
+    #[test]
+    fn test_needs_multicast_discovery() {
+        assert!(needs_multicast_discovery(&"fd00::/64".parse().unwrap()));
+        assert!(needs_multicast_discovery(&"fd00::/111".parse().unwrap()));
+        assert!(!needs_multicast_discovery(&"fd00::/112".parse().unwrap()));
+        assert!(!needs_multicast_discovery(&"fd00::/128".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_solicitation() {
+        let mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0, 0, 1);
+        let target: Ipv6Addr = "fd00::12:3456".parse().unwrap();
+        let frame = build_solicitation(mac, local_ip(), target);
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            ethernet.get_destination(),
+            MacAddr::new(0x33, 0x33, 0xff, 0x12, 0x34, 0x56)
+        );
+        let ipv6 = Ipv6Packet::new(ethernet.payload()).unwrap();
+        assert_eq!(ipv6.get_hop_limit(), NDP_HOP_LIMIT);
+        assert_eq!(
+            ipv6.get_destination(),
+            "ff02::1:ff12:3456".parse::<Ipv6Addr>().unwrap()
+        );
+        let icmp = Icmpv6Packet::new(ipv6.payload()).unwrap();
+        assert_eq!(
+            checksum(&icmp, &ipv6.get_source(), &ipv6.get_destination()),
+            icmp.get_checksum()
+        );
+        assert_eq!(&ipv6.payload()[8..24], &target.octets());
+        assert_eq!(&ipv6.payload()[26..32], &mac.octets());
+        // Our own solicitation isn't an answer
+        assert!(parse_advertisement(&frame).is_none());
+    }
+
+    #[test]
+    fn test_parse_advertisement() {
+        let mac = MacAddr::new(0xaa, 0xbb, 0xcc, 0, 0, 2);
+        let ip: Ipv6Addr = "fd00::2".parse().unwrap();
+        assert_eq!(
+            parse_advertisement(&build_advertisement(mac, ip, NDP_HOP_LIMIT)),
+            Some((ip, mac))
+        );
+        // Anything that was routed to us is spoofed
+        assert!(parse_advertisement(&build_advertisement(mac, ip, 64)).is_none());
+    }
+
+    #[test]
+    fn test_find_interface() {
+        let mut interface = dummy::dummy_interface(0);
+        interface.ips = vec![IpNetwork::V6(Ipv6Network::new(local_ip(), 64).unwrap())];
+        // IFF_UP
+        interface.flags = 0x1;
+        let interfaces = vec![dummy::dummy_interface(1), interface];
+        let network: Ipv6Net = "fd00::/64".parse().unwrap();
+        let (found, source_ip) = find_interface(&interfaces, &network).unwrap();
+        assert_eq!(found.name, "eth0");
+        assert_eq!(source_ip, local_ip());
+        assert!(find_interface(&interfaces, &"fd01::/64".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_collect_responders() {
+        let interface = dummy::dummy_interface(0);
+        let mut config = dummy::Config::default();
+        let inject = config.inject_handle().unwrap();
+        let sent = config.read_handle().unwrap();
+        let (mut tx, mut rx) = match dummy::channel(&interface, config).unwrap() {
+            Channel::Ethernet(tx, rx) => (tx, rx),
+            _ => panic!("The dummy channel is always ethernet"),
+        };
+
+        let identity = 0xbeef;
+        let first: Ipv6Addr = "fd00::20".parse().unwrap();
+        let second: Ipv6Addr = "fe80::30".parse().unwrap();
+        inject.send(Ok(build_echo_reply(first, identity))).unwrap();
+        // Duplicates, other identities and our own request are dropped
+        inject.send(Ok(build_echo_reply(first, identity))).unwrap();
+        inject
+            .send(Ok(build_echo_reply("fd00::99".parse().unwrap(), 1)))
+            .unwrap();
+        inject
+            .send(Ok(build_echo_reply(local_ip(), identity)))
+            .unwrap();
+        inject.send(Ok(build_echo_reply(second, identity))).unwrap();
+        // The dummy receiver blocks once it runs out of frames, so keep waking it
+        // up until the test is done with it.
+        thread::spawn(move || {
+            while inject
+                .send(Err(io::Error::from(io::ErrorKind::TimedOut)))
+                .is_ok()
+            {
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        let responders = collect_responders(
+            interface.mac.unwrap(),
+            local_ip(),
+            identity,
+            &mut *tx,
+            &mut *rx,
+            Duration::from_millis(200),
+        );
+        assert_eq!(responders, vec![first, second]);
+
+        let frame = sent.try_recv().unwrap();
+        assert_eq!(parse_echo_reply(&frame, identity), None);
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            ethernet.get_destination(),
+            MacAddr::new(0x33, 0x33, 0, 0, 0, 1)
+        );
+        let ipv6 = Ipv6Packet::new(ethernet.payload()).unwrap();
+        assert_eq!(ipv6.get_destination(), ALL_NODES);
+        let icmp = Icmpv6Packet::new(ipv6.payload()).unwrap();
+        assert_eq!(
+            checksum(&icmp, &local_ip(), &ALL_NODES),
+            icmp.get_checksum()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! The unprivileged TCP ping.  We attempt a full connection to each port and
+//! treat a refused connection the same as an accepted one, since either way
+//! something on the host answered us.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use tokio::{net::TcpStream, sync::Semaphore, task, time::timeout};
+use tracing::{debug, instrument};
+
+use crate::{
+    discovery::{TcpPingResult, TcpPingResultType},
+    target::TargetInstance,
+};
+
+#[instrument(level = "trace", skip(semaphore))]
+pub(crate) fn tcp_connect_sweep(
+    targets: Vec<TargetInstance>,
+    ports: Vec<u16>,
+    semaphore: Arc<Semaphore>,
+    wait: Duration,
+) -> impl Stream<Item = (TargetInstance, TcpPingResult)> {
+    let futures = FuturesUnordered::new();
+    for target in targets {
+        let ports = ports.clone();
+        let semaphore = semaphore.clone();
+        futures.push(task::spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let result = connect_ping(target.get_ip(), ports, wait).await;
+            (target, result)
+        }));
+    }
+    // Same as the full open port scan, a failure here means the task was
+    // canceled or panicked.
+    futures.map(|x| x.unwrap())
+}
+
+#[instrument(level = "trace")]
+async fn connect_ping(ip: IpAddr, ports: Vec<u16>, wait: Duration) -> TcpPingResult {
+    let probe_sent = SystemTime::now();
+    let mut attempts: FuturesUnordered<_> = ports
+        .into_iter()
+        .map(|port| async move { (port, TcpStream::connect(SocketAddr::new(ip, port)).await) })
+        .collect();
+    let mut first_error: Option<io::Error> = None;
+    let answer = timeout(wait, async {
+        while let Some((port, result)) = attempts.next().await {
+            match result {
+                Ok(_) => {
+                    return Some(TcpPingResultType::Open {
+                        port,
+                        time_received: SystemTime::now(),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    return Some(TcpPingResultType::Reset {
+                        port,
+                        time_received: SystemTime::now(),
+                    })
+                }
+                Err(e) => {
+                    debug!("Connect ping to {}:{} failed {:?}", ip, port, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        None
+    })
+    .await;
+    let result_type = match answer {
+        Ok(Some(result_type)) => result_type,
+        // Every port failed without a response, for example the host is unreachable
+        Ok(None) => match first_error {
+            Some(e) => TcpPingResultType::Error(e),
+            None => TcpPingResultType::Timeout,
+        },
+        Err(_elapsed) => TcpPingResultType::Timeout,
+    };
+    TcpPingResult {
+        probe_sent,
+        result_type,
+        fingerprint: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use tokio::net::TcpListener;
+
+    use crate::discovery::{tcp_connect::connect_ping, TcpPingResultType};
+
+    #[tokio::test]
+    async fn test_open_and_refused_ports_mean_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        // Grab a free port and then release it so nothing is listening on it
+        let closed_port = {
+            let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            closed.local_addr().unwrap().port()
+        };
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let wait = Duration::from_secs(1);
+
+        let open = connect_ping(localhost, vec![open_port], wait).await;
+        assert!(matches!(
+            open.result_type,
+            TcpPingResultType::Open { port, .. } if port == open_port
+        ));
+        assert!(open.host_up());
+        assert!(open.round_trip_time().is_some());
+
+        let refused = connect_ping(localhost, vec![closed_port], wait).await;
+        assert!(matches!(
+            refused.result_type,
+            TcpPingResultType::Reset { port, .. } if port == closed_port
+        ));
+        assert!(refused.host_up());
+
+        let no_ports = connect_ping(localhost, vec![], wait).await;
+        assert!(matches!(no_ports.result_type, TcpPingResultType::Timeout));
+    }
+}
@@ -0,0 +1,456 @@
+//! TCP SYN and ACK pings.  These build TCP segments by hand and send them over
+//! raw sockets, so they need privileged access.  The replies are read off a raw
+//! socket too, which sees every TCP segment the host receives, so anything
+//! that isn't a response to one of our probes gets filtered out.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_stream::{stream, try_stream};
+use futures::{select, stream::select as combine, FutureExt, Stream, StreamExt};
+use pnet::packet::{
+    ipv4::Ipv4Packet,
+    tcp::{ipv4_checksum, ipv6_checksum, MutableTcpPacket, TcpFlags, TcpPacket},
+    MutablePacket, Packet,
+};
+use rand::{random, thread_rng, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{io::unix::AsyncFd, sync::Semaphore, time::sleep};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    discovery::{TcpPingResult, TcpPingResultType},
+    os_detection::TcpFingerprint,
+    target::TargetInstance,
+    utils::raw_socket::{cast_as_maybe, local_address_for, recv_from, send_to},
+    PortscanErr,
+};
+
+/// A TCP header without any options.
+const TCP_HEADER_SIZE: usize = 20;
+/// The options we offer in every SYN.  Hosts only send back the options we
+/// offer, and which ones they send and in what order is a big part of
+/// fingerprinting their OS.  This is MSS 1460, SACK permitted, timestamps and
+/// window scale 7, laid out the same way Linux does.
+const SYN_OPTIONS: [u8; 20] = [
+    2, 4, 5, 180, 4, 2, 8, 10, 0, 0, 0, 0, 0, 0, 0, 0, 1, 3, 3, 7,
+];
+
+/// The type of segment we send to each port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RawTcpProbe {
+    Syn,
+    Ack,
+}
+
+/// What a host said in response to one of our probes.
+#[derive(Debug, Eq, PartialEq)]
+enum ProbeAnswer {
+    Open,
+    Reset,
+}
+
+/// The parts of an incoming TCP segment we care about.
+#[derive(Debug)]
+struct ReceivedTcpSegment {
+    source: IpAddr,
+    source_port: u16,
+    destination_port: u16,
+    flags: u8,
+    sequence: u32,
+    acknowledgement: u32,
+    /// Only set for SYN/ACKs
+    fingerprint: Option<TcpFingerprint>,
+    time_received: SystemTime,
+}
+
+#[instrument(level = "trace", skip(semaphore))]
+pub(crate) async fn tcp_raw_sweep(
+    targets: Vec<TargetInstance>,
+    probe: RawTcpProbe,
+    ports: Vec<u16>,
+    semaphore: Arc<Semaphore>,
+    wait: Duration,
+) -> Result<impl Stream<Item = (TargetInstance, TcpPingResult)>, PortscanErr> {
+    #[instrument(level = "error")]
+    fn socket_open_error(_: io::Error) -> PortscanErr {
+        PortscanErr::InsufficientPermission
+    }
+
+    let ipv4_sender =
+        Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::TCP)).map_err(socket_open_error)?;
+    let ipv6_sender =
+        Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::TCP)).map_err(socket_open_error)?;
+    let ipv4_listener_socket =
+        Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::TCP)).map_err(socket_open_error)?;
+    let ipv6_listener_socket =
+        Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::TCP)).map_err(socket_open_error)?;
+    let listener = combine(
+        listen_for_tcp(ipv4_listener_socket).boxed(),
+        listen_for_tcp(ipv6_listener_socket).boxed(),
+    );
+
+    // Replies are matched to this sweep by our source port and sequence number
+    let source_port: u16 = thread_rng().gen_range(32768..61000);
+    let sequence: u32 = random();
+    let mut pending = HashMap::new();
+    let mut errors = Vec::new();
+    for target in targets {
+        let ip = target.get_ip();
+        let sender = match ip {
+            IpAddr::V4(_) => &ipv4_sender,
+            IpAddr::V6(_) => &ipv6_sender,
+        };
+        let _permit = semaphore.clone().acquire_owned().await;
+        let probe_sent = SystemTime::now();
+        match send_probes(sender, probe, ip, &ports, source_port, sequence).await {
+            Ok(()) => {
+                pending.insert(ip, (target, probe_sent));
+            }
+            Err(e) => errors.push((
+                target,
+                TcpPingResult {
+                    probe_sent,
+                    result_type: TcpPingResultType::Error(e),
+                    fingerprint: None,
+                },
+            )),
+        }
+    }
+
+    Ok(combine(
+        futures::stream::iter(errors),
+        await_answers(pending, listener, probe, ports, source_port, sequence, wait),
+    ))
+}
+
+#[instrument(level = "trace")]
+async fn send_probes(
+    socket: &Socket,
+    probe: RawTcpProbe,
+    destination: IpAddr,
+    ports: &[u16],
+    source_port: u16,
+    sequence: u32,
+) -> io::Result<()> {
+    let source = SocketAddr::new(local_address_for(destination)?, source_port);
+    for port in ports {
+        let segment = build_probe(probe, source, SocketAddr::new(destination, *port), sequence)?;
+        let async_fd = AsyncFd::new(socket.as_raw_fd())?;
+        // The port is carried in the TCP header we built.  Raw sockets want it
+        // left out of the address.
+        send_to(
+            async_fd,
+            socket,
+            SocketAddr::new(destination, 0).into(),
+            &segment,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+fn build_probe(
+    probe: RawTcpProbe,
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+) -> io::Result<Vec<u8>> {
+    let options: &[u8] = match probe {
+        RawTcpProbe::Syn => &SYN_OPTIONS,
+        RawTcpProbe::Ack => &[],
+    };
+    let mut buffer = vec![0u8; TCP_HEADER_SIZE + options.len()];
+    let mut packet = MutableTcpPacket::new(&mut buffer).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Buffer is too small for a TCP header",
+        )
+    })?;
+    packet.set_source(source.port());
+    packet.set_destination(destination.port());
+    packet.set_sequence(sequence);
+    packet.set_data_offset(((TCP_HEADER_SIZE + options.len()) / 4) as u8);
+    packet.set_window(1024);
+    packet.packet_mut()[TCP_HEADER_SIZE..].copy_from_slice(options);
+    match probe {
+        RawTcpProbe::Syn => packet.set_flags(TcpFlags::SYN),
+        RawTcpProbe::Ack => {
+            packet.set_flags(TcpFlags::ACK);
+            // The host will use this as the sequence number of its RST
+            packet.set_acknowledgement(sequence);
+        }
+    }
+    let checksum = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            ipv4_checksum(&packet.to_immutable(), &source, &destination)
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            ipv6_checksum(&packet.to_immutable(), &source, &destination)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Source and destination must be the same IP version",
+            ))
+        }
+    };
+    packet.set_checksum(checksum);
+    Ok(buffer)
+}
+
+/// Check if a segment is a response to one of our probes.
+fn classify(
+    probe: RawTcpProbe,
+    sequence: u32,
+    segment: &ReceivedTcpSegment,
+) -> Option<ProbeAnswer> {
+    let reset = segment.flags & TcpFlags::RST != 0;
+    let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+    match probe {
+        RawTcpProbe::Syn if segment.acknowledgement == sequence.wrapping_add(1) => {
+            if reset {
+                Some(ProbeAnswer::Reset)
+            } else if segment.flags & syn_ack == syn_ack {
+                Some(ProbeAnswer::Open)
+            } else {
+                None
+            }
+        }
+        RawTcpProbe::Ack if reset && segment.sequence == sequence => Some(ProbeAnswer::Reset),
+        _ => None,
+    }
+}
+
+#[instrument(level = "trace", skip(pending, listener))]
+fn await_answers(
+    mut pending: HashMap<IpAddr, (TargetInstance, SystemTime)>,
+    mut listener: impl Stream<Item = io::Result<ReceivedTcpSegment>> + Unpin,
+    probe: RawTcpProbe,
+    ports: Vec<u16>,
+    source_port: u16,
+    sequence: u32,
+    wait: Duration,
+) -> impl Stream<Item = (TargetInstance, TcpPingResult)> {
+    stream! {
+        let mut timeout = sleep(wait).boxed().fuse();
+        let mut segment_future = listener.next().fuse();
+        while !pending.is_empty() {
+            select! {
+                () = timeout => {
+                    debug!("TCP ping timed out with {} targets left", pending.len());
+                    break;
+                }
+                result = segment_future => {
+                    segment_future = listener.next().fuse();
+                    match result {
+                        Some(Ok(segment)) => {
+                            let ours = segment.destination_port == source_port
+                                && ports.contains(&segment.source_port);
+                            let answer = if ours { classify(probe, sequence, &segment) } else { None };
+                            match (answer, pending.remove(&segment.source)) {
+                                (Some(answer), Some((target, probe_sent))) => {
+                                    let port = segment.source_port;
+                                    let time_received = segment.time_received;
+                                    let result_type = match answer {
+                                        ProbeAnswer::Open => TcpPingResultType::Open { port, time_received },
+                                        ProbeAnswer::Reset => TcpPingResultType::Reset { port, time_received },
+                                    };
+                                    let fingerprint = segment.fingerprint;
+                                    yield (target, TcpPingResult { probe_sent, result_type, fingerprint });
+                                }
+                                (None, Some(entry)) => {
+                                    // Not an answer to our probe, keep waiting on this host
+                                    pending.insert(segment.source, entry);
+                                }
+                                (_, None) => {}
+                            }
+                        }
+                        Some(Err(e)) => {
+                            error!("Found an error when reading a TCP segment {:?}", e);
+                        }
+                        None => {
+                            debug!("The stream is done");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        for (_, (target, probe_sent)) in pending {
+            yield (target, TcpPingResult { probe_sent, result_type: TcpPingResultType::Timeout, fingerprint: None });
+        }
+    }
+}
+
+#[instrument(level = "trace")]
+fn listen_for_tcp(mut socket: Socket) -> impl Stream<Item = io::Result<ReceivedTcpSegment>> {
+    try_stream! {
+        socket.set_nonblocking(true)?;
+        let mut buffer = [0u8; 65535];
+        let fd = socket.as_raw_fd();
+        let async_fd = AsyncFd::new(fd)?;
+        loop {
+            let (bytes_read, source) = recv_from(&async_fd, &mut socket, cast_as_maybe(&mut buffer)).await?;
+            if let Some(std_src) = source.as_socket() {
+                if let Some(segment) = parse_segment(std_src, &buffer[..bytes_read]) {
+                    yield segment;
+                }
+            } else {
+                warn!("We read in {} bytes but didn't have a source.", bytes_read);
+            }
+        }
+    }
+}
+
+fn parse_segment(source: SocketAddr, buffer: &[u8]) -> Option<ReceivedTcpSegment> {
+    // Raw IPv4 sockets hand us the IP header but raw IPv6 sockets don't, so we
+    // only learn the TTL for IPv4
+    let to_segment = |tcp: TcpPacket, ttl: Option<u8>| {
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        let fingerprint = if tcp.get_flags() & syn_ack == syn_ack {
+            TcpFingerprint::from_segment(tcp.packet(), ttl)
+        } else {
+            None
+        };
+        ReceivedTcpSegment {
+            source: source.ip(),
+            source_port: tcp.get_source(),
+            destination_port: tcp.get_destination(),
+            flags: tcp.get_flags(),
+            sequence: tcp.get_sequence(),
+            acknowledgement: tcp.get_acknowledgement(),
+            fingerprint,
+            time_received: SystemTime::now(),
+        }
+    };
+    let to_ret = match source {
+        SocketAddr::V4(_) => {
+            let ip_packet = Ipv4Packet::new(buffer)?;
+            TcpPacket::new(ip_packet.payload())
+                .map(|tcp| to_segment(tcp, Some(ip_packet.get_ttl())))
+        }
+        SocketAddr::V6(_) => TcpPacket::new(buffer).map(|tcp| to_segment(tcp, None)),
+    };
+    if to_ret.is_none() {
+        info!("Failed to parse TCP segment from {:?}", source);
+    }
+    to_ret
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use pnet::packet::tcp::{ipv6_checksum, TcpFlags, TcpPacket};
+
+    use crate::discovery::tcp_raw::{
+        build_probe, classify, parse_segment, ProbeAnswer, RawTcpProbe,
+    };
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+    }
+
+    /// Build the segment a host would send back after we probe `port`.
+    fn build_response(port: u16, flags: u8, sequence: u32, acknowledgement: u32) -> Vec<u8> {
+        let mut buffer = build_probe(RawTcpProbe::Syn, v6(port), v6(40000), 0).unwrap();
+        let mut packet = pnet::packet::tcp::MutableTcpPacket::new(&mut buffer).unwrap();
+        packet.set_flags(flags);
+        packet.set_sequence(sequence);
+        packet.set_acknowledgement(acknowledgement);
+        buffer
+    }
+
+    #[test]
+    fn test_build_probe() {
+        let segment = build_probe(RawTcpProbe::Syn, v6(40000), v6(443), 1234).unwrap();
+        let packet = TcpPacket::new(&segment).unwrap();
+        assert_eq!(packet.get_source(), 40000);
+        assert_eq!(packet.get_destination(), 443);
+        assert_eq!(packet.get_flags(), TcpFlags::SYN);
+        assert_eq!(packet.get_sequence(), 1234);
+        assert_eq!(packet.get_data_offset(), 10);
+        assert_eq!(
+            packet.get_checksum(),
+            ipv6_checksum(&packet, &Ipv6Addr::LOCALHOST, &Ipv6Addr::LOCALHOST)
+        );
+
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 80);
+        assert!(build_probe(RawTcpProbe::Ack, v4, v6(443), 1234).is_err());
+    }
+
+    #[test]
+    fn test_classify_responses() {
+        let syn_ack = build_response(443, TcpFlags::SYN | TcpFlags::ACK, 99, 1235);
+        let segment = parse_segment(v6(0), &syn_ack).unwrap();
+        assert_eq!(segment.source_port, 443);
+        assert_eq!(segment.destination_port, 40000);
+        // Our SYN's options echoed back look just like Linux
+        let fingerprint = segment.fingerprint.as_ref().unwrap();
+        assert_eq!(fingerprint.window_scale, Some(7));
+        assert_eq!(fingerprint.ttl, None);
+        assert_eq!(
+            classify(RawTcpProbe::Syn, 1234, &segment),
+            Some(ProbeAnswer::Open)
+        );
+        // Someone else's handshake
+        assert_eq!(classify(RawTcpProbe::Syn, 5000, &segment), None);
+
+        let rst_ack = build_response(443, TcpFlags::RST | TcpFlags::ACK, 0, 1235);
+        let segment = parse_segment(v6(0), &rst_ack).unwrap();
+        assert_eq!(
+            classify(RawTcpProbe::Syn, 1234, &segment),
+            Some(ProbeAnswer::Reset)
+        );
+
+        let rst = build_response(80, TcpFlags::RST, 1234, 0);
+        let segment = parse_segment(v6(0), &rst).unwrap();
+        assert_eq!(
+            classify(RawTcpProbe::Ack, 1234, &segment),
+            Some(ProbeAnswer::Reset)
+        );
+        assert_eq!(classify(RawTcpProbe::Ack, 1, &segment), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4_segment() {
+        let rst = build_response(80, TcpFlags::RST, 1234, 0);
+        let total_length = (20 + rst.len()) as u8;
+        let mut packet = vec![
+            0x45,
+            0,
+            0,
+            total_length,
+            0,
+            0,
+            0,
+            0,
+            64,
+            6,
+            0,
+            0,
+            127,
+            0,
+            0,
+            1,
+            127,
+            0,
+            0,
+            1,
+        ];
+        packet.extend(rst);
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let segment = parse_segment(source, &packet).unwrap();
+        assert_eq!(segment.source, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(segment.source_port, 80);
+        assert_eq!(segment.flags, TcpFlags::RST);
+        assert!(segment.fingerprint.is_none());
+    }
+}
@@ -10,6 +10,17 @@ pub enum PortscanErr {
     /// likely the use of a raw socket.  Examples of scans that require that
     /// are ICMP and SYN scans.
     InsufficientPermission,
+    /// The network has too many addresses to scan one by one and we couldn't
+    /// discover its hosts with multicast, most likely because it isn't
+    /// directly attached.  Only large IPv6 networks hit this.
+    NetworkTooLarge,
+    /// A service detection signature couldn't be loaded.  This holds a
+    /// description of what was wrong with it.
+    InvalidSignature(String),
+    /// The service detection rules depend on each other in a way that can't
+    /// be run, like a loop or a missing dependency.  This holds a description
+    /// of the problem.
+    InvalidRuleGraph(String),
     // /// We can't always predict or manage all types of errors and make unique variants for
     // each. /// This acts as catch all.
     // UnknownError(Box<dyn std::error::Error>)
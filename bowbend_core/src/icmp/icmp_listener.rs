@@ -1,32 +1,73 @@
 use std::{
     io,
-    mem::MaybeUninit,
     net::{IpAddr, SocketAddr},
     os::unix::io::AsRawFd,
     time::SystemTime,
 };
 
 use async_stream::try_stream;
-use byteorder::{BigEndian, ByteOrder};
-use futures::Stream;
-use pnet::packet::{icmp::IcmpPacket, ipv4::Ipv4Packet, ipv6::Ipv6Packet, Packet};
-use socket2::{SockAddr, Socket};
+use futures::{future::ready, Stream, StreamExt};
+use socket2::Socket;
 use tokio::io::unix::AsyncFd;
-use tracing::{info, instrument, warn};
-//TODO: We should differentiate between replies and Destination Unreachable
-// etc.  Right now this is does what we need.  The difference between a timeout
-// and host unreachable is kind of semantics.  We still won't continue the scan
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    icmp::{
+        packet::{decode_timestamp, IcmpMessage, IcmpV4, IcmpV6, IpDatagram, Proto},
+        IcmpSocketKind,
+    },
+    utils::raw_socket::{cast_as_maybe, recv_from},
+};
+
+/// An ICMP message related to one of our echo requests.
 #[derive(Debug)]
 pub(crate) struct ReceivedIcmpPacket {
+    /// The host that sent us this message.  For a reply this is the host we
+    /// pinged, for errors it is usually some router along the way.
     pub source: IpAddr,
+    /// The destination of the echo request this message is about.
+    pub target: IpAddr,
     pub identity: u16,
+    /// The sequence number of the echo request this message is about.
+    pub sequence: u16,
+    /// When the echo request was sent, if the message carried the timestamp
+    /// we put in its payload.
+    pub time_sent: Option<SystemTime>,
+    pub kind: ReceivedIcmpKind,
+    /// The TTL, or hop limit, the message arrived with.  This is only known
+    /// when the socket hands us the IP header.
+    pub ttl: Option<u8>,
+    pub time_received: SystemTime,
+}
+
+/// The types of ICMP messages we can receive in response to an echo request.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ReceivedIcmpKind {
+    EchoReply,
+    DestinationUnreachable { code: u8 },
+    TimeExceeded,
+}
+
+/// An ICMP message read off of a socket.  Anything that cares about ICMP
+/// messages besides echo replies, like traceroute, decodes these itself.
+#[derive(Debug)]
+pub(crate) struct RawIcmpMessage {
+    /// The host that sent us the message
+    pub source: IpAddr,
+    /// The ICMP message without any IP header
+    pub bytes: Vec<u8>,
+    /// The TTL, or hop limit, the message arrived with.  This is only known
+    /// when the socket hands us the IP header.
+    pub ttl: Option<u8>,
     pub time_received: SystemTime,
 }
 
+/// Read every ICMP message that arrives on the socket.
 #[instrument(level = "trace")]
-pub(crate) fn listen_for_icmp(
+pub(crate) fn read_icmp(
     mut socket: Socket,
-) -> impl Stream<Item = io::Result<ReceivedIcmpPacket>> {
+    kind: IcmpSocketKind,
+) -> impl Stream<Item = io::Result<RawIcmpMessage>> {
     try_stream! {
         socket.set_nonblocking(true)?;
         // TODO:  I think I can make this a little smaller but not positive.  Going big just to be safe.  Also might want to move it off the stack
@@ -34,11 +75,16 @@ pub(crate) fn listen_for_icmp(
         let fd = socket.as_raw_fd();
         let async_fd = AsyncFd::new(fd)?;
         loop {
-            let (bytes_read, source) = internal_read(&async_fd, &mut socket, cast_as_maybe(&mut buffer)).await?;
+            let (bytes_read, source) = recv_from(&async_fd, &mut socket, cast_as_maybe(&mut buffer)).await?;
             if let Some(std_src) = source.as_socket(){
-                info!("We got a ping with {} bytes from {:?}", bytes_read, std_src);
-                if let Some(to_ret) = parse_packet(std_src, &buffer, bytes_read){
-                    yield to_ret;
+                info!("We got an ICMP message with {} bytes from {:?}", bytes_read, std_src);
+                if let Some((icmp, ttl)) = icmp_payload(std_src, &buffer[..bytes_read], kind){
+                    yield RawIcmpMessage {
+                        source: std_src.ip(),
+                        bytes: icmp.to_vec(),
+                        ttl,
+                        time_received: SystemTime::now(),
+                    };
                 }
             } else {
                 warn!("We read in {} bytes but didn't have a source.", bytes_read);
@@ -47,65 +93,189 @@ pub(crate) fn listen_for_icmp(
     }
 }
 
-#[tracing::instrument(level = "trace", skip(buffer))]
-async fn internal_read(
-    async_fd: &AsyncFd<i32>,
-    socket: &mut Socket,
-    buffer: &mut [MaybeUninit<u8>],
-) -> Result<(usize, SockAddr), io::Error> {
-    loop {
-        let mut read_guard = async_fd.readable().await?;
-        match read_guard.try_io(|_| socket.recv_from(buffer)) {
-            Ok(result) => return result,
-            Err(_would_block) => continue,
-        }
-    }
+/// Read the ICMP messages related to our echo requests.
+#[instrument(level = "trace")]
+pub(crate) fn listen_for_icmp(
+    socket: Socket,
+    kind: IcmpSocketKind,
+) -> impl Stream<Item = io::Result<ReceivedIcmpPacket>> {
+    read_icmp(socket, kind).filter_map(|message| {
+        ready(match message {
+            Ok(message) => parse_icmp(message.source, &message.bytes, message.ttl).map(Ok),
+            Err(e) => Some(Err(e)),
+        })
+    })
 }
 
-#[instrument(level = "trace", skip(buffer))]
-fn parse_packet(
+/// Only raw IPv4 sockets include the IP header.  Raw IPv6 sockets and
+/// datagram sockets hand us the ICMP message on its own, so we don't learn the
+/// TTL it arrived with.
+fn icmp_payload(
     source: SocketAddr,
     buffer: &[u8],
-    bytes_read: usize,
-) -> Option<ReceivedIcmpPacket> {
-    match &source {
-        SocketAddr::V4(_) => {
-            if let Some(ip_packet) = Ipv4Packet::new(&buffer[..bytes_read]) {
-                parse_icmp(source, ip_packet.payload())
-            } else {
-                info!("Failed to parse IPv4 packet");
-                None
-            }
-        }
-        SocketAddr::V6(_) => {
-            if let Some(ip_packet) = Ipv6Packet::new(&buffer[..bytes_read]) {
-                parse_icmp(source, ip_packet.payload())
-            } else {
-                info!("Failed to parse IPv6 packet");
+    kind: IcmpSocketKind,
+) -> Option<(&[u8], Option<u8>)> {
+    match (&source, kind) {
+        (SocketAddr::V4(_), IcmpSocketKind::Raw) => match IpDatagram::decode(buffer) {
+            Ok(datagram) => Some((datagram.payload, Some(datagram.ttl))),
+            Err(e) => {
+                info!("Failed to parse IPv4 packet {:?}", e);
                 None
             }
-        }
+        },
+        _ => Some((buffer, None)),
     }
 }
-//TODO: replace pnet's implementation with my own.  It's already there just
-// need to drop it in and test it
-fn parse_icmp(source: SocketAddr, ip_payload: &[u8]) -> Option<ReceivedIcmpPacket> {
-    let to_ret = IcmpPacket::new(ip_payload).map(|icmp_packet| {
-        let identity = BigEndian::read_u16(icmp_packet.payload());
-        ReceivedIcmpPacket {
-            source: source.ip(),
-            identity,
-            time_received: SystemTime::now(),
-        }
-    });
+
+fn parse_icmp(source: IpAddr, ip_payload: &[u8], ttl: Option<u8>) -> Option<ReceivedIcmpPacket> {
+    let to_ret = match source {
+        IpAddr::V4(_) => decode_icmp::<IcmpV4>(source, ip_payload, ttl),
+        IpAddr::V6(_) => decode_icmp::<IcmpV6>(source, ip_payload, ttl),
+    };
     if to_ret.is_none() {
-        info!("Failed to parse ICMP packet")
+        info!("Failed to parse ICMP packet or it wasn't related to an echo request")
     }
     to_ret
 }
 
-fn cast_as_maybe(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
-    // Here is documentation on why this extremely unsafe looking thing is actually
-    // safe: https://docs.rs/socket2/0.4.1/socket2/struct.Socket.html#safety
-    unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) }
+fn decode_icmp<P: Proto>(
+    source: IpAddr,
+    buffer: &[u8],
+    ttl: Option<u8>,
+) -> Option<ReceivedIcmpPacket> {
+    let (target, identity, sequence, time_sent, kind) = match IcmpMessage::decode::<P>(buffer) {
+        Ok(IcmpMessage::EchoReply(reply)) => (
+            source,
+            reply.ident,
+            reply.seq_cnt,
+            decode_timestamp(reply.payload),
+            ReceivedIcmpKind::EchoReply,
+        ),
+        Ok(IcmpMessage::DestinationUnreachable(unreachable)) => {
+            let (identity, sequence) = unreachable.original.echo_request()?;
+            (
+                unreachable.original.destination,
+                identity,
+                sequence,
+                None,
+                ReceivedIcmpKind::DestinationUnreachable {
+                    code: unreachable.code,
+                },
+            )
+        }
+        Ok(IcmpMessage::TimeExceeded(exceeded)) => {
+            let (identity, sequence) = exceeded.original.echo_request()?;
+            (
+                exceeded.original.destination,
+                identity,
+                sequence,
+                None,
+                ReceivedIcmpKind::TimeExceeded,
+            )
+        }
+        Ok(IcmpMessage::ParameterProblem(problem)) => {
+            debug!(
+                "Ignoring a parameter problem at offset {} for {:?}",
+                problem.pointer, problem.original.destination
+            );
+            return None;
+        }
+        Ok(IcmpMessage::PacketTooBig(too_big)) => {
+            debug!(
+                "Ignoring packet too big with MTU {} for {:?}",
+                too_big.mtu, too_big.original.destination
+            );
+            return None;
+        }
+        Ok(IcmpMessage::Other { type_, code }) => {
+            debug!(
+                "Ignoring ICMP message with type {} and code {}",
+                type_, code
+            );
+            return None;
+        }
+        Err(e) => {
+            debug!("Failed to decode ICMP message {:?}", e);
+            return None;
+        }
+    };
+    Some(ReceivedIcmpPacket {
+        source,
+        target,
+        identity,
+        sequence,
+        time_sent,
+        kind,
+        ttl,
+        time_received: SystemTime::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::icmp::{
+        icmp_listener::{icmp_payload, parse_icmp, ReceivedIcmpKind, ReceivedIcmpPacket},
+        packet::{write_checksum, EchoRequest, IcmpV4, IcmpV6},
+        IcmpSocketKind,
+    };
+
+    fn parse_packet(
+        source: SocketAddr,
+        buffer: &[u8],
+        kind: IcmpSocketKind,
+    ) -> Option<ReceivedIcmpPacket> {
+        let (payload, ttl) = icmp_payload(source, buffer, kind)?;
+        parse_icmp(source.ip(), payload, ttl)
+    }
+
+    #[test]
+    fn test_datagram_reads_have_no_ip_header() {
+        let mut icmp = [0u8; 8];
+        EchoRequest {
+            ident: 7,
+            seq_cnt: 3,
+            payload: &[],
+        }
+        .encode::<IcmpV4>(&mut icmp)
+        .unwrap();
+        // Turn the request into a reply
+        icmp[0] = 0;
+        write_checksum(&mut icmp);
+        let source = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 0);
+
+        let packet = parse_packet(source, &icmp, IcmpSocketKind::Datagram).unwrap();
+        assert_eq!(packet.kind, ReceivedIcmpKind::EchoReply);
+        assert_eq!((packet.identity, packet.sequence), (7, 3));
+        assert_eq!(packet.ttl, None);
+
+        let mut datagram = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 1, 0, 0];
+        datagram.extend_from_slice(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        datagram.extend_from_slice(&icmp);
+        let packet = parse_packet(source, &datagram, IcmpSocketKind::Raw).unwrap();
+        assert_eq!((packet.identity, packet.sequence), (7, 3));
+        assert_eq!(packet.ttl, Some(64));
+        // Reading a raw socket's data as if it came from a datagram socket
+        // finds garbage instead of a reply
+        assert!(parse_packet(source, &datagram, IcmpSocketKind::Datagram).is_none());
+    }
+
+    #[test]
+    fn test_raw_icmpv6_reads_have_no_ip_header() {
+        let mut icmp = [0u8; 8];
+        EchoRequest {
+            ident: 9,
+            seq_cnt: 1,
+            payload: &[],
+        }
+        .encode::<IcmpV6>(&mut icmp)
+        .unwrap();
+        icmp[0] = 129;
+        let source = SocketAddr::new("fd00::2".parse().unwrap(), 0);
+        let packet = parse_packet(source, &icmp, IcmpSocketKind::Raw).unwrap();
+        assert_eq!(packet.kind, ReceivedIcmpKind::EchoReply);
+        assert_eq!(packet.target, source.ip());
+        assert_eq!((packet.identity, packet.sequence), (9, 1));
+    }
 }
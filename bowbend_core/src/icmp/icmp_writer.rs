@@ -5,8 +5,11 @@ use tokio::{io::unix::AsyncFd, sync::Semaphore};
 use tracing::{info, instrument};
 
 use crate::{
-    icmp::packet::{EchoRequest, IcmpV4, IcmpV6},
+    icmp::packet::{
+        encode_timestamp, EchoRequest, IcmpV4, IcmpV6, HEADER_SIZE, TIMESTAMP_PAYLOAD_SIZE,
+    },
     target::TargetInstance,
+    utils::raw_socket::send_to,
 };
 
 pub(crate) struct PingSentSummary {
@@ -41,8 +44,11 @@ pub(crate) async fn send_ping(
     semaphore: Arc<Semaphore>,
 ) -> Result<PingSentSummary, PingWriteError> {
     let _permit = semaphore.acquire_owned().await;
-    let mut buffer = [0; 12];
-    let payload = vec![1, 2, 3, 4];
+    let mut buffer = [0; HEADER_SIZE + TIMESTAMP_PAYLOAD_SIZE];
+    // The reply echoes our payload back, so stamping the send time here lets us
+    // work out the round trip time for every request, not just the first.
+    let time_sent = SystemTime::now();
+    let payload = encode_timestamp(time_sent);
     let request = EchoRequest {
         ident: icmp_identity,
         seq_cnt: sequence_count,
@@ -60,8 +66,7 @@ pub(crate) async fn send_ping(
             .encode::<IcmpV6>(&mut buffer)
             .map_err(|e| PingWriteError::new(target_instance.clone(), e))?,
     }
-    let time_sent = SystemTime::now();
-    match internal_write(async_fd, socket, destination, &buffer).await {
+    match send_to(async_fd, socket, destination, &buffer).await {
         Ok(_) => {
             info!("Ping successfully sent");
             Ok(PingSentSummary {
@@ -76,19 +81,3 @@ pub(crate) async fn send_ping(
         }),
     }
 }
-
-#[instrument(level = "trace")]
-async fn internal_write(
-    async_fd: AsyncFd<i32>,
-    socket: &Socket,
-    destination: SockAddr,
-    buffer: &[u8],
-) -> io::Result<usize> {
-    loop {
-        let mut write_guard = async_fd.writable().await?;
-        match write_guard.try_io(|_| socket.send_to(buffer, &destination)) {
-            Ok(result) => return result,
-            Err(_would_block) => continue,
-        }
-    }
-}
@@ -2,9 +2,9 @@
 //! are up.
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::{Duration, SystemTime},
 };
@@ -18,17 +18,17 @@ use tracing::{debug, error, instrument};
 
 use crate::{
     icmp::{
-        icmp_listener::{listen_for_icmp, ReceivedIcmpPacket},
+        icmp_listener::{listen_for_icmp, ReceivedIcmpKind, ReceivedIcmpPacket},
         icmp_writer::{send_ping, PingSentSummary},
     },
     stream::iter,
     target::TargetInstance,
-    PortscanErr,
+    ConfigBuilder, PortscanErr,
 };
 
 pub(crate) mod icmp_listener;
 pub(crate) mod icmp_writer;
-mod packet;
+pub(crate) mod packet;
 
 /// The results of an send ICMP hello if sent.
 #[derive(Debug)]
@@ -38,6 +38,22 @@ pub struct PingResult {
     /// The result of the ping.  Either we got a reply, we timed out or hit some
     /// type of IO failure
     pub result_type: PingResultType,
+    /// Statistics across every echo request sent to the host.  This is `None`
+    /// if we failed to send the first echo request.
+    pub statistics: Option<PingStatistics>,
+}
+
+impl PingResult {
+    /// How long it took to receive a reply.  This is only set if we actually
+    /// got a reply.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        match &self.result_type {
+            PingResultType::Reply(summary) => {
+                summary.time_received.duration_since(summary.time_sent).ok()
+            }
+            _ => None,
+        }
+    }
 }
 
 /// The result from our ICMP stage.
@@ -49,105 +65,403 @@ pub enum PingResultType {
     Timeout,
     /// We received a reply
     Reply(IcmpSummary),
+    /// Some host along the way told us the target couldn't be reached.
+    Unreachable {
+        /// The ICMP code giving the reason.  These differ between ICMP and
+        /// ICMPv6.
+        code: u8,
+        /// The host that sent the unreachable message.
+        router: IpAddr,
+    },
+    /// The echo request ran out of hops before reaching the target.  This
+    /// usually points to a routing loop.
+    TtlExceeded {
+        /// The host that dropped our echo request.
+        router: IpAddr,
+    },
 }
 
-/// Details on an ICMP reply we received.  Right now we are just holding onto
-/// the time received.
+/// Details on an ICMP reply we received.  When multiple echo requests are sent
+/// this describes the first reply to arrive.
 #[derive(Debug)]
 pub struct IcmpSummary {
+    /// The time the echo request that triggered this reply was sent.
+    pub time_sent: SystemTime,
     /// The time the reply was received.
     pub time_received: SystemTime,
+    /// The TTL, or hop limit, the reply arrived with.  This is only known for
+    /// IPv4 replies read off a raw socket.
+    pub ttl: Option<u8>,
+}
+
+/// Statistics gathered across every echo request sent to a host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PingStatistics {
+    /// The number of echo requests we attempted to send.
+    pub transmitted: u16,
+    /// The number of replies we received.
+    pub received: u16,
+    /// The fraction of echo requests that went unanswered, between 0.0 and
+    /// 1.0.
+    pub packet_loss: f64,
+    /// The fastest round trip time.  This is `None` if we got no replies.
+    pub min_rtt: Option<Duration>,
+    /// The mean round trip time.  This is `None` if we got no replies.
+    pub avg_rtt: Option<Duration>,
+    /// The slowest round trip time.  This is `None` if we got no replies.
+    pub max_rtt: Option<Duration>,
+    /// The mean difference in round trip time between consecutive replies.
+    /// This is `None` if we got fewer than two replies.
+    pub jitter: Option<Duration>,
+}
+
+impl PingStatistics {
+    /// Build the statistics from the round trip times of every reply, ordered
+    /// by sequence number.
+    fn new(transmitted: u16, round_trip_times: &[Duration]) -> Self {
+        let received = round_trip_times.len() as u16;
+        let packet_loss = if transmitted == 0 {
+            0.0
+        } else {
+            1.0 - f64::from(received) / f64::from(transmitted)
+        };
+        let avg_rtt = if round_trip_times.is_empty() {
+            None
+        } else {
+            Some(round_trip_times.iter().sum::<Duration>() / u32::from(received))
+        };
+        let jitter = if round_trip_times.len() < 2 {
+            None
+        } else {
+            let total: Duration = round_trip_times
+                .windows(2)
+                // Duration::abs_diff is newer than our minimum supported Rust version
+                .map(|pair| {
+                    pair[1]
+                        .checked_sub(pair[0])
+                        .unwrap_or_else(|| pair[0] - pair[1])
+                })
+                .sum();
+            Some(total / (round_trip_times.len() as u32 - 1))
+        };
+        PingStatistics {
+            transmitted,
+            received,
+            packet_loss,
+            min_rtt: round_trip_times.iter().min().cloned(),
+            avg_rtt,
+            max_rtt: round_trip_times.iter().max().cloned(),
+            jitter,
+        }
+    }
+}
+
+/// The kind of socket we managed to open for ICMP.  This changes what we read
+/// back and how echo requests are matched up with replies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum IcmpSocketKind {
+    /// A raw socket.  This sees every ICMP message the host receives and needs
+    /// privileged access.
+    Raw,
+    /// An ICMP datagram socket.  Linux lets unprivileged users open these if
+    /// their group is in `net.ipv4.ping_group_range`.  The kernel only hands
+    /// us replies to our own echo requests, never includes the IP header and
+    /// overwrites the identifier of every request with the socket's port.
+    Datagram,
+}
+
+/// Everything needed to ping over one address family.
+struct IcmpSockets {
+    sender: Arc<Socket>,
+    listener: Socket,
+    kind: IcmpSocketKind,
+    /// Set when the kernel picks the echo request identifier for us.
+    fixed_identity: Option<u16>,
+}
+
+impl IcmpSockets {
+    /// Open a raw socket if we can, otherwise fall back on a datagram socket.
+    fn open(domain: Domain, protocol: Protocol) -> io::Result<Self> {
+        let raw = Socket::new_raw(domain, Type::RAW, Some(protocol))
+            .and_then(|sender| Ok((sender, Socket::new_raw(domain, Type::RAW, Some(protocol))?)));
+        let e = match raw {
+            Ok((sender, listener)) => {
+                return Ok(IcmpSockets {
+                    sender: Arc::new(sender),
+                    listener,
+                    kind: IcmpSocketKind::Raw,
+                    fixed_identity: None,
+                })
+            }
+            Err(e) => e,
+        };
+        debug!(
+            "Failed to open a raw ICMP socket, trying a datagram socket {:?}",
+            e
+        );
+        let sender = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+        // Binding has the kernel pick the identifier now so we know what to
+        // expect in the replies
+        let unspecified: IpAddr = if domain == Domain::IPV4 {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        sender.bind(&SocketAddr::new(unspecified, 0).into())?;
+        let identity = sender.local_addr()?.as_socket().map(|local| local.port());
+        // Replies are only delivered to the socket that sent the request
+        let listener = sender.try_clone()?;
+        Ok(IcmpSockets {
+            sender: Arc::new(sender),
+            listener,
+            kind: IcmpSocketKind::Datagram,
+            fixed_identity: identity,
+        })
+    }
+}
+
+/// Settings controlling how many echo requests each host gets and how long we
+/// wait for them.
+#[derive(Clone, Debug)]
+pub(crate) struct PingSettings {
+    /// The number of echo requests sent to each host
+    pub count: u16,
+    /// How long to wait between each round of echo requests
+    pub interval: Duration,
+    /// How long to wait for replies after the last echo request is sent
+    pub timeout: Duration,
+}
+
+impl PingSettings {
+    /// The total amount of time from sending the first echo request until we
+    /// give up waiting on replies.
+    fn deadline(&self) -> Duration {
+        self.interval * u32::from(self.count.saturating_sub(1)) + self.timeout
+    }
+}
+
+impl From<&ConfigBuilder> for PingSettings {
+    fn from(config: &ConfigBuilder) -> Self {
+        PingSettings {
+            // Sending zero pings doesn't make sense.  Treat it the same as one.
+            count: config.ping_count.max(1),
+            interval: config.ping_interval,
+            timeout: config.ping_timeout,
+        }
+    }
 }
 
 #[tracing::instrument(skip(target_stream))]
 pub(crate) async fn icmp_sweep(
     mut target_stream: impl Stream<Item = TargetInstance> + 'static + Send + Unpin,
     semaphore: Arc<Semaphore>,
+    settings: PingSettings,
 ) -> Result<impl Stream<Item = (TargetInstance, Option<PingResult>)>, PortscanErr> {
     #[instrument(level = "error")]
     fn socket_open_error(_: io::Error) -> PortscanErr {
         PortscanErr::InsufficientPermission
     }
 
-    let icmpv4_sender = Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
-        .map_err(socket_open_error)?;
-    let icmpv6_sender = Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
-        .map_err(socket_open_error)?;
-    let icmpv4_listener_socket = Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
-        .map_err(socket_open_error)?;
-    let icmpv6_listener_socket = Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
-        .map_err(socket_open_error)?;
-    let icmpv4_listener = listen_for_icmp(icmpv4_listener_socket).boxed();
-    let icmpv6_listener = listen_for_icmp(icmpv6_listener_socket).boxed();
-    let mut sent_pings = HashMap::new();
-
-    while let Some(target) = target_stream.next().await {
-        let dest = SocketAddr::new(target.get_ip(), 0).into();
-        match target.get_ip() {
-            IpAddr::V4(_) => {
-                let future = send_ping(
-                    target.clone(),
-                    &icmpv4_sender,
-                    dest,
-                    random(),
-                    random(),
-                    semaphore.clone(),
-                );
-                sent_pings.insert(target, future);
-            }
-            IpAddr::V6(_) => {
-                let future = send_ping(
-                    target.clone(),
-                    &icmpv6_sender,
-                    dest,
-                    random(),
-                    random(),
-                    semaphore.clone(),
-                );
-                sent_pings.insert(target, future);
-            }
+    let icmpv4 = IcmpSockets::open(Domain::IPV4, Protocol::ICMPV4).map_err(socket_open_error)?;
+    let icmpv6 = IcmpSockets::open(Domain::IPV6, Protocol::ICMPV6).map_err(socket_open_error)?;
+    let icmpv4_listener = listen_for_icmp(icmpv4.listener, icmpv4.kind).boxed();
+    let icmpv6_listener = listen_for_icmp(icmpv6.listener, icmpv6.kind).boxed();
+    let (icmpv4_sender, icmpv6_sender) = (icmpv4.sender, icmpv6.sender);
+    let sender_for = move |ip: IpAddr| match ip {
+        IpAddr::V4(_) => icmpv4_sender.clone(),
+        IpAddr::V6(_) => icmpv6_sender.clone(),
+    };
+    let (icmpv4_identity, icmpv6_identity) = (icmpv4.fixed_identity, icmpv6.fixed_identity);
+    let identity_for = |ip: IpAddr| {
+        match ip {
+            IpAddr::V4(_) => icmpv4_identity,
+            IpAddr::V6(_) => icmpv6_identity,
         }
-    }
+        .unwrap_or_else(random)
+    };
 
     let mut targets = HashMap::new();
     let mut errors = Vec::new();
-    for (target, output) in sent_pings {
-        match output.await {
+    while let Some(target) = target_stream.next().await {
+        let dest = SocketAddr::new(target.get_ip(), 0).into();
+        let sender = sender_for(target.get_ip());
+        match send_ping(
+            target.clone(),
+            &sender,
+            dest,
+            identity_for(target.get_ip()),
+            0,
+            semaphore.clone(),
+        )
+        .await
+        {
             Ok(identity) => {
-                targets.insert(target.get_ip(), (target.to_owned(), identity));
+                targets.insert(target.get_ip(), (target, identity));
             }
             Err(e) => {
                 errors.push((
                     e.target_instance,
                     Some(PingResult {
-                        ping_sent: SystemTime::now(),
+                        ping_sent: e.time_attempted,
                         result_type: PingResultType::Error(e.error),
+                        statistics: None,
                     }),
                 ));
             }
         };
     }
+
+    // The first round has gone out.  Any remaining rounds are sent in the
+    // background so we can read replies as they arrive and keep our round trip
+    // times accurate.
+    if settings.count > 1 {
+        let follow_ups: Vec<(TargetInstance, u16)> = targets
+            .values()
+            .map(|(target, summary)| (target.clone(), summary.icmp_identity))
+            .collect();
+        let settings = settings.clone();
+        let semaphore = semaphore.clone();
+        tokio::spawn(async move {
+            for sequence in 1..settings.count {
+                sleep(settings.interval).await;
+                for (target, identity) in &follow_ups {
+                    let dest = SocketAddr::new(target.get_ip(), 0).into();
+                    let sender = sender_for(target.get_ip());
+                    if let Err(e) = send_ping(
+                        target.clone(),
+                        &sender,
+                        dest,
+                        *identity,
+                        sequence,
+                        semaphore.clone(),
+                    )
+                    .await
+                    {
+                        // The missing reply will be counted as packet loss
+                        debug!("Failed to send follow up ping {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
     let merged_stream = combine(icmpv4_listener, icmpv6_listener);
-    Ok(combine(iter(errors), await_results(targets, merged_stream)))
+    Ok(combine(
+        iter(errors),
+        await_results(targets, merged_stream, settings),
+    ))
+}
+
+/// Book keeping for a target we are still waiting to hear back from.
+struct PendingPing {
+    instance: TargetInstance,
+    sent: PingSentSummary,
+    /// Every sequence number we've heard back about, either with a reply or
+    /// an error.
+    answered: HashSet<u16>,
+    /// Round trip times for every reply indexed by sequence number.
+    replies: BTreeMap<u16, Duration>,
+    /// The first reply to arrive.
+    first_reply: Option<IcmpSummary>,
+    /// The first error message, like destination unreachable, to arrive.
+    first_error: Option<PingResultType>,
+}
+
+impl PendingPing {
+    fn new(instance: TargetInstance, sent: PingSentSummary) -> Self {
+        PendingPing {
+            instance,
+            sent,
+            answered: HashSet::new(),
+            replies: BTreeMap::new(),
+            first_reply: None,
+            first_error: None,
+        }
+    }
+
+    /// Record a message related to this target.  This returns false if we've
+    /// already heard about this sequence number.
+    fn record(&mut self, packet: ReceivedIcmpPacket) -> bool {
+        if !self.answered.insert(packet.sequence) {
+            return false;
+        }
+        match packet.kind {
+            ReceivedIcmpKind::EchoReply => {
+                // Fall back on the first send time if the reply didn't echo back our
+                // timestamp
+                let time_sent = packet.time_sent.unwrap_or(self.sent.time_sent);
+                let rtt = packet
+                    .time_received
+                    .duration_since(time_sent)
+                    .unwrap_or_default();
+                self.replies.insert(packet.sequence, rtt);
+                if self.first_reply.is_none() {
+                    self.first_reply = Some(IcmpSummary {
+                        time_sent,
+                        time_received: packet.time_received,
+                        ttl: packet.ttl,
+                    });
+                }
+            }
+            ReceivedIcmpKind::DestinationUnreachable { code } => {
+                if self.first_error.is_none() {
+                    self.first_error = Some(PingResultType::Unreachable {
+                        code,
+                        router: packet.source,
+                    });
+                }
+            }
+            ReceivedIcmpKind::TimeExceeded => {
+                if self.first_error.is_none() {
+                    self.first_error = Some(PingResultType::TtlExceeded {
+                        router: packet.source,
+                    });
+                }
+            }
+        }
+        true
+    }
+
+    /// Wrap up all the details we have on this target.  The host counts as up
+    /// if any echo request got a reply.
+    fn finish(self, count: u16) -> (TargetInstance, Option<PingResult>) {
+        let round_trip_times: Vec<Duration> = self.replies.values().cloned().collect();
+        let result_type = match (self.first_reply, self.first_error) {
+            (Some(reply), _) => PingResultType::Reply(reply),
+            (None, Some(error)) => error,
+            (None, None) => PingResultType::Timeout,
+        };
+        (
+            self.instance,
+            Some(PingResult {
+                ping_sent: self.sent.time_sent,
+                result_type,
+                statistics: Some(PingStatistics::new(count, &round_trip_times)),
+            }),
+        )
+    }
 }
 
 #[instrument(skip(targets, icmp_listener))]
 fn await_results(
-    mut targets: HashMap<IpAddr, (TargetInstance, PingSentSummary)>,
+    targets: HashMap<IpAddr, (TargetInstance, PingSentSummary)>,
     mut icmp_listener: impl Stream<Item = io::Result<ReceivedIcmpPacket>> + Unpin,
+    settings: PingSettings,
 ) -> impl Stream<Item = (TargetInstance, Option<PingResult>)> {
+    let mut targets: HashMap<IpAddr, PendingPing> = targets
+        .into_iter()
+        .map(|(ip, (instance, sent))| (ip, PendingPing::new(instance, sent)))
+        .collect();
     stream! {
-        let mut ping_timeout = sleep(Duration::from_millis(500)).boxed().fuse();
+        let mut ping_timeout = sleep(settings.deadline()).boxed().fuse();
         let mut icmp_stream_future = icmp_listener.next().fuse();
         loop {
             select!{
                 () = ping_timeout => {
-                    // We've hit our timeout.  Anything left in the target list will get
-                    for entry in targets {
-                        yield (entry.1.0, Some(PingResult {
-                            ping_sent: entry.1.1.time_sent,
-                            result_type: PingResultType::Timeout
-                        }));
+                    // We've hit our timeout.  Anything left in the target list gets whatever
+                    // we've heard so far.
+                    for (_, pending) in targets {
+                        yield pending.finish(settings.count);
                     }
                     break;
                 }
@@ -155,21 +469,27 @@ fn await_results(
                     icmp_stream_future = icmp_listener.next().fuse();
                     match result {
                         Some(Ok(packet)) => {
-                            if let Some(entry) = targets.remove(&packet.source){
-                                //TODO:  We shouldn't remove this if the IDs don't match
-                                if packet.identity == entry.1.icmp_identity {
-                                    debug!("We got a match!  Yielding");
-                                    yield (entry.0, Some(PingResult{
-                                        ping_sent: entry.1.time_sent,
-                                        result_type: PingResultType::Reply(IcmpSummary{
-                                            time_received: packet.time_received
-                                        })
-                                    }));
-                                } else {
-                                    debug!("We got a message for {:?} which is a target but the identity doesn't match. {} {}", packet.source, packet.identity, entry.1.icmp_identity);
+                            let target = packet.target;
+                            match targets.get_mut(&target) {
+                                Some(pending) if pending.sent.icmp_identity == packet.identity => {
+                                    if !pending.record(packet) {
+                                        debug!("Dropping a duplicate message for {:?}", target);
+                                    } else if pending.answered.len() >= usize::from(settings.count) {
+                                        debug!("We've heard back about every echo request.  Yielding");
+                                        // We just confirmed the entry exists above
+                                        yield targets.remove(&target).unwrap().finish(settings.count);
+                                        if targets.is_empty() {
+                                            debug!("Every target has been accounted for");
+                                            break;
+                                        }
+                                    }
+                                }
+                                Some(pending) => {
+                                    debug!("We got a message for {:?} which is a target but the identity doesn't match. {} {}", target, packet.identity, pending.sent.icmp_identity);
+                                }
+                                None => {
+                                    debug!("We got an ICMP message for {:?} which isn't one of our targets.  Dropping it", target);
                                 }
-                            } else {
-                                debug!("We got an ICMP message for {:?} which isn't one of our targets.  Dropping it", packet.source);
                             }
                         }
                         Some(Err(e)) => {
@@ -177,6 +497,9 @@ fn await_results(
                         }
                         None => {
                             debug!("The stream is done");
+                            for (_, pending) in targets {
+                                yield pending.finish(settings.count);
+                            }
                             break;
                         }
                     }
@@ -186,21 +509,24 @@ fn await_results(
     }
 }
 
-#[instrument(skip(target_stream))]
-pub(crate) async fn skip_icmp(
-    target_stream: impl Stream<Item = TargetInstance> + 'static + Send,
-) -> Result<impl Stream<Item = (TargetInstance, Option<PingResult>)>, PortscanErr> {
-    Ok(target_stream.map(|target| (target, None)))
-}
-
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, io, net::IpAddr, time::SystemTime};
+    use std::{
+        collections::HashMap,
+        io,
+        net::IpAddr,
+        time::{Duration, SystemTime},
+    };
 
     use futures::{stream, StreamExt};
 
     use crate::{
-        icmp::{await_results, icmp_listener::ReceivedIcmpPacket, icmp_writer::PingSentSummary},
+        icmp::{
+            await_results,
+            icmp_listener::{ReceivedIcmpKind, ReceivedIcmpPacket},
+            icmp_writer::PingSentSummary,
+            IcmpSummary, PingResult, PingResultType, PingSettings, PingStatistics,
+        },
         target::TargetInstance,
     };
 
@@ -208,13 +534,46 @@ mod tests {
         dest_least_significant_byte: u8,
         identity: u16,
     ) -> io::Result<ReceivedIcmpPacket> {
+        let ip = IpAddr::from([0, 0, 0, dest_least_significant_byte]);
         Ok(ReceivedIcmpPacket {
-            source: IpAddr::from([0, 0, 0, dest_least_significant_byte]),
+            source: ip,
+            target: ip,
             identity,
+            sequence: 0,
+            time_sent: None,
+            kind: ReceivedIcmpKind::EchoReply,
+            ttl: None,
             time_received: SystemTime::now(),
         })
     }
 
+    fn build_reply(
+        dest_least_significant_byte: u8,
+        sequence: u16,
+        time_sent: SystemTime,
+        round_trip_time: Duration,
+    ) -> io::Result<ReceivedIcmpPacket> {
+        let ip = IpAddr::from([0, 0, 0, dest_least_significant_byte]);
+        Ok(ReceivedIcmpPacket {
+            source: ip,
+            target: ip,
+            identity: 1,
+            sequence,
+            time_sent: Some(time_sent),
+            kind: ReceivedIcmpKind::EchoReply,
+            ttl: None,
+            time_received: time_sent + round_trip_time,
+        })
+    }
+
+    fn build_settings(count: u16) -> PingSettings {
+        PingSettings {
+            count,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        }
+    }
+
     fn build_targets(
         dest_least_significant_byte: u8,
         icmp_identity: u16,
@@ -243,9 +602,129 @@ mod tests {
             .map(|number| build_received(number as u8, number))
             .collect();
 
-        let ping_results: Vec<_> = await_results(target, stream::iter(received_pings))
-            .collect()
-            .await;
+        let ping_results: Vec<_> =
+            await_results(target, stream::iter(received_pings), build_settings(1))
+                .collect()
+                .await;
         assert_eq!(ping_results.len(), 10);
     }
+
+    #[tokio::test]
+    async fn test_unreachable_and_mismatched_identity() {
+        let mut target = HashMap::new();
+        for number in 0..3u16 {
+            let x = build_targets(number as u8, number);
+            target.insert(x.0, x.1);
+        }
+        let router = IpAddr::from([10, 0, 0, 1]);
+        let received_pings = vec![
+            // The wrong identity shouldn't knock the target out of the list
+            build_received(0, 1234),
+            build_received(0, 0),
+            Ok(ReceivedIcmpPacket {
+                source: router,
+                target: IpAddr::from([0, 0, 0, 1]),
+                identity: 1,
+                sequence: 0,
+                time_sent: None,
+                kind: ReceivedIcmpKind::DestinationUnreachable { code: 1 },
+                ttl: None,
+                time_received: SystemTime::now(),
+            }),
+            Ok(ReceivedIcmpPacket {
+                source: router,
+                target: IpAddr::from([0, 0, 0, 2]),
+                identity: 2,
+                sequence: 0,
+                time_sent: None,
+                kind: ReceivedIcmpKind::TimeExceeded,
+                ttl: None,
+                time_received: SystemTime::now(),
+            }),
+        ];
+        let ping_results: HashMap<IpAddr, PingResultType> =
+            await_results(target, stream::iter(received_pings), build_settings(1))
+                .map(|(instance, result)| (instance.get_ip(), result.unwrap().result_type))
+                .collect()
+                .await;
+        assert_eq!(ping_results.len(), 3);
+        assert!(matches!(
+            ping_results[&IpAddr::from([0, 0, 0, 0])],
+            PingResultType::Reply(_)
+        ));
+        assert!(matches!(
+            ping_results[&IpAddr::from([0, 0, 0, 1])],
+            PingResultType::Unreachable { code: 1, router: r } if r == router
+        ));
+        assert!(matches!(
+            ping_results[&IpAddr::from([0, 0, 0, 2])],
+            PingResultType::TtlExceeded { router: r } if r == router
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_loss_statistics() {
+        let mut target = HashMap::new();
+        for number in 0..2u16 {
+            let x = build_targets(number as u8, 1);
+            target.insert(x.0, x.1);
+        }
+        let start = SystemTime::now();
+        let received_pings = vec![
+            build_reply(0, 0, start, Duration::from_millis(10)),
+            build_reply(0, 2, start, Duration::from_millis(30)),
+            // A duplicate reply shouldn't be counted twice
+            build_reply(0, 2, start, Duration::from_millis(30)),
+            build_reply(0, 3, start, Duration::from_millis(20)),
+        ];
+        let ping_results: HashMap<IpAddr, PingResult> =
+            await_results(target, stream::iter(received_pings), build_settings(4))
+                .map(|(instance, result)| (instance.get_ip(), result.unwrap()))
+                .collect()
+                .await;
+
+        let answered = &ping_results[&IpAddr::from([0, 0, 0, 0])];
+        assert!(matches!(answered.result_type, PingResultType::Reply(_)));
+        assert_eq!(answered.round_trip_time(), Some(Duration::from_millis(10)));
+        assert_eq!(
+            answered.statistics,
+            Some(PingStatistics {
+                transmitted: 4,
+                received: 3,
+                packet_loss: 0.25,
+                min_rtt: Some(Duration::from_millis(10)),
+                avg_rtt: Some(Duration::from_millis(20)),
+                max_rtt: Some(Duration::from_millis(30)),
+                jitter: Some(Duration::from_millis(15)),
+            })
+        );
+
+        let silent = &ping_results[&IpAddr::from([0, 0, 0, 1])];
+        assert!(matches!(silent.result_type, PingResultType::Timeout));
+        let statistics = silent.statistics.as_ref().unwrap();
+        assert_eq!(statistics.received, 0);
+        assert_eq!(statistics.packet_loss, 1.0);
+        assert_eq!(statistics.avg_rtt, None);
+    }
+
+    #[test]
+    fn test_round_trip_time() {
+        let ping_sent = SystemTime::now();
+        let reply = PingResult {
+            ping_sent,
+            result_type: PingResultType::Reply(IcmpSummary {
+                time_sent: ping_sent,
+                time_received: ping_sent + Duration::from_millis(15),
+                ttl: None,
+            }),
+            statistics: None,
+        };
+        assert_eq!(reply.round_trip_time(), Some(Duration::from_millis(15)));
+        let timeout = PingResult {
+            ping_sent,
+            result_type: PingResultType::Timeout,
+            statistics: None,
+        };
+        assert_eq!(timeout.round_trip_time(), None);
+    }
 }
@@ -1,8 +1,18 @@
 #![allow(dead_code)]
 
-use std::{io, io::Write};
+use std::{
+    io,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub const HEADER_SIZE: usize = 8;
+/// The size of the timestamp we put in the payload of every echo request.
+pub const TIMESTAMP_PAYLOAD_SIZE: usize = 8;
+const IPV4_PROTOCOL_ICMP: u8 = 1;
+const IPV6_NEXT_HEADER_ICMPV6: u8 = 58;
+const IPV6_HEADER_SIZE: usize = 40;
 
 pub struct IcmpV4;
 pub struct IcmpV6;
@@ -12,6 +22,19 @@ pub trait Proto {
     const ECHO_REQUEST_CODE: u8;
     const ECHO_REPLY_TYPE: u8;
     const ECHO_REPLY_CODE: u8;
+    const DESTINATION_UNREACHABLE_TYPE: u8;
+    const TIME_EXCEEDED_TYPE: u8;
+    const PARAMETER_PROBLEM_TYPE: u8;
+    /// ICMP has no packet too big message.  IPv4 uses a destination
+    /// unreachable code instead.
+    const PACKET_TOO_BIG_TYPE: Option<u8>;
+    /// ICMP only has room for a one byte pointer in parameter problem
+    /// messages.  ICMPv6 uses four.
+    const PARAMETER_PROBLEM_POINTER_SIZE: usize;
+    /// The ICMPv6 checksum covers a pseudo header with our own address, which
+    /// raw sockets don't give us.  The kernel verifies ICMPv6 checksums for
+    /// us so we only check ICMP ones.
+    const VERIFY_CHECKSUM: bool;
 }
 
 impl Proto for IcmpV4 {
@@ -19,6 +42,12 @@ impl Proto for IcmpV4 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 0;
     const ECHO_REPLY_CODE: u8 = 0;
+    const DESTINATION_UNREACHABLE_TYPE: u8 = 3;
+    const TIME_EXCEEDED_TYPE: u8 = 11;
+    const PARAMETER_PROBLEM_TYPE: u8 = 12;
+    const PACKET_TOO_BIG_TYPE: Option<u8> = None;
+    const PARAMETER_PROBLEM_POINTER_SIZE: usize = 1;
+    const VERIFY_CHECKSUM: bool = true;
 }
 
 impl Proto for IcmpV6 {
@@ -26,6 +55,31 @@ impl Proto for IcmpV6 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 129;
     const ECHO_REPLY_CODE: u8 = 0;
+    const DESTINATION_UNREACHABLE_TYPE: u8 = 1;
+    const TIME_EXCEEDED_TYPE: u8 = 3;
+    const PARAMETER_PROBLEM_TYPE: u8 = 4;
+    const PACKET_TOO_BIG_TYPE: Option<u8> = Some(2);
+    const PARAMETER_PROBLEM_POINTER_SIZE: usize = 4;
+    const VERIFY_CHECKSUM: bool = false;
+}
+
+/// Encode the time an echo request was sent so it can be put in the payload.
+/// Hosts copy the payload into their reply, which lets us match each reply to
+/// the exact time its request was sent.
+pub fn encode_timestamp(time: SystemTime) -> [u8; TIMESTAMP_PAYLOAD_SIZE] {
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    micros.to_be_bytes()
+}
+
+/// Decode a timestamp written by [`encode_timestamp`] from the start of a
+/// payload.
+pub fn decode_timestamp(payload: &[u8]) -> Option<SystemTime> {
+    let bytes: [u8; TIMESTAMP_PAYLOAD_SIZE] =
+        payload.get(..TIMESTAMP_PAYLOAD_SIZE)?.try_into().ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_micros(u64::from_be_bytes(bytes)))
 }
 
 pub struct EchoRequest<'a> {
@@ -60,7 +114,7 @@ pub struct EchoReply<'a> {
 
 impl<'a> EchoReply<'a> {
     pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
-        tracing::info!("Parsing buffer {:x?}", buffer);
+        tracing::trace!("Parsing buffer {:x?}", buffer);
         if buffer.as_ref().len() < HEADER_SIZE {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -70,7 +124,7 @@ impl<'a> EchoReply<'a> {
 
         let type_ = buffer[0];
         let code = buffer[1];
-        if type_ != P::ECHO_REPLY_TYPE && code != P::ECHO_REPLY_CODE {
+        if type_ != P::ECHO_REPLY_TYPE || code != P::ECHO_REPLY_CODE {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid type"));
         }
 
@@ -87,7 +141,227 @@ impl<'a> EchoReply<'a> {
     }
 }
 
-fn write_checksum(buffer: &mut [u8]) {
+/// An ICMP error message telling us one of our probes couldn't be delivered.
+/// The code gives the reason.  The meaning of each code differs between ICMP
+/// and ICMPv6.
+#[derive(Debug)]
+pub struct DestinationUnreachable<'a> {
+    pub code: u8,
+    pub original: IpDatagram<'a>,
+}
+
+impl<'a> DestinationUnreachable<'a> {
+    pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
+        let (code, original) = decode_error_message::<P>(buffer, P::DESTINATION_UNREACHABLE_TYPE)?;
+        Ok(DestinationUnreachable { code, original })
+    }
+}
+
+/// An ICMP error message telling us one of our probes ran out of hops before
+/// reaching its destination.
+#[derive(Debug)]
+pub struct TimeExceeded<'a> {
+    pub code: u8,
+    pub original: IpDatagram<'a>,
+}
+
+impl<'a> TimeExceeded<'a> {
+    pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
+        let (code, original) = decode_error_message::<P>(buffer, P::TIME_EXCEEDED_TYPE)?;
+        Ok(TimeExceeded { code, original })
+    }
+}
+
+/// An ICMP error message telling us something in the header of one of our
+/// probes couldn't be processed.
+#[derive(Debug)]
+pub struct ParameterProblem<'a> {
+    pub code: u8,
+    /// The offset of the byte in the original packet that caused the problem
+    pub pointer: u32,
+    pub original: IpDatagram<'a>,
+}
+
+impl<'a> ParameterProblem<'a> {
+    pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
+        let (code, original) = decode_error_message::<P>(buffer, P::PARAMETER_PROBLEM_TYPE)?;
+        let pointer = buffer[4..4 + P::PARAMETER_PROBLEM_POINTER_SIZE]
+            .iter()
+            .fold(0u32, |pointer, byte| (pointer << 8) | u32::from(*byte));
+        Ok(ParameterProblem {
+            code,
+            pointer,
+            original,
+        })
+    }
+}
+
+/// An ICMPv6 error message telling us one of our probes was larger than the
+/// MTU of a link along the way.
+#[derive(Debug)]
+pub struct PacketTooBig<'a> {
+    pub mtu: u32,
+    pub original: IpDatagram<'a>,
+}
+
+impl<'a> PacketTooBig<'a> {
+    pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
+        let expected_type = P::PACKET_TOO_BIG_TYPE
+            .ok_or_else(|| invalid_input("Packet too big only exists in ICMPv6"))?;
+        let (_, original) = decode_error_message::<P>(buffer, expected_type)?;
+        let mtu = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+        Ok(PacketTooBig { mtu, original })
+    }
+}
+
+/// An IP header and whatever follows it.  Raw IPv4 sockets hand us these, and
+/// ICMP error messages embed the start of the packet that triggered them.
+/// That is the IP header and at least the first 8 bytes of its payload, which
+/// is enough to match the error back up to the probe we sent.
+#[derive(Debug)]
+pub struct IpDatagram<'a> {
+    /// Where the packet came from
+    pub source: IpAddr,
+    /// Where the packet was headed
+    pub destination: IpAddr,
+    /// The IP protocol number, or IPv6 next header, of the packet
+    pub protocol: u8,
+    /// The TTL, or IPv6 hop limit, left on the packet
+    pub ttl: u8,
+    /// The packet's payload.  For embedded packets this is only the start of
+    /// it.
+    pub payload: &'a [u8],
+}
+
+impl<'a> IpDatagram<'a> {
+    pub fn decode(buffer: &'a [u8]) -> io::Result<Self> {
+        match buffer.first().map(|first| first >> 4) {
+            Some(4) => {
+                let header_length = usize::from(buffer[0] & 0x0f) * 4;
+                if header_length < 20 || buffer.len() < header_length {
+                    return Err(invalid_input("IPv4 header is truncated"));
+                }
+                // Trim any padding past the end of the packet.  Embedded packets are
+                // usually cut short so the total length can run past the buffer.
+                let total_length = usize::from(u16::from_be_bytes([buffer[2], buffer[3]]));
+                let end = if total_length >= header_length {
+                    total_length.min(buffer.len())
+                } else {
+                    buffer.len()
+                };
+                let source = Ipv4Addr::new(buffer[12], buffer[13], buffer[14], buffer[15]);
+                let destination = Ipv4Addr::new(buffer[16], buffer[17], buffer[18], buffer[19]);
+                Ok(IpDatagram {
+                    source: IpAddr::V4(source),
+                    destination: IpAddr::V4(destination),
+                    protocol: buffer[9],
+                    ttl: buffer[8],
+                    payload: &buffer[header_length..end],
+                })
+            }
+            Some(6) => {
+                if buffer.len() < IPV6_HEADER_SIZE {
+                    return Err(invalid_input("IPv6 header is truncated"));
+                }
+                let mut source = [0u8; 16];
+                source.copy_from_slice(&buffer[8..24]);
+                let mut destination = [0u8; 16];
+                destination.copy_from_slice(&buffer[24..40]);
+                Ok(IpDatagram {
+                    source: IpAddr::V6(Ipv6Addr::from(source)),
+                    destination: IpAddr::V6(Ipv6Addr::from(destination)),
+                    protocol: buffer[6],
+                    ttl: buffer[7],
+                    payload: &buffer[IPV6_HEADER_SIZE..],
+                })
+            }
+            _ => Err(invalid_input("Packet isn't IPv4 or IPv6")),
+        }
+    }
+
+    /// If the original packet was one of our echo requests, return its
+    /// identifier and sequence number.
+    pub fn echo_request(&self) -> Option<(u16, u16)> {
+        let expected_type = match self.protocol {
+            IPV4_PROTOCOL_ICMP => IcmpV4::ECHO_REQUEST_TYPE,
+            IPV6_NEXT_HEADER_ICMPV6 => IcmpV6::ECHO_REQUEST_TYPE,
+            _ => return None,
+        };
+        if self.payload.len() < HEADER_SIZE || self.payload[0] != expected_type {
+            return None;
+        }
+        let ident = (u16::from(self.payload[4]) << 8) + u16::from(self.payload[5]);
+        let seq_cnt = (u16::from(self.payload[6]) << 8) + u16::from(self.payload[7]);
+        Some((ident, seq_cnt))
+    }
+}
+
+/// Every ICMP message we understand.  This is the main entry point for
+/// decoding anything read off of an ICMP socket.
+#[derive(Debug)]
+pub enum IcmpMessage<'a> {
+    EchoReply(EchoReply<'a>),
+    DestinationUnreachable(DestinationUnreachable<'a>),
+    TimeExceeded(TimeExceeded<'a>),
+    ParameterProblem(ParameterProblem<'a>),
+    PacketTooBig(PacketTooBig<'a>),
+    /// Some type of message we don't care about, like an echo request.
+    Other {
+        type_: u8,
+        code: u8,
+    },
+}
+
+impl<'a> IcmpMessage<'a> {
+    pub fn decode<P: Proto>(buffer: &'a [u8]) -> io::Result<Self> {
+        if buffer.len() < HEADER_SIZE {
+            return Err(invalid_input("Buffer too small"));
+        }
+        if P::VERIFY_CHECKSUM && !checksum_is_valid(buffer) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad checksum"));
+        }
+        let type_ = buffer[0];
+        if type_ == P::ECHO_REPLY_TYPE {
+            EchoReply::decode::<P>(buffer).map(IcmpMessage::EchoReply)
+        } else if type_ == P::DESTINATION_UNREACHABLE_TYPE {
+            DestinationUnreachable::decode::<P>(buffer).map(IcmpMessage::DestinationUnreachable)
+        } else if type_ == P::TIME_EXCEEDED_TYPE {
+            TimeExceeded::decode::<P>(buffer).map(IcmpMessage::TimeExceeded)
+        } else if type_ == P::PARAMETER_PROBLEM_TYPE {
+            ParameterProblem::decode::<P>(buffer).map(IcmpMessage::ParameterProblem)
+        } else if Some(type_) == P::PACKET_TOO_BIG_TYPE {
+            PacketTooBig::decode::<P>(buffer).map(IcmpMessage::PacketTooBig)
+        } else {
+            Ok(IcmpMessage::Other {
+                type_,
+                code: buffer[1],
+            })
+        }
+    }
+}
+
+fn decode_error_message<P: Proto>(
+    buffer: &[u8],
+    expected_type: u8,
+) -> io::Result<(u8, IpDatagram<'_>)> {
+    if buffer.len() < HEADER_SIZE {
+        return Err(invalid_input("Buffer too small"));
+    }
+    if buffer[0] != expected_type {
+        return Err(invalid_input("Invalid type"));
+    }
+    // Bytes 4 through 8 are unused or hold details we don't care about, like the
+    // next hop MTU.  The original datagram starts right after them.
+    let original = IpDatagram::decode(&buffer[HEADER_SIZE..])?;
+    Ok((buffer[1], original))
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+/// The one's complement sum of the buffer, folded down to 16 bits.
+fn ones_complement_sum(buffer: &[u8]) -> u16 {
     let mut sum = 0u32;
     for word in buffer.chunks(2) {
         let mut part = u16::from(word[0]) << 8;
@@ -100,9 +374,293 @@ fn write_checksum(buffer: &mut [u8]) {
     while (sum >> 16) > 0 {
         sum = (sum & 0xffff) + (sum >> 16);
     }
+    sum as u16
+}
 
-    let sum = !sum as u16;
+/// Fill in the checksum of an ICMP message.
+pub fn write_checksum(buffer: &mut [u8]) {
+    buffer[2] = 0;
+    buffer[3] = 0;
+    let sum = !ones_complement_sum(buffer);
 
     buffer[2] = (sum >> 8) as u8;
     buffer[3] = (sum & 0xff) as u8;
 }
+
+/// Summing a message along with its checksum comes out to all ones if nothing
+/// was corrupted.
+fn checksum_is_valid(buffer: &[u8]) -> bool {
+    ones_complement_sum(buffer) == 0xffff
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::icmp::packet::{
+        decode_timestamp, encode_timestamp, write_checksum, EchoReply, EchoRequest, IcmpMessage,
+        IcmpV4, IcmpV6, IpDatagram, Proto, HEADER_SIZE,
+    };
+
+    /// The property tests are seeded so any failure can be reproduced.
+    const PROPERTY_TEST_SEED: u64 = 0x0b0b_bead;
+    const PROPERTY_TEST_CASES: usize = 10_000;
+
+    /// Build an ICMPv4 error message that embeds one of our echo requests.
+    fn build_v4_error(type_: u8, code: u8, destination: Ipv4Addr) -> Vec<u8> {
+        let mut echo = [0u8; 12];
+        EchoRequest {
+            ident: 0xbeef,
+            seq_cnt: 7,
+            payload: &[1, 2, 3, 4],
+        }
+        .encode::<IcmpV4>(&mut echo)
+        .unwrap();
+        let mut ip_header = vec![0x45, 0, 0, 32, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1];
+        ip_header.extend_from_slice(&destination.octets());
+        let mut message = vec![type_, code, 0, 0, 0, 0, 0, 0];
+        message.extend(ip_header);
+        message.extend_from_slice(&echo);
+        write_checksum(&mut message);
+        message
+    }
+
+    /// Build an ICMPv6 error message that embeds one of our echo requests.
+    /// The checksum is left empty since the kernel checks those.
+    fn build_v6_error(type_: u8, code: u8, extra: [u8; 4], destination: Ipv6Addr) -> Vec<u8> {
+        let mut echo = [0u8; 8];
+        EchoRequest {
+            ident: 0xbeef,
+            seq_cnt: 7,
+            payload: &[],
+        }
+        .encode::<IcmpV6>(&mut echo)
+        .unwrap();
+        let mut message = vec![type_, code, 0, 0];
+        message.extend_from_slice(&extra);
+        message.extend_from_slice(&[0x60, 0, 0, 0, 0, 8, 58, 64]);
+        message.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        message.extend_from_slice(&destination.octets());
+        message.extend_from_slice(&echo);
+        message
+    }
+
+    fn echo_reply<P: Proto>(ident: u16, seq_cnt: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; HEADER_SIZE + payload.len()];
+        EchoRequest {
+            ident,
+            seq_cnt,
+            payload,
+        }
+        .encode::<P>(&mut buffer)
+        .unwrap();
+        buffer[0] = P::ECHO_REPLY_TYPE;
+        write_checksum(&mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn test_decode_destination_unreachable() {
+        let destination = Ipv4Addr::new(192, 168, 1, 20);
+        let buffer = build_v4_error(3, 1, destination);
+        match IcmpMessage::decode::<IcmpV4>(&buffer).unwrap() {
+            IcmpMessage::DestinationUnreachable(unreachable) => {
+                assert_eq!(unreachable.code, 1);
+                assert_eq!(unreachable.original.destination, IpAddr::V4(destination));
+                assert_eq!(unreachable.original.echo_request(), Some((0xbeef, 7)));
+            }
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_decode_time_exceeded() {
+        let destination = Ipv4Addr::new(8, 8, 8, 8);
+        let buffer = build_v4_error(11, 0, destination);
+        match IcmpMessage::decode::<IcmpV4>(&buffer).unwrap() {
+            IcmpMessage::TimeExceeded(exceeded) => {
+                assert_eq!(exceeded.original.destination, IpAddr::V4(destination));
+                assert_eq!(exceeded.original.echo_request(), Some((0xbeef, 7)));
+            }
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_decode_icmpv6_errors() {
+        let destination: Ipv6Addr = "fd00::20".parse().unwrap();
+        let buffer = build_v6_error(1, 3, [0; 4], destination);
+        match IcmpMessage::decode::<IcmpV6>(&buffer).unwrap() {
+            IcmpMessage::DestinationUnreachable(unreachable) => {
+                assert_eq!(unreachable.code, 3);
+                assert_eq!(unreachable.original.destination, IpAddr::V6(destination));
+                assert_eq!(unreachable.original.echo_request(), Some((0xbeef, 7)));
+            }
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+        let buffer = build_v6_error(2, 0, 1280u32.to_be_bytes(), destination);
+        match IcmpMessage::decode::<IcmpV6>(&buffer).unwrap() {
+            IcmpMessage::PacketTooBig(too_big) => {
+                assert_eq!(too_big.mtu, 1280);
+                assert_eq!(too_big.original.echo_request(), Some((0xbeef, 7)));
+            }
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+        let buffer = build_v6_error(4, 0, 40u32.to_be_bytes(), destination);
+        match IcmpMessage::decode::<IcmpV6>(&buffer).unwrap() {
+            IcmpMessage::ParameterProblem(problem) => assert_eq!(problem.pointer, 40),
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+        // ICMP packs the pointer into a single byte
+        let mut buffer = build_v4_error(12, 0, Ipv4Addr::new(10, 0, 0, 1));
+        buffer[4] = 9;
+        write_checksum(&mut buffer);
+        match IcmpMessage::decode::<IcmpV4>(&buffer).unwrap() {
+            IcmpMessage::ParameterProblem(problem) => assert_eq!(problem.pointer, 9),
+            other => panic!("Decoded the wrong message {other:?}"),
+        }
+        // Type 2 means nothing special in ICMP
+        let mut buffer = echo_reply::<IcmpV4>(1, 1, &[]);
+        buffer[0] = 2;
+        write_checksum(&mut buffer);
+        assert!(matches!(
+            IcmpMessage::decode::<IcmpV4>(&buffer).unwrap(),
+            IcmpMessage::Other { type_: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn test_bad_checksum_is_rejected() {
+        let mut buffer = build_v4_error(3, 1, Ipv4Addr::new(10, 0, 0, 1));
+        buffer[2] ^= 0xff;
+        assert!(IcmpMessage::decode::<IcmpV4>(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_decode_ip_datagram() {
+        let reply = echo_reply::<IcmpV4>(1, 1, &[1, 2, 3, 4]);
+        let mut datagram = vec![0x46, 0, 0, 36, 0, 0, 0, 0, 64, 1, 0, 0];
+        datagram.extend_from_slice(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        // Options make the header 24 bytes
+        datagram.extend_from_slice(&[1, 1, 1, 0]);
+        datagram.extend_from_slice(&reply);
+        // Padding past the total length is dropped
+        datagram.extend_from_slice(&[0, 0]);
+        let decoded = IpDatagram::decode(&datagram).unwrap();
+        assert_eq!(decoded.source, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(decoded.destination, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(decoded.protocol, 1);
+        assert_eq!(decoded.payload, &reply[..]);
+        assert!(IpDatagram::decode(&datagram[..20]).is_err());
+        assert!(IpDatagram::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_decode_never_panics_on_random_input() {
+        let mut rng = StdRng::seed_from_u64(PROPERTY_TEST_SEED);
+        let interesting_types = [0, 1, 2, 3, 4, 8, 11, 12, 128, 129];
+        for _ in 0..PROPERTY_TEST_CASES {
+            let length = rng.gen_range(0..96);
+            let mut buffer: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            if let Some(first) = buffer.first_mut() {
+                // Steer most cases toward the types we actually decode
+                if rng.gen_bool(0.8) {
+                    *first = interesting_types[rng.gen_range(0..interesting_types.len())];
+                }
+            }
+            if buffer.len() > HEADER_SIZE && rng.gen_bool(0.5) {
+                // Make the embedded packet look like IP so the error decoding runs
+                buffer[HEADER_SIZE] = if rng.gen() { 0x45 } else { 0x60 };
+            }
+            if buffer.len() >= HEADER_SIZE && rng.gen_bool(0.5) {
+                write_checksum(&mut buffer);
+            }
+            let _ = IcmpMessage::decode::<IcmpV4>(&buffer);
+            let _ = IcmpMessage::decode::<IcmpV6>(&buffer);
+            let _ = IpDatagram::decode(&buffer);
+        }
+    }
+
+    #[test]
+    fn test_echo_round_trips() {
+        let mut rng = StdRng::seed_from_u64(PROPERTY_TEST_SEED);
+        for _ in 0..PROPERTY_TEST_CASES {
+            let ident = rng.gen();
+            let seq_cnt = rng.gen();
+            let payload: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+            for buffer in [
+                echo_reply::<IcmpV4>(ident, seq_cnt, &payload),
+                echo_reply::<IcmpV6>(ident, seq_cnt, &payload),
+            ] {
+                let decoded = if buffer[0] == 0 {
+                    IcmpMessage::decode::<IcmpV4>(&buffer)
+                } else {
+                    IcmpMessage::decode::<IcmpV6>(&buffer)
+                };
+                match decoded.unwrap() {
+                    IcmpMessage::EchoReply(reply) => {
+                        assert_eq!(reply.ident, ident);
+                        assert_eq!(reply.seq_cnt, seq_cnt);
+                        assert_eq!(reply.payload, &payload[..]);
+                    }
+                    other => panic!("Decoded the wrong message {other:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_any_single_bit_flip_is_detected() {
+        let mut rng = StdRng::seed_from_u64(PROPERTY_TEST_SEED);
+        for _ in 0..PROPERTY_TEST_CASES {
+            let payload: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+            let mut buffer = echo_reply::<IcmpV4>(rng.gen(), rng.gen(), &payload);
+            let bit = rng.gen_range(0..buffer.len() * 8);
+            buffer[bit / 8] ^= 1 << (bit % 8);
+            assert!(IcmpMessage::decode::<IcmpV4>(&buffer).is_err());
+        }
+    }
+
+    #[test]
+    fn test_echo_request_is_not_a_reply() {
+        let mut buffer = [0u8; 12];
+        EchoRequest {
+            ident: 1,
+            seq_cnt: 1,
+            payload: &[1, 2, 3, 4],
+        }
+        .encode::<IcmpV6>(&mut buffer)
+        .unwrap();
+        assert!(matches!(
+            IcmpMessage::decode::<IcmpV6>(&buffer).unwrap(),
+            IcmpMessage::Other { type_: 128, .. }
+        ));
+        // A truncated error message shouldn't decode
+        assert!(IcmpMessage::decode::<IcmpV4>(&[3, 1, 0, 0, 0, 0, 0, 0, 0x45]).is_err());
+    }
+
+    #[test]
+    fn test_timestamp_round_trips_through_echo() {
+        let sent = UNIX_EPOCH + Duration::from_micros(1_650_000_000_123_456);
+        let payload = encode_timestamp(sent);
+        let mut buffer = [0u8; 16];
+        EchoRequest {
+            ident: 1,
+            seq_cnt: 2,
+            payload: &payload,
+        }
+        .encode::<IcmpV6>(&mut buffer)
+        .unwrap();
+        // Turn the request into a reply the same way a remote host would
+        buffer[0] = 129;
+        let reply = EchoReply::decode::<IcmpV6>(&buffer).unwrap();
+        assert_eq!(reply.seq_cnt, 2);
+        assert_eq!(decode_timestamp(reply.payload), Some(sent));
+        assert_eq!(decode_timestamp(&payload[..4]), None);
+    }
+}
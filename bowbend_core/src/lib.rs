@@ -9,21 +9,41 @@ use futures::stream;
 
 pub use crate::{
     config::ConfigBuilder,
+    diff::{
+        diff_reports, HostKey, HostSnapshot, PortSnapshot, ScanChange, ScanSnapshot,
+        ServiceSnapshot,
+    },
+    discovery::{
+        arp::{ArpResult, ArpResultType},
+        HostDiscoveryMethod, HostDiscoveryPolicy, TcpPingResult, TcpPingResultType,
+    },
     err::PortscanErr,
-    icmp::{PingResult, PingResultType},
+    icmp::{IcmpSummary, PingResult, PingResultType, PingStatistics},
+    os_detection::{OsFamily, OsGuess, TcpFingerprint, TcpOptionKind},
     report::{PortReport, PortStatus, Report, ReportContents},
     scan::start_scan,
-    service_detection::framework::{ServiceDetectionCertainty, ServiceDetectionConclusion},
+    service_detection::{
+        framework::{
+            FailedRule, RuleEvidence, RuleFailure, RuleId, RuleLoudness, RuleScope,
+            ServiceDetectionCertainty, ServiceDetectionConclusion, ServiceDetectionIntensity,
+        },
+        test_plan::{SkipReason, SkippedRule},
+    },
     target::{Target, TargetInstance},
+    traceroute::{TracerouteHop, TracerouteMethod, TracerouteResult},
 };
 
 mod config;
+mod diff;
+mod discovery;
 mod err;
 mod icmp;
 mod logging;
+mod os_detection;
 mod report;
 mod scan;
 mod service_detection;
 mod target;
 mod tcp;
+mod traceroute;
 pub(crate) mod utils;
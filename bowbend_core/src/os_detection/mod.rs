@@ -0,0 +1,410 @@
+//! Passive OS fingerprinting.  Every OS picks its own initial TTL and lays out
+//! the options in its SYN/ACKs a little differently, so the replies we already
+//! got during host discovery say a lot about what sent them.  Nothing extra is
+//! sent to the host.  The TCP fingerprint is only captured by the TCP SYN
+//! ping, since that is the only method that reads SYN/ACKs off a raw socket.
+
+use futures::{Stream, StreamExt};
+
+use crate::{
+    discovery::TcpPingResult, icmp::PingResultType, report::Report, ReportContents,
+    ServiceDetectionCertainty,
+};
+
+const TCP_HEADER_SIZE: usize = 20;
+const OPTION_END_OF_LIST: u8 = 0;
+const OPTION_NO_OPERATION: u8 = 1;
+const OPTION_MAXIMUM_SEGMENT_SIZE: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_TIMESTAMPS: u8 = 8;
+
+/// The OS families we can tell apart.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OsFamily {
+    /// Linux, including Android
+    Linux,
+    /// Windows
+    Windows,
+    /// macOS and iOS
+    MacOs,
+    /// FreeBSD, OpenBSD and the rest of the BSD family
+    Bsd,
+    /// Solaris and illumos
+    Solaris,
+    /// Routers, switches and other embedded network gear, like Cisco IOS
+    NetworkDevice,
+}
+
+/// Our best guess at the OS running on a host.
+#[derive(Clone, Debug)]
+pub struct OsGuess {
+    /// The OS family
+    pub family: OsFamily,
+    /// How certain we are.  A guess from the TTL alone is never better than
+    /// [`ServiceDetectionCertainty::Low`].
+    pub certainty: ServiceDetectionCertainty,
+    /// The TTL we think the host started its packets with, if we saw one.
+    pub initial_ttl: Option<u8>,
+}
+
+/// The kinds of TCP options, in the order a host put them in its SYN/ACK.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpOptionKind {
+    /// End of the option list
+    EndOfList,
+    /// Padding between options
+    NoOperation,
+    /// Maximum segment size
+    MaximumSegmentSize,
+    /// Window scale
+    WindowScale,
+    /// Selective acknowledgements are allowed
+    SackPermitted,
+    /// Timestamps
+    Timestamps,
+    /// Anything else, by its option number
+    Other(u8),
+}
+
+/// What a SYN/ACK looked like.  These details are left up to the OS, which
+/// makes them a good fingerprint.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TcpFingerprint {
+    /// The TTL, or hop limit, the SYN/ACK arrived with.  Raw IPv6 sockets
+    /// don't give us this.
+    pub ttl: Option<u8>,
+    /// The advertised window size
+    pub window_size: u16,
+    /// The maximum segment size option, if it was set
+    pub maximum_segment_size: Option<u16>,
+    /// The window scale option, if it was set
+    pub window_scale: Option<u8>,
+    /// Every option in the order it was sent
+    pub options: Vec<TcpOptionKind>,
+}
+
+impl TcpFingerprint {
+    /// Build a fingerprint from a raw TCP segment, header included.  This is
+    /// `None` if the header is truncated.
+    pub(crate) fn from_segment(segment: &[u8], ttl: Option<u8>) -> Option<Self> {
+        if segment.len() < TCP_HEADER_SIZE {
+            return None;
+        }
+        let window_size = u16::from_be_bytes([segment[14], segment[15]]);
+        let header_length = usize::from(segment[12] >> 4) * 4;
+        let options = segment.get(TCP_HEADER_SIZE..header_length).unwrap_or(&[]);
+        let mut fingerprint = TcpFingerprint {
+            ttl,
+            window_size,
+            maximum_segment_size: None,
+            window_scale: None,
+            options: vec![],
+        };
+        let mut offset = 0;
+        while let Some(&kind) = options.get(offset) {
+            let (option, length) = match kind {
+                OPTION_END_OF_LIST => (TcpOptionKind::EndOfList, 1),
+                OPTION_NO_OPERATION => (TcpOptionKind::NoOperation, 1),
+                _ => {
+                    // Every other option carries its own length.  A bad one means
+                    // the rest of the list can't be trusted.
+                    let length = match options.get(offset + 1) {
+                        Some(&length) if length >= 2 => usize::from(length),
+                        _ => break,
+                    };
+                    let data = match options.get(offset + 2..offset + length) {
+                        Some(data) => data,
+                        None => break,
+                    };
+                    let option = match kind {
+                        OPTION_MAXIMUM_SEGMENT_SIZE => {
+                            if let [high, low] = data {
+                                fingerprint.maximum_segment_size =
+                                    Some(u16::from_be_bytes([*high, *low]));
+                            }
+                            TcpOptionKind::MaximumSegmentSize
+                        }
+                        OPTION_WINDOW_SCALE => {
+                            fingerprint.window_scale = data.first().cloned();
+                            TcpOptionKind::WindowScale
+                        }
+                        OPTION_SACK_PERMITTED => TcpOptionKind::SackPermitted,
+                        OPTION_TIMESTAMPS => TcpOptionKind::Timestamps,
+                        other => TcpOptionKind::Other(other),
+                    };
+                    (option, length)
+                }
+            };
+            fingerprint.options.push(option);
+            if option == TcpOptionKind::EndOfList {
+                break;
+            }
+            offset += length;
+        }
+        Some(fingerprint)
+    }
+}
+
+/// What one OS's replies look like.  These are SYN/ACKs sent in answer to a
+/// SYN offering every option, which is what the TCP SYN ping sends.
+struct OsSignature {
+    family: OsFamily,
+    initial_ttl: u8,
+    /// Every option layout the OS is known to use
+    option_layouts: &'static [&'static [TcpOptionKind]],
+    /// Window sizes the OS commonly advertises
+    window_sizes: &'static [u16],
+    /// Window scales the OS commonly uses.  Linux picks one based on how much
+    /// memory it has.  This is empty if the OS doesn't send the option.
+    window_scales: &'static [u8],
+}
+
+use TcpOptionKind::{
+    EndOfList as E, MaximumSegmentSize as M, NoOperation as N, SackPermitted as S, Timestamps as T,
+    WindowScale as W,
+};
+
+/// When signatures match equally well the one listed first wins, so the more
+/// common OS in each group goes first.
+const SIGNATURES: &[OsSignature] = &[
+    OsSignature {
+        family: OsFamily::Linux,
+        initial_ttl: 64,
+        option_layouts: &[&[M, S, T, N, W], &[M, N, N, S, N, W]],
+        window_sizes: &[65160, 64240, 65483, 43690, 28960, 29200, 14480, 5792],
+        window_scales: &[7, 8, 9, 10],
+    },
+    OsSignature {
+        family: OsFamily::Windows,
+        initial_ttl: 128,
+        option_layouts: &[&[M, N, W, S, T], &[M, N, W, N, N, S]],
+        window_sizes: &[65535, 64240, 8192],
+        window_scales: &[8],
+    },
+    OsSignature {
+        family: OsFamily::MacOs,
+        initial_ttl: 64,
+        option_layouts: &[&[M, N, W, N, N, T, S, E], &[M, N, W, S, T]],
+        window_sizes: &[65535],
+        window_scales: &[5, 6],
+    },
+    OsSignature {
+        family: OsFamily::Bsd,
+        initial_ttl: 64,
+        option_layouts: &[&[M, N, W, S, T], &[M, N, N, S, N, W, N, N, T]],
+        window_sizes: &[65535, 16384],
+        window_scales: &[6, 3],
+    },
+    OsSignature {
+        family: OsFamily::Solaris,
+        initial_ttl: 64,
+        option_layouts: &[&[N, N, T, M, N, W, N, N, S]],
+        window_sizes: &[64436, 49232, 32806],
+        window_scales: &[1, 2],
+    },
+    OsSignature {
+        family: OsFamily::NetworkDevice,
+        initial_ttl: 255,
+        option_layouts: &[&[M]],
+        window_sizes: &[4128, 8192, 16384],
+        window_scales: &[],
+    },
+];
+
+/// Work out what TTL a packet started with.  Every common OS starts at one of
+/// these and no real path is long enough to cross from one to the next.
+fn initial_ttl(observed: u8) -> u8 {
+    match observed {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
+
+/// How well a signature matches.  A TTL that disagrees rules the signature
+/// out entirely since a host can't raise its TTL on the way to us.
+fn match_signature(
+    signature: &OsSignature,
+    ttl: Option<u8>,
+    tcp: Option<&TcpFingerprint>,
+) -> Option<ServiceDetectionCertainty> {
+    if matches!(ttl, Some(ttl) if ttl != signature.initial_ttl) {
+        return None;
+    }
+    let (layout_matches, window_matches) = match tcp {
+        Some(tcp) => {
+            let layout_matches = signature.option_layouts.contains(&tcp.options.as_slice());
+            let scale_matches = match tcp.window_scale {
+                Some(scale) => signature.window_scales.contains(&scale),
+                None => signature.window_scales.is_empty(),
+            };
+            let window_matches = signature.window_sizes.contains(&tcp.window_size) && scale_matches;
+            (layout_matches, window_matches)
+        }
+        None => (false, false),
+    };
+    match (layout_matches, window_matches, ttl.is_some()) {
+        (true, true, true) => Some(ServiceDetectionCertainty::High),
+        (true, true, false) | (true, false, true) => Some(ServiceDetectionCertainty::Medium),
+        (true, false, false) | (false, _, true) => Some(ServiceDetectionCertainty::Low),
+        (false, _, false) => None,
+    }
+}
+
+/// Guess the OS from everything host discovery saw.  This is `None` if
+/// nothing answered in a way that tells us anything.
+pub(crate) fn guess_os(contents: &ReportContents) -> Option<OsGuess> {
+    let tcp = contents
+        .tcp_syn_ping
+        .as_ref()
+        .and_then(|ping: &TcpPingResult| ping.fingerprint.as_ref());
+    let icmp_ttl = contents
+        .icmp
+        .as_ref()
+        .and_then(|ping| match &ping.result_type {
+            PingResultType::Reply(summary) => summary.ttl,
+            _ => None,
+        });
+    let ttl = tcp.and_then(|tcp| tcp.ttl).or(icmp_ttl).map(initial_ttl);
+    let mut best: Option<OsGuess> = None;
+    for signature in SIGNATURES {
+        if let Some(certainty) = match_signature(signature, ttl, tcp) {
+            let better = match &best {
+                // More certain conclusions sort first
+                Some(best) => certainty < best.certainty,
+                None => true,
+            };
+            if better {
+                best = Some(OsGuess {
+                    family: signature.family,
+                    certainty,
+                    initial_ttl: ttl,
+                });
+            }
+        }
+    }
+    best
+}
+
+/// Attach an OS guess to every report that got far enough to have one.
+pub(crate) fn run_os_detection(
+    report_stream: impl Stream<Item = Report>,
+) -> impl Stream<Item = Report> {
+    report_stream.map(|mut report| {
+        if let Ok(contents) = &mut report.contents {
+            contents.os_guess = guess_os(contents);
+        }
+        report
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::{
+        discovery::{HostDiscoveryResults, TcpPingResult, TcpPingResultType},
+        icmp::{IcmpSummary, PingResult, PingResultType},
+        os_detection::{guess_os, OsFamily, TcpFingerprint, TcpOptionKind},
+        ReportContents, ServiceDetectionCertainty,
+    };
+
+    /// A SYN/ACK header followed by `options`.
+    fn syn_ack(window_size: u16, options: &[u8]) -> Vec<u8> {
+        let header_length = 20 + options.len();
+        let mut segment = vec![0u8; 20];
+        segment[12] = ((header_length / 4) as u8) << 4;
+        segment[13] = 0x12;
+        segment[14..16].copy_from_slice(&window_size.to_be_bytes());
+        segment.extend_from_slice(options);
+        segment
+    }
+
+    fn contents(icmp_ttl: Option<u8>, tcp: Option<TcpFingerprint>) -> ReportContents {
+        let now = SystemTime::now();
+        let discovery = HostDiscoveryResults {
+            icmp: icmp_ttl.map(|ttl| PingResult {
+                ping_sent: now,
+                result_type: PingResultType::Reply(IcmpSummary {
+                    time_sent: now,
+                    time_received: now,
+                    ttl: Some(ttl),
+                }),
+                statistics: None,
+            }),
+            tcp_syn: tcp.map(|fingerprint| TcpPingResult {
+                probe_sent: now,
+                result_type: TcpPingResultType::Open {
+                    port: 80,
+                    time_received: now,
+                },
+                fingerprint: Some(fingerprint),
+            }),
+            ..Default::default()
+        };
+        ReportContents::new(discovery, None)
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        // MSS 1460, SACK permitted, timestamps, NOP, window scale 7
+        let options = [
+            2, 4, 5, 180, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+        ];
+        let fingerprint =
+            TcpFingerprint::from_segment(&syn_ack(65160, &options), Some(61)).unwrap();
+        assert_eq!(fingerprint.window_size, 65160);
+        assert_eq!(fingerprint.maximum_segment_size, Some(1460));
+        assert_eq!(fingerprint.window_scale, Some(7));
+        assert_eq!(
+            fingerprint.options,
+            vec![
+                TcpOptionKind::MaximumSegmentSize,
+                TcpOptionKind::SackPermitted,
+                TcpOptionKind::Timestamps,
+                TcpOptionKind::NoOperation,
+                TcpOptionKind::WindowScale
+            ]
+        );
+
+        // An option running off the end stops parsing without panicking
+        let truncated = TcpFingerprint::from_segment(&syn_ack(1024, &[1, 2, 40, 0]), None).unwrap();
+        assert_eq!(truncated.options, vec![TcpOptionKind::NoOperation]);
+        assert!(TcpFingerprint::from_segment(&[0u8; 12], None).is_none());
+    }
+
+    #[test]
+    fn test_guess_os() {
+        let linux = [
+            2, 4, 5, 180, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+        ];
+        let fingerprint = TcpFingerprint::from_segment(&syn_ack(65160, &linux), Some(57)).unwrap();
+        let guess = guess_os(&contents(None, Some(fingerprint.clone()))).unwrap();
+        assert_eq!(guess.family, OsFamily::Linux);
+        assert!(matches!(guess.certainty, ServiceDetectionCertainty::High));
+        assert_eq!(guess.initial_ttl, Some(64));
+
+        // The same options with a Windows TTL can't be Linux.  Nothing matches
+        // the options so all we have is the TTL.
+        let mut from_windows = fingerprint;
+        from_windows.ttl = Some(120);
+        let guess = guess_os(&contents(None, Some(from_windows))).unwrap();
+        assert_eq!(guess.family, OsFamily::Windows);
+        assert!(matches!(guess.certainty, ServiceDetectionCertainty::Low));
+
+        let windows = [2, 4, 5, 180, 1, 3, 3, 8, 1, 1, 4, 2];
+        let fingerprint = TcpFingerprint::from_segment(&syn_ack(64240, &windows), None).unwrap();
+        // An ICMP reply fills in the TTL when the SYN/ACK didn't have one
+        let guess = guess_os(&contents(Some(110), Some(fingerprint))).unwrap();
+        assert_eq!(guess.family, OsFamily::Windows);
+        assert!(matches!(guess.certainty, ServiceDetectionCertainty::High));
+
+        let guess = guess_os(&contents(Some(250), None)).unwrap();
+        assert_eq!(guess.family, OsFamily::NetworkDevice);
+        assert!(matches!(guess.certainty, ServiceDetectionCertainty::Low));
+
+        assert!(guess_os(&contents(None, None)).is_none());
+    }
+}
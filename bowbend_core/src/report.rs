@@ -1,13 +1,24 @@
 //! This module contains everything we need to describe the results of a
 //! portscan.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    discovery::{arp::ArpResult, HostDiscoveryResults, TcpPingResult},
     err::PortscanErr,
     icmp::PingResult,
-    service_detection::framework::ServiceDetectionConclusion,
+    os_detection::OsGuess,
+    service_detection::{
+        framework::{FailedRule, RuleEvidence, ServiceDetectionConclusion},
+        test_plan::SkippedRule,
+    },
     target::{Target, TargetInstance},
+    traceroute::TracerouteResult,
 };
 
 /// A portscan will produce a stream of Reports to notify the caller of
@@ -34,9 +45,41 @@ pub struct Report {
 pub struct ReportContents {
     /// The results of pinging the host.
     pub icmp: Option<PingResult>,
+    /// The results of the TCP SYN ping, if it was run.
+    pub tcp_syn_ping: Option<TcpPingResult>,
+    /// The results of the TCP ACK ping, if it was run.
+    pub tcp_ack_ping: Option<TcpPingResult>,
+    /// The results of the TCP connect ping, if it was run.
+    pub tcp_connect_ping: Option<TcpPingResult>,
+    /// The results of the ARP request, if it was run.  This includes the MAC
+    /// address of the host if it answered.
+    pub arp: Option<ArpResult>,
     /// This will be none if we never made it to the point of running the
-    /// portscan, for example if we pinged and it timed out
+    /// portscan, for example if the host discovery policy skipped the host
     pub ports: Option<HashMap<u16, PortReport>>,
+    /// The route to the host, if traceroute was turned on.
+    pub traceroute: Option<TracerouteResult>,
+    /// Our guess at the host's OS, if OS detection was turned on and host
+    /// discovery heard enough back to make one.
+    pub os_guess: Option<OsGuess>,
+}
+
+impl ReportContents {
+    pub(crate) fn new(
+        discovery: HostDiscoveryResults,
+        ports: Option<HashMap<u16, PortReport>>,
+    ) -> Self {
+        ReportContents {
+            icmp: discovery.icmp,
+            tcp_syn_ping: discovery.tcp_syn,
+            tcp_ack_ping: discovery.tcp_ack,
+            tcp_connect_ping: discovery.tcp_connect,
+            arp: discovery.arp,
+            ports,
+            traceroute: None,
+            os_guess: None,
+        }
+    }
 }
 
 /// The status of an individual port that was scanned.
@@ -46,12 +89,36 @@ pub struct PortReport {
     pub port: u16,
     /// If it is open, closed, filtered, etc
     pub status: PortStatus,
-    /// The summary of all service detection conclusions, if run.
+    /// The summary of all service detection conclusions, if run.  Duplicates
+    /// are merged and they are ordered from most to least certain.
     pub service_detection_conclusions: Option<Vec<ServiceDetectionConclusion>>,
+    /// The single most likely service on the port.  This is `None` if service
+    /// detection didn't run or couldn't come to any conclusion.
+    pub best_service_guess: Option<ServiceDetectionConclusion>,
+    /// The service detection rules that weren't run against this port and
+    /// why, if service detection ran.
+    pub skipped_rules: Option<Vec<SkippedRule>>,
+    /// The service detection rules that were run against this port but
+    /// failed or timed out, if service detection ran.
+    pub failed_rules: Option<Vec<FailedRule>>,
+    /// A record of every service detection rule run against this port and
+    /// what it saw.  This is only set when evidence recording is turned on.
+    pub service_detection_evidence: Option<Vec<RuleEvidence>>,
+    /// If TLS or SSL was enabled on the port.  This is only set when service
+    /// detection ran and was able to complete a TLS probe.
+    pub tls_enabled: Option<bool>,
+    /// The time we started trying to connect to the port.
+    pub probe_sent: SystemTime,
+    /// The time we heard back from the port, either with a successful
+    /// connection or a rejection.  This is `None` if we timed out.
+    pub response_received: Option<SystemTime>,
+    /// How long it took to hear back from the port.  This is `None` if we
+    /// timed out.
+    pub connect_latency: Option<Duration>,
 }
 
 /// The state of the port scanned
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum PortStatus {
     /// The port is ready to open and establish and connection.  We either fully
     /// established one or
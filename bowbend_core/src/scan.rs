@@ -5,11 +5,14 @@ use tokio::sync::Semaphore;
 use tracing::trace;
 
 use crate::{
-    icmp::{icmp_sweep, skip_icmp},
+    discovery::{discover_hosts, ndp::multicast_discovery},
+    icmp::PingSettings,
     logging::setup_tracing,
-    service_detection::run_service_detection_on_target,
-    target::targets_to_instance_stream,
+    os_detection::run_os_detection,
+    service_detection::{run_service_detection_on_target, ServiceDetectionSettings},
+    target::{split_large_networks, targets_to_instance_stream},
     tcp::full_open::full_open_port_scan,
+    traceroute::{run_traceroute, TracerouteSettings},
     utils::throttle_stream::throttle_stream,
     ConfigBuilder, PortscanErr, Report,
 };
@@ -23,32 +26,63 @@ pub async fn start_scan(
         setup_tracing()
     }
     let semaphore = Arc::new(Semaphore::new(config_builder.max_in_flight as usize));
-    let (target_stream, failed) = targets_to_instance_stream(config_builder.targets);
+    let ping_settings = PingSettings::from(&config_builder);
+    let traceroute_settings = TracerouteSettings::from_config(&config_builder);
+    let service_detection_settings = config_builder
+        .run_service_detection
+        .then(|| ServiceDetectionSettings::from_config(&config_builder))
+        .transpose()?;
+    let (targets, large_networks) = split_large_networks(config_builder.targets);
+    let (target_stream, mut failed) = targets_to_instance_stream(targets);
+    let (discovered, undiscoverable) =
+        multicast_discovery(large_networks, ping_settings.timeout).await;
+    failed.extend(undiscoverable);
+    let target_stream = target_stream.chain(stream::iter(discovered));
     let throttled_stream = if let Some(ref range) = config_builder.throttle_range {
         throttle_stream(range.clone(), target_stream).boxed()
     } else {
         target_stream.boxed()
     };
-    let ping_result_stream = if config_builder.ping {
-        icmp_sweep(throttled_stream, semaphore.clone())
-            .await?
-            .boxed()
-    } else {
-        skip_icmp(throttled_stream).await?.boxed()
-    };
-    trace!("We have ping results back");
+    let discovery_stream = discover_hosts(
+        throttled_stream,
+        config_builder.host_discovery_methods,
+        semaphore.clone(),
+        ping_settings,
+    )
+    .await?;
+    trace!("We have host discovery results back");
     let results = full_open_port_scan(
-        Box::pin(ping_result_stream),
+        discovery_stream,
         config_builder.ports,
         semaphore.clone(),
         config_builder.throttle_range.clone(),
+        config_builder.host_discovery_policy,
     )
     .await;
     trace!("We finished a full open port scan");
 
-    let results = if config_builder.run_service_detection {
-        run_service_detection_on_target(results, semaphore.clone(), config_builder.throttle_range)
-            .await
+    let results = if config_builder.run_os_detection {
+        run_os_detection(results).boxed()
+    } else {
+        results.boxed()
+    };
+
+    let results = if let Some(settings) = service_detection_settings {
+        run_service_detection_on_target(
+            results,
+            semaphore.clone(),
+            config_builder.throttle_range,
+            settings,
+        )
+        .await
+        .boxed()
+    } else {
+        results.boxed()
+    };
+
+    let results = if let Some(settings) = traceroute_settings {
+        run_traceroute(results, settings, semaphore.clone())
+            .await?
             .boxed()
     } else {
         results.boxed()
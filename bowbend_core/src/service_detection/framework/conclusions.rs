@@ -0,0 +1,211 @@
+use crate::service_detection::framework::ServiceDetectionConclusion;
+
+/// Merge the conclusions all the rules came to about a port.  Conclusions
+/// naming the same service, ignoring case and spacing, are merged into one
+/// with the highest certainty any of them had.  The most specific version wins
+/// so "1.18.0" replaces "1.18", but when versions disagree we keep the one from
+/// the more certain conclusion.  The result is ordered from most to least
+/// certain, with conclusions that have a version first within each level, so
+/// the first entry is our best guess.
+pub fn aggregate_conclusions(
+    mut conclusions: Vec<ServiceDetectionConclusion>,
+) -> Vec<ServiceDetectionConclusion> {
+    // Going from most to least certain means each merge only has to decide if
+    // the less certain conclusion has a more specific version.
+    conclusions.sort_by_key(|conclusion| conclusion.certainty);
+    let mut merged: Vec<ServiceDetectionConclusion> = Vec::new();
+    for conclusion in conclusions {
+        let key = service_key(&conclusion.service_name);
+        match merged
+            .iter_mut()
+            .find(|existing| service_key(&existing.service_name) == key)
+        {
+            Some(existing) => {
+                let more_specific = match (&existing.service_version, &conclusion.service_version) {
+                    (None, Some(_)) => true,
+                    (Some(existing), Some(new)) => extends_version(existing, new),
+                    (_, None) => false,
+                };
+                if more_specific {
+                    existing.service_version = conclusion.service_version;
+                }
+                if existing.vendor.is_none() && existing.product.is_none() {
+                    existing.vendor = conclusion.vendor;
+                    existing.product = conclusion.product;
+                }
+            }
+            None => merged.push(conclusion),
+        }
+    }
+    merged.sort_by(|a, b| {
+        a.certainty
+            .cmp(&b.certainty)
+            .then_with(|| {
+                b.service_version
+                    .is_some()
+                    .cmp(&a.service_version.is_some())
+            })
+            .then_with(|| a.service_name.cmp(&b.service_name))
+    });
+    merged
+}
+
+impl ServiceDetectionConclusion {
+    /// The CPE 2.3 name for the service, like
+    /// `cpe:2.3:a:f5:nginx:1.18.0:*:*:*:*:*:*:*`.  This is `None` unless we
+    /// know both the vendor and the product.  Without a version the version
+    /// is left as a wildcard.
+    pub fn cpe(&self) -> Option<String> {
+        let vendor = cpe_component(self.vendor.as_deref()?);
+        let product = cpe_component(self.product.as_deref()?);
+        let version = self
+            .service_version
+            .as_deref()
+            .map_or_else(|| "*".to_string(), cpe_component);
+        Some(format!(
+            "cpe:2.3:a:{vendor}:{product}:{version}:*:*:*:*:*:*:*"
+        ))
+    }
+}
+
+/// Escape a value for a CPE 2.3 formatted string.  Values are lowercase,
+/// spaces become underscores and any punctuation besides `-`, `.` and `_` is
+/// escaped with a backslash.
+fn cpe_component(value: &str) -> String {
+    let mut component = String::with_capacity(value.len());
+    for c in value.trim().to_lowercase().chars() {
+        match c {
+            c if c.is_whitespace() => component.push('_'),
+            c if c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_') => component.push(c),
+            c => {
+                component.push('\\');
+                component.push(c);
+            }
+        }
+    }
+    component
+}
+
+/// Is `new` a more specific form of `existing`, like "1.18.0" is of "1.18"?
+/// "1.180" isn't.
+fn extends_version(existing: &str, new: &str) -> bool {
+    new.len() > existing.len()
+        && new.starts_with(existing)
+        && !new[existing.len()..].starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Normalize a service name so trivially different spellings compare equal.
+fn service_key(service_name: &str) -> String {
+    service_name
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service_detection::framework::{
+        aggregate_conclusions, ServiceDetectionCertainty, ServiceDetectionConclusion,
+    };
+
+    fn conclusion(
+        certainty: ServiceDetectionCertainty,
+        service_name: &str,
+        service_version: Option<&str>,
+    ) -> ServiceDetectionConclusion {
+        ServiceDetectionConclusion {
+            certainty,
+            service_name: service_name.to_string(),
+            service_version: service_version.map(str::to_string),
+            vendor: None,
+            product: None,
+        }
+    }
+
+    fn summary(conclusions: &[ServiceDetectionConclusion]) -> Vec<(&str, Option<&str>)> {
+        conclusions
+            .iter()
+            .map(|conclusion| {
+                (
+                    conclusion.service_name.as_str(),
+                    conclusion.service_version.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate_conclusions() {
+        let aggregated = aggregate_conclusions(vec![
+            conclusion(ServiceDetectionCertainty::Low, "redis", None),
+            conclusion(ServiceDetectionCertainty::Medium, "nginx", Some("1.18")),
+            conclusion(ServiceDetectionCertainty::Advertised, "nginx", None),
+            conclusion(ServiceDetectionCertainty::Low, "NGINX ", Some("1.18.0")),
+            conclusion(ServiceDetectionCertainty::Low, "nginx", Some("2.0")),
+            conclusion(ServiceDetectionCertainty::Medium, "apache", None),
+            conclusion(ServiceDetectionCertainty::Medium, "openresty", Some("1")),
+            // Not a more specific form of "1"
+            conclusion(ServiceDetectionCertainty::Low, "openresty", Some("10")),
+        ]);
+        assert_eq!(
+            summary(&aggregated),
+            vec![
+                ("nginx", Some("1.18.0")),
+                ("openresty", Some("1")),
+                ("apache", None),
+                ("redis", None),
+            ]
+        );
+        assert!(matches!(
+            aggregated[0].certainty,
+            ServiceDetectionCertainty::Advertised
+        ));
+        assert!(aggregate_conclusions(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_cpe() {
+        let mut nginx = conclusion(
+            ServiceDetectionCertainty::Advertised,
+            "nginx HTTP server",
+            Some("1.18.0"),
+        );
+        assert_eq!(nginx.cpe(), None);
+        nginx.vendor = Some("f5".to_string());
+        nginx.product = Some("nginx".to_string());
+        assert_eq!(
+            nginx.cpe().as_deref(),
+            Some("cpe:2.3:a:f5:nginx:1.18.0:*:*:*:*:*:*:*")
+        );
+        nginx.service_version = None;
+        assert_eq!(
+            nginx.cpe().as_deref(),
+            Some("cpe:2.3:a:f5:nginx:*:*:*:*:*:*:*:*")
+        );
+
+        let mut openssh = conclusion(ServiceDetectionCertainty::High, "OpenSSH", Some("8.9p1"));
+        openssh.vendor = Some("OpenBSD".to_string());
+        openssh.product = Some("Open SSH:server".to_string());
+        assert_eq!(
+            openssh.cpe().as_deref(),
+            Some("cpe:2.3:a:openbsd:open_ssh\\:server:8.9p1:*:*:*:*:*:*:*")
+        );
+    }
+
+    #[test]
+    fn test_aggregate_conclusions_keeps_product() {
+        let mut nginx = conclusion(ServiceDetectionCertainty::Low, "nginx", Some("1.18.0"));
+        nginx.vendor = Some("f5".to_string());
+        nginx.product = Some("nginx".to_string());
+        let aggregated = aggregate_conclusions(vec![
+            nginx,
+            conclusion(ServiceDetectionCertainty::Advertised, "nginx", Some("1.18")),
+        ]);
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(
+            aggregated[0].cpe().as_deref(),
+            Some("cpe:2.3:a:f5:nginx:1.18.0:*:*:*:*:*:*:*")
+        );
+    }
+}
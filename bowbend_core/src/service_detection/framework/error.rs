@@ -1,5 +1,7 @@
 use std::io;
 
+use crate::service_detection::framework::RuleId;
+
 /// The base error type for rules.  This contains all possible error states.
 #[derive(Debug)]
 pub enum RuleError {
@@ -13,8 +15,60 @@ pub enum RuleError {
     InternalRuleError(Box<dyn std::error::Error + Send + Sync>),
 }
 
+impl RuleError {
+    /// Is this an error that might go away if the rule is run again?  Only
+    /// IO errors where the connection was dropped or interrupted count.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RuleError::IOError(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+            ),
+            RuleError::InternalRuleError(_) => false,
+        }
+    }
+}
+
 impl From<io::Error> for RuleError {
     fn from(e: io::Error) -> Self {
         RuleError::IOError(e)
     }
 }
+
+/// Why a rule that was run didn't produce a result.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RuleFailure {
+    /// The rule didn't finish within the configured timeout.
+    TimedOut,
+    /// The rule hit an IO error it couldn't recover from, even after any
+    /// retries.  This holds the kind of error and a description of it.
+    IOError(io::ErrorKind, String),
+    /// The rule failed because of a bug or bad internal state.  This holds a
+    /// description of the error.
+    InternalError(String),
+}
+
+impl From<RuleError> for RuleFailure {
+    fn from(e: RuleError) -> Self {
+        match e {
+            RuleError::IOError(e) => RuleFailure::IOError(e.kind(), e.to_string()),
+            RuleError::InternalRuleError(e) => RuleFailure::InternalError(e.to_string()),
+        }
+    }
+}
+
+/// A service detection rule that was run against a port but failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FailedRule {
+    /// The rule that failed
+    pub rule: RuleId,
+    /// Why it failed the last time it was run
+    pub failure: RuleFailure,
+    /// How many times the rule was run, including retries
+    pub attempts: u32,
+}
@@ -0,0 +1,343 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep_until;
+use tracing::debug;
+
+use crate::service_detection::framework::{
+    PortToAnalyze, Rule, RuleFailure, RuleId, RuleResult, RuleResults,
+};
+
+/// How the framework runs each rule.
+#[derive(Clone, Debug)]
+pub(crate) struct ExecutionSettings {
+    /// How long a single attempt at a rule may take before we give up on it
+    pub(crate) rule_timeout: Duration,
+    /// How many times to retry a rule that failed with a transient IO error
+    pub(crate) retries: u8,
+}
+
+/// Keeps track of how long an attempt at a rule spent waiting in
+/// [`PortToAnalyze::wait_for_clearance`].  The rule timeout is only meant to
+/// cover the rule's own work, not time spent queued behind the throttle and
+/// the in flight limit.
+#[derive(Debug, Default)]
+pub(crate) struct ClearanceClock {
+    state: Mutex<ClearanceState>,
+}
+
+#[derive(Debug, Default)]
+struct ClearanceState {
+    /// Time spent in waits that have finished
+    waited: Duration,
+    /// How many waits are going on right now
+    waiting: usize,
+    /// When the current stretch of waiting started
+    waiting_since: Option<Instant>,
+}
+
+impl ClearanceClock {
+    /// Note that the rule started waiting for clearance.  The wait ends when
+    /// the returned guard is dropped.
+    pub(crate) fn start_waiting(&self) -> ClearanceWait<'_> {
+        let mut state = self.state.lock().unwrap();
+        state.waiting += 1;
+        if state.waiting == 1 {
+            state.waiting_since = Some(Instant::now());
+        }
+        ClearanceWait { clock: self }
+    }
+
+    /// How much time has been spent waiting for clearance up to `now`.
+    /// Overlapping waits are only counted once.
+    fn waited(&self, now: Instant) -> Duration {
+        let state = self.state.lock().unwrap();
+        let current = state
+            .waiting_since
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        state.waited + current
+    }
+}
+
+/// Guard for a single wait for clearance.
+pub(crate) struct ClearanceWait<'a> {
+    clock: &'a ClearanceClock,
+}
+
+impl Drop for ClearanceWait<'_> {
+    fn drop(&mut self) {
+        let mut state = self.clock.state.lock().unwrap();
+        state.waiting -= 1;
+        if state.waiting == 0 {
+            if let Some(since) = state.waiting_since.take() {
+                state.waited += since.elapsed();
+            }
+        }
+    }
+}
+
+/// The outcome of running a rule against a port.
+pub(crate) struct RuleRun {
+    /// The result of the last attempt
+    pub(crate) result: Result<Box<dyn RuleResult>, RuleFailure>,
+    /// How many times the rule was run, including retries
+    pub(crate) attempts: u32,
+    /// How long all the attempts took together
+    pub(crate) duration: Duration,
+}
+
+/// A record of a rule that was run against a port.  These are only kept when
+/// evidence recording is turned on with
+/// [`crate::ConfigBuilder::set_record_service_detection_evidence`].
+#[derive(Clone, Debug)]
+pub struct RuleEvidence {
+    /// The rule that was run
+    pub rule: RuleId,
+    /// How long the rule took, including any retries
+    pub duration: Duration,
+    /// How many times the rule was run, including retries
+    pub attempts: u32,
+    /// Everything the rule observed, as the debug representation of its
+    /// result, or why it failed.
+    pub outcome: Result<String, RuleFailure>,
+}
+
+impl RuleEvidence {
+    pub(crate) fn new(rule: RuleId, run: &RuleRun) -> Self {
+        Self {
+            rule,
+            duration: run.duration,
+            attempts: run.attempts,
+            outcome: match &run.result {
+                Ok(result) => Ok(format!("{result:?}")),
+                Err(failure) => Err(failure.clone()),
+            },
+        }
+    }
+}
+
+/// Run a rule against a port.  Each attempt is abandoned if it takes longer
+/// than the timeout, not counting time spent waiting for clearance.
+/// Transient IO errors are retried as many times as the settings allow while
+/// anything else fails right away.
+pub(crate) async fn execute_rule(
+    rule: &dyn Rule,
+    port_to_analyze: Arc<PortToAnalyze>,
+    rule_results: Arc<RuleResults>,
+    settings: &ExecutionSettings,
+) -> RuleRun {
+    let started = Instant::now();
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let clock = Arc::new(ClearanceClock::default());
+        let execution = rule.get_execution_method()(
+            port_to_analyze.with_clearance_clock(clock.clone()),
+            rule_results.clone(),
+        );
+        match run_attempt(execution, &clock, settings.rule_timeout).await {
+            Some(Ok(result)) => break Ok(result),
+            Some(Err(e)) if e.is_transient() && attempts <= u32::from(settings.retries) => {
+                debug!("Retrying rule {} after {:?}", rule.rule_id(), e);
+            }
+            Some(Err(e)) => break Err(e.into()),
+            None => break Err(RuleFailure::TimedOut),
+        }
+    };
+    RuleRun {
+        result,
+        attempts,
+        duration: started.elapsed(),
+    }
+}
+
+/// Drive a single attempt at a rule, giving up with `None` once it has spent
+/// longer than `rule_timeout` outside of waiting for clearance.
+async fn run_attempt<F: Future>(
+    execution: F,
+    clock: &ClearanceClock,
+    rule_timeout: Duration,
+) -> Option<F::Output> {
+    let started = Instant::now();
+    tokio::pin!(execution);
+    let mut deadline = started + rule_timeout;
+    loop {
+        tokio::select! {
+            result = &mut execution => return Some(result),
+            _ = sleep_until(deadline.into()) => {
+                // Push the deadline back by however long we've spent waiting.
+                // While a wait is still going on this keeps moving forward.
+                let now = Instant::now();
+                deadline = started + rule_timeout + clock.waited(now);
+                if deadline <= now {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::pending,
+        io,
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{sync::Semaphore, time::sleep};
+
+    use crate::{
+        service_detection::framework::{
+            execute_rule, ExecutionSettings, PortHint, PortToAnalyze, Rule, RuleClosure, RuleError,
+            RuleFailure, RuleId, RuleLoudness, RuleResult, RuleResults, ServiceDetectionConclusion,
+        },
+        target::TargetInstance,
+    };
+
+    #[derive(Debug)]
+    struct TestResult;
+
+    impl RuleResult for TestResult {
+        fn get_rule_id(&self) -> RuleId {
+            RuleId::named("test")
+        }
+
+        fn get_conclusion(&self) -> Option<ServiceDetectionConclusion> {
+            None
+        }
+    }
+
+    /// Waits for clearance then fails with `error` the first `failures` times
+    /// it's run, then succeeds.  If `error` is `None` it hangs instead of
+    /// failing.
+    #[derive(Debug)]
+    struct FlakyRule {
+        calls: Arc<AtomicU32>,
+        failures: u32,
+        error: Option<io::ErrorKind>,
+    }
+
+    impl Rule for FlakyRule {
+        fn rule_id(&self) -> RuleId {
+            RuleId::named("test")
+        }
+
+        fn port_hints(&self) -> Vec<PortHint> {
+            vec![PortHint::any()]
+        }
+
+        fn loudness(&self) -> RuleLoudness {
+            RuleLoudness::Quiet
+        }
+
+        fn get_execution_method(&self) -> RuleClosure {
+            let calls = self.calls.clone();
+            let failures = self.failures;
+            let error = self.error;
+            Box::new(move |target, _| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    let _permit = target.wait_for_clearance().await;
+                    if call >= failures {
+                        return Ok(Box::new(TestResult) as Box<dyn RuleResult>);
+                    }
+                    match error {
+                        Some(kind) => Err(RuleError::IOError(io::Error::from(kind))),
+                        None => pending().await,
+                    }
+                })
+            })
+        }
+    }
+
+    async fn run(failures: u32, error: Option<io::ErrorKind>) -> (Result<(), RuleFailure>, u32) {
+        run_with_semaphore(failures, error, Arc::new(Semaphore::new(1))).await
+    }
+
+    async fn run_with_semaphore(
+        failures: u32,
+        error: Option<io::ErrorKind>,
+        semaphore: Arc<Semaphore>,
+    ) -> (Result<(), RuleFailure>, u32) {
+        let rule = FlakyRule {
+            calls: Arc::new(AtomicU32::new(0)),
+            failures,
+            error,
+        };
+        let port_to_analyze = PortToAnalyze::new(
+            semaphore,
+            None,
+            TargetInstance::IP(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            80,
+        );
+        let settings = ExecutionSettings {
+            rule_timeout: Duration::from_millis(50),
+            retries: 2,
+        };
+        let run = execute_rule(&rule, port_to_analyze, RuleResults::new(), &settings).await;
+        assert_eq!(rule.calls.load(Ordering::SeqCst), run.attempts);
+        (run.result.map(|_| ()), run.attempts)
+    }
+
+    #[tokio::test]
+    async fn test_execute_rule_retries_and_times_out() {
+        assert_eq!(run(0, None).await, (Ok(()), 1));
+        // Transient errors are retried until we run out of retries
+        assert_eq!(
+            run(2, Some(io::ErrorKind::ConnectionReset)).await,
+            (Ok(()), 3)
+        );
+        assert!(matches!(
+            run(3, Some(io::ErrorKind::ConnectionReset)).await,
+            (
+                Err(RuleFailure::IOError(io::ErrorKind::ConnectionReset, _)),
+                3
+            )
+        ));
+        // Anything else fails right away
+        assert!(matches!(
+            run(1, Some(io::ErrorKind::ConnectionRefused)).await,
+            (
+                Err(RuleFailure::IOError(io::ErrorKind::ConnectionRefused, _)),
+                1
+            )
+        ));
+        // A hung rule is abandoned and not retried
+        assert_eq!(run(1, None).await, (Err(RuleFailure::TimedOut), 1));
+    }
+
+    #[tokio::test]
+    async fn test_execute_rule_timeout_ignores_waiting_for_clearance() {
+        // Another rule holds the only permit for twice the timeout
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            drop(permit);
+        });
+        assert_eq!(
+            run_with_semaphore(0, None, semaphore.clone()).await,
+            (Ok(()), 1)
+        );
+
+        // Once it's cleared the rule is still held to the timeout
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            drop(permit);
+        });
+        assert_eq!(
+            run_with_semaphore(1, None, semaphore).await,
+            (Err(RuleFailure::TimedOut), 1)
+        );
+    }
+}
@@ -1,21 +1,35 @@
 //! This is core of the framework for managing rule detection.  It provides all
 //! types that help make up rules, assist in their scheduling and running.
 
+mod conclusions;
 mod error;
+mod execution;
 mod rule_results;
+mod validation;
 
 use std::{
-    any::TypeId, fmt::Debug, future::Future, net::SocketAddr, ops::Range, pin::Pin, sync::Arc,
+    any::{type_name, TypeId},
+    fmt::{self, Debug, Display, Formatter},
+    future::Future,
+    net::SocketAddr,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
     time::Duration,
 };
 
-pub use error::RuleError;
+pub use conclusions::aggregate_conclusions;
+pub use error::{FailedRule, RuleError, RuleFailure};
+pub use execution::RuleEvidence;
+pub(crate) use execution::{execute_rule, ClearanceClock, ExecutionSettings};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 pub use rule_results::{RuleResult, RuleResults};
+use serde::Deserialize;
 use tokio::{
     sync::{AcquireError, Semaphore, SemaphorePermit},
     time::sleep,
 };
+pub use validation::validate_rules;
 
 use crate::target::TargetInstance;
 
@@ -39,6 +53,7 @@ pub struct PortToAnalyze {
     throttle_range: Option<Range<u64>>,
     target_instance: TargetInstance,
     port: u16,
+    clearance_clock: Arc<ClearanceClock>,
 }
 
 impl PortToAnalyze {
@@ -54,6 +69,16 @@ impl PortToAnalyze {
             throttle_range,
             target_instance,
             port,
+            clearance_clock: Arc::default(),
+        })
+    }
+
+    /// A copy that reports its waits for clearance to `clearance_clock` so a
+    /// single attempt at a rule can be timed without them.
+    pub(crate) fn with_clearance_clock(&self, clearance_clock: Arc<ClearanceClock>) -> Arc<Self> {
+        Arc::new(PortToAnalyze {
+            clearance_clock,
+            ..self.clone()
         })
     }
 
@@ -65,6 +90,7 @@ impl PortToAnalyze {
     /// more than one permit, artificially limiting the number of requests
     /// allowed in flight.
     pub async fn wait_for_clearance(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let _waiting = self.clearance_clock.start_waiting();
         if let Some(ref throttle_range) = self.throttle_range {
             sleep(Duration::from_millis(
                 StdRng::from_entropy().gen_range(throttle_range.clone()),
@@ -96,21 +122,45 @@ impl PortToAnalyze {
     }
 }
 
-/// A automatically derived unique identifier for any rule.
+/// A unique identifier for any rule.  Rules written in Rust derive it from
+/// their type.  Rules built at runtime, like signatures, all share a type so
+/// they are identified by name instead.
 #[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct RuleId {
-    // Right now this will use the [`TypeId`] since all rules are 'static but I
-    // think in the future we might not be able to lean on this.  We might
-    // load rules through plugins etc.  Keeping this will encapsulated
-    // should help us refactor when the time comes.
-    internal: TypeId,
+    internal: RuleIdKind,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+enum RuleIdKind {
+    // The type name only comes along so we can print something readable
+    Type(TypeId, &'static str),
+    Named(String),
 }
 
 impl RuleId {
     /// Simple constructor for [`RuleId`]
-    pub fn new<T: Rule>() -> Self {
+    pub fn new<T: Rule + ?Sized>() -> Self {
         Self {
-            internal: TypeId::of::<T>(),
+            internal: RuleIdKind::Type(TypeId::of::<T>(), type_name::<T>()),
+        }
+    }
+
+    /// Build the [`RuleId`] for a rule that was loaded at runtime.  The name
+    /// must be unique among all such rules.
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            internal: RuleIdKind::Named(name.into()),
+        }
+    }
+}
+
+impl Display for RuleId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.internal {
+            RuleIdKind::Type(_, name) => {
+                write!(f, "{}", name.rsplit("::").next().unwrap_or(name))
+            }
+            RuleIdKind::Named(name) => write!(f, "{name}"),
         }
     }
 }
@@ -121,7 +171,6 @@ pub struct PortHint {
     ports: Range<u16>,
     /// The likelihood that this service will be on the ports covered by this
     /// hint.
-    #[allow(dead_code)]
     likeliness: PortLikeliness,
 }
 
@@ -165,6 +214,11 @@ impl PortHint {
     pub fn port_range(&self) -> &Range<u16> {
         &self.ports
     }
+
+    /// How likely the service is to be on the ports covered by this hint.
+    pub fn likeliness(&self) -> PortLikeliness {
+        self.likeliness
+    }
 }
 
 /// This allows a rule to say how likely it expects the covered service to be on
@@ -172,8 +226,9 @@ impl PortHint {
 /// each rule.  On quicker, more stealthy scans we will stick to more likely
 /// rules on more standard ports.  If we are running a more comprehensive scan
 /// we might start include running rules on `Unusual` or even `Rare` ports
-/// included in the scan.
-#[allow(dead_code)]
+/// included in the scan.  The variants are ordered from most to least likely.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
 pub enum PortLikeliness {
     /// This is the default ports.  If this protocol had an RFC, then this port
     /// is mentioned in it. The majority of implementations will default to
@@ -197,6 +252,33 @@ pub enum PortLikeliness {
     Rare,
 }
 
+/// How hard service detection tries on each port.  Each level runs the rules
+/// the level below it does plus the rules that only expect their service on
+/// the port with the next [`PortLikeliness`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServiceDetectionIntensity {
+    /// Only run rules that have a `Standard` hint for the port.
+    Low,
+    /// Also run rules that have a `Common` hint for the port.
+    Medium,
+    /// Also run rules that have an `Unusual` hint for the port.
+    High,
+    /// Run every rule with a hint for the port, even `Rare` ones.
+    Exhaustive,
+}
+
+impl ServiceDetectionIntensity {
+    /// The least likely [`PortLikeliness`] this intensity will run rules for.
+    pub fn max_likeliness(self) -> PortLikeliness {
+        match self {
+            ServiceDetectionIntensity::Low => PortLikeliness::Standard,
+            ServiceDetectionIntensity::Medium => PortLikeliness::Common,
+            ServiceDetectionIntensity::High => PortLikeliness::Unusual,
+            ServiceDetectionIntensity::Exhaustive => PortLikeliness::Rare,
+        }
+    }
+}
+
 /// The base trait for a rule.  This can either be a final rule that enumerates
 /// a service or it could be an intermediate rule that is used to perform some
 /// reusable action.
@@ -204,9 +286,7 @@ pub trait Rule: Debug + Send + Sync + 'static {
     /// The unique ID for the rule.  This is automatically derive and we really
     /// should never override this.
     fn rule_id(&self) -> RuleId {
-        RuleId {
-            internal: TypeId::of::<Self>(),
-        }
+        RuleId::new::<Self>()
     }
 
     /// The list of IDs for all rules this rule depends on.  By default a rule
@@ -223,6 +303,12 @@ pub trait Rule: Debug + Send + Sync + 'static {
     /// How much network traffic can we expect this rule to generate?
     fn loudness(&self) -> RuleLoudness;
 
+    /// Is the result about the port or the whole host?  By default results
+    /// are about the port.
+    fn scope(&self) -> RuleScope {
+        RuleScope::Port
+    }
+
     /// Does the required privilege access to run?  To be more specific, does
     /// this rule try to open a raw socket? Rules requiring high access than
     /// available will be pruned.
@@ -236,8 +322,23 @@ pub trait Rule: Debug + Send + Sync + 'static {
     fn get_execution_method(&self) -> RuleClosure;
 }
 
-/// This is how certain we are of our conclusion.
-#[derive(Clone, Debug)]
+/// What a rule's result describes, which decides who can see it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleScope {
+    /// The result is about one port.  Only rules running against the same port
+    /// can see it.
+    Port,
+    /// The result is about the host, like a TLS certificate's names or an SSH
+    /// host key.  Rules on every port of the host can see it and depend on
+    /// it.  The rule only runs once per host, on the first port it applies
+    /// to, unless it fails there.
+    Host,
+}
+
+/// This is how certain we are of our conclusion.  The variants are ordered from
+/// most to least certain.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
 pub enum ServiceDetectionCertainty {
     /// This is the highest level but still isn't absolute.  We found a version
     /// header or banner somewhere and are trusting that.  Obviously this
@@ -267,31 +368,37 @@ pub struct ServiceDetectionConclusion {
     /// be set here.  This human readable string could be an exact version or a
     /// range.
     pub service_version: Option<String>,
+    /// Who makes the service, spelled the way CPE names spell it.  For
+    /// example "f5" for nginx.
+    pub vendor: Option<String>,
+    /// The service, spelled the way CPE names spell it.  For example "nginx"
+    /// or "http_server" for Apache.
+    pub product: Option<String>,
 }
 
 /// Describes the "loudness" of a rule.  This can be measured in the amount of
 /// traffic it generated or in how much this traffic stands out.  Requests
 /// likely to trip firewall rules, fire alerts, crash services are also
-/// considered "loud".  This isn't used by the system today but still required
-/// for all rules.  Eventually it will be possible to configure the planner to
-/// only run rules within our desired "loudness"
-#[allow(dead_code)]
+/// considered "loud".  The planner skips rules louder than the maximum set with
+/// [`crate::ConfigBuilder::set_max_service_detection_loudness`].  The variants
+/// are ordered from quietest to loudest so they can be compared.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum RuleLoudness {
-    /// This rule is going to make a lot of requests.  These requests might be
-    /// extremely strange, generate suspicious errors in logs, or possibly even
-    /// crash the service.
-    BangingTogetherPotsAndPans,
-    /// This rule is making somewhere between 3 and 10 or so requests.  It
-    Noisy,
+    /// This rule makes no additional network requests.  It relies solely on the
+    /// results of previous rule runs.
+    Silent,
+    /// This rule may make at most one network request but it is likely it will
+    /// makes none.
+    Quiet,
     /// This rule will always make at least one request, but probably no more
     /// than 3 or so.  The requests aren't too different than what a service
     /// expects to see during normal use from a trusted, well behaving
     /// client.
     Standard,
-    /// This rule may make at most one network request but it is likely it will
-    /// makes none.
-    Quiet,
-    /// This rule makes no additional network requests.  It relies solely on the
-    /// results of previous rule runs.
-    Silent,
+    /// This rule is making somewhere between 3 and 10 or so requests.
+    Noisy,
+    /// This rule is going to make a lot of requests.  These requests might be
+    /// extremely strange, generate suspicious errors in logs, or possibly even
+    /// crash the service.
+    BangingTogetherPotsAndPans,
 }
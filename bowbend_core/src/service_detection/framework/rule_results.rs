@@ -27,9 +27,13 @@ impl Downcast for dyn RuleResult {}
 
 /// This is a container that packages up all dependent intermediate rule
 /// results.  It is only guaranteed to hold rules included in the list returned
-/// by `dependent_rules`.
+/// by `dependent_rules`.  Each port gets its own container.  Results of
+/// [`crate::service_detection::framework::RuleScope::Host`] rules live in a
+/// container shared by every port on the host, which the port's container
+/// falls back to.
 pub struct RuleResults {
     store: RwLock<HashMap<RuleId, Box<dyn RuleResult>>>,
+    host: Option<Arc<RuleResults>>,
 }
 
 impl RuleResults {
@@ -38,6 +42,16 @@ impl RuleResults {
     pub fn new() -> Arc<Self> {
         Arc::new(Self {
             store: RwLock::new(HashMap::new()),
+            host: None,
+        })
+    }
+
+    /// Create the results for one port that can also see everything in the
+    /// results for its host.
+    pub fn new_for_port(host: Arc<RuleResults>) -> Arc<Self> {
+        Arc::new(Self {
+            store: RwLock::new(HashMap::new()),
+            host: Some(host),
         })
     }
 
@@ -49,27 +63,55 @@ impl RuleResults {
         self.store.write().await.insert(rule_id, result);
     }
 
+    /// Does this hold a result for the rule?  This doesn't look at the host's
+    /// results.
+    pub(crate) async fn contains(&self, rule_id: &RuleId) -> bool {
+        self.store.read().await.contains_key(rule_id)
+    }
+
+    /// Find the result for a rule, looking through our own results before the
+    /// host's.
+    async fn find(&self, rule_id: &RuleId) -> Option<RwLockReadGuard<'_, Box<dyn RuleResult>>> {
+        let read = self.store.read().await;
+        if let Ok(result) = RwLockReadGuard::try_map(read, |guard| guard.get(rule_id)) {
+            return Some(result);
+        }
+        let read = self.host.as_ref()?.store.read().await;
+        RwLockReadGuard::try_map(read, |guard| guard.get(rule_id)).ok()
+    }
+
     /// Get a typed result for some dependent rule.  This will panic if the rule
     /// attempts to access an intermediate that it didn't list as a
     /// dependency.
     pub async fn get_results<T1: Rule, T2: RuleResult>(&self) -> RwLockReadGuard<T2> {
-        let read = self.store.read().await;
         let rule_id = RuleId::new::<T1>();
         debug!("Fetching results for rule {:?}", rule_id);
-        RwLockReadGuard::map(read, |guard| {
-            guard.get(&rule_id)
-                .map(|result|{
-                    result
-                        .downcast_ref::<T2>()
-                        .expect("The result stored for a give rule doesn't match our expected type.  This is an internal implementation bug with either the rule fetching the result or the one that created it")
-                })
-                .expect("A rule attempted to get the results for one its dependencies but it wasn't there")
+        let result = self.find(&rule_id).await.expect(
+            "A rule attempted to get the results for one its dependencies but it wasn't there",
+        );
+        RwLockReadGuard::map(result, |result| {
+            result
+                .downcast_ref::<T2>()
+                .expect("The result stored for a give rule doesn't match our expected type.  This is an internal implementation bug with either the rule fetching the result or the one that created it")
         })
     }
 
+    /// Get a typed result for some rule if it has been run.  Unlike
+    /// [`RuleResults::get_results`] this won't panic if the rule never ran or
+    /// failed.  This is meant for the framework to pull out details after
+    /// all rules have finished, not for rules looking up their dependencies.
+    pub async fn try_get_results<T1: Rule, T2: RuleResult>(
+        &self,
+    ) -> Option<RwLockReadGuard<'_, T2>> {
+        let result = self.find(&RuleId::new::<T1>()).await?;
+        RwLockReadGuard::try_map(result, |result| result.downcast_ref::<T2>()).ok()
+    }
+
     /// Walks through all results currently registers and returns all
     /// conclusions.  It doesn't try to pick our most certain conclusions or
-    /// order them. It returns them all.
+    /// order them. It returns them all.  The host's results aren't included
+    /// since they would be repeated on every port.  The framework reports
+    /// them on the port the host rule ran for instead.
     pub async fn get_conclusion(&self) -> Vec<ServiceDetectionConclusion> {
         let lock = self.store.read().await;
         lock.values()
@@ -118,6 +160,8 @@ mod tests {
                     certainty: ServiceDetectionCertainty::Advertised,
                     service_name: String::from("test"),
                     service_version: None,
+                    vendor: None,
+                    product: None,
                 })
             }
         }
@@ -128,4 +172,57 @@ mod tests {
         let _ = results.get_results::<TestRule, TestRuleResult>().await;
         assert_eq!(results.get_conclusion().await.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_port_results_fall_back_to_host() {
+        #[derive(Debug, Default)]
+        struct HostRule {}
+
+        impl Rule for HostRule {
+            fn port_hints(&self) -> Vec<PortHint> {
+                todo!()
+            }
+
+            fn loudness(&self) -> RuleLoudness {
+                todo!()
+            }
+
+            fn get_execution_method(&self) -> RuleClosure {
+                todo!()
+            }
+        }
+
+        #[derive(Debug, Default)]
+        struct HostRuleResult {}
+
+        impl RuleResult for HostRuleResult {
+            fn get_rule_id(&self) -> RuleId {
+                RuleId::new::<HostRule>()
+            }
+
+            fn get_conclusion(&self) -> Option<ServiceDetectionConclusion> {
+                Some(ServiceDetectionConclusion {
+                    certainty: ServiceDetectionCertainty::Advertised,
+                    service_name: String::from("test"),
+                    service_version: None,
+                    vendor: None,
+                    product: None,
+                })
+            }
+        }
+
+        let host = RuleResults::new();
+        host.insert_result(Box::new(HostRuleResult::default()))
+            .await;
+        let port = RuleResults::new_for_port(host.clone());
+
+        // A port can see its host's results but doesn't report them
+        let _ = port.get_results::<HostRule, HostRuleResult>().await;
+        assert!(port
+            .try_get_results::<HostRule, HostRuleResult>()
+            .await
+            .is_some());
+        assert!(!port.contains(&RuleId::new::<HostRule>()).await);
+        assert!(port.get_conclusion().await.is_empty());
+    }
 }
@@ -0,0 +1,173 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::service_detection::framework::{Rule, RuleId};
+
+/// Something wrong with how a set of rules depend on each other.  These are
+/// bugs in the rules, or signatures, rather than anything about the target.
+#[derive(Debug, PartialEq)]
+pub enum RuleGraphError {
+    /// Two rules share the same [`RuleId`]
+    DuplicateRule(RuleId),
+    /// A rule depends on a rule that doesn't exist
+    MissingDependency {
+        /// The rule with the dependency
+        rule: RuleId,
+        /// The dependency that doesn't exist
+        dependency: RuleId,
+    },
+    /// The rules depend on each other in a loop so none of them could ever
+    /// run.  The first rule is repeated at the end to close the loop.
+    Cycle(Vec<RuleId>),
+}
+
+impl Display for RuleGraphError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleGraphError::DuplicateRule(rule) => write!(f, "{rule} is registered twice"),
+            RuleGraphError::MissingDependency { rule, dependency } => {
+                write!(f, "{rule} depends on {dependency} which doesn't exist")
+            }
+            RuleGraphError::Cycle(cycle) => {
+                let cycle: Vec<String> = cycle.iter().map(ToString::to_string).collect();
+                write!(
+                    f,
+                    "rules depend on each other in a loop: {}",
+                    cycle.join(" -> ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for RuleGraphError {}
+
+/// Check that every dependency of every rule exists and that no rules depend
+/// on each other in a loop.
+pub fn validate_rules(rules: &[Box<dyn Rule>]) -> Result<(), RuleGraphError> {
+    let mut graph = HashMap::new();
+    for rule in rules {
+        if graph.insert(rule.rule_id(), rule.dependencies()).is_some() {
+            return Err(RuleGraphError::DuplicateRule(rule.rule_id()));
+        }
+    }
+    for (rule, dependencies) in &graph {
+        if let Some(dependency) = dependencies.iter().find(|dep| !graph.contains_key(dep)) {
+            return Err(RuleGraphError::MissingDependency {
+                rule: rule.clone(),
+                dependency: dependency.clone(),
+            });
+        }
+    }
+    let mut finished = HashSet::new();
+    for rule in graph.keys() {
+        let mut path = Vec::new();
+        find_cycle(&graph, rule, &mut path, &mut finished)?;
+    }
+    Ok(())
+}
+
+/// Depth first walk of the dependencies.  `path` holds the rules we are in the
+/// middle of visiting so running into one of them again means a loop.
+fn find_cycle<'a>(
+    graph: &'a HashMap<RuleId, Vec<RuleId>>,
+    rule: &'a RuleId,
+    path: &mut Vec<&'a RuleId>,
+    finished: &mut HashSet<&'a RuleId>,
+) -> Result<(), RuleGraphError> {
+    if finished.contains(rule) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|visiting| *visiting == rule) {
+        let mut cycle: Vec<RuleId> = path[start..].iter().map(|id| (*id).clone()).collect();
+        cycle.push(rule.clone());
+        return Err(RuleGraphError::Cycle(cycle));
+    }
+    path.push(rule);
+    for dependency in &graph[rule] {
+        find_cycle(graph, dependency, path, finished)?;
+    }
+    path.pop();
+    finished.insert(rule);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service_detection::framework::{
+        validation::{validate_rules, RuleGraphError},
+        PortHint, Rule, RuleClosure, RuleId, RuleLoudness,
+    };
+
+    #[derive(Debug)]
+    struct TestRule {
+        name: &'static str,
+        dependencies: Vec<&'static str>,
+    }
+
+    impl Rule for TestRule {
+        fn rule_id(&self) -> RuleId {
+            RuleId::named(self.name)
+        }
+
+        fn dependencies(&self) -> Vec<RuleId> {
+            self.dependencies
+                .iter()
+                .map(|dep| RuleId::named(*dep))
+                .collect()
+        }
+
+        fn port_hints(&self) -> Vec<PortHint> {
+            vec![PortHint::any()]
+        }
+
+        fn loudness(&self) -> RuleLoudness {
+            RuleLoudness::Silent
+        }
+
+        fn get_execution_method(&self) -> RuleClosure {
+            unimplemented!()
+        }
+    }
+
+    fn rules(graph: &[(&'static str, &[&'static str])]) -> Vec<Box<dyn Rule>> {
+        graph
+            .iter()
+            .map(|(name, dependencies)| {
+                Box::new(TestRule {
+                    name,
+                    dependencies: dependencies.to_vec(),
+                }) as Box<dyn Rule>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        assert_eq!(
+            validate_rules(&rules(&[("a", &[]), ("b", &["a"]), ("c", &["a", "b"])])),
+            Ok(())
+        );
+        assert_eq!(
+            validate_rules(&rules(&[("a", &[]), ("b", &["x"])])),
+            Err(RuleGraphError::MissingDependency {
+                rule: RuleId::named("b"),
+                dependency: RuleId::named("x"),
+            })
+        );
+        assert_eq!(
+            validate_rules(&rules(&[("a", &[]), ("a", &[])])),
+            Err(RuleGraphError::DuplicateRule(RuleId::named("a")))
+        );
+        match validate_rules(&rules(&[("a", &["c"]), ("b", &["a"]), ("c", &["b"])])) {
+            Err(RuleGraphError::Cycle(cycle)) => {
+                assert_eq!(cycle.len(), 4);
+                assert_eq!(cycle.first(), cycle.last());
+            }
+            other => panic!("Expected a cycle, got {other:?}"),
+        }
+    }
+}
@@ -3,47 +3,129 @@
 //! system and everything else should be contained in this module.
 use std::{future::ready, ops::Range, sync::Arc};
 
-use framework::RuleError;
 use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::sync::Semaphore;
-use tracing::info;
+use tracing::{debug, info};
 
 use crate::{
-    report::{PortStatus, Report},
+    config::ConfigBuilder,
+    err::PortscanErr,
+    report::{PortReport, PortStatus, Report},
     service_detection::{
-        framework::{PortToAnalyze, RuleResult, RuleResults, ServiceDetectionConclusion},
-        rules::get_all_rules,
-        test_plan::PortTestPlan,
+        framework::{
+            aggregate_conclusions, execute_rule, validate_rules, ExecutionSettings, FailedRule,
+            PortToAnalyze, Rule, RuleEvidence, RuleResults, RuleScope,
+        },
+        rules::{
+            get_all_rules,
+            ssl::{BasicSSLProbe, BasicSSLProbeResult},
+        },
+        signatures::{builtin_signatures, SignatureProbe, SignatureRule},
+        test_plan::{PlanSettings, PortTestPlan},
     },
     target::TargetInstance,
 };
 
 pub mod framework;
 pub mod rules;
-mod test_plan;
+pub(crate) mod signatures;
+pub(crate) mod test_plan;
+
+/// The service detection settings pulled from the config.
+#[derive(Clone)]
+pub(crate) struct ServiceDetectionSettings {
+    signatures: Vec<Arc<SignatureProbe>>,
+    plan: PlanSettings,
+    execution: ExecutionSettings,
+    record_evidence: bool,
+}
+
+impl ServiceDetectionSettings {
+    /// Signatures loaded by the user replace built in ones with the same name.
+    /// This fails if the rules don't form a graph we can plan with, which
+    /// would be a bug in one of them.
+    pub(crate) fn from_config(config: &ConfigBuilder) -> Result<Self, PortscanErr> {
+        let mut signatures: Vec<Arc<SignatureProbe>> = builtin_signatures()
+            .into_iter()
+            .filter(|builtin| {
+                !config
+                    .signatures
+                    .iter()
+                    .any(|probe| probe.name == builtin.name)
+            })
+            .map(Arc::new)
+            .collect();
+        signatures.extend(config.signatures.iter().cloned());
+        let settings = Self {
+            signatures,
+            plan: PlanSettings {
+                max_loudness: config.service_detection_max_loudness,
+                max_likeliness: config.service_detection_intensity.max_likeliness(),
+                privileged: raw_sockets_available(),
+            },
+            execution: ExecutionSettings {
+                rule_timeout: config.service_detection_rule_timeout,
+                retries: config.service_detection_retries,
+            },
+            record_evidence: config.record_service_detection_evidence,
+        };
+        validate_rules(&settings.rules())
+            .map_err(|e| PortscanErr::InvalidRuleGraph(e.to_string()))?;
+        Ok(settings)
+    }
+
+    /// Every rule to plan with, both the ones written in Rust and the ones
+    /// built from signatures.
+    fn rules(&self) -> Vec<Box<dyn Rule>> {
+        let mut rules = get_all_rules();
+        rules.extend(self.signatures.iter().cloned().map(SignatureRule::new));
+        rules
+    }
+}
+
+/// Can we open raw sockets?  Rules that need them are skipped when we can't.
+/// This is checked once per scan rather than for every port.
+fn raw_sockets_available() -> bool {
+    match Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::TCP)) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("Raw sockets aren't available, skipping privileged rules: {e}");
+            false
+        }
+    }
+}
 
 /// The one entry point to service detection.  It accepts a stream of reports,
 /// runs service detection for it and then decorates them with the conclusions.
-pub async fn run_service_detection_on_target(
+pub(crate) async fn run_service_detection_on_target(
     mut report_stream: impl Stream<Item = Report> + Unpin,
     semaphore: Arc<Semaphore>,
     throttle_range: Option<Range<u64>>,
+    settings: ServiceDetectionSettings,
 ) -> impl Stream<Item = Report> {
     let futures = FuturesUnordered::new();
     while let Some(mut report) = report_stream.next().await {
         if let (Some(instance), Ok(contents)) = (&report.instance, &mut report.contents) {
             if let Some(ports) = &mut contents.ports {
+                // Shared by every port on the host so host rules only run once
+                let host_results = RuleResults::new();
                 for port in ports.values_mut() {
                     if port.status == PortStatus::Open {
-                        let service_detection_output = run_service_detection_on_port(
+                        run_service_detection_on_port(
                             instance.clone(),
-                            port.port,
+                            port,
+                            settings.rules(),
+                            host_results.clone(),
                             semaphore.clone(),
                             throttle_range.clone(),
+                            &settings,
                         )
                         .await;
-                        info!("Output of service detection {:?}", service_detection_output);
-                        port.service_detection_conclusions = Some(service_detection_output);
+                        info!(
+                            "Output of service detection {:?}",
+                            port.service_detection_conclusions
+                        );
                     }
                 }
             };
@@ -57,46 +139,351 @@ pub async fn run_service_detection_on_target(
 
 async fn run_service_detection_on_port(
     target_instance: TargetInstance,
-    port: u16,
+    port_report: &mut PortReport,
+    rules: Vec<Box<dyn Rule>>,
+    host_results: Arc<RuleResults>,
     semaphore: Arc<Semaphore>,
     throttle_range: Option<Range<u64>>,
-) -> Vec<ServiceDetectionConclusion> {
+    settings: &ServiceDetectionSettings,
+) {
     let port_to_analyze = PortToAnalyze::new(
         semaphore.clone(),
         throttle_range,
         target_instance.clone(),
-        port,
+        port_report.port,
     );
-    let mut plan = PortTestPlan::new(port_to_analyze.clone(), get_all_rules());
+    let mut already_run = Vec::new();
+    for rule in &rules {
+        if rule.scope() == RuleScope::Host && host_results.contains(&rule.rule_id()).await {
+            already_run.push(rule.rule_id());
+        }
+    }
+    let mut plan = PortTestPlan::new(port_report.port, rules, &settings.plan, &already_run);
     let port_to_analyze = port_to_analyze.clone();
-    let rule_results = RuleResults::new();
+    let rule_results = RuleResults::new_for_port(host_results.clone());
+    let mut failed_rules = Vec::new();
+    // Host rules that ran for this port are reported here.  Later ports reuse
+    // their results without reporting them again.
+    let mut host_conclusions = Vec::new();
+    let mut evidence = settings.record_evidence.then(Vec::new);
 
     while plan.has_actions_to_run() {
         let futures: FuturesUnordered<_> = plan
             .rules_to_run()
             .iter()
             .map(|rule| {
-                let execution_closure = rule.get_execution_method();
-                execution_closure(port_to_analyze.clone(), rule_results.clone())
+                let execution = execute_rule(
+                    rule.as_ref(),
+                    port_to_analyze.clone(),
+                    rule_results.clone(),
+                    &settings.execution,
+                );
+                async move { (rule.rule_id(), rule.scope(), execution.await) }
             })
             .collect();
 
-        let result_batch: Vec<Result<Box<dyn RuleResult>, RuleError>> = futures.collect().await;
+        let result_batch: Vec<_> = futures.collect().await;
 
         let mut successfully_run = Vec::new();
-        for result in result_batch {
-            match result {
+        let mut failed = Vec::new();
+        for (rule_id, scope, run) in result_batch {
+            if let Some(evidence) = &mut evidence {
+                evidence.push(RuleEvidence::new(rule_id.clone(), &run));
+            }
+            match run.result {
                 Ok(result) => {
-                    successfully_run.push(result.get_rule_id());
-                    rule_results.insert_result(result).await;
+                    successfully_run.push(rule_id);
+                    match scope {
+                        RuleScope::Port => rule_results.insert_result(result).await,
+                        RuleScope::Host => {
+                            host_conclusions.extend(result.get_conclusion());
+                            host_results.insert_result(result).await
+                        }
+                    }
                 }
-                Err(e) => {
-                    tracing::error!("Rule failed to run {:?}", e);
+                Err(failure) => {
+                    tracing::error!(
+                        "Rule {} failed to run after {} attempts {:?}",
+                        rule_id,
+                        run.attempts,
+                        failure
+                    );
+                    failed.push(rule_id.clone());
+                    failed_rules.push(FailedRule {
+                        rule: rule_id,
+                        failure,
+                        attempts: run.attempts,
+                    });
                 }
             }
         }
 
-        plan = plan.build_next_stage_plan(successfully_run);
+        plan = plan.build_next_stage_plan(successfully_run, failed);
+    }
+    port_report.skipped_rules = Some(plan.into_skipped());
+    port_report.failed_rules = Some(failed_rules);
+    port_report.service_detection_evidence = evidence;
+    port_report.tls_enabled = rule_results
+        .try_get_results::<BasicSSLProbe, BasicSSLProbeResult>()
+        .await
+        .map(|result| result.ssl_enabled);
+    let mut conclusions = rule_results.get_conclusion().await;
+    conclusions.extend(host_conclusions);
+    let conclusions = aggregate_conclusions(conclusions);
+    port_report.best_service_guess = conclusions.first().cloned();
+    port_report.service_detection_conclusions = Some(conclusions);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    };
+
+    use tokio::sync::Semaphore;
+
+    use crate::{
+        report::{PortReport, PortStatus},
+        service_detection::{
+            framework::{
+                ExecutionSettings, PortHint, Rule, RuleClosure, RuleId, RuleLoudness, RuleResult,
+                RuleResults, RuleScope, ServiceDetectionCertainty, ServiceDetectionConclusion,
+            },
+            run_service_detection_on_port,
+            test_plan::PlanSettings,
+            ServiceDetectionSettings,
+        },
+        target::TargetInstance,
+    };
+
+    fn conclusion(
+        service_name: &str,
+        service_version: Option<String>,
+    ) -> ServiceDetectionConclusion {
+        ServiceDetectionConclusion {
+            certainty: ServiceDetectionCertainty::High,
+            service_name: service_name.to_string(),
+            service_version,
+            vendor: None,
+            product: None,
+        }
+    }
+
+    /// Counts how many times it has run.
+    #[derive(Debug)]
+    struct CountingRule {
+        runs: Arc<AtomicU32>,
+        scope: RuleScope,
+    }
+
+    #[derive(Debug)]
+    struct CountingRuleResult {
+        runs: u32,
+    }
+
+    impl RuleResult for CountingRuleResult {
+        fn get_rule_id(&self) -> RuleId {
+            RuleId::new::<CountingRule>()
+        }
+
+        fn get_conclusion(&self) -> Option<ServiceDetectionConclusion> {
+            Some(conclusion("counting", None))
+        }
+    }
+
+    impl Rule for CountingRule {
+        fn port_hints(&self) -> Vec<PortHint> {
+            vec![PortHint::any()]
+        }
+
+        fn loudness(&self) -> RuleLoudness {
+            RuleLoudness::Quiet
+        }
+
+        fn scope(&self) -> RuleScope {
+            self.scope
+        }
+
+        fn get_execution_method(&self) -> RuleClosure {
+            let runs = self.runs.clone();
+            Box::new(move |_, _| {
+                let runs = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                Box::pin(
+                    async move { Ok(Box::new(CountingRuleResult { runs }) as Box<dyn RuleResult>) },
+                )
+            })
+        }
+    }
+
+    /// Reports how many times the counting rule had run when it saw its
+    /// result.
+    #[derive(Debug)]
+    struct DependentRule;
+
+    #[derive(Debug)]
+    struct DependentRuleResult {
+        counted_runs: u32,
+    }
+
+    impl RuleResult for DependentRuleResult {
+        fn get_rule_id(&self) -> RuleId {
+            RuleId::new::<DependentRule>()
+        }
+
+        fn get_conclusion(&self) -> Option<ServiceDetectionConclusion> {
+            Some(conclusion("dependent", Some(self.counted_runs.to_string())))
+        }
+    }
+
+    impl Rule for DependentRule {
+        fn dependencies(&self) -> Vec<RuleId> {
+            vec![RuleId::new::<CountingRule>()]
+        }
+
+        fn port_hints(&self) -> Vec<PortHint> {
+            vec![PortHint::any()]
+        }
+
+        fn loudness(&self) -> RuleLoudness {
+            RuleLoudness::Quiet
+        }
+
+        fn get_execution_method(&self) -> RuleClosure {
+            Box::new(|_, rule_results| {
+                Box::pin(async move {
+                    let counted_runs = rule_results
+                        .get_results::<CountingRule, CountingRuleResult>()
+                        .await
+                        .runs;
+                    Ok(Box::new(DependentRuleResult { counted_runs }) as Box<dyn RuleResult>)
+                })
+            })
+        }
+    }
+
+    /// Run service detection with the given rules against an open port.
+    async fn run_on_port(
+        port: u16,
+        rules: Vec<Box<dyn Rule>>,
+        host_results: Arc<RuleResults>,
+        record_evidence: bool,
+    ) -> PortReport {
+        let settings = ServiceDetectionSettings {
+            signatures: Vec::new(),
+            plan: PlanSettings::default(),
+            execution: ExecutionSettings {
+                rule_timeout: Duration::from_secs(5),
+                retries: 0,
+            },
+            record_evidence,
+        };
+        let mut port_report = PortReport {
+            port,
+            status: PortStatus::Open,
+            service_detection_conclusions: None,
+            best_service_guess: None,
+            skipped_rules: None,
+            failed_rules: None,
+            service_detection_evidence: None,
+            tls_enabled: None,
+            probe_sent: SystemTime::now(),
+            response_received: None,
+            connect_latency: None,
+        };
+        run_service_detection_on_port(
+            TargetInstance::IP(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            &mut port_report,
+            rules,
+            host_results,
+            Arc::new(Semaphore::new(1)),
+            None,
+            &settings,
+        )
+        .await;
+        port_report
+    }
+
+    #[tokio::test]
+    async fn test_host_rules_run_once_per_host() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let host_results = RuleResults::new();
+        let mut conclusions = HashMap::new();
+        for port in [80, 443] {
+            let rules: Vec<Box<dyn Rule>> = vec![
+                Box::new(CountingRule {
+                    runs: runs.clone(),
+                    scope: RuleScope::Host,
+                }),
+                Box::new(DependentRule),
+            ];
+            let port_report = run_on_port(port, rules, host_results.clone(), false).await;
+            let names: Vec<_> = port_report
+                .service_detection_conclusions
+                .unwrap()
+                .into_iter()
+                .map(|conclusion| (conclusion.service_name, conclusion.service_version))
+                .collect();
+            conclusions.insert(port, names);
+        }
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // The host rule is reported on the port it ran for and the dependent
+        // rule on the second port still sees its result
+        assert_eq!(
+            conclusions[&80],
+            vec![
+                ("dependent".to_string(), Some("1".to_string())),
+                ("counting".to_string(), None)
+            ]
+        );
+        assert_eq!(
+            conclusions[&443],
+            vec![("dependent".to_string(), Some("1".to_string()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evidence_is_only_recorded_when_asked_for() {
+        let rules = || -> Vec<Box<dyn Rule>> {
+            vec![
+                Box::new(CountingRule {
+                    runs: Arc::new(AtomicU32::new(0)),
+                    scope: RuleScope::Port,
+                }),
+                Box::new(DependentRule),
+            ]
+        };
+        let port_report = run_on_port(80, rules(), RuleResults::new(), false).await;
+        assert!(port_report.service_detection_evidence.is_none());
+
+        let port_report = run_on_port(80, rules(), RuleResults::new(), true).await;
+        let evidence = port_report.service_detection_evidence.unwrap();
+        // The dependent rule can only run in the stage after the counting rule
+        let observed: Vec<_> = evidence
+            .iter()
+            .map(|evidence| {
+                assert_eq!(evidence.attempts, 1);
+                (evidence.rule.clone(), evidence.outcome.clone())
+            })
+            .collect();
+        assert_eq!(
+            observed,
+            vec![
+                (
+                    RuleId::new::<CountingRule>(),
+                    Ok("CountingRuleResult { runs: 1 }".to_string())
+                ),
+                (
+                    RuleId::new::<DependentRule>(),
+                    Ok("DependentRuleResult { counted_runs: 1 }".to_string())
+                ),
+            ]
+        );
     }
-    rule_results.get_conclusion().await
 }
@@ -8,7 +8,10 @@ use crate::service_detection::{
         PortHint, PortLikeliness, PortToAnalyze, Rule, RuleClosure, RuleError, RuleId,
         RuleLoudness, RuleResult, RuleResults, ServiceDetectionConclusion,
     },
-    rules::ssl::{BasicSSLProbe, BasicSSLProbeResult},
+    rules::{
+        register_rule,
+        ssl::{BasicSSLProbe, BasicSSLProbeResult},
+    },
 };
 
 /// Rule to do a simple HTTP GET request for /.  It captures the output to be
@@ -23,6 +26,8 @@ impl BasicHttpGetProbe {
     }
 }
 
+register_rule!(BasicHttpGetProbe);
+
 impl Rule for BasicHttpGetProbe {
     fn dependencies(&self) -> Vec<RuleId> {
         vec![RuleId::new::<BasicSSLProbe>()]
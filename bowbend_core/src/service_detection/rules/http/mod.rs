@@ -3,4 +3,6 @@ mod basic_http_probe;
 mod nginx;
 
 pub use basic_http_probe::{BasicHttpGetProbe, BasicHttpGetProbeResult};
-pub use nginx::{NginxDetectionRule, NginxDetectionRuleResult};
+// Rules are found through the registry so only the tests name this directly
+#[cfg(test)]
+pub(crate) use nginx::NginxDetectionRule;
@@ -11,7 +11,10 @@ use crate::service_detection::{
         PortHint, PortToAnalyze, Rule, RuleClosure, RuleError, RuleId, RuleLoudness, RuleResult,
         RuleResults, ServiceDetectionCertainty, ServiceDetectionConclusion,
     },
-    rules::http::basic_http_probe::{BasicHttpGetProbe, BasicHttpGetProbeResult},
+    rules::{
+        http::basic_http_probe::{BasicHttpGetProbe, BasicHttpGetProbeResult},
+        register_rule,
+    },
 };
 
 /// This rule detects if an nginx instance is listening to the port.  It doesn't
@@ -28,6 +31,8 @@ impl NginxDetectionRule {
     }
 }
 
+register_rule!(NginxDetectionRule);
+
 impl Rule for NginxDetectionRule {
     fn dependencies(&self) -> Vec<RuleId> {
         vec![RuleId::new::<BasicHttpGetProbe>()]
@@ -59,6 +64,8 @@ impl Rule for NginxDetectionRule {
                                     certainty: ServiceDetectionCertainty::Advertised,
                                     service_name: "nginx HTTP server".to_string(),
                                     service_version: Some(parsed.version.to_owned()),
+                                    vendor: Some("f5".to_string()),
+                                    product: Some("nginx".to_string()),
                                 }),
                             }
                         } else {
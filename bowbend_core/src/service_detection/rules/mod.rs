@@ -1,26 +1,71 @@
 //! Module containing all rules build into the system itself.
 
 #![allow(clippy::new_ret_no_self)]
-use crate::service_detection::{
-    framework::Rule,
-    rules::{
-        http::{BasicHttpGetProbe, NginxDetectionRule},
-        ssl::BasicSSLProbe,
-    },
-};
+use crate::service_detection::framework::Rule;
 
 pub mod http;
 pub mod ssl;
 
+/// An entry in the registry of rules written in Rust.  Rules add themselves
+/// with [`register_rule!`] rather than building these by hand.
+pub struct RuleRegistration {
+    constructor: fn() -> Box<dyn Rule>,
+}
+
+impl RuleRegistration {
+    /// Simple constructor for [`RuleRegistration`]
+    pub const fn new(constructor: fn() -> Box<dyn Rule>) -> Self {
+        Self { constructor }
+    }
+}
+
+inventory::collect!(RuleRegistration);
+
+/// Add a rule to the registry so [`get_all_rules`] will find it.  The rule
+/// needs a `new` constructor that takes no arguments and returns it boxed.
+/// This belongs right after the rule's definition.
+macro_rules! register_rule {
+    ($rule:ty) => {
+        inventory::submit! {
+            $crate::service_detection::rules::RuleRegistration::new(|| <$rule>::new())
+        }
+    };
+}
+
+pub(crate) use register_rule;
+
 /// Get all rules that currently exist in the system.  This is used to feed into
 /// the test planner.  From the starting list it will filter out undesired rules
 /// and build an initial test plan.
 pub fn get_all_rules() -> Vec<Box<dyn Rule>> {
-    // This isn't a great solution.  It will be easy to forget to add new rules to
-    // the list. We should create a macro to populate the list
-    vec![
-        BasicSSLProbe::new(),
-        BasicHttpGetProbe::new(),
-        NginxDetectionRule::new(),
-    ]
+    inventory::iter::<RuleRegistration>
+        .into_iter()
+        .map(|registration| (registration.constructor)())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service_detection::{
+        framework::{validate_rules, RuleId},
+        rules::{
+            get_all_rules,
+            http::{BasicHttpGetProbe, NginxDetectionRule},
+            ssl::BasicSSLProbe,
+        },
+    };
+
+    #[test]
+    fn test_registered_rules() {
+        let rules = get_all_rules();
+        let ids: Vec<RuleId> = rules.iter().map(|rule| rule.rule_id()).collect();
+        for id in [
+            RuleId::new::<BasicSSLProbe>(),
+            RuleId::new::<BasicHttpGetProbe>(),
+            RuleId::new::<NginxDetectionRule>(),
+        ] {
+            assert!(ids.contains(&id), "{id} isn't registered");
+        }
+        assert_eq!(validate_rules(&rules), Ok(()));
+    }
 }
@@ -8,9 +8,12 @@ use tokio::net::TcpStream;
 use tokio_openssl::SslStream;
 use tracing::instrument;
 
-use crate::service_detection::framework::{
-    PortHint, PortToAnalyze, Rule, RuleClosure, RuleError, RuleId, RuleLoudness, RuleResult,
-    RuleResults, ServiceDetectionConclusion,
+use crate::service_detection::{
+    framework::{
+        PortHint, PortToAnalyze, Rule, RuleClosure, RuleError, RuleId, RuleLoudness, RuleResult,
+        RuleResults, ServiceDetectionConclusion,
+    },
+    rules::register_rule,
 };
 
 /// This is a very simple probe to see if SSL or TLS is enabled on the port.  It
@@ -25,6 +28,8 @@ impl BasicSSLProbe {
     }
 }
 
+register_rule!(BasicSSLProbe);
+
 impl Rule for BasicSSLProbe {
     fn port_hints(&self) -> Vec<PortHint> {
         vec![PortHint::any()]
@@ -0,0 +1,625 @@
+//! Service detection rules described by data instead of code.  A signature
+//! file is a list of probes.  Each probe says what to send to a port, which
+//! ports it is worth trying on and a list of regexes to run against whatever
+//! comes back.  The first regex that matches decides the service and, through
+//! its capture groups, the version.
+//!
+//! Signatures are written in TOML.  The ones we ship live in `builtin.toml`
+//! next to this file and double as an example of the format.
+//!
+//! ```toml
+//! [[probe]]
+//! name = "redis-ping"
+//! # "tcp" sends `send` and reads the reply.  If `send` is empty we just wait
+//! # for a banner.  "http_get" reuses the response from the basic HTTP probe.
+//! kind = "tcp"
+//! send = "PING\r\n"
+//! # How long to wait for a reply.  Defaults to 3 seconds.
+//! wait_ms = 1000
+//! # Leaving this off lets the probe run on any port.
+//! ports = [{ ports = "6379", likeliness = "standard" }]
+//!
+//! [[probe.match]]
+//! pattern = '^\+PONG'
+//! # Both the service and the version can use capture groups, like `${1}`
+//! service = "Redis"
+//! # One of advertised, high, medium or low.  Defaults to high.
+//! certainty = "high"
+//! # The vendor and product as spelled in CPE names, used to build the
+//! # conclusion's CPE.  These can use capture groups too.
+//! vendor = "redis"
+//! product = "redis"
+//! ```
+//!
+//! Matches for an `http_get` probe also need the `header` they run against.
+
+use std::{collections::HashSet, ops::Range, pin::Pin, sync::Arc, time::Duration};
+
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use regex::bytes::{Captures, Regex};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout_at, Instant},
+};
+use tokio_openssl::SslStream;
+use tracing::{debug, instrument};
+
+use crate::{
+    err::PortscanErr,
+    service_detection::{
+        framework::{
+            PortHint, PortLikeliness, PortToAnalyze, Rule, RuleClosure, RuleError, RuleId,
+            RuleLoudness, RuleResult, RuleResults, ServiceDetectionCertainty,
+            ServiceDetectionConclusion,
+        },
+        rules::{
+            http::{BasicHttpGetProbe, BasicHttpGetProbeResult},
+            ssl::{BasicSSLProbe, BasicSSLProbeResult},
+        },
+    },
+};
+
+mod nmap;
+
+pub(crate) use nmap::parse_nmap_service_probes;
+
+/// The signatures compiled into bowbend.
+const BUILTIN_SIGNATURES: &str = include_str!("builtin.toml");
+
+/// How long we wait for a reply when a probe doesn't say.
+const DEFAULT_WAIT: Duration = Duration::from_secs(3);
+
+/// Once a reply starts arriving we only wait this long for more of it.
+const TRAILING_WAIT: Duration = Duration::from_millis(250);
+
+/// We stop reading a reply after this many bytes.  Banners and the start of
+/// a reply are all the signatures should need.
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// One probe loaded from a signature file.
+#[derive(Clone, Debug)]
+pub(crate) struct SignatureProbe {
+    pub(crate) name: String,
+    pub(crate) kind: ProbeKind,
+    pub(crate) ports: Vec<SignaturePorts>,
+    /// How long to wait for a reply
+    pub(crate) wait: Duration,
+    /// Tried in order, the first one to match wins
+    pub(crate) matches: Vec<SignatureMatch>,
+}
+
+/// How a probe gets the data its matches run against.
+#[derive(Clone, Debug)]
+pub(crate) enum ProbeKind {
+    /// Connect, wrapping the connection in TLS if the port speaks it, send the
+    /// payload and read the reply.  An empty payload just reads the banner.
+    Tcp { payload: Vec<u8> },
+    /// Match against the headers captured by [`BasicHttpGetProbe`].
+    HttpGet,
+}
+
+/// The ports a probe should run on.
+#[derive(Clone, Debug)]
+pub(crate) struct SignaturePorts {
+    pub(crate) ports: Range<u16>,
+    pub(crate) likeliness: PortLikeliness,
+}
+
+/// A regex to run against a reply and the conclusion to draw when it matches.
+#[derive(Clone, Debug)]
+pub(crate) struct SignatureMatch {
+    pub(crate) pattern: Regex,
+    /// The lowercase name of the HTTP header to match against.  Only used by
+    /// [`ProbeKind::HttpGet`].
+    pub(crate) header: Option<String>,
+    /// Template for the service name, expanded with the pattern's captures
+    pub(crate) service: String,
+    /// Template for the version, expanded with the pattern's captures
+    pub(crate) version: Option<String>,
+    /// Template for the CPE vendor, expanded with the pattern's captures
+    pub(crate) vendor: Option<String>,
+    /// Template for the CPE product, expanded with the pattern's captures
+    pub(crate) product: Option<String>,
+    pub(crate) certainty: ServiceDetectionCertainty,
+}
+
+impl SignatureMatch {
+    fn conclude(&self, response: &[u8]) -> Option<ServiceDetectionConclusion> {
+        let captures = self.pattern.captures(response)?;
+        let service_name = expand(&captures, &self.service);
+        if service_name.is_empty() {
+            debug!("Signature {} matched but named no service", self.pattern);
+            return None;
+        }
+        Some(ServiceDetectionConclusion {
+            certainty: self.certainty,
+            service_name,
+            service_version: expand_optional(&captures, &self.version),
+            vendor: expand_optional(&captures, &self.vendor),
+            product: expand_optional(&captures, &self.product),
+        })
+    }
+}
+
+fn expand(captures: &Captures, template: &str) -> String {
+    let mut expanded = Vec::new();
+    captures.expand(template.as_bytes(), &mut expanded);
+    String::from_utf8_lossy(&expanded).trim().to_string()
+}
+
+/// Expand an optional template, treating an empty expansion as missing.
+fn expand_optional(captures: &Captures, template: &Option<String>) -> Option<String> {
+    template
+        .as_ref()
+        .map(|template| expand(captures, template))
+        .filter(|expanded| !expanded.is_empty())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignatureFile {
+    #[serde(default)]
+    probe: Vec<ProbeDefinition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeDefinition {
+    name: String,
+    #[serde(default)]
+    kind: ProbeKindDefinition,
+    #[serde(default)]
+    send: String,
+    wait_ms: Option<u64>,
+    #[serde(default)]
+    ports: Vec<PortsDefinition>,
+    #[serde(default, rename = "match")]
+    matches: Vec<MatchDefinition>,
+}
+
+#[derive(Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ProbeKindDefinition {
+    #[default]
+    Tcp,
+    HttpGet,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PortsDefinition {
+    ports: String,
+    likeliness: PortLikeliness,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchDefinition {
+    pattern: String,
+    header: Option<String>,
+    service: String,
+    version: Option<String>,
+    vendor: Option<String>,
+    product: Option<String>,
+    #[serde(default = "default_certainty")]
+    certainty: ServiceDetectionCertainty,
+}
+
+fn default_certainty() -> ServiceDetectionCertainty {
+    ServiceDetectionCertainty::High
+}
+
+/// Parse a signature file.  Nothing is partially loaded, any problem with the
+/// file fails the whole thing.
+pub(crate) fn parse_signatures(input: &str) -> Result<Vec<SignatureProbe>, PortscanErr> {
+    let file: SignatureFile =
+        toml::from_str(input).map_err(|e| PortscanErr::InvalidSignature(e.to_string()))?;
+    let mut names = HashSet::new();
+    file.probe
+        .into_iter()
+        .map(|probe| {
+            if !names.insert(probe.name.clone()) {
+                return Err(invalid(&probe.name, "the name is used more than once"));
+            }
+            build_probe(probe)
+        })
+        .collect()
+}
+
+/// The signatures compiled into bowbend.
+pub(crate) fn builtin_signatures() -> Vec<SignatureProbe> {
+    parse_signatures(BUILTIN_SIGNATURES)
+        .expect("The built in service detection signatures are invalid")
+}
+
+fn build_probe(probe: ProbeDefinition) -> Result<SignatureProbe, PortscanErr> {
+    if probe.name.is_empty() {
+        return Err(PortscanErr::InvalidSignature(
+            "A probe is missing its name".to_string(),
+        ));
+    }
+    if probe.matches.is_empty() {
+        return Err(invalid(&probe.name, "it has no matches"));
+    }
+    let kind = match probe.kind {
+        ProbeKindDefinition::Tcp => ProbeKind::Tcp {
+            payload: probe.send.into_bytes(),
+        },
+        ProbeKindDefinition::HttpGet if probe.send.is_empty() => ProbeKind::HttpGet,
+        ProbeKindDefinition::HttpGet => {
+            return Err(invalid(&probe.name, "http_get probes can't send a payload"))
+        }
+    };
+    let ports = probe
+        .ports
+        .iter()
+        .map(|ports| {
+            Ok(SignaturePorts {
+                ports: parse_port_range(&ports.ports)
+                    .ok_or_else(|| invalid(&probe.name, &format!("bad ports {:?}", ports.ports)))?,
+                likeliness: ports.likeliness,
+            })
+        })
+        .collect::<Result<_, PortscanErr>>()?;
+    let matches = probe
+        .matches
+        .into_iter()
+        .map(|definition| {
+            let header = match (&kind, definition.header) {
+                (ProbeKind::HttpGet, Some(header)) => Some(header.to_lowercase()),
+                (ProbeKind::HttpGet, None) => {
+                    return Err(invalid(&probe.name, "http_get matches need a header"))
+                }
+                (ProbeKind::Tcp { .. }, Some(_)) => {
+                    return Err(invalid(
+                        &probe.name,
+                        "only http_get matches can use a header",
+                    ))
+                }
+                (ProbeKind::Tcp { .. }, None) => None,
+            };
+            Ok(SignatureMatch {
+                pattern: Regex::new(&definition.pattern)
+                    .map_err(|e| invalid(&probe.name, &e.to_string()))?,
+                header,
+                service: definition.service,
+                version: definition.version,
+                vendor: definition.vendor,
+                product: definition.product,
+                certainty: definition.certainty,
+            })
+        })
+        .collect::<Result<_, PortscanErr>>()?;
+    Ok(SignatureProbe {
+        name: probe.name,
+        kind,
+        ports,
+        wait: probe.wait_ms.map_or(DEFAULT_WAIT, Duration::from_millis),
+        matches,
+    })
+}
+
+fn invalid(name: &str, problem: &str) -> PortscanErr {
+    PortscanErr::InvalidSignature(format!("Probe {name}: {problem}"))
+}
+
+/// Parse a single port, "22", or an inclusive range, "8000-8099", into a half
+/// open range.
+fn parse_port_range(ports: &str) -> Option<Range<u16>> {
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let port: u16 = ports.trim().parse().ok()?;
+            (port, port)
+        }
+    };
+    if start > end {
+        return None;
+    }
+    // Like `PortHint::any` we can't express 65535 in a half open range of u16s
+    Some(start..end.saturating_add(1))
+}
+
+/// Adapts a [`SignatureProbe`] to the [`Rule`] framework.
+#[derive(Debug)]
+pub(crate) struct SignatureRule {
+    probe: Arc<SignatureProbe>,
+}
+
+impl SignatureRule {
+    /// Simple constructor for [`SignatureRule`]
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(probe: Arc<SignatureProbe>) -> Box<dyn Rule> {
+        Box::new(SignatureRule { probe })
+    }
+}
+
+impl Rule for SignatureRule {
+    fn rule_id(&self) -> RuleId {
+        RuleId::named(&self.probe.name)
+    }
+
+    fn dependencies(&self) -> Vec<RuleId> {
+        match self.probe.kind {
+            ProbeKind::Tcp { .. } => vec![RuleId::new::<BasicSSLProbe>()],
+            ProbeKind::HttpGet => vec![RuleId::new::<BasicHttpGetProbe>()],
+        }
+    }
+
+    fn port_hints(&self) -> Vec<PortHint> {
+        if self.probe.ports.is_empty() {
+            return vec![PortHint::any()];
+        }
+        self.probe
+            .ports
+            .iter()
+            .map(|ports| PortHint::new_from_range(ports.ports.clone(), ports.likeliness))
+            .collect()
+    }
+
+    fn loudness(&self) -> RuleLoudness {
+        match &self.probe.kind {
+            ProbeKind::Tcp { payload } if payload.is_empty() => RuleLoudness::Quiet,
+            ProbeKind::Tcp { .. } => RuleLoudness::Standard,
+            ProbeKind::HttpGet => RuleLoudness::Silent,
+        }
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    fn get_execution_method(&self) -> RuleClosure {
+        async fn exec(
+            probe: Arc<SignatureProbe>,
+            target: Arc<PortToAnalyze>,
+            results: Arc<RuleResults>,
+        ) -> Result<Box<dyn RuleResult>, RuleError> {
+            let conclusion = match &probe.kind {
+                ProbeKind::HttpGet => {
+                    let http_result = results
+                        .get_results::<BasicHttpGetProbe, BasicHttpGetProbeResult>()
+                        .await;
+                    probe.matches.iter().find_map(|signature| {
+                        let header = http_result.headers.get(signature.header.as_ref()?)?;
+                        signature.conclude(header)
+                    })
+                }
+                ProbeKind::Tcp { payload } => {
+                    let ssl_enabled = results
+                        .get_results::<BasicSSLProbe, BasicSSLProbeResult>()
+                        .await
+                        .ssl_enabled;
+                    let response = grab_response(&target, ssl_enabled, payload, probe.wait).await?;
+                    probe
+                        .matches
+                        .iter()
+                        .find_map(|signature| signature.conclude(&response))
+                }
+            };
+            Ok(Box::new(SignatureRuleResult {
+                rule_id: RuleId::named(&probe.name),
+                conclusion,
+            }))
+        }
+        let probe = self.probe.clone();
+        Box::new(
+            move |target: Arc<PortToAnalyze>, results: Arc<RuleResults>| {
+                Box::pin(exec(probe.clone(), target, results))
+            },
+        )
+    }
+}
+
+async fn grab_response(
+    target: &PortToAnalyze,
+    ssl_enabled: bool,
+    payload: &[u8],
+    wait: Duration,
+) -> Result<Vec<u8>, RuleError> {
+    let _permit = target.wait_for_clearance().await;
+    let mut stream = TcpStream::connect(target.get_socket_addr()).await?;
+    if !ssl_enabled {
+        return Ok(exchange(&mut stream, payload, wait).await?);
+    }
+    let mut builder = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| RuleError::InternalRuleError(e.into()))?;
+    // We only care what is on the other end, not whether we trust it
+    builder.set_verify(SslVerifyMode::NONE);
+    let mut stream = builder
+        .build()
+        .configure()
+        .and_then(|config| config.into_ssl(&target.get_hostname()))
+        .and_then(|ssl| SslStream::new(ssl, stream))
+        .map_err(|e| RuleError::InternalRuleError(e.into()))?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|e| RuleError::InternalRuleError(e.into()))?;
+    Ok(exchange(&mut stream, payload, wait).await?)
+}
+
+/// Send the payload and read until the other side closes the connection,
+/// stops talking or we've read enough.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    payload: &[u8],
+    wait: Duration,
+) -> std::io::Result<Vec<u8>> {
+    if !payload.is_empty() {
+        stream.write_all(payload).await?;
+    }
+    let deadline = Instant::now() + wait;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; MAX_RESPONSE_SIZE];
+    while response.len() < MAX_RESPONSE_SIZE {
+        let read_deadline = if response.is_empty() {
+            deadline
+        } else {
+            deadline.min(Instant::now() + TRAILING_WAIT)
+        };
+        match timeout_at(read_deadline, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(read)) => response.extend_from_slice(&buffer[..read]),
+            // Some services hang up hard once they've said their piece
+            Ok(Err(_)) if !response.is_empty() => break,
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    response.truncate(MAX_RESPONSE_SIZE);
+    Ok(response)
+}
+
+/// The results of a [`SignatureRule`]
+#[derive(Debug, Clone)]
+pub struct SignatureRuleResult {
+    rule_id: RuleId,
+    conclusion: Option<ServiceDetectionConclusion>,
+}
+
+impl RuleResult for SignatureRuleResult {
+    fn get_rule_id(&self) -> RuleId {
+        self.rule_id.clone()
+    }
+
+    fn get_conclusion(&self) -> Option<ServiceDetectionConclusion> {
+        self.conclusion.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::Semaphore,
+    };
+
+    use crate::{
+        err::PortscanErr,
+        service_detection::{
+            framework::{PortToAnalyze, RuleResults, ServiceDetectionCertainty},
+            rules::ssl::BasicSSLProbeResult,
+            signatures::{builtin_signatures, parse_signatures, ProbeKind, SignatureRule},
+        },
+        target::TargetInstance,
+    };
+
+    #[test]
+    fn test_builtin_signatures_match_banners() {
+        let signatures = builtin_signatures();
+        let ssh = signatures
+            .iter()
+            .find(|probe| probe.name == "ssh-banner")
+            .unwrap();
+        assert_eq!(ssh.ports[0].ports, 22..23);
+        let conclusion = ssh
+            .matches
+            .iter()
+            .find_map(|signature| signature.conclude(b"SSH-2.0-OpenSSH_9.3p1 Debian-1\r\n"))
+            .unwrap();
+        assert_eq!(conclusion.service_name, "OpenSSH");
+        assert_eq!(conclusion.service_version.as_deref(), Some("9.3p1"));
+        assert!(matches!(
+            conclusion.certainty,
+            ServiceDetectionCertainty::Advertised
+        ));
+        assert_eq!(
+            conclusion.cpe().as_deref(),
+            Some("cpe:2.3:a:openbsd:openssh:9.3p1:*:*:*:*:*:*:*")
+        );
+
+        let conclusion = ssh
+            .matches
+            .iter()
+            .find_map(|signature| signature.conclude(b"SSH-2.0-libssh_0.10\r\n"))
+            .unwrap();
+        assert_eq!(conclusion.service_name, "SSH");
+        assert_eq!(conclusion.service_version, None);
+        assert_eq!(conclusion.cpe(), None);
+    }
+
+    #[test]
+    fn test_parse_signatures() {
+        let signatures = parse_signatures(
+            r#"
+            [[probe]]
+            name = "in-house"
+            send = "HELLO\r\n"
+            wait_ms = 500
+            ports = [{ ports = "9000-9009", likeliness = "common" }]
+
+            [[probe.match]]
+            pattern = '^WELCOME (?P<product>\w+) v(\d+)'
+            service = "Acme ${product}"
+            version = "${2}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(signatures.len(), 1);
+        let probe = &signatures[0];
+        assert!(matches!(&probe.kind, ProbeKind::Tcp { payload } if payload == b"HELLO\r\n"));
+        assert_eq!(probe.ports[0].ports, 9000..9010);
+        let conclusion = probe.matches[0].conclude(b"WELCOME Widget v3\r\n").unwrap();
+        assert_eq!(conclusion.service_name, "Acme Widget");
+        assert_eq!(conclusion.service_version.as_deref(), Some("3"));
+        assert!(matches!(
+            conclusion.certainty,
+            ServiceDetectionCertainty::High
+        ));
+        assert!(probe.matches[0].conclude(b"GOODBYE").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_signature_rule_sends_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 6];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"PING\r\n");
+            stream.write_all(b"+PONG\r\n").await.unwrap();
+        });
+        let probe = builtin_signatures()
+            .into_iter()
+            .find(|probe| probe.name == "redis-ping")
+            .unwrap();
+        let results = RuleResults::new();
+        results
+            .insert_result(Box::new(BasicSSLProbeResult { ssl_enabled: false }))
+            .await;
+        let target = PortToAnalyze::new(
+            Arc::new(Semaphore::new(1)),
+            None,
+            TargetInstance::IP(addr.ip()),
+            addr.port(),
+        );
+        let rule = SignatureRule::new(Arc::new(probe));
+        let result = rule.get_execution_method()(target, results).await.unwrap();
+        assert_eq!(result.get_rule_id(), rule.rule_id());
+        assert_eq!(result.get_conclusion().unwrap().service_name, "Redis");
+    }
+
+    #[test]
+    fn test_invalid_signatures() {
+        for input in [
+            // Not TOML
+            "[[probe]",
+            // Bad regex
+            "[[probe]]\nname = \"a\"\n[[probe.match]]\npattern = \"(\"\nservice = \"a\"",
+            // http_get matches need a header
+            "[[probe]]\nname = \"a\"\nkind = \"http_get\"\n[[probe.match]]\npattern = \"a\"\nservice = \"a\"",
+            // Bad port range
+            "[[probe]]\nname = \"a\"\nports = [{ ports = \"90-80\", likeliness = \"rare\" }]\n[[probe.match]]\npattern = \"a\"\nservice = \"a\"",
+            // Duplicate names
+            "[[probe]]\nname = \"a\"\n[[probe.match]]\npattern = \"a\"\nservice = \"a\"\n[[probe]]\nname = \"a\"\n[[probe.match]]\npattern = \"a\"\nservice = \"a\"",
+        ] {
+            assert!(
+                matches!(parse_signatures(input), Err(PortscanErr::InvalidSignature(_))),
+                "{input}"
+            );
+        }
+    }
+}
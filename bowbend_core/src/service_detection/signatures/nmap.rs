@@ -0,0 +1,490 @@
+//! Import probes from nmap's `nmap-service-probes` format.  Each TCP `Probe`
+//! becomes a [`SignatureProbe`] and its `match` and `softmatch` lines become
+//! [`SignatureMatch`]es, so they run through the same rule as our own
+//! signatures.
+//!
+//! A few parts of the format don't carry over:
+//! * UDP probes are skipped since we only scan TCP ports.
+//! * nmap's regexes are PCRE.  Patterns using something the `regex` crate
+//!   doesn't support, like look-around or back references, are skipped.
+//! * Only the product and version fields of a match are used, along with the
+//!   vendor and product of the first application CPE.  `$P()` and `$SUBST()` in
+//!   them are replaced by the raw capture group and `$I()` is dropped.
+
+use std::{ops::Range, time::Duration};
+
+use regex::bytes::{Regex, RegexBuilder};
+use tracing::{debug, warn};
+
+use crate::{
+    err::PortscanErr,
+    service_detection::{
+        framework::{PortLikeliness, ServiceDetectionCertainty},
+        signatures::{
+            parse_port_range, ProbeKind, SignatureMatch, SignaturePorts, SignatureProbe,
+            DEFAULT_WAIT,
+        },
+    },
+};
+
+/// Our probes are named after nmap's with this prefix to keep them apart from
+/// our own signatures.
+const NAME_PREFIX: &str = "nmap-";
+
+/// nmap falls back on the matches of this probe when no others match.
+const NULL_PROBE: &str = "NULL";
+
+/// A probe as it's being read, before fallbacks are resolved.
+struct NmapProbe {
+    name: String,
+    tcp: bool,
+    payload: Vec<u8>,
+    ports: Vec<Range<u16>>,
+    rarity: u8,
+    wait: Option<Duration>,
+    fallbacks: Vec<String>,
+    matches: Vec<SignatureMatch>,
+}
+
+/// Parse the contents of an `nmap-service-probes` file.  A malformed line
+/// fails the whole file but a pattern the `regex` crate can't handle only
+/// skips that match.
+pub(crate) fn parse_nmap_service_probes(input: &str) -> Result<Vec<SignatureProbe>, PortscanErr> {
+    let mut probes: Vec<NmapProbe> = Vec::new();
+    let mut skipped_patterns = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            |problem: &str| PortscanErr::InvalidSignature(format!("Line {}: {problem}", index + 1));
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        if directive == "Probe" {
+            probes.push(parse_probe(rest).ok_or_else(|| invalid("bad Probe line"))?);
+            continue;
+        }
+        if directive == "Exclude" {
+            continue;
+        }
+        let probe = probes
+            .last_mut()
+            .ok_or_else(|| invalid("directive before the first Probe"))?;
+        match directive {
+            "match" | "softmatch" => match parse_match(rest, directive == "softmatch") {
+                Ok(Some(signature)) => probe.matches.push(signature),
+                Ok(None) => skipped_patterns += 1,
+                Err(problem) => return Err(invalid(problem)),
+            },
+            "ports" | "sslports" => probe
+                .ports
+                .extend(parse_port_list(rest).ok_or_else(|| invalid("bad port list"))?),
+            "rarity" => {
+                probe.rarity = rest
+                    .parse()
+                    .ok()
+                    .filter(|rarity| (1..=9).contains(rarity))
+                    .ok_or_else(|| invalid("rarity must be from 1 to 9"))?
+            }
+            "totalwaitms" => {
+                probe.wait = Some(Duration::from_millis(
+                    rest.parse().map_err(|_| invalid("bad totalwaitms"))?,
+                ))
+            }
+            "fallback" => probe
+                .fallbacks
+                .extend(rest.split(',').map(|name| name.trim().to_string())),
+            "tcpwrappedms" => {}
+            _ => return Err(invalid(&format!("unknown directive {directive}"))),
+        }
+    }
+    if skipped_patterns > 0 {
+        warn!(
+            "Skipped {} nmap matches with patterns we can't compile",
+            skipped_patterns
+        );
+    }
+    Ok(resolve_probes(probes))
+}
+
+/// Turn what we read into [`SignatureProbe`]s.  Fallback matches are tried
+/// after a probe's own, ending with the NULL probe's like nmap does.
+fn resolve_probes(probes: Vec<NmapProbe>) -> Vec<SignatureProbe> {
+    let matches_for = |name: &str| {
+        probes
+            .iter()
+            .find(|probe| probe.name == name)
+            .map(|probe| probe.matches.clone())
+            .unwrap_or_default()
+    };
+    probes
+        .iter()
+        .filter(|probe| {
+            if !probe.tcp {
+                debug!("Skipping UDP probe {}", probe.name);
+            }
+            probe.tcp
+        })
+        .map(|probe| {
+            let mut matches = probe.matches.clone();
+            for fallback in &probe.fallbacks {
+                matches.extend(matches_for(fallback));
+            }
+            if probe.name != NULL_PROBE && !probe.fallbacks.iter().any(|name| name == NULL_PROBE) {
+                matches.extend(matches_for(NULL_PROBE));
+            }
+            let likeliness = rarity_to_likeliness(probe.rarity);
+            let ports = if probe.ports.is_empty() {
+                // Like `PortHint::any`
+                vec![Range {
+                    start: u16::MIN,
+                    end: u16::MAX,
+                }]
+            } else {
+                probe.ports.clone()
+            };
+            SignatureProbe {
+                name: format!("{NAME_PREFIX}{}", probe.name),
+                kind: ProbeKind::Tcp {
+                    payload: probe.payload.clone(),
+                },
+                ports: ports
+                    .into_iter()
+                    .map(|ports| SignaturePorts { ports, likeliness })
+                    .collect(),
+                wait: probe.wait.unwrap_or(DEFAULT_WAIT),
+                matches,
+            }
+        })
+        .collect()
+}
+
+/// nmap rates how often a probe gets a useful answer from 1, almost always,
+/// to 9, hardly ever.
+fn rarity_to_likeliness(rarity: u8) -> PortLikeliness {
+    match rarity {
+        0..=2 => PortLikeliness::Standard,
+        3..=5 => PortLikeliness::Common,
+        6..=7 => PortLikeliness::Unusual,
+        _ => PortLikeliness::Rare,
+    }
+}
+
+/// Parse the rest of a `Probe <protocol> <name> q|<payload>|` line.
+fn parse_probe(line: &str) -> Option<NmapProbe> {
+    let mut parts = line.splitn(3, ' ');
+    let tcp = match parts.next()? {
+        "TCP" => true,
+        "UDP" => false,
+        _ => return None,
+    };
+    let name = parts.next()?.to_string();
+    let (payload, _) = delimited(parts.next()?.strip_prefix('q')?)?;
+    Some(NmapProbe {
+        name,
+        tcp,
+        payload: unescape(payload)?,
+        ports: vec![],
+        rarity: 1,
+        wait: None,
+        fallbacks: vec![],
+        matches: vec![],
+    })
+}
+
+/// Parse the rest of a `match <service> m|<pattern>|<flags> <version info>`
+/// line.  Returns `None` when the pattern is valid PCRE we can't compile.
+fn parse_match(line: &str, soft: bool) -> Result<Option<SignatureMatch>, &'static str> {
+    let (service, rest) = line.split_once(' ').ok_or("match is missing its pattern")?;
+    let (pattern, rest) = rest
+        .strip_prefix('m')
+        .and_then(delimited)
+        .ok_or("bad match pattern")?;
+    let flags_end = rest.find(' ').unwrap_or(rest.len());
+    let (flags, mut rest) = rest.split_at(flags_end);
+    let pattern = match compile_pattern(pattern, flags)? {
+        Some(pattern) => pattern,
+        None => return Ok(None),
+    };
+
+    let mut product = None;
+    let mut version = None;
+    let mut vendor_and_product = None;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(cpe) = rest.strip_prefix("cpe:") {
+            let (value, after) = delimited(cpe).ok_or("bad cpe field")?;
+            // The CPE may be followed by an 'a' for "application"
+            rest = after.strip_prefix('a').unwrap_or(after);
+            if vendor_and_product.is_none() {
+                vendor_and_product = parse_application_cpe(value);
+            }
+            continue;
+        }
+        let mut chars = rest.chars();
+        let field = chars.next().ok_or("bad version field")?;
+        let (value, after) = delimited(chars.as_str()).ok_or("bad version field")?;
+        rest = after;
+        match field {
+            'p' => product = Some(translate_template(value)),
+            'v' => version = Some(translate_template(value)),
+            'i' | 'h' | 'o' | 'd' => {}
+            _ => return Err("unknown version field"),
+        }
+    }
+    let (cpe_vendor, cpe_product) = match vendor_and_product {
+        Some((vendor, product)) => (Some(vendor), Some(product)),
+        None => (None, None),
+    };
+    Ok(Some(SignatureMatch {
+        pattern,
+        header: None,
+        service: product.unwrap_or_else(|| service.to_string()),
+        version,
+        vendor: cpe_vendor,
+        product: cpe_product,
+        certainty: if soft {
+            ServiceDetectionCertainty::Medium
+        } else {
+            ServiceDetectionCertainty::High
+        },
+    }))
+}
+
+/// Pull the vendor and product out of a CPE 2.2 name, like
+/// `a:igor_sysoev:nginx:$1`, as templates.  Only application CPEs are used, the
+/// ones for operating systems and hardware don't describe the service.
+fn parse_application_cpe(cpe: &str) -> Option<(String, String)> {
+    let mut parts = cpe.split(':');
+    if parts.next()? != "a" {
+        return None;
+    }
+    let vendor = parts.next().filter(|vendor| !vendor.is_empty())?;
+    let product = parts.next().filter(|product| !product.is_empty())?;
+    Some((translate_template(vendor), translate_template(product)))
+}
+
+fn compile_pattern(pattern: &str, flags: &str) -> Result<Option<Regex>, &'static str> {
+    let mut builder = RegexBuilder::new(pattern);
+    // nmap matches against raw bytes and allows escapes like \0
+    builder.unicode(false).octal(true);
+    for flag in flags.chars() {
+        match flag {
+            's' => builder.dot_matches_new_line(true),
+            'i' => builder.case_insensitive(true),
+            _ => return Err("unknown pattern flag"),
+        };
+    }
+    Ok(match builder.build() {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            debug!("Skipping nmap pattern {}: {}", pattern, e);
+            None
+        }
+    })
+}
+
+/// Split `|value|rest` on whatever delimiter the value starts with.
+fn delimited(input: &str) -> Option<(&str, &str)> {
+    let delimiter = input.chars().next()?;
+    let input = &input[delimiter.len_utf8()..];
+    let end = input.find(delimiter)?;
+    Some((&input[..end], &input[end + delimiter.len_utf8()..]))
+}
+
+/// Decode the C style escapes nmap uses in probe payloads.
+fn unescape(payload: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(payload.len());
+    let mut input = payload.bytes();
+    while let Some(byte) = input.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        bytes.push(match input.next()? {
+            b'0' => 0,
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b'x' => {
+                let hex = [input.next()?, input.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            other => other,
+        });
+    }
+    Some(bytes)
+}
+
+/// Rewrite an nmap version template into the syntax of
+/// [`regex::bytes::Captures::expand`].
+fn translate_template(template: &str) -> String {
+    let mut translated = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        translated.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 {
+            translated.push_str(&format!("${{{}}}", &rest[..digits]));
+            rest = &rest[digits..];
+            continue;
+        }
+        let function = ["P(", "SUBST(", "I("]
+            .iter()
+            .find(|function| rest.starts_with(*function));
+        match (function, rest.find(')')) {
+            (Some(function), Some(end)) => {
+                let group: String = rest[function.len()..]
+                    .chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect();
+                // $I() decodes binary integers which we can't do in a template
+                if *function != "I(" {
+                    translated.push_str(&format!("${{{group}}}"));
+                }
+                rest = &rest[end + 1..];
+            }
+            _ => translated.push_str("$$"),
+        }
+    }
+    translated.push_str(rest);
+    translated
+}
+
+/// Parse a comma separated list of ports and inclusive ranges.
+fn parse_port_list(list: &str) -> Option<Vec<Range<u16>>> {
+    list.split(',')
+        .map(|ports| parse_port_range(ports.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        err::PortscanErr,
+        service_detection::{
+            framework::{PortLikeliness, ServiceDetectionCertainty},
+            signatures::{
+                nmap::{parse_nmap_service_probes, translate_template, unescape},
+                ProbeKind,
+            },
+        },
+    };
+
+    const PROBES: &str = r#"
+# A trimmed down nmap-service-probes
+Exclude T:9100-9107
+Probe TCP NULL q||
+totalwaitms 6000
+match ftp m/^220 \(vsFTPd ([\w.]+)\)\r\n/ p/vsftpd/ v/$1/ cpe:/a:beasts:vsftpd:$1/
+match ssh m|^SSH-([\d.]+)-OpenSSH_([\w._-]+)[ -]|i p/OpenSSH/ v/$2/ i/protocol $1/
+match weird m/^(?=lookahead)/ p/Unsupported/
+softmatch ftp m/^220[- ]/
+
+Probe UDP DNSStatusRequest q|\0\0\x10\0\0\0\0\0\0\0\0\0|
+rarity 1
+ports 53
+
+Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+rarity 4
+ports 80-85,8080
+sslports 443
+match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: Apache/([\d.]+)|s p/Apache httpd/ v/$P(1)/
+"#;
+
+    #[test]
+    fn test_parse_nmap_service_probes() {
+        let probes = parse_nmap_service_probes(PROBES).unwrap();
+        // The UDP probe is skipped
+        assert_eq!(probes.len(), 2);
+
+        let null = &probes[0];
+        assert_eq!(null.name, "nmap-NULL");
+        assert!(matches!(&null.kind, ProbeKind::Tcp { payload } if payload.is_empty()));
+        assert_eq!(null.wait.as_millis(), 6000);
+        // The look-ahead can't be compiled so only three matches are left
+        assert_eq!(null.matches.len(), 3);
+        let conclusion = null.matches[0].conclude(b"220 (vsFTPd 3.0.3)\r\n").unwrap();
+        assert_eq!(conclusion.service_name, "vsftpd");
+        assert_eq!(conclusion.service_version.as_deref(), Some("3.0.3"));
+        assert_eq!(
+            conclusion.cpe().as_deref(),
+            Some("cpe:2.3:a:beasts:vsftpd:3.0.3:*:*:*:*:*:*:*")
+        );
+        let conclusion = null.matches[1]
+            .conclude(b"ssh-2.0-openssh_8.9p1 Ubuntu-3\r\n")
+            .unwrap();
+        assert_eq!(conclusion.service_name, "OpenSSH");
+        assert_eq!(conclusion.service_version.as_deref(), Some("8.9p1"));
+        let conclusion = null.matches[2].conclude(b"220-Welcome\r\n").unwrap();
+        assert_eq!(conclusion.service_name, "ftp");
+        assert!(matches!(
+            conclusion.certainty,
+            ServiceDetectionCertainty::Medium
+        ));
+
+        let get = &probes[1];
+        assert!(
+            matches!(&get.kind, ProbeKind::Tcp { payload } if payload == b"GET / HTTP/1.0\r\n\r\n")
+        );
+        let ports: Vec<_> = get.ports.iter().map(|ports| ports.ports.clone()).collect();
+        assert_eq!(ports, vec![80..86, 8080..8081, 443..444]);
+        assert!(matches!(get.ports[0].likeliness, PortLikeliness::Common));
+        // Its own match followed by the NULL probe's
+        assert_eq!(get.matches.len(), 4);
+        let conclusion = get.matches[0]
+            .conclude(b"HTTP/1.1 200 OK\r\nDate: today\r\nServer: Apache/2.4.57 (Debian)\r\n")
+            .unwrap();
+        assert_eq!(conclusion.service_name, "Apache httpd");
+        assert_eq!(conclusion.service_version.as_deref(), Some("2.4.57"));
+    }
+
+    #[test]
+    fn test_translate_template() {
+        assert_eq!(translate_template("$1"), "${1}");
+        assert_eq!(translate_template("$1a"), "${1}a");
+        assert_eq!(
+            translate_template("$P(2) on $SUBST(3,\"_\",\".\")"),
+            "${2} on ${3}"
+        );
+        assert_eq!(translate_template("$I(1,\">\")"), "");
+        assert_eq!(translate_template("US$"), "US$$");
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(
+            unescape(r"\0\x10\r\n\\a|").unwrap(),
+            vec![0, 0x10, b'\r', b'\n', b'\\', b'a', b'|']
+        );
+        assert!(unescape(r"\x1").is_none());
+    }
+
+    #[test]
+    fn test_malformed_nmap_service_probes() {
+        for input in [
+            "match ftp m/^220/",
+            "Probe TCP NULL",
+            "Probe TCP NULL q||\nmatch ftp m/^220",
+            "Probe TCP NULL q||\nrarity 12",
+            "Probe TCP NULL q||\nbogus directive",
+        ] {
+            assert!(
+                matches!(
+                    parse_nmap_service_probes(input),
+                    Err(PortscanErr::InvalidSignature(_))
+                ),
+                "{input}"
+            );
+        }
+    }
+}
@@ -1,82 +1,240 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::collections::HashSet;
 
-use crate::service_detection::framework::{PortToAnalyze, Rule, RuleId};
+use tracing::debug;
 
+use crate::service_detection::framework::{
+    PortLikeliness, Rule, RuleId, RuleLoudness, ServiceDetectionIntensity,
+};
+
+/// Why a rule wasn't run against a port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// None of the rule's port hints cover the port.
+    NotApplicable,
+    /// The rule is louder than the configured maximum loudness.
+    TooLoud,
+    /// The rule's service is less likely to be on the port than the
+    /// configured intensity allows for.
+    TooUnlikely,
+    /// The rule needs privileged access, like raw sockets, that the scan
+    /// doesn't have.
+    RequiresPrivilege,
+    /// The rule depends on a rule that doesn't exist.
+    MissingDependency(RuleId),
+    /// The rule is part of, or depends on, a loop of rules that depend on each
+    /// other.
+    DependencyCycle,
+    /// A dependency was skipped.  Its own entry says why.
+    DependencySkipped(RuleId),
+    /// A dependency ran but failed.
+    DependencyFailed(RuleId),
+}
+
+/// A service detection rule that wasn't run against a port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SkippedRule {
+    /// The rule that was skipped
+    pub rule: RuleId,
+    /// Why it was skipped
+    pub reason: SkipReason,
+}
+
+/// Limits on which rules the planner may pick.
+#[derive(Clone, Debug)]
+pub(crate) struct PlanSettings {
+    /// Rules louder than this are skipped
+    pub(crate) max_loudness: RuleLoudness,
+    /// Rules whose service is less likely than this on the port are skipped
+    pub(crate) max_likeliness: PortLikeliness,
+    /// Can rules use privileged access like raw sockets?  If not, rules that
+    /// need it are skipped
+    pub(crate) privileged: bool,
+}
+
+impl Default for PlanSettings {
+    fn default() -> Self {
+        Self {
+            max_loudness: RuleLoudness::BangingTogetherPotsAndPans,
+            max_likeliness: ServiceDetectionIntensity::Exhaustive.max_likeliness(),
+            privileged: true,
+        }
+    }
+}
+
+/// Decides which rules to run against a port and in what order.  The whole
+/// dependency graph is worked out up front so any rule that could never run
+/// is skipped right away instead of waiting on a dependency forever.  Rules
+/// whose service is most likely to be on the port are run first.
 pub struct PortTestPlan {
-    port_to_analyze: Arc<PortToAnalyze>,
     to_run: Vec<Box<dyn Rule>>,
-    already_successfully_run: Vec<RuleId>,
-    /// A list of rules that are blocked indexed by the first rule blocking
-    /// them.
-    blocked: BTreeMap<RuleId, Vec<Box<dyn Rule>>>,
+    /// Rules waiting on dependencies along with the dependencies they are
+    /// still waiting on.
+    blocked: Vec<(Box<dyn Rule>, Vec<RuleId>)>,
+    skipped: Vec<SkippedRule>,
 }
 
 impl PortTestPlan {
-    pub fn new(port_to_analyze: Arc<PortToAnalyze>, rules: Vec<Box<dyn Rule>>) -> Self {
-        rules.into_iter().fold(
-            PortTestPlan {
-                port_to_analyze,
-                to_run: vec![],
-                already_successfully_run: vec![],
-                blocked: Default::default(),
-            },
-            |mut testplan, rule| {
-                // Does the rule apply to the port we are planning for?
-                let applicable_port = rule.port_hints().iter().any(|port_hint| {
-                    port_hint
-                        .port_range()
-                        .contains(&testplan.port_to_analyze.port())
-                });
-                if applicable_port {
-                    if let Some(first_depedency) = rule.dependencies().first() {
-                        testplan
-                            .blocked
-                            .entry(first_depedency.clone())
-                            .or_default()
-                            .push(rule);
-                        testplan
+    /// `already_run` lists host rules that already ran against another port
+    /// on the host.  They aren't run again but still satisfy dependencies.
+    pub fn new(
+        port: u16,
+        rules: Vec<Box<dyn Rule>>,
+        settings: &PlanSettings,
+        already_run: &[RuleId],
+    ) -> Self {
+        let known: HashSet<RuleId> = rules.iter().map(|rule| rule.rule_id()).collect();
+        let mut skipped = Vec::new();
+        let mut undecided = Vec::new();
+        for rule in rules {
+            if already_run.contains(&rule.rule_id()) {
+                continue;
+            }
+            // Does the rule apply to the port we are planning for?
+            let reason = match likeliness_on_port(rule.as_ref(), port) {
+                None => SkipReason::NotApplicable,
+                Some(likeliness) if likeliness > settings.max_likeliness => SkipReason::TooUnlikely,
+                Some(_) if rule.loudness() > settings.max_loudness => SkipReason::TooLoud,
+                Some(_) if rule.requires_privileged_access() && !settings.privileged => {
+                    SkipReason::RequiresPrivilege
+                }
+                Some(_) => {
+                    undecided.push(rule);
+                    continue;
+                }
+            };
+            skipped.push(SkippedRule {
+                rule: rule.rule_id(),
+                reason,
+            });
+        }
+
+        // Keep sweeping over the rules, deciding any whose dependencies have
+        // all been decided, until nothing changes.
+        let mut runnable: Vec<Box<dyn Rule>> = Vec::new();
+        let mut runnable_ids: HashSet<RuleId> = already_run.iter().cloned().collect();
+        loop {
+            let undecided_count = undecided.len();
+            let skipped_ids: HashSet<RuleId> =
+                skipped.iter().map(|skip| skip.rule.clone()).collect();
+            let mut still_undecided = Vec::new();
+            for rule in undecided {
+                let dependencies = rule.dependencies();
+                let reason = dependencies.iter().find_map(|dependency| {
+                    if !known.contains(dependency) {
+                        Some(SkipReason::MissingDependency(dependency.clone()))
+                    } else if skipped_ids.contains(dependency) {
+                        Some(SkipReason::DependencySkipped(dependency.clone()))
                     } else {
-                        testplan.to_run.push(rule);
-                        testplan
+                        None
                     }
+                });
+                if let Some(reason) = reason {
+                    skipped.push(SkippedRule {
+                        rule: rule.rule_id(),
+                        reason,
+                    });
+                } else if dependencies
+                    .iter()
+                    .all(|dependency| runnable_ids.contains(dependency))
+                {
+                    runnable_ids.insert(rule.rule_id());
+                    runnable.push(rule);
                 } else {
-                    testplan
+                    still_undecided.push(rule);
                 }
-            },
-        )
+            }
+            let progressed = still_undecided.len() < undecided_count;
+            undecided = still_undecided;
+            if !progressed {
+                break;
+            }
+        }
+        // Anything left waits on itself through some loop
+        for rule in undecided {
+            skipped.push(SkippedRule {
+                rule: rule.rule_id(),
+                reason: SkipReason::DependencyCycle,
+            });
+        }
+
+        // Blocked rules keep this order as they become unblocked so every
+        // stage runs the most likely rules first.
+        runnable.sort_by_cached_key(|rule| likeliness_on_port(rule.as_ref(), port));
+        let (to_run, blocked): (Vec<_>, Vec<_>) = runnable
+            .into_iter()
+            .map(|rule| {
+                let mut dependencies = rule.dependencies();
+                dependencies.retain(|dependency| !already_run.contains(dependency));
+                (rule, dependencies)
+            })
+            .partition(|(_, dependencies)| dependencies.is_empty());
+        for skip in &skipped {
+            debug!(
+                "Skipping rule {} on port {}: {:?}",
+                skip.rule, port, skip.reason
+            );
+        }
+        PortTestPlan {
+            to_run: to_run.into_iter().map(|(rule, _)| rule).collect(),
+            blocked,
+            skipped,
+        }
     }
 
-    pub fn build_next_stage_plan(mut self, mut successfully_run: Vec<RuleId>) -> Self {
-        self.to_run = Vec::new();
-        // First let's get a list of new rules to evaluate
-        let mut possibly_unblocked_rules = Vec::new();
-        for rule_id in &successfully_run {
-            if let Some(mut rules) = self.blocked.remove(rule_id) {
-                possibly_unblocked_rules.append(&mut rules);
+    /// Move on to the next batch of rules now that the last batch finished.
+    /// Rules depending on one that failed are skipped along with anything
+    /// depending on them.
+    pub fn build_next_stage_plan(
+        mut self,
+        successfully_run: Vec<RuleId>,
+        failed: Vec<RuleId>,
+    ) -> Self {
+        let failed: HashSet<RuleId> = failed.into_iter().collect();
+        let mut pruned = HashSet::new();
+        loop {
+            let (to_prune, blocked): (Vec<_>, Vec<_>) =
+                self.blocked.into_iter().partition(|(_, waiting_on)| {
+                    waiting_on.iter().any(|dependency| {
+                        failed.contains(dependency) || pruned.contains(dependency)
+                    })
+                });
+            self.blocked = blocked;
+            if to_prune.is_empty() {
+                break;
+            }
+            for (rule, waiting_on) in to_prune {
+                let reason = match waiting_on
+                    .iter()
+                    .find(|dependency| failed.contains(*dependency))
+                {
+                    Some(dependency) => SkipReason::DependencyFailed(dependency.clone()),
+                    None => SkipReason::DependencySkipped(
+                        waiting_on
+                            .iter()
+                            .find(|dependency| pruned.contains(*dependency))
+                            .cloned()
+                            .expect("The rule was pruned because of one of its dependencies"),
+                    ),
+                };
+                pruned.insert(rule.rule_id());
+                self.skipped.push(SkippedRule {
+                    rule: rule.rule_id(),
+                    reason,
+                });
             }
         }
-        // Add these successfully run rules to our list of already run rules
-        self.already_successfully_run.append(&mut successfully_run);
 
-        for possibly_unblocked_rule in possibly_unblocked_rules {
-            let unsatisfied_deps = possibly_unblocked_rule
-                .dependencies()
-                .iter()
-                .filter(|rule_id| !self.already_successfully_run.contains(rule_id))
-                .cloned()
-                .collect::<Vec<RuleId>>();
-            if let Some(first_unmet_dep) = unsatisfied_deps.first() {
-                // There was some unmet dep.  Let's file this away as blocked under the first
-                // rule in the list.  When it is finished we will check again
-                self.blocked
-                    .entry(first_unmet_dep.clone().clone())
-                    .or_default()
-                    .push(possibly_unblocked_rule);
+        self.to_run = Vec::new();
+        let mut still_blocked = Vec::new();
+        for (rule, mut waiting_on) in self.blocked {
+            waiting_on.retain(|dependency| !successfully_run.contains(dependency));
+            if waiting_on.is_empty() {
+                self.to_run.push(rule);
             } else {
-                // There are no unmet deps, let's queue it to run.
-                self.to_run.push(possibly_unblocked_rule);
+                still_blocked.push((rule, waiting_on));
             }
         }
+        self.blocked = still_blocked;
         self
     }
 
@@ -87,4 +245,361 @@ impl PortTestPlan {
     pub fn rules_to_run(&self) -> &[Box<dyn Rule>] {
         &self.to_run
     }
+
+    /// Every rule that was skipped so far and why.
+    pub fn into_skipped(self) -> Vec<SkippedRule> {
+        self.skipped
+    }
+}
+
+/// How likely the rule's service is to be on the port, going by the most
+/// likely of the rule's port hints that cover it.  `None` if none of them do.
+fn likeliness_on_port(rule: &dyn Rule, port: u16) -> Option<PortLikeliness> {
+    rule.port_hints()
+        .iter()
+        .filter(|port_hint| port_hint.port_range().contains(&port))
+        .map(|port_hint| port_hint.likeliness())
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use crate::service_detection::{
+        framework::{
+            PortHint, PortLikeliness, Rule, RuleClosure, RuleId, RuleLoudness,
+            ServiceDetectionIntensity,
+        },
+        test_plan::{PlanSettings, PortTestPlan, SkipReason, SkippedRule},
+    };
+
+    #[derive(Clone, Debug)]
+    struct TestRule {
+        name: &'static str,
+        port: Option<u16>,
+        likeliness: PortLikeliness,
+        loudness: RuleLoudness,
+        privileged: bool,
+        dependencies: Vec<&'static str>,
+    }
+
+    impl Rule for TestRule {
+        fn rule_id(&self) -> RuleId {
+            RuleId::named(self.name)
+        }
+
+        fn dependencies(&self) -> Vec<RuleId> {
+            self.dependencies
+                .iter()
+                .map(|dep| RuleId::named(*dep))
+                .collect()
+        }
+
+        fn port_hints(&self) -> Vec<PortHint> {
+            match self.port {
+                Some(port) => vec![PortHint::new(port, self.likeliness)],
+                None => vec![PortHint::new_from_range(
+                    Range {
+                        start: u16::MIN,
+                        end: u16::MAX,
+                    },
+                    self.likeliness,
+                )],
+            }
+        }
+
+        fn loudness(&self) -> RuleLoudness {
+            self.loudness
+        }
+
+        fn requires_privileged_access(&self) -> bool {
+            self.privileged
+        }
+
+        fn get_execution_method(&self) -> RuleClosure {
+            unimplemented!()
+        }
+    }
+
+    fn rule(name: &'static str, port: Option<u16>, dependencies: &[&'static str]) -> Box<dyn Rule> {
+        Box::new(TestRule {
+            name,
+            port,
+            likeliness: PortLikeliness::Standard,
+            loudness: RuleLoudness::Quiet,
+            privileged: false,
+            dependencies: dependencies.to_vec(),
+        })
+    }
+
+    fn names(rules: &[Box<dyn Rule>]) -> Vec<String> {
+        let mut names: Vec<String> = rules
+            .iter()
+            .map(|rule| rule.rule_id().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn skip(name: &'static str, reason: SkipReason) -> SkippedRule {
+        SkippedRule {
+            rule: RuleId::named(name),
+            reason,
+        }
+    }
+
+    #[test]
+    fn test_plan_skips_rules_that_can_never_run() {
+        let plan = PortTestPlan::new(
+            80,
+            vec![
+                rule("root", None, &[]),
+                rule("ssh", Some(22), &[]),
+                rule("needs_ssh", None, &["ssh"]),
+                rule("needs_needs_ssh", None, &["needs_ssh"]),
+                rule("needs_nothing_real", None, &["ghost"]),
+                rule("loop_a", None, &["loop_b"]),
+                rule("loop_b", None, &["loop_a"]),
+                rule("needs_loop", None, &["root", "loop_a"]),
+                rule("needs_root", None, &["root"]),
+            ],
+            &PlanSettings::default(),
+            &[],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["root"]);
+        let plan = plan.build_next_stage_plan(vec![RuleId::named("root")], vec![]);
+        assert_eq!(names(plan.rules_to_run()), vec!["needs_root"]);
+        let plan = plan.build_next_stage_plan(vec![RuleId::named("needs_root")], vec![]);
+        assert!(!plan.has_actions_to_run());
+
+        let skipped = plan.into_skipped();
+        assert_eq!(skipped.len(), 7);
+        for expected in [
+            skip("ssh", SkipReason::NotApplicable),
+            skip(
+                "needs_ssh",
+                SkipReason::DependencySkipped(RuleId::named("ssh")),
+            ),
+            skip(
+                "needs_needs_ssh",
+                SkipReason::DependencySkipped(RuleId::named("needs_ssh")),
+            ),
+            skip(
+                "needs_nothing_real",
+                SkipReason::MissingDependency(RuleId::named("ghost")),
+            ),
+            skip("loop_a", SkipReason::DependencyCycle),
+            skip("loop_b", SkipReason::DependencyCycle),
+            skip("needs_loop", SkipReason::DependencyCycle),
+        ] {
+            assert!(skipped.contains(&expected), "{expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_plan_respects_loudness_budget() {
+        let loud = Box::new(TestRule {
+            name: "loud",
+            port: None,
+            likeliness: PortLikeliness::Standard,
+            loudness: RuleLoudness::Noisy,
+            privileged: false,
+            dependencies: vec![],
+        });
+        let rules = || {
+            vec![
+                rule("quiet", None, &[]),
+                loud.clone() as Box<dyn Rule>,
+                rule("needs_loud", None, &["loud"]),
+            ]
+        };
+        let plan = PortTestPlan::new(
+            80,
+            rules(),
+            &PlanSettings {
+                max_loudness: RuleLoudness::Standard,
+                ..PlanSettings::default()
+            },
+            &[],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["quiet"]);
+        assert_eq!(
+            plan.into_skipped(),
+            vec![
+                skip("loud", SkipReason::TooLoud),
+                skip(
+                    "needs_loud",
+                    SkipReason::DependencySkipped(RuleId::named("loud"))
+                ),
+            ]
+        );
+
+        let plan = PortTestPlan::new(80, rules(), &PlanSettings::default(), &[]);
+        assert_eq!(names(plan.rules_to_run()), vec!["loud", "quiet"]);
+    }
+
+    #[test]
+    fn test_plan_respects_intensity_and_orders_by_likeliness() {
+        let likely =
+            |name: &'static str, likeliness: PortLikeliness, dependencies: &[&'static str]| {
+                Box::new(TestRule {
+                    name,
+                    port: Some(80),
+                    likeliness,
+                    loudness: RuleLoudness::Quiet,
+                    privileged: false,
+                    dependencies: dependencies.to_vec(),
+                }) as Box<dyn Rule>
+            };
+        let rules = || {
+            vec![
+                likely("rare", PortLikeliness::Rare, &[]),
+                likely("unusual", PortLikeliness::Unusual, &[]),
+                likely("standard", PortLikeliness::Standard, &[]),
+                likely("common", PortLikeliness::Common, &[]),
+                likely("needs_rare", PortLikeliness::Standard, &["rare"]),
+            ]
+        };
+        let settings = |intensity: ServiceDetectionIntensity| PlanSettings {
+            max_likeliness: intensity.max_likeliness(),
+            ..PlanSettings::default()
+        };
+
+        let plan = PortTestPlan::new(80, rules(), &settings(ServiceDetectionIntensity::Low), &[]);
+        assert_eq!(names(plan.rules_to_run()), vec!["standard"]);
+        assert_eq!(
+            plan.into_skipped(),
+            vec![
+                skip("rare", SkipReason::TooUnlikely),
+                skip("unusual", SkipReason::TooUnlikely),
+                skip("common", SkipReason::TooUnlikely),
+                skip(
+                    "needs_rare",
+                    SkipReason::DependencySkipped(RuleId::named("rare"))
+                ),
+            ]
+        );
+
+        let plan = PortTestPlan::new(80, rules(), &settings(ServiceDetectionIntensity::High), &[]);
+        assert_eq!(
+            names(plan.rules_to_run()),
+            vec!["common", "standard", "unusual"]
+        );
+
+        // Most likely first rather than sorted by name
+        let plan = PortTestPlan::new(
+            80,
+            rules(),
+            &settings(ServiceDetectionIntensity::Exhaustive),
+            &[],
+        );
+        let in_order: Vec<String> = plan
+            .rules_to_run()
+            .iter()
+            .map(|rule| rule.rule_id().to_string())
+            .collect();
+        assert_eq!(in_order, vec!["standard", "common", "unusual", "rare"]);
+    }
+
+    #[test]
+    fn test_plan_skips_privileged_rules_without_privilege() {
+        let privileged = Box::new(TestRule {
+            name: "raw",
+            port: None,
+            likeliness: PortLikeliness::Standard,
+            loudness: RuleLoudness::Quiet,
+            privileged: true,
+            dependencies: vec![],
+        });
+        let rules = || {
+            vec![
+                rule("plain", None, &[]),
+                privileged.clone() as Box<dyn Rule>,
+                rule("needs_raw", None, &["raw"]),
+            ]
+        };
+        let plan = PortTestPlan::new(
+            80,
+            rules(),
+            &PlanSettings {
+                privileged: false,
+                ..PlanSettings::default()
+            },
+            &[],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["plain"]);
+        assert_eq!(
+            plan.into_skipped(),
+            vec![
+                skip("raw", SkipReason::RequiresPrivilege),
+                skip(
+                    "needs_raw",
+                    SkipReason::DependencySkipped(RuleId::named("raw"))
+                ),
+            ]
+        );
+
+        let plan = PortTestPlan::new(80, rules(), &PlanSettings::default(), &[]);
+        assert_eq!(names(plan.rules_to_run()), vec!["plain", "raw"]);
+    }
+
+    #[test]
+    fn test_plan_reuses_host_rules_that_already_ran() {
+        let plan = PortTestPlan::new(
+            80,
+            vec![
+                rule("host", None, &[]),
+                rule("port", None, &[]),
+                rule("needs_host", None, &["host"]),
+                rule("needs_both", None, &["host", "port"]),
+            ],
+            &PlanSettings::default(),
+            &[RuleId::named("host")],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["needs_host", "port"]);
+        let plan = plan.build_next_stage_plan(
+            vec![RuleId::named("needs_host"), RuleId::named("port")],
+            vec![],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["needs_both"]);
+        let plan = plan.build_next_stage_plan(vec![RuleId::named("needs_both")], vec![]);
+        assert!(!plan.has_actions_to_run());
+        assert!(plan.into_skipped().is_empty());
+    }
+
+    #[test]
+    fn test_plan_prunes_dependents_of_failed_rules() {
+        let plan = PortTestPlan::new(
+            80,
+            vec![
+                rule("flaky", None, &[]),
+                rule("fine", None, &[]),
+                rule("needs_flaky", None, &["flaky", "fine"]),
+                rule("needs_needs_flaky", None, &["needs_flaky"]),
+                rule("needs_fine", None, &["fine"]),
+            ],
+            &PlanSettings::default(),
+            &[],
+        );
+        assert_eq!(names(plan.rules_to_run()), vec!["fine", "flaky"]);
+        let plan =
+            plan.build_next_stage_plan(vec![RuleId::named("fine")], vec![RuleId::named("flaky")]);
+        assert_eq!(names(plan.rules_to_run()), vec!["needs_fine"]);
+        let plan = plan.build_next_stage_plan(vec![RuleId::named("needs_fine")], vec![]);
+        assert!(!plan.has_actions_to_run());
+        assert_eq!(
+            plan.into_skipped(),
+            vec![
+                skip(
+                    "needs_flaky",
+                    SkipReason::DependencyFailed(RuleId::named("flaky"))
+                ),
+                skip(
+                    "needs_needs_flaky",
+                    SkipReason::DependencySkipped(RuleId::named("needs_flaky"))
+                ),
+            ]
+        );
+    }
 }
@@ -7,17 +7,18 @@ use std::{
 };
 
 use futures::{stream, Stream};
-use ipnet::IpNet;
+use ipnet::{IpNet, Ipv6Net};
 use rand::{seq::SliceRandom, thread_rng};
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{err::PortscanErr, report::Report};
+use crate::{discovery::ndp::needs_multicast_discovery, err::PortscanErr, report::Report};
 
 /// This structure represents an argument into the port scanner itself.  This
 /// will be broken down into individual instances almost immediately.  The
 /// [`TargetInstance`] structure is what the internals will actually work
 /// on
-#[derive(Clone, Eq, Debug, PartialEq, Hash)]
+#[derive(Clone, Eq, Debug, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Target {
     /// One IP address.  IPv4 or IPv6
     IP(IpAddr),
@@ -112,6 +113,22 @@ impl TargetInstance {
     }
 }
 
+/// Pull out the IPv6 networks that are too large to enumerate.  Their hosts
+/// have to be discovered instead.
+pub(crate) fn split_large_networks(targets: Vec<Target>) -> (Vec<Target>, Vec<Ipv6Net>) {
+    let mut remaining = vec![];
+    let mut large = vec![];
+    for target in targets {
+        match target {
+            Target::Network(IpNet::V6(network)) if needs_multicast_discovery(&network) => {
+                large.push(network)
+            }
+            target => remaining.push(target),
+        }
+    }
+    (remaining, large)
+}
+
 #[instrument(level = "trace")]
 pub(crate) fn targets_to_instance_stream(
     targets: Vec<Target>,
@@ -156,3 +173,23 @@ pub(crate) fn targets_to_instance_stream(
     instances.shuffle(&mut thread_rng());
     (stream::iter(instances), reports)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::target::{split_large_networks, Target};
+
+    #[test]
+    fn test_split_large_networks() {
+        let targets = vec![
+            Target::Network("fd00::/64".parse().unwrap()),
+            Target::Network("fd01::/120".parse().unwrap()),
+            Target::Network("10.0.0.0/8".parse().unwrap()),
+            Target::IP("fd00::1".parse::<IpAddr>().unwrap()),
+        ];
+        let (remaining, large) = split_large_networks(targets);
+        assert_eq!(large, vec!["fd00::/64".parse().unwrap()]);
+        assert_eq!(remaining.len(), 3);
+    }
+}
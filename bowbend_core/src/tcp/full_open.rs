@@ -4,9 +4,16 @@
 //! The big advantage is that it doesn't need privileged access to open raw
 //! sockets, so can be run as normal user
 
-use std::{collections::HashMap, io, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use futures::{future::join_all, FutureExt, Stream, StreamExt};
+use futures::{future::join_all, Stream, StreamExt};
 use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use tokio::{
     net::TcpStream,
@@ -17,7 +24,7 @@ use tokio::{
 use tracing::instrument;
 
 use crate::{
-    icmp::{PingResult, PingResultType},
+    discovery::{HostDiscoveryPolicy, HostDiscoveryResults},
     report::{PortReport, PortStatus, Report, ReportContents},
     stream,
     stream::FuturesUnordered,
@@ -26,20 +33,16 @@ use crate::{
 
 #[instrument(level = "trace", skip(input_stream))]
 pub(crate) async fn full_open_port_scan(
-    mut input_stream: impl Stream<Item = (TargetInstance, Option<PingResult>)> + Unpin,
+    mut input_stream: impl Stream<Item = (TargetInstance, HostDiscoveryResults)> + Unpin,
     port_list: Vec<u16>,
     semaphore: Arc<Semaphore>,
     throttle_range: Option<Range<u64>>,
+    policy: HostDiscoveryPolicy,
 ) -> impl Stream<Item = Report> {
     let futures = FuturesUnordered::new();
     let mut skipped = Vec::new();
     while let Some(target) = input_stream.next().await {
-        let should_scan = target
-            .1
-            .as_ref()
-            .map(|x| !matches!(x.result_type, PingResultType::Error(_)))
-            .unwrap_or(true);
-        if should_scan {
+        if target.1.should_scan(policy) {
             let port_list = port_list.clone();
             let throttle_range = throttle_range.clone();
             let semaphore = semaphore.clone();
@@ -52,10 +55,7 @@ pub(crate) async fn full_open_port_scan(
             skipped.push(Report {
                 target: target.0.clone().into(),
                 instance: Some(target.0),
-                contents: Ok(ReportContents {
-                    icmp: target.1,
-                    ports: None,
-                }),
+                contents: Ok(ReportContents::new(target.1, None)),
             })
         }
     }
@@ -75,7 +75,7 @@ pub(crate) async fn full_open_port_scan(
 #[instrument(level = "trace")]
 async fn scan_host(
     target: TargetInstance,
-    ping_result: Option<PingResult>,
+    discovery: HostDiscoveryResults,
     mut ports: Vec<u16>,
     throttle_range: Option<Range<u64>>,
 ) -> Report {
@@ -85,48 +85,86 @@ async fn scan_host(
     ports.shuffle(&mut rng);
     for port in ports {
         let socket_addr = SocketAddr::new(ip, port);
-        let connect_future = TcpStream::connect(socket_addr);
-        let timout_future =
-            timeout(Duration::from_millis(500), connect_future).map(move |result| (port, result));
+        // The timestamps are captured inside the future so they are taken when the
+        // connection is actually attempted, not when the future is built.
+        let timout_future = async move {
+            let probe_sent = SystemTime::now();
+            let start = Instant::now();
+            let result = timeout(Duration::from_millis(500), TcpStream::connect(socket_addr)).await;
+            (port, probe_sent, start.elapsed(), result)
+        };
         connection_futures.push(timout_future);
         if let Some(range) = throttle_range.clone() {
             sleep(Duration::from_millis(rng.gen_range(range))).await;
         }
     }
-    let results: Vec<(u16, Result<io::Result<_>, Elapsed>)> = join_all(connection_futures).await;
-    let ports: HashMap<u16, PortReport> =
-        results
-            .iter()
-            .fold(HashMap::new(), |mut map, (port, result)| match result {
-                Ok(Ok(_)) => {
-                    map.insert(
-                        *port,
-                        PortReport {
-                            port: *port,
-                            status: PortStatus::Open,
-                            service_detection_conclusions: None,
-                        },
-                    );
-                    map
-                }
-                _ => {
-                    map.insert(
-                        *port,
-                        PortReport {
-                            port: *port,
-                            status: PortStatus::Closed,
-                            service_detection_conclusions: None,
-                        },
-                    );
-                    map
-                }
-            });
+    let results: Vec<(u16, SystemTime, Duration, Result<io::Result<_>, Elapsed>)> =
+        join_all(connection_futures).await;
+    let ports: HashMap<u16, PortReport> = results
+        .iter()
+        .map(|(port, probe_sent, elapsed, result)| {
+            let status = match result {
+                Ok(Ok(_)) => PortStatus::Open,
+                _ => PortStatus::Closed,
+            };
+            // A timeout means we never heard anything back.  Anything else, even a
+            // refused connection, is some kind of response from the host.
+            let connect_latency = result.as_ref().ok().map(|_| *elapsed);
+            (
+                *port,
+                PortReport {
+                    port: *port,
+                    status,
+                    service_detection_conclusions: None,
+                    best_service_guess: None,
+                    skipped_rules: None,
+                    failed_rules: None,
+                    service_detection_evidence: None,
+                    tls_enabled: None,
+                    probe_sent: *probe_sent,
+                    response_received: connect_latency.map(|latency| *probe_sent + latency),
+                    connect_latency,
+                },
+            )
+        })
+        .collect();
     Report {
         target: target.clone().into(),
         instance: Some(target),
-        contents: Ok(ReportContents {
-            icmp: ping_result,
-            ports: Some(ports),
-        }),
+        contents: Ok(ReportContents::new(discovery, Some(ports))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        discovery::HostDiscoveryResults, report::PortStatus, target::TargetInstance,
+        tcp::full_open::scan_host,
+    };
+
+    #[tokio::test]
+    async fn test_port_timing_is_recorded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let target = TargetInstance::IP(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let report = scan_host(
+            target,
+            HostDiscoveryResults::default(),
+            vec![open_port],
+            None,
+        )
+        .await;
+        let ports = report.contents.unwrap().ports.unwrap();
+        let port_report = ports.get(&open_port).unwrap();
+        assert_eq!(port_report.status, PortStatus::Open);
+        let latency = port_report.connect_latency.unwrap();
+        assert_eq!(
+            port_report.response_received.unwrap(),
+            port_report.probe_sent + latency
+        );
     }
 }
@@ -0,0 +1,549 @@
+//! Traceroute finds the routers between us and a host.  We send a probe for
+//! every TTL up to a limit and each router that drops one because it ran out
+//! of hops tells us about it with an ICMP time exceeded message.  This is
+//! mostly useful for working out where a host that looks filtered is being
+//! blocked.  Reading the ICMP messages needs a raw socket so this requires
+//! privileged access.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures::{
+    stream::{select, FuturesUnordered},
+    Stream, StreamExt,
+};
+use rand::random;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use tokio::{
+    io::unix::AsyncFd,
+    net::{TcpSocket, UdpSocket},
+    sync::Semaphore,
+    task,
+    time::{timeout, timeout_at},
+};
+use tracing::{debug, instrument};
+
+use crate::{
+    icmp::{
+        icmp_listener::{read_icmp, RawIcmpMessage},
+        packet::{EchoRequest, IcmpMessage, IcmpV4, IcmpV6, IpDatagram, Proto, HEADER_SIZE},
+        IcmpSocketKind,
+    },
+    report::Report,
+    utils::raw_socket::send_to,
+    ConfigBuilder, PortscanErr,
+};
+
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// The kind of probe to trace the route with.  Firewalls often treat these
+/// differently so the one that gets furthest depends on the network.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TracerouteMethod {
+    /// Send ICMP echo requests.
+    Icmp,
+    /// Send empty UDP datagrams to a port.  The target answers with an ICMP
+    /// port unreachable if nothing is listening.
+    Udp {
+        /// The port to send to
+        port: u16,
+    },
+    /// Attempt TCP connections to a port.  This tends to get through
+    /// firewalls that let traffic to that port in.
+    Tcp {
+        /// The port to connect to
+        port: u16,
+    },
+}
+
+/// The path to a host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracerouteResult {
+    /// Every hop up to the target, or up to the last router that answered if
+    /// we never reached it.  The first entry is the first hop.
+    pub hops: Vec<TracerouteHop>,
+    /// Whether any of our probes made it all the way to the target.
+    pub reached_target: bool,
+}
+
+/// A single hop along the path to a host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TracerouteHop {
+    /// The TTL, or hop limit for IPv6, of the probe that found this hop
+    pub ttl: u8,
+    /// The router at this hop.  This is `None` if nothing answered.
+    pub router: Option<IpAddr>,
+    /// How long it took the router to answer
+    pub round_trip_time: Option<Duration>,
+}
+
+/// Everything needed to run a traceroute against each host.
+#[derive(Clone, Debug)]
+pub(crate) struct TracerouteSettings {
+    pub method: TracerouteMethod,
+    pub max_hops: u8,
+    /// How long to wait for answers after the last probe is sent
+    pub timeout: Duration,
+}
+
+impl TracerouteSettings {
+    /// This is `None` unless traceroute was turned on.
+    pub fn from_config(config: &ConfigBuilder) -> Option<Self> {
+        config.traceroute.clone().map(|method| TracerouteSettings {
+            method,
+            // A TTL of zero would never leave the host
+            max_hops: config.traceroute_max_hops.max(1),
+            timeout: config.traceroute_timeout,
+        })
+    }
+}
+
+/// How we match an ICMP message back up to the probe that caused it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum ProbeKey {
+    /// An echo request.  The sequence number is the TTL.
+    Echo { identity: u16, sequence: u16 },
+    /// A UDP or TCP probe, identified by its source port.
+    Port(u16),
+}
+
+/// Something we heard back about one of our probes.
+#[derive(Debug, Eq, PartialEq)]
+enum Answer {
+    /// A router along the way answered.  If it told us the target is
+    /// unreachable then the path ends with it.
+    Hop {
+        key: ProbeKey,
+        router: IpAddr,
+        end_of_path: bool,
+    },
+    /// The probe made it to the target.
+    Reached { key: ProbeKey },
+}
+
+/// Run a traceroute against every host we have a report for and attach the
+/// results.
+#[instrument(level = "trace", skip(report_stream))]
+pub(crate) async fn run_traceroute(
+    mut report_stream: impl Stream<Item = Report> + Unpin,
+    settings: TracerouteSettings,
+    semaphore: Arc<Semaphore>,
+) -> Result<impl Stream<Item = Report>, PortscanErr> {
+    // Fail up front rather than for every host
+    Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
+        .map_err(|_| PortscanErr::InsufficientPermission)?;
+
+    let futures = FuturesUnordered::new();
+    while let Some(mut report) = report_stream.next().await {
+        let settings = settings.clone();
+        let semaphore = semaphore.clone();
+        futures.push(task::spawn(async move {
+            if let (Some(instance), Ok(contents)) = (&report.instance, &mut report.contents) {
+                let _permit = semaphore.acquire_owned().await;
+                match trace_route(instance.get_ip(), &settings).await {
+                    Ok(result) => contents.traceroute = Some(result),
+                    Err(e) => debug!("Traceroute to {:?} failed {:?}", instance, e),
+                }
+            }
+            report
+        }));
+    }
+    // Same as the full open port scan, a failure here means the task was
+    // canceled or panicked.
+    Ok(futures.map(|x| x.unwrap()))
+}
+
+#[instrument(level = "trace")]
+async fn trace_route(
+    target: IpAddr,
+    settings: &TracerouteSettings,
+) -> io::Result<TracerouteResult> {
+    let listener = match target {
+        IpAddr::V4(_) => Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?,
+        IpAddr::V6(_) => Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?,
+    };
+    let icmp_answers = read_icmp(listener, IcmpSocketKind::Raw).filter_map(move |message| {
+        let answer = match message {
+            Ok(message) => classify(target, &message).map(|answer| (answer, message.time_received)),
+            Err(e) => {
+                debug!("Failed to read an ICMP message {:?}", e);
+                None
+            }
+        };
+        futures::future::ready(answer)
+    });
+
+    let mut probes: HashMap<ProbeKey, (u8, SystemTime)> = HashMap::new();
+    // TCP probes can reach the target without any ICMP message, so their
+    // connection attempts are watched alongside the listener.
+    let connections = FuturesUnordered::new();
+    // The UDP sockets are held until we're done so their ports aren't reused
+    let mut udp_sockets = Vec::new();
+    let icmp_sender = match settings.method {
+        TracerouteMethod::Icmp => Some(match target {
+            IpAddr::V4(_) => Socket::new_raw(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?,
+            IpAddr::V6(_) => Socket::new_raw(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?,
+        }),
+        _ => None,
+    };
+    let identity: u16 = random();
+
+    for ttl in 1..=settings.max_hops {
+        let sent = SystemTime::now();
+        let key = match &settings.method {
+            TracerouteMethod::Icmp => {
+                // Only set for this method
+                let socket = icmp_sender.as_ref().unwrap();
+                set_hop_limit(&SockRef::from(socket), target, ttl)?;
+                send_echo(socket, target, identity, u16::from(ttl)).await?;
+                ProbeKey::Echo {
+                    identity,
+                    sequence: u16::from(ttl),
+                }
+            }
+            TracerouteMethod::Udp { port } => {
+                let socket = UdpSocket::bind(SocketAddr::new(unspecified(target), 0)).await?;
+                set_hop_limit(&SockRef::from(&socket), target, ttl)?;
+                socket.send_to(&[], SocketAddr::new(target, *port)).await?;
+                let key = ProbeKey::Port(socket.local_addr()?.port());
+                udp_sockets.push(socket);
+                key
+            }
+            TracerouteMethod::Tcp { port } => {
+                let socket = match target {
+                    IpAddr::V4(_) => TcpSocket::new_v4()?,
+                    IpAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                set_hop_limit(&SockRef::from(&socket), target, ttl)?;
+                // Binding up front gets us the source port before the SYN goes out
+                socket.bind(SocketAddr::new(unspecified(target), 0))?;
+                let key = ProbeKey::Port(socket.local_addr()?.port());
+                let destination = SocketAddr::new(target, *port);
+                let wait = settings.timeout;
+                connections.push(async move {
+                    match timeout(wait, socket.connect(destination)).await {
+                        // Either way the target itself answered
+                        Ok(Ok(_)) => Some((Answer::Reached { key }, SystemTime::now())),
+                        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            Some((Answer::Reached { key }, SystemTime::now()))
+                        }
+                        _ => None,
+                    }
+                });
+                key
+            }
+        };
+        probes.insert(key, (ttl, sent));
+    }
+
+    let connection_answers = connections.filter_map(futures::future::ready);
+    let mut answers_stream = select(icmp_answers.boxed(), connection_answers.boxed());
+    let deadline = tokio::time::Instant::now() + settings.timeout;
+    let mut answers: BTreeMap<u8, (IpAddr, Duration)> = BTreeMap::new();
+    let mut end_of_path: Option<u8> = None;
+    let mut reached_target = false;
+    while let Ok(Some((answer, received))) = timeout_at(deadline, answers_stream.next()).await {
+        let (key, router, ends_path, reached) = match answer {
+            Answer::Hop {
+                key,
+                router,
+                end_of_path,
+            } => (key, router, end_of_path, false),
+            Answer::Reached { key } => (key, target, true, true),
+        };
+        let (ttl, sent) = match probes.get(&key) {
+            Some(probe) => *probe,
+            None => continue,
+        };
+        // The clock can jump backwards while we wait
+        let round_trip_time = received.duration_since(sent).unwrap_or_default();
+        answers.entry(ttl).or_insert((router, round_trip_time));
+        if ends_path && !matches!(end_of_path, Some(end) if end <= ttl) {
+            end_of_path = Some(ttl);
+            reached_target = reached;
+        }
+        // Every hop before the end has answered so there is nothing left to
+        // wait for
+        if let Some(end) = end_of_path {
+            if (1..=end).all(|ttl| answers.contains_key(&ttl)) {
+                break;
+            }
+        }
+    }
+    drop(udp_sockets);
+    Ok(build_result(&answers, end_of_path, reached_target))
+}
+
+/// Line up the answers by TTL.  Silent hops after the last answer are
+/// dropped.
+fn build_result(
+    answers: &BTreeMap<u8, (IpAddr, Duration)>,
+    end_of_path: Option<u8>,
+    reached_target: bool,
+) -> TracerouteResult {
+    let last = end_of_path
+        .or_else(|| answers.keys().next_back().cloned())
+        .unwrap_or(0);
+    let hops = (1..=last)
+        .map(|ttl| TracerouteHop {
+            ttl,
+            router: answers.get(&ttl).map(|(router, _)| *router),
+            round_trip_time: answers.get(&ttl).map(|(_, rtt)| *rtt),
+        })
+        .collect();
+    TracerouteResult {
+        hops,
+        reached_target,
+    }
+}
+
+/// Work out what an ICMP message tells us about our probes to `target`.
+fn classify(target: IpAddr, message: &RawIcmpMessage) -> Option<Answer> {
+    match target {
+        IpAddr::V4(_) => classify_with::<IcmpV4>(target, message),
+        IpAddr::V6(_) => classify_with::<IcmpV6>(target, message),
+    }
+}
+
+fn classify_with<P: Proto>(target: IpAddr, message: &RawIcmpMessage) -> Option<Answer> {
+    match IcmpMessage::decode::<P>(&message.bytes).ok()? {
+        IcmpMessage::EchoReply(reply) if message.source == target => Some(Answer::Reached {
+            key: ProbeKey::Echo {
+                identity: reply.ident,
+                sequence: reply.seq_cnt,
+            },
+        }),
+        IcmpMessage::TimeExceeded(exceeded) if exceeded.original.destination == target => {
+            Some(Answer::Hop {
+                key: probe_key(&exceeded.original)?,
+                router: message.source,
+                end_of_path: false,
+            })
+        }
+        IcmpMessage::DestinationUnreachable(unreachable)
+            if unreachable.original.destination == target =>
+        {
+            let key = probe_key(&unreachable.original)?;
+            // The target telling us a port is closed still means we got there
+            if message.source == target {
+                Some(Answer::Reached { key })
+            } else {
+                Some(Answer::Hop {
+                    key,
+                    router: message.source,
+                    end_of_path: true,
+                })
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Find which of our probes an ICMP error message is about.
+fn probe_key(original: &IpDatagram) -> Option<ProbeKey> {
+    match original.protocol {
+        IP_PROTOCOL_TCP | IP_PROTOCOL_UDP => {
+            // The source port is the first thing in both headers
+            let port = original.payload.get(..2)?;
+            Some(ProbeKey::Port(u16::from_be_bytes([port[0], port[1]])))
+        }
+        _ => original
+            .echo_request()
+            .map(|(identity, sequence)| ProbeKey::Echo { identity, sequence }),
+    }
+}
+
+fn set_hop_limit(socket: &SockRef, target: IpAddr, ttl: u8) -> io::Result<()> {
+    match target {
+        IpAddr::V4(_) => socket.set_ttl(u32::from(ttl)),
+        IpAddr::V6(_) => socket.set_unicast_hops_v6(u32::from(ttl)),
+    }
+}
+
+fn unspecified(target: IpAddr) -> IpAddr {
+    match target {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+async fn send_echo(
+    socket: &Socket,
+    target: IpAddr,
+    identity: u16,
+    sequence: u16,
+) -> io::Result<()> {
+    let mut buffer = [0u8; HEADER_SIZE];
+    let request = EchoRequest {
+        ident: identity,
+        seq_cnt: sequence,
+        payload: &[],
+    };
+    match target {
+        IpAddr::V4(_) => request.encode::<IcmpV4>(&mut buffer)?,
+        IpAddr::V6(_) => request.encode::<IcmpV6>(&mut buffer)?,
+    }
+    let async_fd = AsyncFd::new(socket.as_raw_fd())?;
+    let destination: SockAddr = SocketAddr::new(target, 0).into();
+    send_to(async_fd, socket, destination, &buffer).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        icmp::{
+            icmp_listener::RawIcmpMessage,
+            packet::{write_checksum, EchoRequest, IcmpV4},
+        },
+        traceroute::{build_result, classify, Answer, ProbeKey, TracerouteHop},
+    };
+
+    const TARGET: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 9);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    /// An ICMPv4 error message embedding the start of a probe we sent.
+    fn icmp_error(type_: u8, code: u8, protocol: u8, probe: &[u8]) -> Vec<u8> {
+        let total_length = (20 + probe.len()) as u16;
+        let mut message = vec![type_, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&[0x45, 0]);
+        message.extend_from_slice(&total_length.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 0, 0, 1, protocol, 0, 0]);
+        message.extend_from_slice(&[10, 0, 0, 1]);
+        message.extend_from_slice(&TARGET.octets());
+        message.extend_from_slice(probe);
+        write_checksum(&mut message);
+        message
+    }
+
+    fn received(source: Ipv4Addr, bytes: Vec<u8>) -> RawIcmpMessage {
+        RawIcmpMessage {
+            source: source.into(),
+            bytes,
+            ttl: None,
+            time_received: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_classify_udp_probes() {
+        // Source port 33000 then destination port 33434
+        let probe = [0x80, 0xe8, 0x82, 0x9a, 0, 8, 0, 0];
+        let key = ProbeKey::Port(33000);
+
+        let exceeded = received(ROUTER, icmp_error(11, 0, 17, &probe));
+        assert_eq!(
+            classify(TARGET.into(), &exceeded),
+            Some(Answer::Hop {
+                key,
+                router: ROUTER.into(),
+                end_of_path: false
+            })
+        );
+        // A router telling us the host is unreachable ends the path
+        let unreachable = received(ROUTER, icmp_error(3, 1, 17, &probe));
+        assert_eq!(
+            classify(TARGET.into(), &unreachable),
+            Some(Answer::Hop {
+                key,
+                router: ROUTER.into(),
+                end_of_path: true
+            })
+        );
+        // The target itself saying the port is closed means we got there
+        let port_unreachable = received(TARGET, icmp_error(3, 3, 17, &probe));
+        assert_eq!(
+            classify(TARGET.into(), &port_unreachable),
+            Some(Answer::Reached { key })
+        );
+        // Errors about other hosts aren't ours
+        assert_eq!(classify(Ipv4Addr::new(10, 0, 0, 8).into(), &exceeded), None);
+    }
+
+    #[test]
+    fn test_classify_echo_probes() {
+        let mut probe = [0u8; 8];
+        EchoRequest {
+            ident: 77,
+            seq_cnt: 4,
+            payload: &[],
+        }
+        .encode::<IcmpV4>(&mut probe)
+        .unwrap();
+        let key = ProbeKey::Echo {
+            identity: 77,
+            sequence: 4,
+        };
+        let exceeded = received(ROUTER, icmp_error(11, 0, 1, &probe));
+        assert_eq!(
+            classify(TARGET.into(), &exceeded),
+            Some(Answer::Hop {
+                key,
+                router: ROUTER.into(),
+                end_of_path: false
+            })
+        );
+
+        let mut reply = probe.to_vec();
+        reply[0] = 0;
+        write_checksum(&mut reply);
+        assert_eq!(
+            classify(TARGET.into(), &received(TARGET, reply.clone())),
+            Some(Answer::Reached { key })
+        );
+        // A reply from anyone else isn't the end of our path
+        assert_eq!(classify(TARGET.into(), &received(ROUTER, reply)), None);
+    }
+
+    #[test]
+    fn test_build_result() {
+        let router: IpAddr = ROUTER.into();
+        let mut answers = BTreeMap::new();
+        answers.insert(1, (router, Duration::from_millis(1)));
+        answers.insert(3, (IpAddr::from(TARGET), Duration::from_millis(5)));
+
+        let result = build_result(&answers, Some(3), true);
+        assert!(result.reached_target);
+        assert_eq!(
+            result.hops,
+            vec![
+                TracerouteHop {
+                    ttl: 1,
+                    router: Some(router),
+                    round_trip_time: Some(Duration::from_millis(1))
+                },
+                TracerouteHop {
+                    ttl: 2,
+                    router: None,
+                    round_trip_time: None
+                },
+                TracerouteHop {
+                    ttl: 3,
+                    router: Some(TARGET.into()),
+                    round_trip_time: Some(Duration::from_millis(5))
+                },
+            ]
+        );
+
+        // Without reaching the target the path stops at the last router to
+        // answer
+        answers.remove(&3);
+        let result = build_result(&answers, None, false);
+        assert!(!result.reached_target);
+        assert_eq!(result.hops.len(), 1);
+        assert!(build_result(&BTreeMap::new(), None, false).hops.is_empty());
+    }
+}
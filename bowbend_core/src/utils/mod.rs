@@ -1,2 +1,3 @@
 pub(crate) mod downcast;
+pub(crate) mod raw_socket;
 pub(crate) mod throttle_stream;
@@ -0,0 +1,64 @@
+//! Helpers shared by everything that reads from or writes to raw sockets.
+//! socket2 only gives us blocking calls so these bridge them into tokio.
+
+use std::{
+    io,
+    mem::MaybeUninit,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use socket2::{SockAddr, Socket};
+use tokio::io::unix::AsyncFd;
+use tracing::instrument;
+
+#[tracing::instrument(level = "trace", skip(buffer))]
+pub(crate) async fn recv_from(
+    async_fd: &AsyncFd<i32>,
+    socket: &mut Socket,
+    buffer: &mut [MaybeUninit<u8>],
+) -> Result<(usize, SockAddr), io::Error> {
+    loop {
+        let mut read_guard = async_fd.readable().await?;
+        match read_guard.try_io(|_| socket.recv_from(buffer)) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[instrument(level = "trace")]
+pub(crate) async fn send_to(
+    async_fd: AsyncFd<i32>,
+    socket: &Socket,
+    destination: SockAddr,
+    buffer: &[u8],
+) -> io::Result<usize> {
+    loop {
+        let mut write_guard = async_fd.writable().await?;
+        match write_guard.try_io(|_| socket.send_to(buffer, &destination)) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+pub(crate) fn cast_as_maybe(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    // Here is documentation on why this extremely unsafe looking thing is actually
+    // safe: https://docs.rs/socket2/0.4.1/socket2/struct.Socket.html#safety
+    unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) }
+}
+
+/// Find the local address the OS would use to reach `destination`.  Anything
+/// that builds its own transport headers needs this for the checksum.
+/// Connecting a UDP socket doesn't send any packets, it just has the OS pick a
+/// route.
+pub(crate) fn local_address_for(destination: IpAddr) -> io::Result<IpAddr> {
+    let unspecified: IpAddr = match destination {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    // The port doesn't matter, nothing is sent
+    socket.connect(SocketAddr::new(destination, 9))?;
+    Ok(socket.local_addr()?.ip())
+}
@@ -2,14 +2,18 @@
 //! the FFI.  The core of the state is held in a type provided by
 //! `bowbend_core`. The methods exposed out of this opaque type across FFI are
 //! just simple bridges into the core configuration builder type.
-use std::ops::Range;
+use std::{ops::Range, time::Duration};
 
 use ::safer_ffi::prelude::*;
-use bowbend_core::ConfigBuilder as InternalConfigBuilder;
-use safer_ffi::{boxed::Box as FfiBox, slice::slice_ref};
+use bowbend_core::{
+    ConfigBuilder as InternalConfigBuilder, HostDiscoveryMethod,
+    HostDiscoveryPolicy as InternalHostDiscoveryPolicy, TracerouteMethod,
+};
+use safer_ffi::{boxed::Box as FfiBox, slice::slice_ref, string::str_ref};
 
 use crate::{
     result::{FfiResult, StatusCodes},
+    service_detection::{RuleLoudness, ServiceDetectionIntensity},
     target::Target,
 };
 
@@ -22,6 +26,36 @@ pub struct ConfigBuilder {
     contents: InternalConfigBuilder,
 }
 
+/// What to do with each host once host discovery has run.
+#[derive_ReprC]
+#[repr(i8)]
+#[derive(Clone, Copy, Debug)]
+pub enum HostDiscoveryPolicy {
+    /// Port scan every host unless every discovery method that ran failed to
+    /// even probe it.  Silence isn't enough to skip a host since plenty of
+    /// hosts ignore our probes.
+    ScanAll = 0,
+    /// Only port scan hosts that answered at least one discovery method.
+    ScanRespondersOnly = 1,
+    /// Port scan hosts that answered along with hosts no discovery method was
+    /// able to probe.  Only hosts that stayed silent are skipped.
+    ScanRespondersAndUnknown = 2,
+}
+
+impl From<HostDiscoveryPolicy> for InternalHostDiscoveryPolicy {
+    fn from(policy: HostDiscoveryPolicy) -> Self {
+        match policy {
+            HostDiscoveryPolicy::ScanAll => InternalHostDiscoveryPolicy::ScanAll,
+            HostDiscoveryPolicy::ScanRespondersOnly => {
+                InternalHostDiscoveryPolicy::ScanRespondersOnly
+            }
+            HostDiscoveryPolicy::ScanRespondersAndUnknown => {
+                InternalHostDiscoveryPolicy::ScanRespondersAndUnknown
+            }
+        }
+    }
+}
+
 /// Constructor for [`ConfigBuilder`]
 #[ffi_export]
 pub fn new_builder() -> FfiBox<ConfigBuilder> {
@@ -54,12 +88,184 @@ pub fn set_run_service_detection(builder: &mut ConfigBuilder, run_service_detect
         .set_run_service_detection(run_service_detection)
 }
 
+/// Set the loudest service detection rules we are willing to run.  Louder rules
+/// are skipped along with any rules depending on them.
+#[ffi_export]
+pub fn set_max_service_detection_loudness(builder: &mut ConfigBuilder, max_loudness: RuleLoudness) {
+    builder
+        .contents
+        .set_max_service_detection_loudness(max_loudness.into())
+}
+
+/// Set how hard service detection tries on each port.  Lower intensities only
+/// run rules for services that are likely to be on the port.
+#[ffi_export]
+pub fn set_service_detection_intensity(
+    builder: &mut ConfigBuilder,
+    intensity: ServiceDetectionIntensity,
+) {
+    builder
+        .contents
+        .set_service_detection_intensity(intensity.into())
+}
+
+/// Set how long, in milliseconds, a single service detection rule may run
+/// against a port before we give up on it.
+#[ffi_export]
+pub fn set_service_detection_rule_timeout(builder: &mut ConfigBuilder, timeout: u64) {
+    builder
+        .contents
+        .set_service_detection_rule_timeout(Duration::from_millis(timeout))
+}
+
+/// Set how many times to retry a service detection rule that failed with a
+/// transient IO error.
+#[ffi_export]
+pub fn set_service_detection_retries(builder: &mut ConfigBuilder, retries: u8) {
+    builder.contents.set_service_detection_retries(retries)
+}
+
+/// Set if each port should carry a record of every service detection rule run
+/// against it, how long it took and what it saw or why it failed.
+#[ffi_export]
+pub fn set_record_service_detection_evidence(builder: &mut ConfigBuilder, record_evidence: bool) {
+    builder
+        .contents
+        .set_record_service_detection_evidence(record_evidence)
+}
+
+/// Load service detection signatures in TOML on top of the built in ones.
+/// Probes replace any already loaded probe with the same name.  Nothing is
+/// loaded if any of the signatures are invalid.
+#[ffi_export]
+pub fn add_service_detection_signatures(
+    builder: &mut ConfigBuilder,
+    signatures: str_ref<'_>,
+) -> FfiResult<()> {
+    match std::str::from_utf8(signatures.as_bytes()) {
+        Ok(signatures) => match builder
+            .contents
+            .add_service_detection_signatures(signatures)
+        {
+            Ok(()) => FfiResult::ok(()),
+            Err(e) => e.into(),
+        },
+        Err(_) => FfiResult::err(StatusCodes::InvalidUTF8),
+    }
+}
+
+/// Load probes from the contents of an nmap `nmap-service-probes` file.  UDP
+/// probes and matches with regexes we can't compile are skipped.  Nothing is
+/// loaded if any line is malformed.
+#[ffi_export]
+pub fn add_nmap_service_probes(builder: &mut ConfigBuilder, probes: str_ref<'_>) -> FfiResult<()> {
+    match std::str::from_utf8(probes.as_bytes()) {
+        Ok(probes) => match builder.contents.add_nmap_service_probes(probes) {
+            Ok(()) => FfiResult::ok(()),
+            Err(e) => e.into(),
+        },
+        Err(_) => FfiResult::err(StatusCodes::InvalidUTF8),
+    }
+}
+
+/// Set if we should guess each host's OS from how it answered host discovery.
+/// This needs an ICMP echo or TCP SYN ping to have something to go on.
+#[ffi_export]
+pub fn set_run_os_detection(builder: &mut ConfigBuilder, run_os_detection: bool) {
+    builder.contents.set_run_os_detection(run_os_detection)
+}
+
 /// Set if we should ping each target before scanning or not.
 #[ffi_export]
 pub fn set_ping(builder: &mut ConfigBuilder, ping: bool) {
     builder.contents.set_ping(ping)
 }
 
+/// Check each target with ICMP echo requests before scanning it.
+#[ffi_export]
+pub fn add_icmp_echo_discovery(builder: &mut ConfigBuilder) {
+    builder
+        .contents
+        .add_host_discovery_method(HostDiscoveryMethod::IcmpEcho)
+}
+
+/// Check each target by sending a TCP SYN to each of the ports before scanning
+/// it.
+#[ffi_export]
+pub fn add_tcp_syn_discovery(builder: &mut ConfigBuilder, ports: slice_ref<'_, u16>) {
+    builder
+        .contents
+        .add_host_discovery_method(HostDiscoveryMethod::TcpSyn {
+            ports: ports.to_vec(),
+        })
+}
+
+/// Check each target by sending a TCP ACK to each of the ports before scanning
+/// it.
+#[ffi_export]
+pub fn add_tcp_ack_discovery(builder: &mut ConfigBuilder, ports: slice_ref<'_, u16>) {
+    builder
+        .contents
+        .add_host_discovery_method(HostDiscoveryMethod::TcpAck {
+            ports: ports.to_vec(),
+        })
+}
+
+/// Check each target by attempting a full TCP connection to each of the ports
+/// before scanning it.  This doesn't need privileged access.
+#[ffi_export]
+pub fn add_tcp_connect_discovery(builder: &mut ConfigBuilder, ports: slice_ref<'_, u16>) {
+    builder
+        .contents
+        .add_host_discovery_method(HostDiscoveryMethod::TcpConnect {
+            ports: ports.to_vec(),
+        })
+}
+
+/// Check each target on a directly attached subnet with an ARP request, or a
+/// Neighbor Solicitation for IPv6, before scanning it.
+#[ffi_export]
+pub fn add_arp_discovery(builder: &mut ConfigBuilder) {
+    builder
+        .contents
+        .add_host_discovery_method(HostDiscoveryMethod::Arp)
+}
+
+/// Remove every host discovery method, including ICMP echo.
+#[ffi_export]
+pub fn clear_host_discovery_methods(builder: &mut ConfigBuilder) {
+    builder.contents.set_host_discovery_methods(vec![])
+}
+
+/// Set which hosts get port scanned based on what host discovery found.
+#[ffi_export]
+pub fn set_host_discovery_policy(builder: &mut ConfigBuilder, policy: HostDiscoveryPolicy) {
+    builder.contents.set_host_discovery_policy(policy.into())
+}
+
+/// Set the number of echo requests sent to each target when pinging.
+#[ffi_export]
+pub fn set_ping_count(builder: &mut ConfigBuilder, ping_count: u16) {
+    builder.contents.set_ping_count(ping_count)
+}
+
+/// Set how long to wait between each round of echo requests, in milliseconds.
+#[ffi_export]
+pub fn set_ping_interval(builder: &mut ConfigBuilder, ping_interval: u64) {
+    builder
+        .contents
+        .set_ping_interval(Duration::from_millis(ping_interval))
+}
+
+/// Set how long to wait for replies after the last echo request is sent, in
+/// milliseconds.
+#[ffi_export]
+pub fn set_ping_timeout(builder: &mut ConfigBuilder, ping_timeout: u64) {
+    builder
+        .contents
+        .set_ping_timeout(Duration::from_millis(ping_timeout))
+}
+
 /// Enable or disable extremely detailed internal logging.  This is only useful
 /// for internal development.
 #[ffi_export]
@@ -85,6 +291,55 @@ pub fn set_throttle(builder: &mut ConfigBuilder, min: u64, max: u64) -> FfiResul
     }
 }
 
+/// Trace the route to every host using ICMP echo requests.  This needs
+/// privileged access.
+#[ffi_export]
+pub fn set_traceroute_icmp(builder: &mut ConfigBuilder) {
+    builder
+        .contents
+        .set_traceroute(Some(TracerouteMethod::Icmp))
+}
+
+/// Trace the route to every host using UDP datagrams sent to `port`.  This
+/// needs privileged access.
+#[ffi_export]
+pub fn set_traceroute_udp(builder: &mut ConfigBuilder, port: u16) {
+    builder
+        .contents
+        .set_traceroute(Some(TracerouteMethod::Udp { port }))
+}
+
+/// Trace the route to every host using TCP connection attempts to `port`.
+/// This needs privileged access.
+#[ffi_export]
+pub fn set_traceroute_tcp(builder: &mut ConfigBuilder, port: u16) {
+    builder
+        .contents
+        .set_traceroute(Some(TracerouteMethod::Tcp { port }))
+}
+
+/// Turn traceroute back off.
+#[ffi_export]
+pub fn clear_traceroute(builder: &mut ConfigBuilder) {
+    builder.contents.set_traceroute(None)
+}
+
+/// Set the highest TTL, or hop limit for IPv6, to probe with when tracing a
+/// route.
+#[ffi_export]
+pub fn set_traceroute_max_hops(builder: &mut ConfigBuilder, max_hops: u8) {
+    builder.contents.set_traceroute_max_hops(max_hops)
+}
+
+/// Set how long to wait for routers to answer after the last traceroute probe
+/// is sent, in milliseconds.
+#[ffi_export]
+pub fn set_traceroute_timeout(builder: &mut ConfigBuilder, timeout: u64) {
+    builder
+        .contents
+        .set_traceroute_timeout(Duration::from_millis(timeout))
+}
+
 /// Set the maximum number of in flight tasks for a port scan.  This is useful
 /// for limiting resource utilization.
 #[ffi_export]
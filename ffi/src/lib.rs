@@ -9,6 +9,7 @@ pub mod result;
 pub mod scan;
 pub mod service_detection;
 pub mod target;
+pub mod time;
 
 /// The following test function is necessary for the header generation.
 #[::safer_ffi::cfg_headers]
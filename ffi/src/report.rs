@@ -2,17 +2,25 @@ use std::collections::HashMap;
 
 use ::safer_ffi::prelude::*;
 use bowbend_core::{
-    PingResult as InternalPingResult, PingResultType as InternalPingResultType,
+    ArpResult as InternalArpResult, ArpResultType as InternalArpResultType,
+    OsFamily as InternalOsFamily, OsGuess as InternalOsGuess, PingResult as InternalPingResult,
+    PingResultType as InternalPingResultType, PingStatistics as InternalPingStatistics,
     PortReport as InternalPortReport, PortStatus as InternalPortStatus, PortscanErr,
     Report as InternalReport, ReportContents as InternalReportContents,
+    TcpPingResult as InternalTcpPingResult, TcpPingResultType as InternalTcpPingResultType,
+    TracerouteHop as InternalTracerouteHop, TracerouteResult as InternalTracerouteResult,
 };
 use safer_ffi::boxed::Box as FfiBox;
 
 use crate::{
     ip::Ip,
-    result::{FfiResult, StatusCodes},
-    service_detection::ServiceDetectionConclusion,
+    result::{FfiResult, IoError, StatusCodes},
+    service_detection::{
+        FailedRule, RuleEvidence, ServiceDetectionCertainty, ServiceDetectionConclusion,
+        SkippedRule,
+    },
     target::Target,
+    time::{Duration, Timestamp},
 };
 
 /// A final report for one host.  This is should contain everything the
@@ -39,6 +47,9 @@ impl From<InternalReport> for Report {
                 PortscanErr::InsufficientPermission => {
                     FfiResult::err(StatusCodes::InsufficientPermission)
                 }
+                PortscanErr::NetworkTooLarge => FfiResult::err(StatusCodes::NetworkTooLarge),
+                PortscanErr::InvalidSignature(_) => FfiResult::err(StatusCodes::InvalidSignature),
+                PortscanErr::InvalidRuleGraph(_) => FfiResult::err(StatusCodes::InvalidRuleGraph),
             },
         };
 
@@ -56,7 +67,13 @@ impl From<InternalReport> for Report {
 #[repr(opaque)]
 pub struct ReportContents {
     icmp: Option<FfiBox<PingResult>>,
+    tcp_syn_ping: Option<FfiBox<TcpPingResult>>,
+    tcp_ack_ping: Option<FfiBox<TcpPingResult>>,
+    tcp_connect_ping: Option<FfiBox<TcpPingResult>>,
+    arp: Option<FfiBox<ArpResult>>,
     ports: HashMap<u16, PortReport>,
+    traceroute: Option<FfiBox<Traceroute>>,
+    os_guess: Option<FfiBox<OsGuess>>,
 }
 
 #[ffi_export]
@@ -64,6 +81,36 @@ pub fn get_icmp_result(report_contents: &ReportContents) -> Option<&PingResult>
     report_contents.icmp.as_deref()
 }
 
+#[ffi_export]
+pub fn get_tcp_syn_ping_result(report_contents: &ReportContents) -> Option<&TcpPingResult> {
+    report_contents.tcp_syn_ping.as_deref()
+}
+
+#[ffi_export]
+pub fn get_tcp_ack_ping_result(report_contents: &ReportContents) -> Option<&TcpPingResult> {
+    report_contents.tcp_ack_ping.as_deref()
+}
+
+#[ffi_export]
+pub fn get_tcp_connect_ping_result(report_contents: &ReportContents) -> Option<&TcpPingResult> {
+    report_contents.tcp_connect_ping.as_deref()
+}
+
+#[ffi_export]
+pub fn get_arp_result(report_contents: &ReportContents) -> Option<&ArpResult> {
+    report_contents.arp.as_deref()
+}
+
+#[ffi_export]
+pub fn get_traceroute(report_contents: &ReportContents) -> Option<&Traceroute> {
+    report_contents.traceroute.as_deref()
+}
+
+#[ffi_export]
+pub fn get_os_guess(report_contents: &ReportContents) -> Option<&OsGuess> {
+    report_contents.os_guess.as_deref()
+}
+
 #[ffi_export]
 pub fn get_port_report(report_contents: &ReportContents, port: u16) -> Option<&PortReport> {
     report_contents.ports.get(&port)
@@ -97,7 +144,25 @@ impl From<InternalReportContents> for ReportContents {
         let icmp = to_convert
             .icmp
             .map(|x| Box::<PingResult>::new(x.into()).into());
-        ReportContents { icmp, ports }
+        let tcp_ping = |result: Option<InternalTcpPingResult>| {
+            result.map(|x| Box::<TcpPingResult>::new(x.into()).into())
+        };
+        ReportContents {
+            icmp,
+            tcp_syn_ping: tcp_ping(to_convert.tcp_syn_ping),
+            tcp_ack_ping: tcp_ping(to_convert.tcp_ack_ping),
+            tcp_connect_ping: tcp_ping(to_convert.tcp_connect_ping),
+            arp: to_convert
+                .arp
+                .map(|x| Box::<ArpResult>::new(x.into()).into()),
+            ports,
+            traceroute: to_convert
+                .traceroute
+                .map(|x| Box::<Traceroute>::new(x.into()).into()),
+            os_guess: to_convert
+                .os_guess
+                .map(|x| Box::<OsGuess>::new(x.into()).into()),
+        }
     }
 }
 
@@ -107,6 +172,19 @@ pub struct PortReport {
     pub port: u16,
     pub status: PortStatus,
     pub service_detection_conclusions: Option<safer_ffi::Vec<ServiceDetectionConclusion>>,
+    pub best_service_guess: Option<FfiBox<ServiceDetectionConclusion>>,
+    /// The service detection rules that weren't run against the port and why.
+    /// Only set if service detection ran.
+    pub skipped_rules: Option<safer_ffi::Vec<SkippedRule>>,
+    /// The service detection rules that were run against the port but failed
+    /// or timed out.  Only set if service detection ran.
+    pub failed_rules: Option<safer_ffi::Vec<FailedRule>>,
+    /// Every service detection rule run against the port.  Only set when
+    /// evidence recording is turned on.
+    pub service_detection_evidence: Option<safer_ffi::Vec<RuleEvidence>>,
+    pub probe_sent: Timestamp,
+    pub response_received: Option<FfiBox<Timestamp>>,
+    pub connect_latency: Option<FfiBox<Duration>>,
 }
 
 impl From<InternalPortReport> for PortReport {
@@ -122,6 +200,40 @@ impl From<InternalPortReport> for PortReport {
                         .collect::<Vec<ServiceDetectionConclusion>>(),
                 )
             }),
+            best_service_guess: x
+                .best_service_guess
+                .map(|conclusion| Box::<ServiceDetectionConclusion>::new(conclusion.into()).into()),
+            skipped_rules: x.skipped_rules.map(|skipped| {
+                safer_ffi::Vec::from(
+                    skipped
+                        .into_iter()
+                        .map(SkippedRule::from)
+                        .collect::<Vec<SkippedRule>>(),
+                )
+            }),
+            failed_rules: x.failed_rules.map(|failed| {
+                safer_ffi::Vec::from(
+                    failed
+                        .into_iter()
+                        .map(FailedRule::from)
+                        .collect::<Vec<FailedRule>>(),
+                )
+            }),
+            service_detection_evidence: x.service_detection_evidence.map(|evidence| {
+                safer_ffi::Vec::from(
+                    evidence
+                        .into_iter()
+                        .map(RuleEvidence::from)
+                        .collect::<Vec<RuleEvidence>>(),
+                )
+            }),
+            probe_sent: x.probe_sent.into(),
+            response_received: x
+                .response_received
+                .map(|time| Box::<Timestamp>::new(time.into()).into()),
+            connect_latency: x
+                .connect_latency
+                .map(|latency| Box::<Duration>::new(latency.into()).into()),
         }
     }
 }
@@ -148,28 +260,337 @@ pub enum PingResultType {
     ReceivedReply = 0,
     IoError = 1,
     Timeout = 2,
+    Unreachable = 3,
+    TtlExceeded = 4,
 }
 
+/// The results of pinging a host.
 #[derive_ReprC]
 #[repr(C)]
 pub struct PingResult {
     result_type: PingResultType,
+    /// The time when either the ping was sent or attempted to be sent
+    ping_sent: Timestamp,
+    /// The time the reply was received.  Only set if we got a reply.
+    time_received: Option<FfiBox<Timestamp>>,
+    /// How long it took to get a reply.  Only set if we got a reply.
+    round_trip_time: Option<FfiBox<Duration>>,
+    /// What went wrong when sending the ping.  Only set on `IoError`.
+    error: Option<FfiBox<IoError>>,
+    /// The host that told us the target was unreachable or that the TTL was
+    /// exceeded.  Only set on `Unreachable` and `TtlExceeded`.
+    router: Option<FfiBox<Ip>>,
+    /// The ICMP code explaining why the target was unreachable.  Only
+    /// meaningful on `Unreachable`.
+    unreachable_code: u8,
+    /// Statistics across every echo request sent to the host.  Not set if we
+    /// failed to send the first echo request.
+    statistics: Option<FfiBox<PingStatistics>>,
+}
+
+/// Statistics gathered across every echo request sent to a host.
+#[derive_ReprC]
+#[repr(C)]
+pub struct PingStatistics {
+    transmitted: u16,
+    received: u16,
+    /// The fraction of echo requests that went unanswered, between 0.0 and
+    /// 1.0.
+    packet_loss: f64,
+    /// Round trip times.  These are only set if we got at least one reply.
+    min_rtt: Option<FfiBox<Duration>>,
+    avg_rtt: Option<FfiBox<Duration>>,
+    max_rtt: Option<FfiBox<Duration>>,
+    /// The mean difference in round trip time between consecutive replies.
+    /// Only set if we got at least two replies.
+    jitter: Option<FfiBox<Duration>>,
+}
+
+impl From<InternalPingStatistics> for PingStatistics {
+    fn from(internal: InternalPingStatistics) -> Self {
+        let to_ffi = |duration: Option<std::time::Duration>| {
+            duration.map(|duration| Box::<Duration>::new(duration.into()).into())
+        };
+        PingStatistics {
+            transmitted: internal.transmitted,
+            received: internal.received,
+            packet_loss: internal.packet_loss,
+            min_rtt: to_ffi(internal.min_rtt),
+            avg_rtt: to_ffi(internal.avg_rtt),
+            max_rtt: to_ffi(internal.max_rtt),
+            jitter: to_ffi(internal.jitter),
+        }
+    }
 }
 
 impl From<InternalPingResult> for PingResult {
     fn from(internal: InternalPingResult) -> Self {
-        // We can add the timestamps of these events on.  Right now u128 isn't ReprC but
-        // once it is, we can easily add them
+        let round_trip_time = internal
+            .round_trip_time()
+            .map(|rtt| Box::<Duration>::new(rtt.into()).into());
+        let ping_sent = internal.ping_sent.into();
+        let statistics = internal
+            .statistics
+            .map(|statistics| Box::<PingStatistics>::new(statistics.into()).into());
         match internal.result_type {
-            InternalPingResultType::Error(_) => PingResult {
+            InternalPingResultType::Error(e) => PingResult {
                 result_type: PingResultType::IoError,
+                ping_sent,
+                time_received: None,
+                round_trip_time,
+                error: Some(Box::<IoError>::new((&e).into()).into()),
+                router: None,
+                unreachable_code: 0,
+                statistics,
             },
             InternalPingResultType::Timeout => PingResult {
                 result_type: PingResultType::Timeout,
+                ping_sent,
+                time_received: None,
+                round_trip_time,
+                error: None,
+                router: None,
+                unreachable_code: 0,
+                statistics,
             },
-            InternalPingResultType::Reply(_) => PingResult {
+            InternalPingResultType::Reply(summary) => PingResult {
                 result_type: PingResultType::ReceivedReply,
+                ping_sent,
+                time_received: Some(Box::<Timestamp>::new(summary.time_received.into()).into()),
+                round_trip_time,
+                error: None,
+                router: None,
+                unreachable_code: 0,
+                statistics,
+            },
+            InternalPingResultType::Unreachable { code, router } => PingResult {
+                result_type: PingResultType::Unreachable,
+                ping_sent,
+                time_received: None,
+                round_trip_time,
+                error: None,
+                router: Some(Box::<Ip>::new(router.into()).into()),
+                unreachable_code: code,
+                statistics,
+            },
+            InternalPingResultType::TtlExceeded { router } => PingResult {
+                result_type: PingResultType::TtlExceeded,
+                ping_sent,
+                time_received: None,
+                round_trip_time,
+                error: None,
+                router: Some(Box::<Ip>::new(router.into()).into()),
+                unreachable_code: 0,
+                statistics,
             },
         }
     }
 }
+
+#[derive_ReprC]
+#[repr(i8)]
+pub enum TcpPingResultType {
+    Open = 0,
+    Reset = 1,
+    Timeout = 2,
+    IoError = 3,
+}
+
+/// The results of one of the TCP based host discovery methods.
+#[derive_ReprC]
+#[repr(C)]
+pub struct TcpPingResult {
+    result_type: TcpPingResultType,
+    /// The time we started sending probes to the host
+    probe_sent: Timestamp,
+    /// The port that answered.  Only meaningful on `Open` and `Reset`.
+    port: u16,
+    /// The time the answer was received.  Only set on `Open` and `Reset`.
+    time_received: Option<FfiBox<Timestamp>>,
+    /// How long it took to get an answer.  Only set on `Open` and `Reset`.
+    round_trip_time: Option<FfiBox<Duration>>,
+    /// What went wrong when probing the host.  Only set on `IoError`.
+    error: Option<FfiBox<IoError>>,
+}
+
+impl From<InternalTcpPingResult> for TcpPingResult {
+    fn from(internal: InternalTcpPingResult) -> Self {
+        let round_trip_time = internal
+            .round_trip_time()
+            .map(|rtt| Box::<Duration>::new(rtt.into()).into());
+        let (result_type, port, time_received, error) = match internal.result_type {
+            InternalTcpPingResultType::Open {
+                port,
+                time_received,
+            } => (TcpPingResultType::Open, port, Some(time_received), None),
+            InternalTcpPingResultType::Reset {
+                port,
+                time_received,
+            } => (TcpPingResultType::Reset, port, Some(time_received), None),
+            InternalTcpPingResultType::Timeout => (TcpPingResultType::Timeout, 0, None, None),
+            InternalTcpPingResultType::Error(e) => (
+                TcpPingResultType::IoError,
+                0,
+                None,
+                Some(Box::<IoError>::new((&e).into()).into()),
+            ),
+        };
+        TcpPingResult {
+            result_type,
+            probe_sent: internal.probe_sent.into(),
+            port,
+            time_received: time_received.map(|time| Box::<Timestamp>::new(time.into()).into()),
+            round_trip_time,
+            error,
+        }
+    }
+}
+
+#[derive_ReprC]
+#[repr(i8)]
+pub enum ArpResultType {
+    Reply = 0,
+    Timeout = 1,
+    NotOnLink = 2,
+    IoError = 3,
+}
+
+/// The results of ARP host discovery.
+#[derive_ReprC]
+#[repr(C)]
+pub struct ArpResult {
+    result_type: ArpResultType,
+    /// The time the ARP request was sent or attempted to be sent
+    request_sent: Timestamp,
+    /// The MAC address of the host.  Only meaningful on `Reply`.
+    mac: [u8; 6],
+    /// The time the reply was received.  Only set on `Reply`.
+    time_received: Option<FfiBox<Timestamp>>,
+    /// How long it took to get a reply.  Only set on `Reply`.
+    round_trip_time: Option<FfiBox<Duration>>,
+    /// What went wrong when sending the request.  Only set on `IoError`.
+    error: Option<FfiBox<IoError>>,
+}
+
+impl From<InternalArpResult> for ArpResult {
+    fn from(internal: InternalArpResult) -> Self {
+        let round_trip_time = internal
+            .round_trip_time()
+            .map(|rtt| Box::<Duration>::new(rtt.into()).into());
+        let (result_type, mac, time_received, error) = match internal.result_type {
+            InternalArpResultType::Reply { mac, time_received } => {
+                (ArpResultType::Reply, mac, Some(time_received), None)
+            }
+            InternalArpResultType::Timeout => (ArpResultType::Timeout, [0; 6], None, None),
+            InternalArpResultType::NotOnLink => (ArpResultType::NotOnLink, [0; 6], None, None),
+            InternalArpResultType::Error(e) => (
+                ArpResultType::IoError,
+                [0; 6],
+                None,
+                Some(Box::<IoError>::new((&e).into()).into()),
+            ),
+        };
+        ArpResult {
+            result_type,
+            request_sent: internal.request_sent.into(),
+            mac,
+            time_received: time_received.map(|time| Box::<Timestamp>::new(time.into()).into()),
+            round_trip_time,
+            error,
+        }
+    }
+}
+
+/// The route to a host.
+#[derive_ReprC]
+#[repr(C)]
+pub struct Traceroute {
+    /// Every hop up to the target, or up to the last router that answered if
+    /// we never reached it.
+    hops: safer_ffi::Vec<TracerouteHop>,
+    reached_target: bool,
+}
+
+/// A single hop along the path to a host.
+#[derive_ReprC]
+#[repr(C)]
+pub struct TracerouteHop {
+    ttl: u8,
+    /// The router at this hop.  Not set if nothing answered.
+    router: Option<FfiBox<Ip>>,
+    /// How long it took the router to answer.  Not set if nothing answered.
+    round_trip_time: Option<FfiBox<Duration>>,
+}
+
+impl From<InternalTracerouteResult> for Traceroute {
+    fn from(internal: InternalTracerouteResult) -> Self {
+        Traceroute {
+            hops: internal
+                .hops
+                .into_iter()
+                .map(TracerouteHop::from)
+                .collect::<Vec<TracerouteHop>>()
+                .into(),
+            reached_target: internal.reached_target,
+        }
+    }
+}
+
+impl From<InternalTracerouteHop> for TracerouteHop {
+    fn from(internal: InternalTracerouteHop) -> Self {
+        TracerouteHop {
+            ttl: internal.ttl,
+            router: internal
+                .router
+                .map(|router| Box::<Ip>::new(router.into()).into()),
+            round_trip_time: internal
+                .round_trip_time
+                .map(|rtt| Box::<Duration>::new(rtt.into()).into()),
+        }
+    }
+}
+
+#[derive_ReprC]
+#[repr(i8)]
+pub enum OsFamily {
+    Linux = 0,
+    Windows = 1,
+    MacOs = 2,
+    Bsd = 3,
+    Solaris = 4,
+    NetworkDevice = 5,
+}
+
+impl From<InternalOsFamily> for OsFamily {
+    fn from(internal: InternalOsFamily) -> Self {
+        match internal {
+            InternalOsFamily::Linux => OsFamily::Linux,
+            InternalOsFamily::Windows => OsFamily::Windows,
+            InternalOsFamily::MacOs => OsFamily::MacOs,
+            InternalOsFamily::Bsd => OsFamily::Bsd,
+            InternalOsFamily::Solaris => OsFamily::Solaris,
+            InternalOsFamily::NetworkDevice => OsFamily::NetworkDevice,
+        }
+    }
+}
+
+/// Our best guess at the OS running on a host.
+#[derive_ReprC]
+#[repr(C)]
+pub struct OsGuess {
+    family: OsFamily,
+    certainty: ServiceDetectionCertainty,
+    /// The TTL we think the host started its packets with.  This is 0 if we
+    /// never saw one.
+    initial_ttl: u8,
+}
+
+impl From<InternalOsGuess> for OsGuess {
+    fn from(internal: InternalOsGuess) -> Self {
+        OsGuess {
+            family: internal.family.into(),
+            certainty: internal.certainty.into(),
+            initial_ttl: internal.initial_ttl.unwrap_or(0),
+        }
+    }
+}
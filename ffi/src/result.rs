@@ -1,3 +1,5 @@
+use std::io;
+
 use ::safer_ffi::prelude::*;
 use bowbend_core::PortscanErr;
 
@@ -32,6 +34,14 @@ pub enum StatusCodes {
     /// A range provided is invalid.  One example possible cause is in the
     /// minimum is equal to or greater than the maximum.
     InvalidRange = -5,
+    /// The network is too large to scan every address and isn't directly
+    /// attached, so we couldn't discover its hosts with multicast.
+    NetworkTooLarge = -6,
+    /// A service detection signature couldn't be parsed.
+    InvalidSignature = -7,
+    /// The service detection rules depend on each other in a way that can't
+    /// be run.
+    InvalidRuleGraph = -8,
     /// We've failed to setup for a portscan for some unknown, internal error.
     UnknownError = -100,
 }
@@ -61,6 +71,9 @@ impl<T> From<PortscanErr> for FfiResult<T> {
         let status_code = match e {
             PortscanErr::FailedToResolveHostname(_) => StatusCodes::FailedToResolveHostname,
             PortscanErr::InsufficientPermission => StatusCodes::InsufficientPermission,
+            PortscanErr::NetworkTooLarge => StatusCodes::NetworkTooLarge,
+            PortscanErr::InvalidSignature(_) => StatusCodes::InvalidSignature,
+            PortscanErr::InvalidRuleGraph(_) => StatusCodes::InvalidRuleGraph,
         };
         FfiResult {
             status_code,
@@ -68,3 +81,73 @@ impl<T> From<PortscanErr> for FfiResult<T> {
         }
     }
 }
+
+/// The details of an I/O error hit while scanning.
+#[derive_ReprC]
+#[repr(C)]
+#[derive(Debug)]
+pub struct IoError {
+    /// The general category of the error.
+    pub kind: IoErrorKind,
+    /// A human readable description of the error.  This will often include
+    /// the OS error code.
+    pub message: safer_ffi::String,
+}
+
+impl From<&io::Error> for IoError {
+    fn from(error: &io::Error) -> Self {
+        IoError {
+            kind: error.kind().into(),
+            message: error.to_string().into(),
+        }
+    }
+}
+
+/// A mirror of [`io::ErrorKind`].  Only the most common kinds are broken out,
+/// everything else is reported as `Other`.
+#[derive_ReprC]
+#[repr(i8)]
+#[derive(Debug)]
+pub enum IoErrorKind {
+    Other = 0,
+    NotFound = 1,
+    PermissionDenied = 2,
+    ConnectionRefused = 3,
+    ConnectionReset = 4,
+    ConnectionAborted = 5,
+    NotConnected = 6,
+    AddrInUse = 7,
+    AddrNotAvailable = 8,
+    BrokenPipe = 9,
+    WouldBlock = 10,
+    InvalidInput = 11,
+    InvalidData = 12,
+    TimedOut = 13,
+    Interrupted = 14,
+    Unsupported = 15,
+    OutOfMemory = 16,
+}
+
+impl From<io::ErrorKind> for IoErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => IoErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => IoErrorKind::PermissionDenied,
+            io::ErrorKind::ConnectionRefused => IoErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset => IoErrorKind::ConnectionReset,
+            io::ErrorKind::ConnectionAborted => IoErrorKind::ConnectionAborted,
+            io::ErrorKind::NotConnected => IoErrorKind::NotConnected,
+            io::ErrorKind::AddrInUse => IoErrorKind::AddrInUse,
+            io::ErrorKind::AddrNotAvailable => IoErrorKind::AddrNotAvailable,
+            io::ErrorKind::BrokenPipe => IoErrorKind::BrokenPipe,
+            io::ErrorKind::WouldBlock => IoErrorKind::WouldBlock,
+            io::ErrorKind::InvalidInput => IoErrorKind::InvalidInput,
+            io::ErrorKind::InvalidData => IoErrorKind::InvalidData,
+            io::ErrorKind::TimedOut => IoErrorKind::TimedOut,
+            io::ErrorKind::Interrupted => IoErrorKind::Interrupted,
+            io::ErrorKind::Unsupported => IoErrorKind::Unsupported,
+            io::ErrorKind::OutOfMemory => IoErrorKind::OutOfMemory,
+            _ => IoErrorKind::Other,
+        }
+    }
+}
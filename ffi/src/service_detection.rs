@@ -1,7 +1,17 @@
 use ::safer_ffi::prelude::*;
 use bowbend_core::{
+    FailedRule as InternalFailedRule, RuleEvidence as InternalRuleEvidence,
+    RuleFailure as InternalRuleFailure, RuleLoudness as InternalRuleLoudness,
     ServiceDetectionCertainty as InternalServiceDetectionCertainty,
     ServiceDetectionConclusion as InternalServiceDetectionConclusion,
+    ServiceDetectionIntensity as InternalServiceDetectionIntensity,
+    SkipReason as InternalSkipReason, SkippedRule as InternalSkippedRule,
+};
+use safer_ffi::boxed::Box as FfiBox;
+
+use crate::{
+    result::{IoError, IoErrorKind},
+    time::Duration,
 };
 
 /// One conclusion about a service that could be running on a port.  An attempt
@@ -15,14 +25,24 @@ pub struct ServiceDetectionConclusion {
     pub certainty: ServiceDetectionCertainty,
     pub service_name: safer_ffi::String,
     pub service_version: Option<safer_ffi::String>,
+    /// Who makes the service, as spelled in CPE names
+    pub vendor: Option<safer_ffi::String>,
+    /// The service, as spelled in CPE names
+    pub product: Option<safer_ffi::String>,
+    /// The CPE 2.3 name for the service, if we know its vendor and product
+    pub cpe: Option<safer_ffi::String>,
 }
 
 impl From<InternalServiceDetectionConclusion> for ServiceDetectionConclusion {
     fn from(x: InternalServiceDetectionConclusion) -> Self {
+        let cpe = x.cpe();
         Self {
             certainty: x.certainty.into(),
             service_name: x.service_name.into(),
             service_version: x.service_version.map(safer_ffi::String::from),
+            vendor: x.vendor.map(safer_ffi::String::from),
+            product: x.product.map(safer_ffi::String::from),
+            cpe: cpe.map(safer_ffi::String::from),
         }
     }
 }
@@ -53,3 +73,215 @@ impl From<InternalServiceDetectionCertainty> for ServiceDetectionCertainty {
         }
     }
 }
+
+/// How much traffic a service detection rule sends and how much it stands
+/// out, from quietest to loudest.
+#[derive_ReprC]
+#[repr(i8)]
+#[derive(Clone, Copy, Debug)]
+pub enum RuleLoudness {
+    /// The rule only looks at what other rules already found.
+    Silent = 0,
+    /// The rule makes at most one request.
+    Quiet = 1,
+    /// The rule makes a few requests that look like a normal client.
+    Standard = 2,
+    /// The rule makes somewhere between 3 and 10 or so requests.
+    Noisy = 3,
+    /// The rule makes a lot of strange requests that could even crash the
+    /// service.
+    BangingTogetherPotsAndPans = 4,
+}
+
+impl From<RuleLoudness> for InternalRuleLoudness {
+    fn from(loudness: RuleLoudness) -> Self {
+        match loudness {
+            RuleLoudness::Silent => InternalRuleLoudness::Silent,
+            RuleLoudness::Quiet => InternalRuleLoudness::Quiet,
+            RuleLoudness::Standard => InternalRuleLoudness::Standard,
+            RuleLoudness::Noisy => InternalRuleLoudness::Noisy,
+            RuleLoudness::BangingTogetherPotsAndPans => {
+                InternalRuleLoudness::BangingTogetherPotsAndPans
+            }
+        }
+    }
+}
+
+/// How hard service detection tries on each port.  Each level also runs the
+/// rules of the levels below it.
+#[derive_ReprC]
+#[repr(i8)]
+#[derive(Clone, Copy, Debug)]
+pub enum ServiceDetectionIntensity {
+    /// Only run rules for services that are standard on the port.
+    Low = 0,
+    /// Also run rules for services that are common on the port.
+    Medium = 1,
+    /// Also run rules for services that are unusual on the port.
+    High = 2,
+    /// Run every rule with a hint for the port.
+    Exhaustive = 3,
+}
+
+impl From<ServiceDetectionIntensity> for InternalServiceDetectionIntensity {
+    fn from(intensity: ServiceDetectionIntensity) -> Self {
+        match intensity {
+            ServiceDetectionIntensity::Low => InternalServiceDetectionIntensity::Low,
+            ServiceDetectionIntensity::Medium => InternalServiceDetectionIntensity::Medium,
+            ServiceDetectionIntensity::High => InternalServiceDetectionIntensity::High,
+            ServiceDetectionIntensity::Exhaustive => InternalServiceDetectionIntensity::Exhaustive,
+        }
+    }
+}
+
+#[derive_ReprC]
+#[repr(i8)]
+pub enum RuleFailureType {
+    TimedOut = 0,
+    IoError = 1,
+    InternalError = 2,
+}
+
+/// Why a service detection rule that was run didn't produce a result.
+#[derive_ReprC]
+#[repr(C)]
+pub struct RuleFailure {
+    pub failure_type: RuleFailureType,
+    /// The IO error the rule gave up on.  Only set on `IoError`.
+    pub error: Option<FfiBox<IoError>>,
+    /// A description of what went wrong.  Only set on `InternalError`.
+    pub message: Option<safer_ffi::String>,
+}
+
+impl From<InternalRuleFailure> for RuleFailure {
+    fn from(failure: InternalRuleFailure) -> Self {
+        match failure {
+            InternalRuleFailure::TimedOut => RuleFailure {
+                failure_type: RuleFailureType::TimedOut,
+                error: None,
+                message: None,
+            },
+            InternalRuleFailure::IOError(kind, message) => RuleFailure {
+                failure_type: RuleFailureType::IoError,
+                error: Some(
+                    Box::<IoError>::new(IoError {
+                        kind: IoErrorKind::from(kind),
+                        message: message.into(),
+                    })
+                    .into(),
+                ),
+                message: None,
+            },
+            InternalRuleFailure::InternalError(message) => RuleFailure {
+                failure_type: RuleFailureType::InternalError,
+                error: None,
+                message: Some(message.into()),
+            },
+        }
+    }
+}
+
+/// A service detection rule that was run against a port but failed.
+#[derive_ReprC]
+#[repr(C)]
+pub struct FailedRule {
+    /// The name of the rule
+    pub rule: safer_ffi::String,
+    /// Why it failed the last time it was run
+    pub failure: RuleFailure,
+    /// How many times the rule was run, including retries
+    pub attempts: u32,
+}
+
+impl From<InternalFailedRule> for FailedRule {
+    fn from(failed: InternalFailedRule) -> Self {
+        FailedRule {
+            rule: failed.rule.to_string().into(),
+            failure: failed.failure.into(),
+            attempts: failed.attempts,
+        }
+    }
+}
+
+/// A record of a service detection rule that was run against a port.
+#[derive_ReprC]
+#[repr(C)]
+pub struct RuleEvidence {
+    /// The name of the rule
+    pub rule: safer_ffi::String,
+    /// How long the rule took, including any retries
+    pub duration: Duration,
+    /// How many times the rule was run, including retries
+    pub attempts: u32,
+    /// Everything the rule observed.  Only set if the rule succeeded.
+    pub observed: Option<safer_ffi::String>,
+    /// Why the rule failed.  Only set if it failed.
+    pub failure: Option<FfiBox<RuleFailure>>,
+}
+
+impl From<InternalRuleEvidence> for RuleEvidence {
+    fn from(evidence: InternalRuleEvidence) -> Self {
+        let (observed, failure) = match evidence.outcome {
+            Ok(observed) => (Some(observed.into()), None),
+            Err(failure) => (None, Some(Box::<RuleFailure>::new(failure.into()).into())),
+        };
+        RuleEvidence {
+            rule: evidence.rule.to_string().into(),
+            duration: evidence.duration.into(),
+            attempts: evidence.attempts,
+            observed,
+            failure,
+        }
+    }
+}
+
+#[derive_ReprC]
+#[repr(i8)]
+pub enum SkipReason {
+    NotApplicable = 0,
+    TooLoud = 1,
+    TooUnlikely = 2,
+    RequiresPrivilege = 3,
+    MissingDependency = 4,
+    DependencyCycle = 5,
+    DependencySkipped = 6,
+    DependencyFailed = 7,
+}
+
+/// A service detection rule that wasn't run against a port.
+#[derive_ReprC]
+#[repr(C)]
+pub struct SkippedRule {
+    /// The name of the rule
+    pub rule: safer_ffi::String,
+    pub reason: SkipReason,
+    /// The dependency that kept the rule from running.  Only set on
+    /// `MissingDependency`, `DependencySkipped` and `DependencyFailed`.
+    pub dependency: Option<safer_ffi::String>,
+}
+
+impl From<InternalSkippedRule> for SkippedRule {
+    fn from(skipped: InternalSkippedRule) -> Self {
+        let (reason, dependency) = match skipped.reason {
+            InternalSkipReason::NotApplicable => (SkipReason::NotApplicable, None),
+            InternalSkipReason::TooLoud => (SkipReason::TooLoud, None),
+            InternalSkipReason::TooUnlikely => (SkipReason::TooUnlikely, None),
+            InternalSkipReason::RequiresPrivilege => (SkipReason::RequiresPrivilege, None),
+            InternalSkipReason::MissingDependency(rule) => {
+                (SkipReason::MissingDependency, Some(rule))
+            }
+            InternalSkipReason::DependencyCycle => (SkipReason::DependencyCycle, None),
+            InternalSkipReason::DependencySkipped(rule) => {
+                (SkipReason::DependencySkipped, Some(rule))
+            }
+            InternalSkipReason::DependencyFailed(rule) => {
+                (SkipReason::DependencyFailed, Some(rule))
+            }
+        };
+        SkippedRule {
+            rule: skipped.rule.to_string().into(),
+            reason,
+            dependency: dependency.map(|rule| rule.to_string().into()),
+        }
+    }
+}
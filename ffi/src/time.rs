@@ -0,0 +1,50 @@
+//! FFI safe types for passing points in time and lengths of time over the FFI
+//! barrier.  `u128` isn't `ReprC` so these are broken up into whole seconds
+//! and the remaining nanoseconds.
+
+use std::time::{Duration as InternalDuration, SystemTime, UNIX_EPOCH};
+
+use ::safer_ffi::prelude::*;
+
+/// A point in time expressed as the time since the Unix epoch.
+#[derive_ReprC]
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct Timestamp {
+    /// Whole seconds since the Unix epoch.
+    pub seconds: u64,
+    /// The fractional part of the timestamp, in nanoseconds.
+    pub nanoseconds: u32,
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        // A time before the epoch would mean the system clock is badly wrong.  We
+        // clamp it to the epoch instead of failing the whole report.
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Timestamp {
+            seconds: since_epoch.as_secs(),
+            nanoseconds: since_epoch.subsec_nanos(),
+        }
+    }
+}
+
+/// A length of time.
+#[derive_ReprC]
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct Duration {
+    /// Whole seconds in the duration.
+    pub seconds: u64,
+    /// The fractional part of the duration, in nanoseconds.
+    pub nanoseconds: u32,
+}
+
+impl From<InternalDuration> for Duration {
+    fn from(duration: InternalDuration) -> Self {
+        Duration {
+            seconds: duration.as_secs(),
+            nanoseconds: duration.subsec_nanos(),
+        }
+    }
+}
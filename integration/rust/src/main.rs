@@ -1,7 +1,10 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, time::Duration};
 
 use bowbend::{
-    start_scan, ConfigBuilder, PingResultType, PortStatus, Report, Target, TargetInstance,
+    start_scan, ArpResultType, ConfigBuilder, HostDiscoveryMethod, HostDiscoveryPolicy, OsFamily,
+    PingResultType, PortStatus, Report, RuleFailure, RuleId, RuleLoudness,
+    ServiceDetectionIntensity, SkipReason, Target, TargetInstance, TcpPingResultType,
+    TracerouteMethod,
 };
 use futures_util::stream::StreamExt;
 
@@ -58,6 +61,92 @@ async fn scan_with_icmp() {
     println!("ICMP scan test passed");
 }
 
+async fn scan_with_tcp_discovery() {
+    let mut builder = ConfigBuilder::default();
+    builder.add_host_discovery_method(HostDiscoveryMethod::TcpSyn { ports: vec![80] });
+    builder.add_host_discovery_method(HostDiscoveryMethod::TcpConnect { ports: vec![1337] });
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::IP("172.0.0.2".parse::<IpAddr>().unwrap()));
+    builder.add_target(Target::IP("172.0.0.4".parse::<IpAddr>().unwrap()));
+    let stream = start_scan(builder).await.unwrap();
+    let reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 2);
+    for report in reports {
+        let ip = report.instance.unwrap().get_ip().to_string();
+        let contents = report.contents.unwrap();
+        let syn = contents.tcp_syn_ping.unwrap();
+        let connect = contents.tcp_connect_ping.unwrap();
+        match ip.as_str() {
+            "172.0.0.2" => {
+                assert!(matches!(
+                    syn.result_type,
+                    TcpPingResultType::Open { port: 80, .. }
+                ));
+                assert!(matches!(
+                    connect.result_type,
+                    TcpPingResultType::Reset { port: 1337, .. }
+                ));
+            }
+            "172.0.0.4" => {
+                assert!(!syn.host_up());
+                assert!(!connect.host_up());
+            }
+            _ => panic!("This doesn't match either target"),
+        }
+    }
+    println!("TCP discovery scan test passed");
+}
+
+async fn scan_with_arp_discovery() {
+    let mut builder = ConfigBuilder::default();
+    builder.add_host_discovery_method(HostDiscoveryMethod::Arp);
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::IP("172.0.0.2".parse::<IpAddr>().unwrap()));
+    builder.add_target(Target::IP("172.0.0.4".parse::<IpAddr>().unwrap()));
+    let stream = start_scan(builder).await.unwrap();
+    let reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 2);
+    for report in reports {
+        let ip = report.instance.unwrap().get_ip().to_string();
+        let arp = report.contents.unwrap().arp.unwrap();
+        match ip.as_str() {
+            "172.0.0.2" => assert!(matches!(arp.result_type, ArpResultType::Reply { .. })),
+            "172.0.0.4" => assert!(matches!(arp.result_type, ArpResultType::Timeout)),
+            _ => panic!("This doesn't match either target"),
+        }
+    }
+    println!("ARP discovery scan test passed");
+}
+
+async fn scan_responders_only() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_ping(true);
+    builder.set_host_discovery_policy(HostDiscoveryPolicy::ScanRespondersOnly);
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::IP("172.0.0.2".parse::<IpAddr>().unwrap()));
+    builder.add_target(Target::IP("172.0.0.4".parse::<IpAddr>().unwrap()));
+    let stream = start_scan(builder).await.unwrap();
+    let reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 2);
+    for report in reports {
+        let ip = report.instance.unwrap().get_ip().to_string();
+        let contents = report.contents.unwrap();
+        match ip.as_str() {
+            "172.0.0.2" => assert!(contents.ports.is_some()),
+            "172.0.0.4" => {
+                // Skipped hosts still come back with their discovery results
+                assert!(contents.ports.is_none());
+                assert!(matches!(
+                    contents.icmp.unwrap().result_type,
+                    PingResultType::Timeout
+                ));
+            }
+            _ => panic!("This doesn't match either target"),
+        }
+    }
+    println!("Responders only scan test passed");
+}
+
 async fn scan_with_service_detection() {
     let mut builder = ConfigBuilder::default();
     builder.set_run_service_detection(true);
@@ -78,12 +167,256 @@ async fn scan_with_service_detection() {
         .unwrap()
         .service_name
         .contains("nginx"));
+    assert!(ports
+        .get(&80)
+        .unwrap()
+        .best_service_guess
+        .as_ref()
+        .unwrap()
+        .service_name
+        .contains("nginx"));
+    assert!(ports
+        .get(&80)
+        .unwrap()
+        .best_service_guess
+        .as_ref()
+        .unwrap()
+        .cpe()
+        .unwrap()
+        .starts_with("cpe:2.3:a:f5:nginx:"));
     println!("Scan with service detection passed");
 }
 
+async fn scan_with_loudness_budget() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder.set_max_service_detection_loudness(RuleLoudness::Silent);
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let port = ports.get(&80).unwrap();
+    // Every rule either probes the port itself or depends on one that does
+    assert!(port
+        .service_detection_conclusions
+        .clone()
+        .unwrap()
+        .is_empty());
+    assert!(port
+        .skipped_rules
+        .clone()
+        .unwrap()
+        .iter()
+        .any(|skipped| skipped.reason == SkipReason::TooLoud));
+    println!("Scan with loudness budget passed");
+}
+
+async fn scan_with_low_intensity() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder.set_service_detection_intensity(ServiceDetectionIntensity::Low);
+    builder
+        .add_service_detection_signatures(
+            r#"
+            [[probe]]
+            name = "rarely-on-80"
+            ports = [{ ports = "80", likeliness = "rare" }]
+
+            [[probe.match]]
+            pattern = '.'
+            service = "should never run"
+            "#,
+        )
+        .unwrap();
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let port = ports.get(&80).unwrap();
+    let conclusions = port.service_detection_conclusions.clone().unwrap();
+    assert!(conclusions
+        .iter()
+        .any(|conclusion| conclusion.service_name.contains("nginx")));
+    assert!(!conclusions
+        .iter()
+        .any(|conclusion| conclusion.service_name == "should never run"));
+    assert!(port.skipped_rules.clone().unwrap().iter().any(|skipped| {
+        skipped.rule == RuleId::named("rarely-on-80") && skipped.reason == SkipReason::TooUnlikely
+    }));
+    println!("Scan with low intensity passed");
+}
+
+async fn scan_with_rule_timeout() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder.set_service_detection_rule_timeout(Duration::from_micros(1));
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let port = ports.get(&80).unwrap();
+    assert!(port
+        .failed_rules
+        .clone()
+        .unwrap()
+        .iter()
+        .any(|failed| failed.failure == RuleFailure::TimedOut && failed.attempts == 1));
+    println!("Scan with rule timeout passed");
+}
+
+async fn scan_with_evidence() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder.set_record_service_detection_evidence(true);
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let evidence = ports
+        .get(&80)
+        .unwrap()
+        .service_detection_evidence
+        .clone()
+        .unwrap();
+    // The HTTP probe's headers are what identify nginx
+    assert!(evidence.iter().any(
+        |evidence| matches!(&evidence.outcome, Ok(observations) if observations.contains("nginx"))
+    ));
+    println!("Scan with evidence passed");
+}
+
+async fn scan_with_signatures() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder
+        .add_service_detection_signatures(
+            r#"
+            [[probe]]
+            name = "nginx-server-header"
+            kind = "http_get"
+            ports = [{ ports = "80", likeliness = "standard" }]
+
+            [[probe.match]]
+            header = "server"
+            pattern = '^nginx/([\d.]+)'
+            service = "nginx from a signature"
+            version = "${1}"
+            "#,
+        )
+        .unwrap();
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let conclusions = ports
+        .get(&80)
+        .unwrap()
+        .service_detection_conclusions
+        .clone()
+        .unwrap();
+    assert!(conclusions.iter().any(|conclusion| conclusion.service_name
+        == "nginx from a signature"
+        && conclusion.service_version.is_some()));
+    println!("Scan with signatures passed");
+}
+
+async fn scan_with_nmap_probes() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_run_service_detection(true);
+    builder
+        .add_nmap_service_probes(
+            r#"
+            Probe TCP GetRequest q|GET / HTTP/1.0\r\n\r\n|
+            rarity 1
+            ports 80
+            match http m|^HTTP/1\.[01] \d\d\d .*\r\nServer: nginx/([\d.]+)|s p/nginx from nmap/ v/$1/
+            "#,
+        )
+        .unwrap();
+    builder.set_port_list(vec![80]);
+    builder.add_target(Target::Hostname("web".to_string()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let report = reports.pop().unwrap();
+    let ports = report.contents.unwrap().ports.unwrap();
+    let conclusions = ports
+        .get(&80)
+        .unwrap()
+        .service_detection_conclusions
+        .clone()
+        .unwrap();
+    assert!(conclusions
+        .iter()
+        .any(|conclusion| conclusion.service_name == "nginx from nmap"
+            && conclusion.service_version.is_some()));
+    println!("Scan with nmap probes passed");
+}
+
+async fn scan_with_os_detection() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_port_list(vec![80]);
+    builder.add_host_discovery_method(HostDiscoveryMethod::TcpSyn { ports: vec![80] });
+    builder.set_run_os_detection(true);
+    builder.add_target(Target::IP("172.0.0.2".parse::<IpAddr>().unwrap()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let guess = reports.pop().unwrap().contents.unwrap().os_guess.unwrap();
+    // Every container runs on the Linux kernel
+    assert_eq!(guess.family, OsFamily::Linux);
+    assert_eq!(guess.initial_ttl, Some(64));
+    println!("Scan with OS detection passed");
+}
+
+async fn scan_with_traceroute() {
+    let mut builder = ConfigBuilder::default();
+    builder.set_port_list(vec![80]);
+    builder.set_traceroute(Some(TracerouteMethod::Tcp { port: 80 }));
+    builder.add_target(Target::IP("172.0.0.2".parse::<IpAddr>().unwrap()));
+    let stream = start_scan(builder).await.unwrap();
+    let mut reports = stream.collect::<Vec<Report>>().await;
+    assert_eq!(reports.len(), 1);
+    let traceroute = reports.pop().unwrap().contents.unwrap().traceroute.unwrap();
+    // The containers share a network so the target is the first hop
+    assert!(traceroute.reached_target);
+    assert_eq!(traceroute.hops.len(), 1);
+    assert_eq!(
+        traceroute.hops[0].router,
+        Some("172.0.0.2".parse::<IpAddr>().unwrap())
+    );
+    println!("Scan with traceroute passed");
+}
+
 #[tokio::main]
 async fn main() {
     basic_ip_scan().await;
     scan_with_icmp().await;
+    scan_with_tcp_discovery().await;
+    scan_with_arp_discovery().await;
+    scan_responders_only().await;
     scan_with_service_detection().await;
+    scan_with_loudness_budget().await;
+    scan_with_low_intensity().await;
+    scan_with_rule_timeout().await;
+    scan_with_evidence().await;
+    scan_with_signatures().await;
+    scan_with_nmap_probes().await;
+    scan_with_os_detection().await;
+    scan_with_traceroute().await;
 }
@@ -2,11 +2,13 @@
 Python bindings for the bowbend port scanner library
 """
 from .bowbend import ffi, lib  # type: ignore # noqa # pylint: disable=import-error
-from .builder import Builder
+from .builder import (Builder, HostDiscoveryPolicy, RuleLoudness,
+                      ServiceDetectionIntensity)
 from .error import Error
 from .scan import Scan, ScanFinished
 from .target import Target
 from .service_detection import ServiceDetectionConclusion
 
-__all__ = ['Error', 'Builder', 'Scan', 'ScanFinished',
-           'ServiceDetectionConclusion', 'Target']
+__all__ = ['Error', 'Builder', 'HostDiscoveryPolicy', 'RuleLoudness', 'Scan',
+           'ScanFinished', 'ServiceDetectionConclusion',
+           'ServiceDetectionIntensity', 'Target']
@@ -1,4 +1,6 @@
-from typing import Any
+from datetime import datetime, timedelta, timezone
+from ipaddress import IPv4Address, IPv6Address
+from typing import Any, Union
 
 from .bowbend import ffi  # type: ignore # noqa # pylint: disable=import-error
 
@@ -22,3 +24,23 @@ def _char_star_to_python_string(ffi_string: Any) -> str:
 
 def _vec_uint8_to_python_string(ffi_string: Any) -> str:
     return ffi.string(ffi_string.ptr, maxlen=ffi_string.len).decode('UTF-8')
+
+
+def _timestamp_to_datetime(ffi_timestamp: Any) -> datetime:
+    seconds = ffi_timestamp.seconds + ffi_timestamp.nanoseconds / 1_000_000_000
+    return datetime.fromtimestamp(seconds, tz=timezone.utc)
+
+
+def _duration_to_timedelta(ffi_duration: Any) -> timedelta:
+    return timedelta(seconds=ffi_duration.seconds,
+                     microseconds=ffi_duration.nanoseconds / 1_000)
+
+
+def _ip_to_python_address(ffi_ip: Any) -> Union[IPv4Address, IPv6Address]:
+    ip_bytes = bytes(ffi.buffer(ffi_ip.ptr, ffi_ip.len))
+    if len(ip_bytes) == 4:
+        return IPv4Address(ip_bytes)
+    if len(ip_bytes) == 16:
+        return IPv6Address(ip_bytes)
+    raise RuntimeError("Internal failure. An IP was returned with "
+                       " an invalid number of bytes")
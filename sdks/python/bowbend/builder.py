@@ -1,11 +1,51 @@
 import logging
-from typing import Any, List
+from enum import Enum
+from typing import Any, List, Tuple
+from ._utils import FfiByteArray
 from .bowbend import ffi, lib  # type: ignore # noqa # pylint: disable=import-error
 from .target import Target
 
 logger = logging.getLogger(__name__)
 
 
+def _port_slice(ports: List[int]) -> Tuple[Any, Any]:
+    """ Build a slice of ports to hand to the FFI.  The second value owns
+    the memory and needs to be kept alive until the call is done. """
+    slice_ref = ffi.new("slice_ref_uint16_t[]", 1)
+    ptr = ffi.new("uint16_t const []", len(ports))
+    slice_ref[0].ptr = ptr
+    for index, port in enumerate(ports):
+        slice_ref[0].ptr[index] = port
+    slice_ref[0].len = len(ports)
+    return slice_ref[0], ptr
+
+
+class HostDiscoveryPolicy(Enum):
+    """ What to do with each host once host discovery has run. """
+    SCAN_ALL = 0
+    SCAN_RESPONDERS_ONLY = 1
+    SCAN_RESPONDERS_AND_UNKNOWN = 2
+
+
+class RuleLoudness(Enum):
+    """ How much traffic a service detection rule sends and how much it
+    stands out, from quietest to loudest. """
+    SILENT = 0
+    QUIET = 1
+    STANDARD = 2
+    NOISY = 3
+    BANGING_TOGETHER_POTS_AND_PANS = 4
+
+
+class ServiceDetectionIntensity(Enum):
+    """ How hard service detection tries on each port.  Each level also runs
+    the rules of the levels below it. """
+    LOW = 0
+    MEDIUM = 1
+    HIGH = 2
+    EXHAUSTIVE = 3
+
+
 class Builder:
     _inner: Any
 
@@ -18,20 +58,124 @@ class Builder:
 
     def set_port_list(self, ports: List[int]) -> None:
         logger.debug("Setting port list %s", ports)
-        slice_ref = ffi.new("slice_ref_uint16_t[]", 1)
-        ptr = ffi.new("uint16_t const []", len(ports))
-        slice_ref[0].ptr = ptr
-        for index, port in enumerate(ports):
-            slice_ref[0].ptr[index] = port
-        slice_ref[0].len = len(ports)
-        lib.set_port_list(self._inner, slice_ref[0])
+        port_slice, _ptr = _port_slice(ports)
+        lib.set_port_list(self._inner, port_slice)
 
     def set_run_service_detection(self, run_service_detection: bool) -> None:
         lib.set_run_service_detection(self._inner, run_service_detection)
 
+    def set_max_service_detection_loudness(
+            self, max_loudness: RuleLoudness) -> None:
+        """ Set the loudest service detection rules we are willing to run.
+        Louder rules are skipped along with any rules depending on them. """
+        lib.set_max_service_detection_loudness(self._inner, max_loudness.value)
+
+    def set_service_detection_intensity(
+            self, intensity: ServiceDetectionIntensity) -> None:
+        """ Set how hard service detection tries on each port.  Lower
+        intensities only run rules for services that are likely to be on the
+        port. """
+        lib.set_service_detection_intensity(self._inner, intensity.value)
+
+    def set_service_detection_rule_timeout(self, timeout: int) -> None:
+        """ Set how long, in milliseconds, a single service detection rule may
+        run against a port before we give up on it. """
+        lib.set_service_detection_rule_timeout(self._inner, timeout)
+
+    def set_service_detection_retries(self, retries: int) -> None:
+        """ Set how many times to retry a service detection rule that failed
+        with a transient IO error. """
+        lib.set_service_detection_retries(self._inner, retries)
+
+    def set_record_service_detection_evidence(self,
+                                              record_evidence: bool) -> None:
+        """ Set if each port should carry a record of every service detection
+        rule run against it, how long it took and what it saw or why it
+        failed. """
+        lib.set_record_service_detection_evidence(self._inner, record_evidence)
+
+    def add_service_detection_signatures(self, signatures: str) -> None:
+        """ Load service detection signatures, written in TOML, on top of the
+        built in ones.  A probe replaces any loaded probe with the same name.
+        Nothing is loaded if any of the signatures are invalid. """
+        signature_bytes = FfiByteArray(signatures.encode("UTF-8"))
+        result = lib.add_service_detection_signatures(
+            self._inner, signature_bytes.get_slice())
+        if result.status_code != lib.STATUS_CODES_OK:
+            raise ValueError("Failed to load service detection signatures")
+
+    def add_nmap_service_probes(self, probes: str) -> None:
+        """ Load probes from the contents of an nmap `nmap-service-probes`
+        file.  UDP probes and matches with regexes we can't compile are
+        skipped.  Nothing is loaded if any line is malformed. """
+        probe_bytes = FfiByteArray(probes.encode("UTF-8"))
+        result = lib.add_nmap_service_probes(self._inner, probe_bytes.get_slice())
+        if result.status_code != lib.STATUS_CODES_OK:
+            raise ValueError("Failed to load nmap service probes")
+
+    def set_run_os_detection(self, run_os_detection: bool) -> None:
+        """ Set if we should guess each host's OS from how it answered host
+        discovery.  This needs an ICMP echo or TCP SYN ping to have something
+        to go on. """
+        lib.set_run_os_detection(self._inner, run_os_detection)
+
     def set_ping(self, ping: bool) -> None:
         lib.set_ping(self._inner, ping)
 
+    def add_icmp_echo_discovery(self) -> None:
+        """ Check each target with ICMP echo requests before scanning it.
+        This is the same as `set_ping(True)`. """
+        lib.add_icmp_echo_discovery(self._inner)
+
+    def add_tcp_syn_discovery(self, ports: List[int]) -> None:
+        """ Check each target by sending a TCP SYN to each port before
+        scanning it.  Requires root. """
+        port_slice, _ptr = _port_slice(ports)
+        lib.add_tcp_syn_discovery(self._inner, port_slice)
+
+    def add_tcp_ack_discovery(self, ports: List[int]) -> None:
+        """ Check each target by sending a TCP ACK to each port before
+        scanning it.  Requires root. """
+        port_slice, _ptr = _port_slice(ports)
+        lib.add_tcp_ack_discovery(self._inner, port_slice)
+
+    def add_tcp_connect_discovery(self, ports: List[int]) -> None:
+        """ Check each target by attempting a full TCP connection to each
+        port before scanning it.  A refused connection still means the host
+        is up. """
+        port_slice, _ptr = _port_slice(ports)
+        lib.add_tcp_connect_discovery(self._inner, port_slice)
+
+    def add_arp_discovery(self) -> None:
+        """ Check each target on a directly attached subnet with an ARP
+        request, or a Neighbor Solicitation for IPv6, before scanning it.
+        Requires root. """
+        lib.add_arp_discovery(self._inner)
+
+    def clear_host_discovery_methods(self) -> None:
+        lib.clear_host_discovery_methods(self._inner)
+
+    def set_host_discovery_policy(self, policy: HostDiscoveryPolicy) -> None:
+        """ Set which hosts get port scanned based on what host discovery
+        found.  Skipped hosts still get a report with their discovery
+        results. """
+        lib.set_host_discovery_policy(self._inner, policy.value)
+
+    def set_ping_count(self, ping_count: int) -> None:
+        """ Set the number of echo requests sent to each target when
+        pinging.  A target is considered up if any of them get a reply. """
+        lib.set_ping_count(self._inner, ping_count)
+
+    def set_ping_interval(self, ping_interval: int) -> None:
+        """ Set how long to wait between each round of echo requests, in
+        milliseconds. """
+        lib.set_ping_interval(self._inner, ping_interval)
+
+    def set_ping_timeout(self, ping_timeout: int) -> None:
+        """ Set how long to wait for replies after the last echo request is
+        sent, in milliseconds. """
+        lib.set_ping_timeout(self._inner, ping_timeout)
+
     def set_tracing(self, tracing: bool) -> None:
         logger.debug("Setting tracing %r", tracing)
         lib.set_tracing(self._inner, tracing)
@@ -44,6 +188,34 @@ class Builder:
         if result.status_code != lib.STATUS_CODES_OK:
             raise ValueError("Failed to set throttle")
 
+    def set_traceroute_icmp(self) -> None:
+        """ Trace the route to every host using ICMP echo requests.  This
+        needs privileged access. """
+        lib.set_traceroute_icmp(self._inner)
+
+    def set_traceroute_udp(self, port: int) -> None:
+        """ Trace the route to every host using UDP datagrams sent to
+        `port`.  This needs privileged access. """
+        lib.set_traceroute_udp(self._inner, port)
+
+    def set_traceroute_tcp(self, port: int) -> None:
+        """ Trace the route to every host using TCP connection attempts to
+        `port`.  This needs privileged access. """
+        lib.set_traceroute_tcp(self._inner, port)
+
+    def clear_traceroute(self) -> None:
+        lib.clear_traceroute(self._inner)
+
+    def set_traceroute_max_hops(self, max_hops: int) -> None:
+        """ Set the highest TTL, or hop limit for IPv6, to probe with when
+        tracing a route. """
+        lib.set_traceroute_max_hops(self._inner, max_hops)
+
+    def set_traceroute_timeout(self, timeout: int) -> None:
+        """ Set how long to wait for routers to answer after the last
+        traceroute probe is sent, in milliseconds. """
+        lib.set_traceroute_timeout(self._inner, timeout)
+
     def set_max_in_flight(self, max_in_flight: int) -> None:
         """ Set the maximum number of in flight tasks for a port scan.  This
         is useful for limiting resource utilization. """
@@ -6,4 +6,28 @@ class Error(Enum):
     INVALID_UTF8 = -2
     FAILED_TO_RESOLVE_HOSTNAME = -3
     INSUFFICIENT_PERMISSION = -4
+    INVALID_RANGE = -5
+    NETWORK_TOO_LARGE = -6
+    INVALID_SIGNATURE = -7
+    INVALID_RULE_GRAPH = -8
     UNKNOWN_ERROR = -100
+
+
+class IoErrorKind(Enum):
+    OTHER = 0
+    NOT_FOUND = 1
+    PERMISSION_DENIED = 2
+    CONNECTION_REFUSED = 3
+    CONNECTION_RESET = 4
+    CONNECTION_ABORTED = 5
+    NOT_CONNECTED = 6
+    ADDR_IN_USE = 7
+    ADDR_NOT_AVAILABLE = 8
+    BROKEN_PIPE = 9
+    WOULD_BLOCK = 10
+    INVALID_INPUT = 11
+    INVALID_DATA = 12
+    TIMED_OUT = 13
+    INTERRUPTED = 14
+    UNSUPPORTED = 15
+    OUT_OF_MEMORY = 16
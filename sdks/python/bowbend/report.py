@@ -1,13 +1,17 @@
+from datetime import datetime, timedelta
 from enum import Enum
 from typing import Union, Optional, Dict, List
 from ipaddress import IPv4Address, IPv6Address
 
 from _cffi_backend import _CDataBase  # type: ignore
 
-from .error import Error
+from ._utils import _duration_to_timedelta, _ip_to_python_address, \
+    _timestamp_to_datetime, _vec_uint8_to_python_string
+from .error import Error, IoErrorKind
 from .bowbend import ffi, lib  # type: ignore # noqa # pylint: disable=import-error
 from .target import Target
-from .service_detection import ServiceDetectionConclusion
+from .service_detection import Certainty, FailedRule, RuleEvidence, \
+    ServiceDetectionConclusion, SkippedRule
 
 
 class PortStatus(Enum):
@@ -28,11 +32,29 @@ class PortReport:
     port: int
     status: PortStatus
     service_detection_conclusions: Optional[List[ServiceDetectionConclusion]]
+    best_service_guess: Optional[ServiceDetectionConclusion]
+    skipped_rules: Optional[List[SkippedRule]]
+    failed_rules: Optional[List[FailedRule]]
+    service_detection_evidence: Optional[List[RuleEvidence]]
+    probe_sent: datetime
+    response_received: Optional[datetime]
+    connect_latency: Optional[timedelta]
 
     def __init__(self, internal):
         assert ffi.typeof(internal) is ffi.typeof("struct PortReport *")
         self.port = internal.port
         self.status = PortStatus(internal.status)
+        self.probe_sent = _timestamp_to_datetime(internal.probe_sent)
+        if internal.response_received != ffi.NULL:
+            self.response_received = \
+                _timestamp_to_datetime(internal.response_received)
+        else:
+            self.response_received = None
+        if internal.connect_latency != ffi.NULL:
+            self.connect_latency = \
+                _duration_to_timedelta(internal.connect_latency)
+        else:
+            self.connect_latency = None
         if ffi.NULL not in (internal.service_detection_conclusions,
                             internal.service_detection_conclusions.ptr):
             self.service_detection_conclusions = []
@@ -42,6 +64,32 @@ class PortReport:
                 self.service_detection_conclusions.append(entry)
         else:
             self.service_detection_conclusions = None
+        if internal.best_service_guess != ffi.NULL:
+            self.best_service_guess = \
+                ServiceDetectionConclusion(internal.best_service_guess[0])
+        else:
+            self.best_service_guess = None
+        if ffi.NULL not in (internal.skipped_rules,
+                            internal.skipped_rules.ptr):
+            self.skipped_rules = [
+                SkippedRule(internal.skipped_rules.ptr[i])
+                for i in range(internal.skipped_rules.len)]
+        else:
+            self.skipped_rules = None
+        if ffi.NULL not in (internal.failed_rules,
+                            internal.failed_rules.ptr):
+            self.failed_rules = [
+                FailedRule(internal.failed_rules.ptr[i])
+                for i in range(internal.failed_rules.len)]
+        else:
+            self.failed_rules = None
+        if ffi.NULL not in (internal.service_detection_evidence,
+                            internal.service_detection_evidence.ptr):
+            self.service_detection_evidence = [
+                RuleEvidence(internal.service_detection_evidence.ptr[i])
+                for i in range(internal.service_detection_evidence.len)]
+        else:
+            self.service_detection_evidence = None
 
     def __str__(self):
         if self.service_detection_conclusions is None:
@@ -56,24 +104,272 @@ class PingResultType(Enum):
     RECEIVED_REPLY = 0
     IO_ERROR = 1
     TIMEOUT = 2
+    UNREACHABLE = 3
+    TTL_EXCEEDED = 4
+
+
+def _optional_duration(internal: _CDataBase) -> Optional[timedelta]:
+    if internal != ffi.NULL:
+        return _duration_to_timedelta(internal)
+    return None
+
+
+class PingStatistics:
+    transmitted: int
+    received: int
+    packet_loss: float
+    min_rtt: Optional[timedelta]
+    avg_rtt: Optional[timedelta]
+    max_rtt: Optional[timedelta]
+    jitter: Optional[timedelta]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct PingStatistics *")
+        self.transmitted = internal.transmitted
+        self.received = internal.received
+        self.packet_loss = internal.packet_loss
+        self.min_rtt = _optional_duration(internal.min_rtt)
+        self.avg_rtt = _optional_duration(internal.avg_rtt)
+        self.max_rtt = _optional_duration(internal.max_rtt)
+        self.jitter = _optional_duration(internal.jitter)
 
 
 class PingResult:
     ping_result_type: PingResultType
+    ping_sent: datetime
+    time_received: Optional[datetime]
+    round_trip_time: Optional[timedelta]
+    error_kind: Optional[IoErrorKind]
+    error_message: Optional[str]
+    router: Optional[Union[IPv4Address, IPv6Address]]
+    unreachable_code: Optional[int]
+    statistics: Optional[PingStatistics]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct PingResult *")
+        self.ping_result_type = PingResultType(internal.result_type)
+        self.ping_sent = _timestamp_to_datetime(internal.ping_sent)
+        if internal.time_received != ffi.NULL:
+            self.time_received = \
+                _timestamp_to_datetime(internal.time_received)
+        else:
+            self.time_received = None
+        if internal.round_trip_time != ffi.NULL:
+            self.round_trip_time = \
+                _duration_to_timedelta(internal.round_trip_time)
+        else:
+            self.round_trip_time = None
+        if internal.error != ffi.NULL:
+            self.error_kind = IoErrorKind(internal.error.kind)
+            self.error_message = \
+                _vec_uint8_to_python_string(internal.error.message)
+        else:
+            self.error_kind = None
+            self.error_message = None
+        if internal.router != ffi.NULL:
+            self.router = _ip_to_python_address(internal.router.ip)
+        else:
+            self.router = None
+        if self.ping_result_type == PingResultType.UNREACHABLE:
+            self.unreachable_code = internal.unreachable_code
+        else:
+            self.unreachable_code = None
+        if internal.statistics != ffi.NULL:
+            self.statistics = PingStatistics(internal.statistics)
+        else:
+            self.statistics = None
+
+
+class TcpPingResultType(Enum):
+    OPEN = 0
+    RESET = 1
+    TIMEOUT = 2
+    IO_ERROR = 3
+
+
+class TcpPingResult:
+    """ The result of one of the TCP based host discovery methods.  Either
+    `OPEN` or `RESET` means the host is up. """
+    tcp_ping_result_type: TcpPingResultType
+    probe_sent: datetime
+    port: Optional[int]
+    time_received: Optional[datetime]
+    round_trip_time: Optional[timedelta]
+    error_kind: Optional[IoErrorKind]
+    error_message: Optional[str]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct TcpPingResult *")
+        self.tcp_ping_result_type = TcpPingResultType(internal.result_type)
+        self.probe_sent = _timestamp_to_datetime(internal.probe_sent)
+        if internal.time_received != ffi.NULL:
+            self.port = internal.port
+            self.time_received = \
+                _timestamp_to_datetime(internal.time_received)
+        else:
+            self.port = None
+            self.time_received = None
+        self.round_trip_time = _optional_duration(internal.round_trip_time)
+        if internal.error != ffi.NULL:
+            self.error_kind = IoErrorKind(internal.error.kind)
+            self.error_message = \
+                _vec_uint8_to_python_string(internal.error.message)
+        else:
+            self.error_kind = None
+            self.error_message = None
+
+    def host_up(self) -> bool:
+        return self.tcp_ping_result_type in (TcpPingResultType.OPEN,
+                                             TcpPingResultType.RESET)
+
 
-    def __init__(self, result_type: int):
-        self.ping_result_type = PingResultType(result_type)
+def _optional_tcp_ping(internal: _CDataBase) -> Optional[TcpPingResult]:
+    if internal != ffi.NULL:
+        return TcpPingResult(internal)
+    return None
+
+
+class ArpResultType(Enum):
+    REPLY = 0
+    TIMEOUT = 1
+    NOT_ON_LINK = 2
+    IO_ERROR = 3
+
+
+class ArpResult:
+    """ The result of ARP host discovery.  `mac` is formatted like
+    `aa:bb:cc:dd:ee:ff` and only set if the host replied. """
+    arp_result_type: ArpResultType
+    request_sent: datetime
+    mac: Optional[str]
+    time_received: Optional[datetime]
+    round_trip_time: Optional[timedelta]
+    error_kind: Optional[IoErrorKind]
+    error_message: Optional[str]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct ArpResult *")
+        self.arp_result_type = ArpResultType(internal.result_type)
+        self.request_sent = _timestamp_to_datetime(internal.request_sent)
+        if self.arp_result_type == ArpResultType.REPLY:
+            self.mac = ":".join(f"{octet:02x}" for octet in internal.mac)
+            self.time_received = \
+                _timestamp_to_datetime(internal.time_received)
+        else:
+            self.mac = None
+            self.time_received = None
+        self.round_trip_time = _optional_duration(internal.round_trip_time)
+        if internal.error != ffi.NULL:
+            self.error_kind = IoErrorKind(internal.error.kind)
+            self.error_message = \
+                _vec_uint8_to_python_string(internal.error.message)
+        else:
+            self.error_kind = None
+            self.error_message = None
+
+    def host_up(self) -> bool:
+        return self.arp_result_type == ArpResultType.REPLY
+
+
+class TracerouteHop:
+    """ A single hop along the path to a host.  `router` and
+    `round_trip_time` are only set if something answered. """
+    ttl: int
+    router: Optional[Union[IPv4Address, IPv6Address]]
+    round_trip_time: Optional[timedelta]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct TracerouteHop")
+        self.ttl = internal.ttl
+        if internal.router != ffi.NULL:
+            self.router = _ip_to_python_address(internal.router.ip)
+        else:
+            self.router = None
+        self.round_trip_time = _optional_duration(internal.round_trip_time)
+
+    def __str__(self):
+        if self.router is None:
+            return f"{self.ttl} *"
+        return f"{self.ttl} {self.router} {self.round_trip_time}"
+
+
+class Traceroute:
+    """ The route to a host.  If we never reached it the hops stop at the
+    last router that answered. """
+    hops: List[TracerouteHop]
+    reached_target: bool
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct Traceroute *")
+        self.hops = [TracerouteHop(internal.hops.ptr[i])
+                     for i in range(internal.hops.len)]
+        self.reached_target = internal.reached_target
+
+
+class OsFamily(Enum):
+    LINUX = 0
+    WINDOWS = 1
+    MAC_OS = 2
+    BSD = 3
+    SOLARIS = 4
+    NETWORK_DEVICE = 5
+
+
+class OsGuess:
+    """ Our best guess at the OS running on a host.  `initial_ttl` is the TTL
+    we think the host started its packets with, if we saw one. """
+    family: OsFamily
+    certainty: Certainty
+    initial_ttl: Optional[int]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct OsGuess *")
+        self.family = OsFamily(internal.family)
+        self.certainty = Certainty(internal.certainty)
+        self.initial_ttl = internal.initial_ttl or None
+
+    def __str__(self):
+        return f"{self.family.name.lower()} with {self.certainty} certainty"
 
 
 class ReportContents:
     ping_result: Optional[PingResult]
+    tcp_syn_ping: Optional[TcpPingResult]
+    tcp_ack_ping: Optional[TcpPingResult]
+    tcp_connect_ping: Optional[TcpPingResult]
+    arp_result: Optional[ArpResult]
+    traceroute: Optional[Traceroute]
+    os_guess: Optional[OsGuess]
     ports: Dict[int, PortReport]
 
     def __init__(self, internal: _CDataBase):
         assert ffi.typeof(internal) is ffi.typeof("ReportContents_t*")
         icmp = lib.get_icmp_result(internal)
         if icmp != ffi.NULL:
-            self.ping_result = PingResult(icmp.result_type)
+            self.ping_result = PingResult(icmp)
+        else:
+            self.ping_result = None
+        self.tcp_syn_ping = \
+            _optional_tcp_ping(lib.get_tcp_syn_ping_result(internal))
+        self.tcp_ack_ping = \
+            _optional_tcp_ping(lib.get_tcp_ack_ping_result(internal))
+        self.tcp_connect_ping = \
+            _optional_tcp_ping(lib.get_tcp_connect_ping_result(internal))
+        arp = lib.get_arp_result(internal)
+        if arp != ffi.NULL:
+            self.arp_result = ArpResult(arp)
+        else:
+            self.arp_result = None
+        traceroute = lib.get_traceroute(internal)
+        if traceroute != ffi.NULL:
+            self.traceroute = Traceroute(traceroute)
+        else:
+            self.traceroute = None
+        os_guess = lib.get_os_guess(internal)
+        if os_guess != ffi.NULL:
+            self.os_guess = OsGuess(os_guess)
+        else:
+            self.os_guess = None
 
         self.ports = {}
         ffi_port_buffer = ffi.gc(lib.get_ports(internal), lib.free_port_list)
@@ -100,16 +396,7 @@ class Report:
         assert ffi.typeof(internal) is ffi.typeof("Report_t*")
         self.target = Target(ffi.addressof(internal.target))
         if internal.instance.ip:
-            internal_ip = internal.instance.ip
-
-            ip_bytes = bytes(ffi.buffer(internal_ip.ptr, internal_ip.len))
-            if len(ip_bytes) == 4:
-                self.instance = IPv4Address(ip_bytes)
-            elif len(ip_bytes) == 16:
-                self.instance = IPv6Address(ip_bytes)
-            else:
-                raise RuntimeError("Internal failure. An IP was returned with "
-                                   " an invalid number of bytes")
+            self.instance = _ip_to_python_address(internal.instance.ip)
         else:
             self.instance = None
 
@@ -1,9 +1,12 @@
+from datetime import timedelta
 from enum import Enum
 from typing import Optional
 
 from _cffi_backend import _CDataBase  # type: ignore
-from bowbend._utils import _vec_uint8_to_python_string
+from bowbend._utils import _duration_to_timedelta, \
+    _vec_uint8_to_python_string
 
+from .error import IoErrorKind
 from .bowbend import ffi  # type: ignore # noqa # pylint: disable=import-error
 
 
@@ -25,10 +28,19 @@ class Certainty(Enum):
                 return "low"
 
 
+def _optional_string(internal: _CDataBase) -> Optional[str]:
+    if internal == ffi.NULL:
+        return None
+    return _vec_uint8_to_python_string(internal)
+
+
 class ServiceDetectionConclusion:
     certainty: Certainty
     service_name: str
     service_version: Optional[str]
+    vendor: Optional[str]
+    product: Optional[str]
+    cpe: Optional[str]
 
     def __init__(self, internal: _CDataBase):
         assert ffi.typeof(internal) is \
@@ -41,6 +53,9 @@ class ServiceDetectionConclusion:
                 _vec_uint8_to_python_string(internal.service_version)
         else:
             self.service_version = None
+        self.vendor = _optional_string(internal.vendor)
+        self.product = _optional_string(internal.product)
+        self.cpe = _optional_string(internal.cpe)
 
     def __str__(self) -> str:
         if self.service_version:
@@ -48,3 +63,88 @@ class ServiceDetectionConclusion:
                    f"{self.service_version} with {self.certainty} certainty"
 
         return f"running {self.service_name} with {self.certainty} certainty"
+
+
+class RuleFailureType(Enum):
+    TIMED_OUT = 0
+    IO_ERROR = 1
+    INTERNAL_ERROR = 2
+
+
+class RuleFailure:
+    failure_type: RuleFailureType
+    error_kind: Optional[IoErrorKind]
+    message: Optional[str]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct RuleFailure *")
+        self.failure_type = RuleFailureType(internal.failure_type)
+        if internal.error != ffi.NULL:
+            self.error_kind = IoErrorKind(internal.error.kind)
+            self.message = _vec_uint8_to_python_string(internal.error.message)
+        elif internal.message != ffi.NULL:
+            self.error_kind = None
+            self.message = _vec_uint8_to_python_string(internal.message)
+        else:
+            self.error_kind = None
+            self.message = None
+
+
+class FailedRule:
+    rule: str
+    failure: RuleFailure
+    attempts: int
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct FailedRule")
+        self.rule = _vec_uint8_to_python_string(internal.rule)
+        self.failure = RuleFailure(ffi.addressof(internal, "failure"))
+        self.attempts = internal.attempts
+
+
+class RuleEvidence:
+    rule: str
+    duration: timedelta
+    attempts: int
+    observed: Optional[str]
+    failure: Optional[RuleFailure]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct RuleEvidence")
+        self.rule = _vec_uint8_to_python_string(internal.rule)
+        self.duration = _duration_to_timedelta(internal.duration)
+        self.attempts = internal.attempts
+        if internal.observed != ffi.NULL:
+            self.observed = _vec_uint8_to_python_string(internal.observed)
+        else:
+            self.observed = None
+        if internal.failure != ffi.NULL:
+            self.failure = RuleFailure(internal.failure)
+        else:
+            self.failure = None
+
+
+class SkipReason(Enum):
+    NOT_APPLICABLE = 0
+    TOO_LOUD = 1
+    TOO_UNLIKELY = 2
+    REQUIRES_PRIVILEGE = 3
+    MISSING_DEPENDENCY = 4
+    DEPENDENCY_CYCLE = 5
+    DEPENDENCY_SKIPPED = 6
+    DEPENDENCY_FAILED = 7
+
+
+class SkippedRule:
+    rule: str
+    reason: SkipReason
+    dependency: Optional[str]
+
+    def __init__(self, internal: _CDataBase):
+        assert ffi.typeof(internal) is ffi.typeof("struct SkippedRule")
+        self.rule = _vec_uint8_to_python_string(internal.rule)
+        self.reason = SkipReason(internal.reason)
+        if internal.dependency != ffi.NULL:
+            self.dependency = _vec_uint8_to_python_string(internal.dependency)
+        else:
+            self.dependency = None